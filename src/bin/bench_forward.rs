@@ -0,0 +1,100 @@
+use std::{sync::Arc, time::{Duration, Instant}, collections::VecDeque};
+
+use bytes::{BytesMut, BufMut};
+use gw808::{service_forward::{ServiceForward, ForwardSimSender}, session_forward::forward_session::ForwardSession};
+use jt808::models::Jt808;
+use tokio::{net::{TcpListener, TcpStream}, io::AsyncReadExt};
+
+//转发扇出压测 用法: bench_forward [设备数] [转发连接数]
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let device_count: usize = args.get(1).and_then(|t| t.parse().ok()).unwrap_or(50000);
+    let client_count: usize = args.get(2).and_then(|t| t.parse().ok()).unwrap_or(4);
+
+    println!("devices:{} forward clients:{}", device_count, client_count);
+
+    //本地接收端 丢弃所有转发数据
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                while let Ok(n) = socket.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let service = Arc::new(ServiceForward::new(0, Duration::ZERO, Default::default()));
+    let sims: Vec<String> = (0..device_count).map(|i| format!("{:012}", 13800000000u64 + i as u64)).collect();
+
+    //设备上线
+    let start = Instant::now();
+    let mut senders: Vec<ForwardSimSender> = sims.iter().map(|sim| ServiceForward::get_forward_sender(&service, sim)).collect();
+    println!("device online: {:?}", start.elapsed());
+
+    //每个转发连接订阅全部sim
+    let mut sessions = Vec::new();
+    let start = Instant::now();
+    for _ in 0..client_count {
+        let (_reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let session = Arc::new(ForwardSession::new(writer));
+        session.handle_cmd(&service, 0x02, sims.clone()).await;
+        sessions.push(session);
+    }
+    println!("subscribe {}x{}: {:?}", client_count, device_count, start.elapsed());
+
+    let frames: Vec<VecDeque<Jt808>> = sims.iter().map(|sim| {
+        let mut packages = VecDeque::new();
+        packages.push_back(Jt808::from(make_0x0200(sim)));
+        packages
+    }).collect();
+
+    //首轮 每个设备一条0x0200
+    round(&mut senders, &frames, client_count, "first round").await;
+    round(&mut senders, &frames, client_count, "steady round").await;
+
+    //单个sim订阅变化 只影响一个设备会话
+    let start = Instant::now();
+    sessions[0].handle_cmd(&service, 0x03, vec![sims[0].clone()]).await;
+    sessions[0].handle_cmd(&service, 0x02, vec![sims[0].clone()]).await;
+    println!("subscription change: {:?}", start.elapsed());
+    round(&mut senders, &frames, client_count, "round after change").await;
+
+    for session in sessions {
+        session.close(&service).await;
+    }
+}
+
+async fn round(senders: &mut [ForwardSimSender], frames: &[VecDeque<Jt808>], client_count: usize, name: &str) {
+    let start = Instant::now();
+    for (sender, packages) in senders.iter_mut().zip(frames) {
+        sender.forward_send(0x0200, 0, packages).await;
+    }
+    let elapsed = start.elapsed();
+    let total = senders.len() * client_count;
+    println!("{}: {} sends in {:?} ({:.0} ns/send)", name, total, elapsed, elapsed.as_nanos() as f64 / total.max(1) as f64);
+}
+
+//2013版0x0200 位置基础信息28字节
+fn make_0x0200(sim: &str) -> bytes::Bytes {
+    let mut buf = BytesMut::with_capacity(48);
+    buf.put_u8(0x7e);
+    buf.put_u16(0x0200);
+    buf.put_u16(28);
+    for i in 0..6 {
+        buf.put_u8(u8::from_str_radix(&sim[i * 2..i * 2 + 2], 16).unwrap());
+    }
+    buf.put_u16(1);
+    buf.put_slice(&[0u8; 28]);
+    let xor = buf[1..].iter().fold(0u8, |acc, b| acc ^ b);
+    buf.put_u8(xor);
+    buf.put_u8(0x7e);
+    buf.freeze()
+}
@@ -1,20 +1,519 @@
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::{collections::HashMap, fs};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ConfigModel {
     pub address_device : String,
     pub address_http : String,
-    pub address_forward: String
+    pub address_forward: String,
+    //1078音视频接收 TCP/UDP
+    #[serde(default = "default_address_media")]
+    pub address_media: String,
+    //0x9101下发给终端的音视频服务器地址 为空时使用address_media
+    #[serde(default)]
+    pub address_media_public: String,
+    //最后一个观看者离开后延迟关闭码流(秒)
+    #[serde(default = "default_stream_grace")]
+    pub stream_grace: u64,
+    //录像保存目录
+    #[serde(default = "default_record_path")]
+    pub record_path: String,
+    //录像缓存最长分钟数 超过时按该值缓存
+    #[serde(default = "default_record_max_minutes")]
+    pub record_max_minutes: u32,
+    //终端文件上传(0x9206)FTP服务
+    #[serde(default = "default_address_ftp")]
+    pub address_ftp: String,
+    //0x9206下发给终端的FTP地址 为空时使用address_ftp
+    #[serde(default)]
+    pub address_ftp_public: String,
+    //被动模式数据端口范围 如30000-30100 为空时随机
+    #[serde(default)]
+    pub ftp_passive_ports: String,
+    //上传文件保存目录
+    #[serde(default = "default_upload_path")]
+    pub upload_path: String,
+    //视频报警联动的报警类型 逗号分隔 为空时不联动
+    #[serde(default)]
+    pub video_alarm_trigger: String,
+    //联动动作 record:录像 snapshot:拍照 逗号分隔
+    #[serde(default = "default_video_alarm_action")]
+    pub video_alarm_action: String,
+    //联动录像时长(秒)
+    #[serde(default = "default_video_alarm_seconds")]
+    pub video_alarm_seconds: u64,
+    //报警没有通道时(如异常驾驶)联动的通道
+    #[serde(default = "default_video_alarm_channel")]
+    pub video_alarm_channel: u8,
+    //主动安全报警附件服务(0x9208)
+    #[serde(default = "default_address_attachment")]
+    pub address_attachment: String,
+    //0x9208下发给终端的附件服务地址 为空时使用address_attachment
+    #[serde(default)]
+    pub address_attachment_public: String,
+    //报警附件保存目录
+    #[serde(default = "default_attachment_path")]
+    pub attachment_path: String,
+    //区域/线路定义存储文件
+    #[serde(default = "default_geofence_path")]
+    pub geofence_path: String,
+    //历史轨迹保存目录
+    #[serde(default = "default_history_path")]
+    pub history_path: String,
+    //历史轨迹保存天数 0:不限制
+    #[serde(default = "default_history_days")]
+    pub history_days: u32,
+    //历史轨迹总大小上限(MB) 0:不限制
+    #[serde(default)]
+    pub history_max_size: u64,
+    //行程分段 行驶速度阈值(km/h)
+    #[serde(default = "default_trip_min_speed")]
+    pub trip_min_speed: f64,
+    //停留超过该时间(秒)行程结束
+    #[serde(default = "default_trip_stop_seconds")]
+    pub trip_stop_seconds: i64,
+    //ACC关时结束行程 终端不上报ACC状态时关闭
+    #[serde(default = "default_trip_use_acc")]
+    pub trip_use_acc: bool,
+    //设备事件HTTP推送地址
+    #[serde(default, rename = "webhook")]
+    pub webhooks: Vec<WebhookConfig>,
+    //推送失败后的重试次数
+    #[serde(default = "default_webhook_retries")]
+    pub webhook_retries: u32,
+    //每个推送地址的队列长度
+    #[serde(default = "default_webhook_queue_size")]
+    pub webhook_queue_size: usize,
+    //重试失败或队列满的事件保存文件
+    #[serde(default = "default_webhook_dead_letter")]
+    pub webhook_dead_letter: String,
+    //JT/T 809上级平台
+    #[serde(default)]
+    pub jt809: Option<Jt809Config>,
+    //MQTT桥接 需要编译feature mqtt
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    //平台侧超速/疲劳驾驶检测规则
+    #[serde(default, rename = "driving_rule")]
+    pub driving_rules: Vec<DrivingRuleConfig>,
+    //主动连接的上级转发平台
+    #[serde(default, rename = "forward_target")]
+    pub forward_targets: Vec<ForwardTargetConfig>,
+    //允许登录的转发客户端
+    #[serde(default, rename = "forward_client")]
+    pub forward_clients: Vec<ForwardClientConfig>,
+    //转发客户端断线缓存最大字节数 0:不缓存
+    #[serde(default)]
+    pub forward_buffer_size: usize,
+    //断线缓存保存时长(秒)
+    #[serde(default = "default_forward_buffer_age")]
+    pub forward_buffer_age: u64,
+    //808透传的第三方平台
+    #[serde(default, rename = "passthrough_target")]
+    pub passthrough_targets: Vec<PassthroughTargetConfig>,
+    //透传平台鉴权码存储文件
+    #[serde(default = "default_passthrough_credentials")]
+    pub passthrough_credentials: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardClientConfig {
+    pub client_id: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardTargetConfig {
+    pub address: String,
+    #[serde(default)]
+    pub client_id: String,
+    #[serde(default)]
+    pub password: String,
+    //转发的sim 逗号分隔 *表示全部
+    #[serde(default)]
+    pub sims: String,
+    //消息过滤 0:不过滤 1:包含 2:排除
+    #[serde(default)]
+    pub filter_mode: u8,
+    //消息ID 16进制逗号分隔 如0200,1205
+    #[serde(default)]
+    pub filter_ids: String,
+    //0x0200仅转发报警
+    #[serde(default)]
+    pub alarm_only: bool,
+}
+
+impl ForwardTargetConfig {
+    pub fn get_sims(&self) -> Vec<String> {
+        split_list(&self.sims).map(|t| t.to_string()).collect()
+    }
+
+    pub fn get_filter_ids(&self) -> Vec<u16> {
+        split_list(&self.filter_ids)
+            .filter_map(|t| u16::from_str_radix(t.trim_start_matches("0x"), 16).ok())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrivingRuleConfig {
+    //适用的sim 逗号分隔 为空时作为默认规则
+    #[serde(default)]
+    pub sims: String,
+    //限速(km/h) 0:不检测超速
+    #[serde(default)]
+    pub speed_limit: u16,
+    //超速持续时间(秒)
+    #[serde(default = "default_overspeed_seconds")]
+    pub overspeed_seconds: u64,
+    //连续驾驶时间(分钟) 0:不检测疲劳驾驶
+    #[serde(default)]
+    pub max_driving_minutes: u64,
+    //最小休息时间(分钟) 停车达到该时间后重新计算连续驾驶
+    #[serde(default = "default_min_rest_minutes")]
+    pub min_rest_minutes: u64,
+    //产生报警时下发0x8300提醒驾驶员
+    #[serde(default)]
+    pub text_warning: bool,
+}
+
+impl DrivingRuleConfig {
+    pub fn get_sims(&self) -> Vec<String> {
+        split_list(&self.sims).map(|t| t.to_string()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    //事件类型 逗号分隔 为空时推送全部
+    #[serde(default)]
+    pub types: String,
+    //sim 逗号分隔 为空时推送全部
+    #[serde(default)]
+    pub sims: String,
+    //HMAC-SHA256签名密钥 为空时不签名
+    #[serde(default)]
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jt809Config {
+    //上级平台主链路地址
+    pub address: String,
+    pub user_id: u32,
+    pub password: String,
+    //下级平台接入码
+    pub center_id: u32,
+    //从链路监听地址
+    pub address_down: String,
+    //登录时告知上级的从链路地址 为空时使用address_down
+    #[serde(default)]
+    pub address_down_public: String,
+    //加密 M1 IA1 IC1
+    #[serde(default)]
+    pub encrypt: bool,
+    #[serde(default)]
+    pub m1: u32,
+    #[serde(default)]
+    pub ia1: u32,
+    #[serde(default)]
+    pub ic1: u32,
+    //主链路心跳间隔(秒)
+    #[serde(default = "default_jt809_heartbeat")]
+    pub heartbeat: u64,
+    //sim=车牌号:车牌颜色 逗号分隔 如013800000000=粤B12345:2
+    #[serde(default)]
+    pub vehicles: String,
+    //从终端注册信息得到的车牌保存文件 重启后终端只鉴权不注册时使用
+    #[serde(default = "default_jt809_vehicles_path")]
+    pub vehicles_path: String,
+}
+
+impl Jt809Config {
+    pub fn get_vehicles(&self) -> HashMap<String, (String, u8)> {
+        split_list(&self.vehicles)
+            .filter_map(|t| {
+                let (sim, vehicle) = t.split_once('=')?;
+                let (plate, color) = vehicle.rsplit_once(':')?;
+                Some((sim.trim().to_string(), (plate.trim().to_string(), color.trim().parse().ok()?)))
+            })
+            .collect()
+    }
+
+    /// 从链路IP和端口
+    pub fn get_down_public(&self) -> (String, u16) {
+        let address = if self.address_down_public.is_empty() { &self.address_down } else { &self.address_down_public };
+        match address.rsplit_once(':') {
+            Some((ip, port)) => (ip.to_string(), port.parse().unwrap_or(0)),
+            None => (address.to_string(), 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttConfig {
+    //broker地址 host:port
+    pub address: String,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    //0 1 2
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+    //原始消息格式 json hex
+    #[serde(default = "default_mqtt_format")]
+    pub format: String,
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    //发布的原始消息ID 16进制逗号分隔 为空时全部发布
+    #[serde(default)]
+    pub frame_ids: String,
+    //发布的事件类型 逗号分隔 为空时全部发布
+    #[serde(default)]
+    pub events: String,
+}
+
+impl MqttConfig {
+    pub fn get_frame_ids(&self) -> Vec<u16> {
+        split_list(&self.frame_ids)
+            .filter_map(|t| u16::from_str_radix(t.trim_start_matches("0x"), 16).ok())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PassthroughTargetConfig {
+    pub address: String,
+    //透传的sim 逗号分隔
+    #[serde(default)]
+    pub sims: String,
+}
+
+impl PassthroughTargetConfig {
+    pub fn get_sims(&self) -> Vec<String> {
+        split_list(&self.sims).map(|t| t.to_string()).collect()
+    }
+}
+
+fn default_address_media() -> String {
+    "127.0.0.1:20891".to_owned()
+}
+
+fn default_stream_grace() -> u64 {
+    10
+}
+
+fn default_record_path() -> String {
+    "record".to_owned()
+}
+
+fn default_record_max_minutes() -> u32 {
+    30
+}
+
+fn default_address_ftp() -> String {
+    "127.0.0.1:20821".to_owned()
+}
+
+fn default_upload_path() -> String {
+    "upload".to_owned()
+}
+
+fn default_video_alarm_action() -> String {
+    "record".to_owned()
+}
+
+fn default_video_alarm_seconds() -> u64 {
+    30
+}
+
+fn default_video_alarm_channel() -> u8 {
+    1
+}
+
+fn default_address_attachment() -> String {
+    "127.0.0.1:20822".to_owned()
+}
+
+fn default_attachment_path() -> String {
+    "attachment".to_owned()
+}
+
+fn default_geofence_path() -> String {
+    "Geofences.json".to_owned()
+}
+
+fn default_history_path() -> String {
+    "history".to_owned()
+}
+
+fn default_history_days() -> u32 {
+    30
+}
+
+fn default_trip_min_speed() -> f64 {
+    5.0
+}
+
+fn default_trip_stop_seconds() -> i64 {
+    180
+}
+
+fn default_trip_use_acc() -> bool {
+    true
+}
+
+fn default_webhook_retries() -> u32 {
+    5
+}
+
+fn default_webhook_queue_size() -> usize {
+    1000
+}
+
+fn default_webhook_dead_letter() -> String {
+    "WebhookDeadLetter.jsonl".to_owned()
+}
+
+fn default_jt809_vehicles_path() -> String {
+    "Jt809Vehicles.json".to_owned()
+}
+
+fn default_jt809_heartbeat() -> u64 {
+    60
+}
+
+fn default_mqtt_client_id() -> String {
+    "gw808".to_owned()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+fn default_mqtt_format() -> String {
+    "json".to_owned()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "jt808".to_owned()
+}
+
+fn default_overspeed_seconds() -> u64 {
+    10
+}
+
+fn default_min_rest_minutes() -> u64 {
+    20
+}
+
+fn default_forward_buffer_age() -> u64 {
+    600
+}
+
+fn default_passthrough_credentials() -> String {
+    "PassthroughCredentials.json".to_owned()
+}
+
+fn split_list(s:&str) -> impl Iterator<Item = &str> {
+    s.split(',').map(|t| t.trim()).filter(|t| !t.is_empty())
 }
 
 
 impl ConfigModel {
+    pub fn get_media_public(&self) -> &str {
+        if self.address_media_public.is_empty() {
+            &self.address_media
+        } else {
+            &self.address_media_public
+        }
+    }
+
+    pub fn get_ftp_public(&self) -> &str {
+        if self.address_ftp_public.is_empty() {
+            &self.address_ftp
+        } else {
+            &self.address_ftp_public
+        }
+    }
+
+    pub fn get_attachment_public(&self) -> &str {
+        if self.address_attachment_public.is_empty() {
+            &self.address_attachment
+        } else {
+            &self.address_attachment_public
+        }
+    }
+
+    /// 转发客户端ID -> 密码
+    pub fn get_forward_credentials(&self) -> HashMap<String, String> {
+        self.forward_clients.iter().map(|t| (t.client_id.clone(), t.password.clone())).collect()
+    }
+
+    pub fn get_video_alarm_trigger(&self) -> Vec<String> {
+        split_list(&self.video_alarm_trigger).map(|t| t.to_string()).collect()
+    }
+
+    pub fn get_video_alarm_action(&self) -> Vec<String> {
+        split_list(&self.video_alarm_action).map(|t| t.to_string()).collect()
+    }
+
+    /// 被动模式端口范围
+    pub fn get_ftp_passive_ports(&self) -> Option<(u16, u16)> {
+        let (start, end) = self.ftp_passive_ports.split_once('-')?;
+        let start = start.trim().parse().ok()?;
+        let end = end.trim().parse().ok()?;
+        if start > end {
+            return None;
+        }
+        Some((start, end))
+    }
+
     fn default() -> Self {
         ConfigModel { 
             address_device:"127.0.0.1:20888".to_owned(),
             address_http:"127.0.0.1:20889".to_owned(),
-            address_forward:"127.0.0.1:20890".to_owned()
+            address_forward:"127.0.0.1:20890".to_owned(),
+            address_media:default_address_media(),
+            address_media_public:String::new(),
+            stream_grace:default_stream_grace(),
+            record_path:default_record_path(),
+            record_max_minutes:default_record_max_minutes(),
+            address_ftp:default_address_ftp(),
+            address_ftp_public:String::new(),
+            ftp_passive_ports:String::new(),
+            upload_path:default_upload_path(),
+            video_alarm_trigger:String::new(),
+            video_alarm_action:default_video_alarm_action(),
+            video_alarm_seconds:default_video_alarm_seconds(),
+            video_alarm_channel:default_video_alarm_channel(),
+            address_attachment:default_address_attachment(),
+            address_attachment_public:String::new(),
+            attachment_path:default_attachment_path(),
+            geofence_path:default_geofence_path(),
+            history_path:default_history_path(),
+            history_days:default_history_days(),
+            history_max_size:0,
+            trip_min_speed:default_trip_min_speed(),
+            trip_stop_seconds:default_trip_stop_seconds(),
+            trip_use_acc:default_trip_use_acc(),
+            webhooks:Vec::new(),
+            webhook_retries:default_webhook_retries(),
+            webhook_queue_size:default_webhook_queue_size(),
+            webhook_dead_letter:default_webhook_dead_letter(),
+            jt809:None,
+            mqtt:None,
+            driving_rules:Vec::new(),
+            forward_targets:Vec::new(),
+            forward_clients:Vec::new(),
+            forward_buffer_size:0,
+            forward_buffer_age:default_forward_buffer_age(),
+            passthrough_targets:Vec::new(),
+            passthrough_credentials:default_passthrough_credentials(),
         }
     }
     pub fn read(path:String) -> Result<ConfigModel, std::io::Error> {
@@ -32,6 +531,9 @@ impl ConfigModel {
         
         let str = std::str::from_utf8(&bts).unwrap();
         let config : ConfigModel = serde_xml_rs::from_str(str).unwrap();
+        if config.jt809.as_ref().is_some_and(|t| t.heartbeat == 0) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "jt809 heartbeat must be greater than 0"));
+        }
         
         Ok(config)
     }
@@ -0,0 +1,34 @@
+pub mod session808;
+pub mod session1078;
+pub mod media;
+pub mod session_forward;
+pub mod session_passthrough;
+pub mod session809;
+
+pub mod config_model;
+pub mod service_device;
+pub mod service_http;
+pub mod service_forward;
+pub mod service_passthrough;
+pub mod service_media;
+pub mod service_live;
+pub mod service_hls;
+pub mod service_playback;
+pub mod service_upload;
+pub mod service_ftp;
+pub mod service_event;
+pub mod service_avinfo;
+pub mod service_ptz;
+pub mod service_intercom;
+pub mod service_alarm;
+pub mod service_geofence;
+pub mod service_driving;
+pub mod service_track;
+pub mod service_trip;
+pub mod service_webhook;
+pub mod service_809;
+#[cfg(feature = "mqtt")]
+pub mod service_mqtt;
+pub mod service_video_alarm;
+pub mod service_safety;
+pub mod service_attachment;
@@ -1,12 +1,6 @@
 use std::{time::Duration, sync::Arc};
 
-mod session808;
-mod session_forward;
-
-mod config_model;
-mod service_device;
-mod service_http;
-mod service_forward;
+use gw808::{config_model, service_device, service_http, service_forward, service_passthrough, service_media, service_live, service_hls, service_playback, service_upload, service_ftp, service_event, service_webhook, service_809, service_alarm, service_geofence, service_driving, service_track, service_trip, service_avinfo, service_intercom, service_video_alarm, service_safety, service_attachment};
 
 
 #[tokio::main]
@@ -18,15 +12,69 @@ async fn main() {
     log4rs::init_file("log4rs.yml", Default::default()).unwrap();
 
     //启动转发服务
-    let fw_service = Arc::new(service_forward::ServiceForward::new());
+    let fw_service = Arc::new(service_forward::ServiceForward::new(config.forward_buffer_size, Duration::from_secs(config.forward_buffer_age), config.get_forward_credentials()));
     let _ = service_forward::ServiceForward::start(fw_service.clone(), &config.address_forward).await;
+    service_forward::ServiceForward::start_targets(fw_service.clone(), &config.forward_targets);
+
+    //启动1078音视频接收
+    let media_service = Arc::new(service_media::ServiceMedia::new());
+    if let Err(err) = service_media::ServiceMedia::start(media_service.clone(), &config.address_media).await {
+        log::error!("[service-media]start failed:{}", err);
+    }
+
+    //终端文件上传FTP
+    let upload_service = Arc::new(service_upload::ServiceUpload::new(config.get_ftp_public(), &config.upload_path));
+    let ftp_service = Arc::new(service_ftp::ServiceFtp::new(upload_service.clone(), config.get_ftp_public(), config.get_ftp_passive_ports()));
+    if let Err(err) = service_ftp::ServiceFtp::start(ftp_service, &config.address_ftp).await {
+        log::error!("[service-ftp]start failed:{}", err);
+    }
+
+    //主动安全报警附件
+    service_safety::init(config.get_attachment_public(), &config.attachment_path);
+    if let Err(err) = service_attachment::start(&config.address_attachment).await {
+        log::error!("[service-attachment]start failed:{}", err);
+    }
+
+    //透传服务
+    let pt_service = Arc::new(service_passthrough::ServicePassthrough::new(config.passthrough_targets.clone(), config.passthrough_credentials.clone()));
 
     //启动设备服务
     service_device::init();
-    let _ = service_device::start(&config.address_device, fw_service.clone()).await;
+    service_event::init();
+    service_webhook::init(config.webhooks.clone(), config.webhook_retries, config.webhook_queue_size, &config.webhook_dead_letter);
+    if let Some(jt809) = &config.jt809 {
+        service_809::init(jt809);
+    }
+    match &config.mqtt {
+        #[cfg(feature = "mqtt")]
+        Some(mqtt) => gw808::service_mqtt::init(mqtt),
+        #[cfg(not(feature = "mqtt"))]
+        Some(_) => log::warn!("[service-mqtt]mqtt configured but feature mqtt not enabled"),
+        None => {},
+    }
+    service_alarm::init();
+    service_geofence::init(&config.geofence_path);
+    service_driving::init(config.driving_rules.clone());
+    service_trip::init(service_trip::TripRule { min_speed: config.trip_min_speed, stop_seconds: config.trip_stop_seconds, use_acc: config.trip_use_acc });
+    service_track::init(&config.history_path, config.history_days, config.history_max_size * 1024 * 1024);
+    service_avinfo::init();
+    service_video_alarm::init();
+    let _ = service_device::start(&config.address_device, fw_service.clone(), pt_service.clone()).await;
 
     //启动http服务
-    service_http::start(&config.address_http).await;
+    let live_service = Arc::new(service_live::ServiceLive::new(media_service.clone(), config.get_media_public(), Duration::from_secs(config.stream_grace)));
+    let hls_service = Arc::new(service_hls::ServiceHls::new(live_service.clone(), config.record_path.clone(), config.record_max_minutes));
+    service_video_alarm::set_trigger(service_video_alarm::VideoAlarmTrigger::new(hls_service.clone(), config.get_video_alarm_trigger(), &config.get_video_alarm_action(), config.video_alarm_seconds, config.video_alarm_channel));
+    let playback_service = Arc::new(service_playback::ServicePlayback::new(live_service.clone()));
+    let intercom_service = Arc::new(service_intercom::ServiceIntercom::new(live_service.clone()));
+    let context = Arc::new(service_http::HttpContext {
+        live: live_service,
+        hls: hls_service,
+        playback: playback_service,
+        upload: upload_service,
+        intercom: intercom_service,
+    });
+    service_http::start(&config.address_http, context).await;
 
     //let _ = service_device::send(&"111221122".to_owned());
 
@@ -0,0 +1,241 @@
+//G.711A/G.711U/ADPCM编解码 1078音频负载类型见JT/T 1078表12
+
+use super::g726::{g726_decode, g726_encode, G726State};
+
+pub const PT_G711A: u8 = 6;
+pub const PT_G711U: u8 = 7;
+pub const PT_G726: u8 = 8;
+pub const PT_AAC: u8 = 19;
+pub const PT_ADPCMA: u8 = 26;
+
+const SEG_AEND: [i32; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+
+pub fn alaw_encode(pcm:i16) -> u8 {
+    let mut pcm = (pcm as i32) >> 3;
+    let mask = if pcm >= 0 {
+        0xD5
+    } else {
+        pcm = -pcm - 1;
+        0x55
+    };
+
+    let seg = SEG_AEND.iter().position(|t| pcm <= *t).unwrap_or(8) as i32;
+    if seg >= 8 {
+        return 0x7Fu8 ^ mask;
+    }
+
+    let mut aval = seg << 4;
+    if seg < 2 {
+        aval |= (pcm >> 1) & 0x0F;
+    } else {
+        aval |= (pcm >> seg) & 0x0F;
+    }
+    (aval as u8) ^ mask
+}
+
+pub fn alaw_decode(alaw:u8) -> i16 {
+    let a = alaw ^ 0x55;
+    let mut t = ((a & 0x0F) as i32) << 4;
+    let seg = (a & 0x70) >> 4;
+    match seg {
+        0 => t += 8,
+        1 => t += 0x108,
+        _ => {
+            t += 0x108;
+            t <<= seg - 1;
+        }
+    }
+    if a & 0x80 > 0 { t as i16 } else { -t as i16 }
+}
+
+const SEG_UEND: [i32; 8] = [0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF, 0x1FFF];
+const ULAW_BIAS: i32 = 0x84;
+
+pub fn ulaw_encode(pcm:i16) -> u8 {
+    let mut pcm = (pcm as i32) >> 2;
+    let mask = if pcm < 0 {
+        pcm = -pcm;
+        0x7F
+    } else {
+        0xFF
+    };
+    pcm = pcm.min(8159) + (ULAW_BIAS >> 2);
+
+    let seg = SEG_UEND.iter().position(|t| pcm <= *t).unwrap_or(8) as i32;
+    if seg >= 8 {
+        return 0x7Fu8 ^ mask;
+    }
+    (((seg << 4) | ((pcm >> (seg + 1)) & 0x0F)) as u8) ^ mask
+}
+
+pub fn ulaw_decode(ulaw:u8) -> i16 {
+    let u = !ulaw;
+    let t = ((((u & 0x0F) as i32) << 3) + ULAW_BIAS) << ((u & 0x70) >> 4);
+    if u & 0x80 > 0 { (ULAW_BIAS - t) as i16 } else { (t - ULAW_BIAS) as i16 }
+}
+
+const ADPCM_INDEX: [i32; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+const ADPCM_STEP: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45,
+    50, 55, 60, 66, 73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307,
+    337, 371, 408, 449, 494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066,
+    2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493, 10442, 11487, 12635, 13899,
+    15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+/// IMA ADPCM状态
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AdpcmState {
+    pub predict: i32,
+    pub index: i32,
+}
+
+impl AdpcmState {
+    fn decode_nibble(&mut self, n:u8) -> i16 {
+        let step = ADPCM_STEP[self.index as usize];
+        let mut diff = step >> 3;
+        if n & 4 > 0 { diff += step; }
+        if n & 2 > 0 { diff += step >> 1; }
+        if n & 1 > 0 { diff += step >> 2; }
+        if n & 8 > 0 {
+            self.predict -= diff;
+        } else {
+            self.predict += diff;
+        }
+        self.predict = self.predict.clamp(-32768, 32767);
+        self.index = (self.index + ADPCM_INDEX[n as usize]).clamp(0, 88);
+        self.predict as i16
+    }
+
+    fn encode_sample(&mut self, pcm:i16) -> u8 {
+        let step = ADPCM_STEP[self.index as usize];
+        let mut diff = pcm as i32 - self.predict;
+        let mut n = 0u8;
+        if diff < 0 {
+            n = 8;
+            diff = -diff;
+        }
+        if diff >= step { n |= 4; diff -= step; }
+        if diff >= step >> 1 { n |= 2; diff -= step >> 1; }
+        if diff >= step >> 2 { n |= 1; }
+        //与解码保持一致
+        self.decode_nibble(n);
+        n
+    }
+}
+
+/// 去掉海思音频头 [00 01 长度/2 00]
+pub fn strip_hisi(data:&[u8]) -> &[u8] {
+    if data.len() >= 4 && data[0] == 0 && data[1] == 1 && data[3] == 0 && (data[2] as usize) * 2 == data.len() - 4 {
+        return &data[4..];
+    }
+    data
+}
+
+/// 1078 ADPCMA 4字节状态头(预测值LE 步长索引 保留)+4bit采样 低位在前
+pub fn adpcm_decode(data:&[u8]) -> Vec<i16> {
+    let data = strip_hisi(data);
+    if data.len() < 4 {
+        return Vec::new();
+    }
+    let mut state = AdpcmState {
+        predict: i16::from_le_bytes([data[0], data[1]]) as i32,
+        index: (data[2] as i32).clamp(0, 88),
+    };
+
+    let mut pcm = Vec::with_capacity((data.len() - 4) * 2);
+    for b in &data[4..] {
+        pcm.push(state.decode_nibble(b & 0x0f));
+        pcm.push(state.decode_nibble(b >> 4));
+    }
+    pcm
+}
+
+/// 编码为1078 ADPCMA 带海思头 state在多帧之间延续
+pub fn adpcm_encode(state:&mut AdpcmState, pcm:&[i16]) -> Vec<u8> {
+    let body_len = 4 + pcm.len().div_ceil(2);
+    let mut data = Vec::with_capacity(4 + body_len);
+    data.extend_from_slice(&[0x00, 0x01, (body_len / 2) as u8, 0x00]);
+    data.extend_from_slice(&(state.predict as i16).to_le_bytes());
+    data.push(state.index as u8);
+    data.push(0);
+    for t in pcm.chunks(2) {
+        let low = state.encode_sample(t[0]);
+        let high = if t.len() > 1 { state.encode_sample(t[1]) } else { 0 };
+        data.push(low | (high << 4));
+    }
+    data
+}
+
+pub fn g711a_to_pcm(data:&[u8]) -> Vec<i16> {
+    strip_hisi(data).iter().map(|t| alaw_decode(*t)).collect()
+}
+
+pub fn pcm_to_g711a(pcm:&[i16]) -> Vec<u8> {
+    pcm.iter().map(|t| alaw_encode(*t)).collect()
+}
+
+/// 对讲用的8kHz单声道PCM与终端音频编码互转 编解码状态在多帧之间延续
+pub struct AudioTranscoder {
+    pub pt: u8,
+    adpcm: AdpcmState,
+    g726_enc: G726State,
+    g726_dec: G726State,
+}
+
+impl AudioTranscoder {
+    /// 支持G.711A/G.711U/G.726(32kbit/s)/ADPCMA 其它编码返回None
+    pub fn new(pt:u8) -> Option<Self> {
+        if !matches!(pt, PT_G711A | PT_G711U | PT_G726 | PT_ADPCMA) {
+            return None;
+        }
+        Some(AudioTranscoder {
+            pt,
+            adpcm: AdpcmState::default(),
+            g726_enc: G726State::new(),
+            g726_dec: G726State::new(),
+        })
+    }
+
+    pub fn encode(&mut self, pcm:&[i16]) -> Vec<u8> {
+        match self.pt {
+            PT_G711A => pcm_to_g711a(pcm),
+            PT_G711U => pcm.iter().map(|t| ulaw_encode(*t)).collect(),
+            PT_G726 => g726_encode(&mut self.g726_enc, pcm),
+            _ => adpcm_encode(&mut self.adpcm, pcm),
+        }
+    }
+
+    /// 终端上行音频 编码与本会话不同时按帧内的负载类型解码
+    pub fn decode(&mut self, pt:u8, data:&[u8]) -> Vec<i16> {
+        match pt {
+            PT_G711A => g711a_to_pcm(data),
+            PT_G711U => strip_hisi(data).iter().map(|t| ulaw_decode(*t)).collect(),
+            PT_G726 => g726_decode(&mut self.g726_dec, strip_hisi(data)),
+            PT_ADPCMA => adpcm_decode(data),
+            _ => Vec::new(),
+        }
+    }
+}
+
+
+#[test]
+fn test_alaw_and_adpcm()
+{
+    for pcm in [0i16, 100, -100, 1000, -1000, 30000, -30000, i16::MIN, i16::MAX] {
+        let t = alaw_decode(alaw_encode(pcm));
+        assert!((t as i32 - pcm as i32).abs() <= (pcm as i32).abs() / 16 + 16, "{} {}", pcm, t);
+        let t = ulaw_decode(ulaw_encode(pcm));
+        assert!((t as i32 - pcm as i32).abs() <= (pcm as i32).abs() / 16 + 16, "{} {}", pcm, t);
+    }
+
+    let pcm: Vec<i16> = (0..320).map(|i| ((i as f64 / 8.0).sin() * 8000.0) as i16).collect();
+    let mut state = AdpcmState::default();
+    let data = adpcm_encode(&mut state, &pcm);
+    assert_eq!(data.len(), 4 + 4 + 160);
+    let out = adpcm_decode(&data);
+    assert_eq!(out.len(), 320);
+    let err: i64 = pcm.iter().zip(&out).map(|(a, b)| (*a as i64 - *b as i64).abs()).sum::<i64>() / 320;
+    assert!(err < 500, "{}", err);
+}
@@ -0,0 +1,197 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::session1078::jt1078_parse::MediaFrame;
+
+use super::{audio::{self, PT_AAC, PT_ADPCMA, PT_G711A, PT_G711U}, nalu};
+
+//1078帧封装为FLV H.265使用扩展codec id 12
+//G.711A/G.711U直接封装 ADPCMA转为G.711A AAC去掉ADTS头
+
+const PT_H264: u8 = 98;
+const PT_H265: u8 = 99;
+
+pub struct FlvMuxer {
+    base_ts: Option<u64>,
+    video_config: Option<Bytes>,
+    audio_config_sent: bool,
+    //收到关键帧前不输出视频
+    wait_key: bool,
+}
+
+impl FlvMuxer {
+    pub fn new() -> Self {
+        FlvMuxer {
+            base_ts: None,
+            video_config: None,
+            audio_config_sent: false,
+            wait_key: true,
+        }
+    }
+
+    /// FLV文件头 含音频和视频
+    pub fn header() -> Bytes {
+        Bytes::from_static(&[0x46, 0x4c, 0x56, 0x01, 0x05, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00])
+    }
+
+    /// 丢帧后等待下一个关键帧
+    pub fn reset_key(&mut self) {
+        self.wait_key = true;
+    }
+
+    pub fn mux(&mut self, frame:&MediaFrame) -> Vec<Bytes> {
+        let ts = match self.base_ts {
+            Some(base) => frame.time_stamp.saturating_sub(base),
+            None => {
+                self.base_ts = Some(frame.time_stamp);
+                0
+            }
+        } as u32;
+
+        if frame.is_video() {
+            self.mux_video(frame, ts)
+        } else if frame.is_audio() {
+            self.mux_audio(frame, ts)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn mux_video(&mut self, frame:&MediaFrame, ts:u32) -> Vec<Bytes> {
+        let (codec_id, hevc) = match frame.pt {
+            PT_H264 => (7u8, false),
+            PT_H265 => (12u8, true),
+            _ => return Vec::new(),
+        };
+
+        let mut tags = Vec::new();
+        let nalus = nalu::split_annexb(&frame.data);
+
+        if frame.is_key() {
+            let config = if hevc { nalu::hevc_config(&nalus) } else { nalu::avc_config(&nalus) };
+            if let Some(config) = config {
+                if self.video_config.as_ref() != Some(&config) {
+                    let mut body = BytesMut::with_capacity(config.len() + 5);
+                    body.put_u8(0x10 | codec_id);
+                    body.put_u8(0);
+                    body.put_slice(&[0, 0, 0]);
+                    body.put_slice(&config);
+                    tags.push(flv_tag(9, ts, &body));
+                    self.video_config = Some(config);
+                }
+                self.wait_key = false;
+            }
+        }
+        if self.wait_key || self.video_config.is_none() {
+            return tags;
+        }
+
+        let mut body = BytesMut::with_capacity(frame.data.len() + 16);
+        body.put_u8(if frame.is_key() { 0x10 } else { 0x20 } | codec_id);
+        body.put_u8(1);
+        body.put_slice(&[0, 0, 0]);
+        for nal in nalus {
+            if nalu::is_param_set(nal, hevc) {
+                continue;
+            }
+            body.put_u32(nal.len() as u32);
+            body.put_slice(nal);
+        }
+        tags.push(flv_tag(9, ts, &body));
+        tags
+    }
+
+    fn mux_audio(&mut self, frame:&MediaFrame, ts:u32) -> Vec<Bytes> {
+        let mut tags = Vec::new();
+        match frame.pt {
+            PT_G711A | PT_G711U => {
+                let data = audio::strip_hisi(&frame.data);
+                let mut body = BytesMut::with_capacity(data.len() + 1);
+                body.put_u8(if frame.pt == PT_G711A { 0x72 } else { 0x82 });
+                body.put_slice(data);
+                tags.push(flv_tag(8, ts, &body));
+            },
+            PT_ADPCMA => {
+                let pcm = audio::adpcm_decode(&frame.data);
+                let mut body = BytesMut::with_capacity(pcm.len() + 1);
+                body.put_u8(0x72);
+                body.put_slice(&audio::pcm_to_g711a(&pcm));
+                tags.push(flv_tag(8, ts, &body));
+            },
+            PT_AAC => {
+                let data = &frame.data;
+                if data.len() < 7 || data[0] != 0xff || data[1] & 0xf0 != 0xf0 {
+                    return tags;
+                }
+                if !self.audio_config_sent {
+                    let profile = (data[2] >> 6) + 1;
+                    let sr_index = (data[2] >> 2) & 0x0f;
+                    let channels = ((data[2] & 0x01) << 2) | (data[3] >> 6);
+                    let config = [(profile << 3) | (sr_index >> 1), ((sr_index & 0x01) << 7) | (channels << 3)];
+                    tags.push(flv_tag(8, ts, &[0xaf, 0x00, config[0], config[1]]));
+                    self.audio_config_sent = true;
+                }
+                let head_len = if data[1] & 0x01 == 0 { 9 } else { 7 };
+                let mut body = BytesMut::with_capacity(data.len());
+                body.put_slice(&[0xaf, 0x01]);
+                body.put_slice(&data[head_len.min(data.len())..]);
+                tags.push(flv_tag(8, ts, &body));
+            },
+            _ => {},
+        }
+        tags
+    }
+}
+
+impl Default for FlvMuxer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FLV tag 含后续PreviousTagSize
+pub fn flv_tag(tag_type:u8, ts:u32, body:&[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(body.len() + 15);
+    buf.put_u8(tag_type);
+    buf.put_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    buf.put_slice(&ts.to_be_bytes()[1..]);
+    buf.put_u8((ts >> 24) as u8);
+    buf.put_slice(&[0, 0, 0]);
+    buf.put_slice(body);
+    buf.put_u32(body.len() as u32 + 11);
+    buf.freeze()
+}
+
+
+#[test]
+fn test_flv_mux()
+{
+    let frame = MediaFrame {
+        sim: "013800000000".to_string(),
+        channel: 1,
+        pt: PT_H264,
+        data_type: 0,
+        time_stamp: 1000,
+        last_i_interval: 0,
+        last_interval: 0,
+        data: Bytes::from_static(&[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1f, 0, 0, 0, 1, 0x68, 0xce, 0, 0, 1, 0x65, 0x88, 0x84]),
+    };
+    let mut muxer = FlvMuxer::new();
+    let tags = muxer.mux(&frame);
+    //序列头+关键帧
+    assert_eq!(tags.len(), 2);
+    assert_eq!(tags[0][0], 9);
+    assert_eq!(&tags[0][11..13], &[0x17, 0x00]);
+    assert_eq!(&tags[1][11..13], &[0x17, 0x01]);
+    //NALU长度+数据
+    assert_eq!(&tags[1][16..23], &[0, 0, 0, 3, 0x65, 0x88, 0x84]);
+
+    //P帧 时间戳相对首帧
+    let mut frame = frame;
+    frame.data_type = 1;
+    frame.time_stamp = 1040;
+    frame.data = Bytes::from_static(&[0, 0, 0, 1, 0x41, 0x9a]);
+    let tags = muxer.mux(&frame);
+    assert_eq!(tags.len(), 1);
+    assert_eq!(&tags[0][4..7], &[0, 0, 40]);
+    assert_eq!(tags[0][11], 0x27);
+}
@@ -0,0 +1,314 @@
+//G.726 32kbit/s 编解码 按ITU-T参考实现(Sun g72x) 4bit码字 每字节两个采样 低4位在前
+
+const POWER2: [i32; 15] = [1, 2, 4, 8, 0x10, 0x20, 0x40, 0x80, 0x100, 0x200, 0x400, 0x800, 0x1000, 0x2000, 0x4000];
+const QTAB: [i32; 7] = [-124, 80, 178, 246, 300, 349, 400];
+const DQLNTAB: [i32; 16] = [-2048, 4, 135, 213, 273, 323, 373, 425, 425, 373, 323, 273, 213, 135, 4, -2048];
+const WITAB: [i32; 16] = [-12, 18, 41, 64, 112, 198, 355, 1122, 1122, 355, 198, 112, 64, 41, 18, -12];
+const FITAB: [i32; 16] = [0, 0, 0, 0x200, 0x200, 0x200, 0x600, 0xE00, 0xE00, 0x600, 0x200, 0x200, 0x200, 0, 0, 0];
+
+//参考实现中的short
+fn s16(v:i32) -> i32 {
+    v as i16 as i32
+}
+
+fn quan(val:i32, table:&[i32]) -> i32 {
+    table.iter().position(|t| val < *t).unwrap_or(table.len()) as i32
+}
+
+fn fmult(an:i32, srn:i32) -> i32 {
+    let anmag = if an > 0 { an } else { (-an) & 0x1FFF };
+    let anexp = quan(anmag, &POWER2) - 6;
+    let anmant = if anmag == 0 {
+        32
+    } else if anexp >= 0 {
+        anmag >> anexp
+    } else {
+        anmag << -anexp
+    };
+    let wanexp = anexp + ((srn >> 6) & 0xF) - 13;
+    let wanmant = (anmant * (srn & 0o77) + 0x30) >> 4;
+    let retval = if wanexp >= 0 { (wanmant << wanexp) & 0x7FFF } else { wanmant >> -wanexp };
+    if (an ^ srn) < 0 { -retval } else { retval }
+}
+
+fn quantize(d:i32, y:i32) -> i32 {
+    let dqm = d.abs();
+    let exp = quan(dqm >> 1, &POWER2);
+    let mant = ((dqm << 7) >> exp) & 0x7F;
+    let dl = (exp << 7) + mant;
+    let dln = s16(dl - (y >> 2));
+    let i = quan(dln, &QTAB);
+    if d < 0 {
+        (7 << 1) + 1 - i
+    } else if i == 0 {
+        (7 << 1) + 1
+    } else {
+        i
+    }
+}
+
+fn reconstruct(sign:bool, dqln:i32, y:i32) -> i32 {
+    let dql = s16(dqln + (y >> 2));
+    if dql < 0 {
+        return if sign { -0x8000 } else { 0 };
+    }
+    let dex = (dql >> 7) & 15;
+    let dqt = 128 + (dql & 127);
+    let dq = s16((dqt << 7) >> (14 - dex));
+    if sign { dq - 0x8000 } else { dq }
+}
+
+/// 编解码状态 连续的帧之间保持
+#[derive(Debug, Clone)]
+pub struct G726State {
+    yl: i32,
+    yu: i32,
+    dms: i32,
+    dml: i32,
+    ap: i32,
+    a: [i32; 2],
+    b: [i32; 6],
+    pk: [i32; 2],
+    dq: [i32; 6],
+    sr: [i32; 2],
+    td: bool,
+}
+
+impl G726State {
+    pub fn new() -> Self {
+        G726State {
+            yl: 34816,
+            yu: 544,
+            dms: 0,
+            dml: 0,
+            ap: 0,
+            a: [0; 2],
+            b: [0; 6],
+            pk: [0; 2],
+            dq: [32; 6],
+            sr: [32; 2],
+            td: false,
+        }
+    }
+
+    fn predictor_zero(&self) -> i32 {
+        (0..6).map(|i| fmult(self.b[i] >> 2, self.dq[i])).sum()
+    }
+
+    fn predictor_pole(&self) -> i32 {
+        fmult(self.a[1] >> 2, self.sr[1]) + fmult(self.a[0] >> 2, self.sr[0])
+    }
+
+    fn step_size(&self) -> i32 {
+        if self.ap >= 256 {
+            return self.yu;
+        }
+        let mut y = self.yl >> 6;
+        let dif = self.yu - y;
+        let al = self.ap >> 2;
+        if dif > 0 {
+            y += (dif * al) >> 6;
+        } else if dif < 0 {
+            y += (dif * al + 0x3F) >> 6;
+        }
+        y
+    }
+
+    fn update(&mut self, y:i32, wi:i32, fi:i32, dq:i32, sr:i32, dqsez:i32) {
+        let pk0 = if dqsez < 0 { 1 } else { 0 };
+        let mut mag = dq & 0x7FFF;
+
+        let ylint = self.yl >> 15;
+        let ylfrac = (self.yl >> 10) & 0x1F;
+        let thr1 = (32 + ylfrac) << ylint;
+        let thr2 = if ylint > 9 { 31 << 10 } else { thr1 };
+        let dqthr = (thr2 + (thr2 >> 1)) >> 1;
+        let tr = self.td && mag > dqthr;
+
+        self.yu = (y + ((wi - y) >> 5)).clamp(544, 5120);
+        self.yl += self.yu + ((-self.yl) >> 6);
+
+        let mut a2p = 0;
+        if tr {
+            self.a = [0; 2];
+            self.b = [0; 6];
+        } else {
+            let pks1 = pk0 ^ self.pk[0];
+            a2p = self.a[1] - (self.a[1] >> 7);
+            if dqsez != 0 {
+                let fa1 = if pks1 != 0 { self.a[0] } else { -self.a[0] };
+                if fa1 < -8191 {
+                    a2p -= 0x100;
+                } else if fa1 > 8191 {
+                    a2p += 0xFF;
+                } else {
+                    a2p += fa1 >> 5;
+                }
+
+                if pk0 ^ self.pk[1] != 0 {
+                    if a2p <= -12160 {
+                        a2p = -12288;
+                    } else if a2p >= 12416 {
+                        a2p = 12288;
+                    } else {
+                        a2p -= 0x80;
+                    }
+                } else if a2p <= -12416 {
+                    a2p = -12288;
+                } else if a2p >= 12160 {
+                    a2p = 12288;
+                } else {
+                    a2p += 0x80;
+                }
+            }
+            a2p = s16(a2p);
+            self.a[1] = a2p;
+
+            self.a[0] -= self.a[0] >> 8;
+            if dqsez != 0 {
+                if pks1 == 0 {
+                    self.a[0] += 192;
+                } else {
+                    self.a[0] -= 192;
+                }
+            }
+            let a1ul = 15360 - a2p;
+            self.a[0] = self.a[0].clamp(-a1ul, a1ul);
+
+            for cnt in 0..6 {
+                self.b[cnt] -= self.b[cnt] >> 8;
+                if dq & 0x7FFF != 0 {
+                    if (dq ^ self.dq[cnt]) >= 0 {
+                        self.b[cnt] += 128;
+                    } else {
+                        self.b[cnt] -= 128;
+                    }
+                }
+                self.b[cnt] = s16(self.b[cnt]);
+            }
+        }
+
+        for cnt in (1..6).rev() {
+            self.dq[cnt] = self.dq[cnt - 1];
+        }
+        if mag == 0 {
+            self.dq[0] = if dq >= 0 { 0x20 } else { s16(0xFC20) };
+        } else {
+            let exp = quan(mag, &POWER2);
+            let v = (exp << 6) + ((mag << 6) >> exp);
+            self.dq[0] = s16(if dq >= 0 { v } else { v - 0x400 });
+        }
+
+        self.sr[1] = self.sr[0];
+        if sr == 0 {
+            self.sr[0] = 0x20;
+        } else if sr > 0 {
+            let exp = quan(sr, &POWER2);
+            self.sr[0] = s16((exp << 6) + ((sr << 6) >> exp));
+        } else if sr > -32768 {
+            mag = -sr;
+            let exp = quan(mag, &POWER2);
+            self.sr[0] = s16((exp << 6) + ((mag << 6) >> exp) - 0x400);
+        } else {
+            self.sr[0] = s16(0xFC20);
+        }
+
+        self.pk[1] = self.pk[0];
+        self.pk[0] = pk0;
+
+        self.td = !tr && a2p < -11776;
+
+        self.dms = s16(self.dms + ((fi - self.dms) >> 5));
+        self.dml = s16(self.dml + (((fi << 2) - self.dml) >> 7));
+
+        if tr {
+            self.ap = 256;
+        } else if y < 1536 || self.td || ((self.dms << 2) - self.dml).abs() >= (self.dml >> 3) {
+            self.ap += (0x200 - self.ap) >> 4;
+        } else {
+            self.ap += (-self.ap) >> 4;
+        }
+        self.ap = s16(self.ap);
+    }
+
+    fn encode_sample(&mut self, pcm:i16) -> u8 {
+        let sl = (pcm as i32) >> 2;
+        let sezi = self.predictor_zero();
+        let sez = s16(sezi) >> 1;
+        let se = s16((sezi + self.predictor_pole()) >> 1);
+        let d = s16(sl - se);
+        let y = s16(self.step_size());
+        let i = quantize(d, y);
+        let dq = s16(reconstruct(i & 8 != 0, DQLNTAB[i as usize], y));
+        let sr = s16(if dq < 0 { se - (dq & 0x3FFF) } else { se + dq });
+        let dqsez = s16(sr + sez - se);
+        self.update(y, WITAB[i as usize] << 5, FITAB[i as usize], dq, sr, dqsez);
+        i as u8
+    }
+
+    fn decode_sample(&mut self, code:u8) -> i16 {
+        let i = (code & 0x0f) as usize;
+        let sezi = self.predictor_zero();
+        let sez = s16(sezi) >> 1;
+        let se = s16((sezi + self.predictor_pole()) >> 1);
+        let y = s16(self.step_size());
+        let dq = s16(reconstruct(i & 8 != 0, DQLNTAB[i], y));
+        let sr = s16(if dq < 0 { se - (dq & 0x3FFF) } else { se + dq });
+        let dqsez = s16(sr - se + sez);
+        self.update(y, WITAB[i] << 5, FITAB[i], dq, sr, dqsez);
+        (sr << 2).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+}
+
+impl Default for G726State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn g726_encode(state:&mut G726State, pcm:&[i16]) -> Vec<u8> {
+    pcm.chunks(2).map(|t| {
+        let low = state.encode_sample(t[0]);
+        let high = if t.len() > 1 { state.encode_sample(t[1]) } else { 0 };
+        low | (high << 4)
+    }).collect()
+}
+
+pub fn g726_decode(state:&mut G726State, data:&[u8]) -> Vec<i16> {
+    let mut pcm = Vec::with_capacity(data.len() * 2);
+    for b in data {
+        pcm.push(state.decode_sample(b & 0x0f));
+        pcm.push(state.decode_sample(b >> 4));
+    }
+    pcm
+}
+
+
+#[test]
+fn test_g726()
+{
+    let pcm: Vec<i16> = (0..1600).map(|i| ((i as f64 / 6.0).sin() * 8000.0) as i16).collect();
+    let data = g726_encode(&mut G726State::new(), &pcm);
+    assert_eq!(data.len(), 800);
+    let out = g726_decode(&mut G726State::new(), &data);
+    assert_eq!(out.len(), 1600);
+    //跳过收敛阶段
+    let err: i64 = pcm.iter().zip(&out).skip(400).map(|(a, b)| (*a as i64 - *b as i64).abs()).sum::<i64>() / 1200;
+    assert!(err < 800, "{}", err);
+}
+
+#[test]
+fn test_g726_quantizer()
+{
+    //G.726 表7 32kbit/s量化器 归一化输入log2|d|-y所在区间对应的|I|
+    //初始状态预测值为0 y=544(log2域1.0625) 首个采样的码字即为量化结果
+    let table: [(f64, u8); 8] = [(-2.0, 0), (-0.18, 1), (1.0, 2), (1.645, 3), (2.125, 4), (2.53, 5), (2.92, 6), (3.6, 7)];
+    for (x, i) in table {
+        let d = 2f64.powf(x + 544.0 / 512.0).round() as i16;
+        //正数的0编码为15 负数为15-|I|
+        let positive = if i == 0 { 15 } else { i };
+        assert_eq!(G726State::new().encode_sample(d * 4), positive, "d:{}", d);
+        assert_eq!(G726State::new().encode_sample(-d * 4), 15 - i, "d:-{}", d);
+    }
+    assert_eq!(G726State::new().encode_sample(0), 15);
+}
@@ -0,0 +1,5 @@
+pub mod audio;
+pub mod g726;
+pub mod flv;
+pub mod nalu;
+pub mod ts;
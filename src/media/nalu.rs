@@ -0,0 +1,134 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+//H.264/H.265 Annex-B NALU处理
+
+/// 按起始码 00 00 01 / 00 00 00 01 分割
+pub fn split_annexb(data:&[u8]) -> Vec<&[u8]> {
+    let mut nalus = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(s) = start {
+                //去掉4字节起始码多出的0
+                let mut end = i;
+                while end > s && data[end - 1] == 0 {
+                    end -= 1;
+                }
+                nalus.push(&data[s..end]);
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    match start {
+        Some(s) if s < data.len() => nalus.push(&data[s..]),
+        None if !data.is_empty() => nalus.push(data),
+        _ => {},
+    }
+    nalus
+}
+
+pub fn nal_type(nal:&[u8], hevc:bool) -> u8 {
+    match nal.first() {
+        Some(b) if hevc => (b >> 1) & 0x3f,
+        Some(b) => b & 0x1f,
+        None => 0,
+    }
+}
+
+/// 参数集及分隔符 不写入帧数据
+pub fn is_param_set(nal:&[u8], hevc:bool) -> bool {
+    let t = nal_type(nal, hevc);
+    if hevc {
+        (32..=35).contains(&t)
+    } else {
+        (7..=9).contains(&t)
+    }
+}
+
+fn find<'a>(nalus:&[&'a [u8]], hevc:bool, t:u8) -> Option<&'a [u8]> {
+    nalus.iter().find(|nal| nal_type(nal, hevc) == t).copied()
+}
+
+/// AVCDecoderConfigurationRecord
+pub fn avc_config(nalus:&[&[u8]]) -> Option<Bytes> {
+    let sps = find(nalus, false, 7)?;
+    let pps = find(nalus, false, 8)?;
+    if sps.len() < 4 {
+        return None;
+    }
+
+    let mut buf = BytesMut::with_capacity(sps.len() + pps.len() + 11);
+    buf.put_u8(1);
+    buf.put_slice(&sps[1..4]);
+    buf.put_u8(0xff);
+    buf.put_u8(0xe1);
+    buf.put_u16(sps.len() as u16);
+    buf.put_slice(sps);
+    buf.put_u8(1);
+    buf.put_u16(pps.len() as u16);
+    buf.put_slice(pps);
+    Some(buf.freeze())
+}
+
+/// 去掉防竞争字节 00 00 03
+fn unescape(nal:&[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for b in nal {
+        if zeros >= 2 && *b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if *b == 0 { zeros + 1 } else { 0 };
+        out.push(*b);
+    }
+    out
+}
+
+/// HEVCDecoderConfigurationRecord profile信息取自SPS
+pub fn hevc_config(nalus:&[&[u8]]) -> Option<Bytes> {
+    let vps = find(nalus, true, 32)?;
+    let sps = find(nalus, true, 33)?;
+    let pps = find(nalus, true, 34)?;
+
+    //NAL头2字节 + 1字节(vps_id/max_sub_layers/temporal_id_nesting) + 12字节general_profile_tier_level
+    let rbsp = unescape(sps);
+    if rbsp.len() < 15 {
+        return None;
+    }
+    let ptl = &rbsp[3..15];
+
+    let mut buf = BytesMut::with_capacity(vps.len() + sps.len() + pps.len() + 38);
+    buf.put_u8(1);
+    buf.put_slice(ptl);
+    buf.put_u16(0xf000);
+    buf.put_u8(0xfc);
+    buf.put_u8(0xfd);
+    buf.put_u8(0xf8);
+    buf.put_u8(0xf8);
+    buf.put_u16(0);
+    buf.put_u8(0x0f);
+    buf.put_u8(3);
+    for (t, nal) in [(32u8, vps), (33, sps), (34, pps)] {
+        buf.put_u8(0x80 | t);
+        buf.put_u16(1);
+        buf.put_u16(nal.len() as u16);
+        buf.put_slice(nal);
+    }
+    Some(buf.freeze())
+}
+
+
+#[test]
+fn test_split_annexb()
+{
+    let data = [0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce, 0, 0, 0, 1, 0x65, 0x88];
+    let nalus = split_annexb(&data);
+    assert_eq!(nalus, vec![&[0x67, 0x42][..], &[0x68, 0xce][..], &[0x65, 0x88][..]]);
+    let config = avc_config(&[&[0x67, 0x42, 0x00, 0x1f], &[0x68, 0xce]]).unwrap();
+    assert_eq!(config.as_ref(), &[1, 0x42, 0x00, 0x1f, 0xff, 0xe1, 0, 4, 0x67, 0x42, 0x00, 0x1f, 1, 0, 2, 0x68, 0xce]);
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use bytes::{BufMut, BytesMut};
+
+//MPEG-TS封装 用于HLS
+//视频H.264/H.265 音频仅AAC(ADTS) G.711/ADPCM没有对应的TS流类型
+
+const PID_PMT: u16 = 0x1000;
+const PID_VIDEO: u16 = 0x100;
+const PID_AUDIO: u16 = 0x101;
+
+pub struct TsMuxer {
+    hevc: bool,
+    has_audio: bool,
+    cc: HashMap<u16, u8>,
+}
+
+impl TsMuxer {
+    pub fn new(hevc:bool, has_audio:bool) -> Self {
+        TsMuxer {
+            hevc,
+            has_audio,
+            cc: HashMap::new(),
+        }
+    }
+
+    /// 每个分片开头写PAT/PMT
+    pub fn write_psi(&mut self, out:&mut BytesMut) {
+        //PAT program 1 -> PMT
+        let pat = [0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xe0 | (PID_PMT >> 8) as u8, PID_PMT as u8];
+        self.write_section(out, 0, &pat);
+
+        let mut pmt = BytesMut::new();
+        let section_len = 13 + if self.has_audio { 10 } else { 5 };
+        pmt.put_u8(0x02);
+        pmt.put_u16(0xb000 | section_len);
+        pmt.put_u16(1);
+        pmt.put_u8(0xc1);
+        pmt.put_u8(0);
+        pmt.put_u8(0);
+        pmt.put_u16(0xe000 | PID_VIDEO);
+        pmt.put_u16(0xf000);
+        pmt.put_u8(if self.hevc { 0x24 } else { 0x1b });
+        pmt.put_u16(0xe000 | PID_VIDEO);
+        pmt.put_u16(0xf000);
+        if self.has_audio {
+            pmt.put_u8(0x0f);
+            pmt.put_u16(0xe000 | PID_AUDIO);
+            pmt.put_u16(0xf000);
+        }
+        self.write_section(out, PID_PMT, &pmt);
+    }
+
+    /// Annex-B视频帧 pts毫秒
+    pub fn write_video(&mut self, out:&mut BytesMut, pts:u64, key:bool, data:&[u8]) {
+        let mut payload = BytesMut::with_capacity(data.len() + 6);
+        //访问单元分隔符
+        if self.hevc {
+            payload.put_slice(&[0, 0, 0, 1, 0x46, 0x01, 0x50]);
+        } else {
+            payload.put_slice(&[0, 0, 0, 1, 0x09, 0xf0]);
+        }
+        payload.put_slice(data);
+        self.write_pes(out, PID_VIDEO, 0xe0, pts, key, &payload);
+    }
+
+    /// ADTS AAC帧
+    pub fn write_audio(&mut self, out:&mut BytesMut, pts:u64, data:&[u8]) {
+        if self.has_audio {
+            self.write_pes(out, PID_AUDIO, 0xc0, pts, false, data);
+        }
+    }
+
+    fn next_cc(&mut self, pid:u16) -> u8 {
+        let cc = self.cc.entry(pid).or_insert(0x0f);
+        *cc = (*cc + 1) & 0x0f;
+        *cc
+    }
+
+    fn write_section(&mut self, out:&mut BytesMut, pid:u16, section:&[u8]) {
+        let cc = self.next_cc(pid);
+        let start = out.len();
+        out.put_u8(0x47);
+        out.put_u16(0x4000 | pid);
+        out.put_u8(0x10 | cc);
+        out.put_u8(0);
+        out.put_slice(section);
+        out.put_u32(crc32(section));
+        out.resize(start + 188, 0xff);
+    }
+
+    fn write_pes(&mut self, out:&mut BytesMut, pid:u16, stream_id:u8, pts:u64, key:bool, data:&[u8]) {
+        let pts = pts * 90;
+        let mut pes = BytesMut::with_capacity(data.len() + 14);
+        pes.put_slice(&[0, 0, 1, stream_id]);
+        let pes_len = data.len() + 8;
+        //视频长度可为0
+        pes.put_u16(if stream_id == 0xe0 || pes_len > 0xffff { 0 } else { pes_len as u16 });
+        pes.put_u8(0x80);
+        pes.put_u8(0x80);
+        pes.put_u8(5);
+        pes.put_u8(0x21 | (((pts >> 30) & 0x07) << 1) as u8);
+        pes.put_u16((((pts >> 15) & 0x7fff) << 1) as u16 | 1);
+        pes.put_u16(((pts & 0x7fff) << 1) as u16 | 1);
+        pes.put_slice(data);
+
+        let mut offset = 0;
+        let mut first = true;
+        while offset < pes.len() {
+            let cc = self.next_cc(pid);
+            let start = out.len();
+            out.put_u8(0x47);
+            out.put_u16(if first { 0x4000 } else { 0 } | pid);
+
+            //首包带PCR
+            let mut adaptation = BytesMut::new();
+            if first && pid == PID_VIDEO {
+                adaptation.put_u8(if key { 0x50 } else { 0x10 });
+                let pcr = pts;
+                adaptation.put_u32((pcr >> 1) as u32);
+                adaptation.put_u8((((pcr & 1) << 7) | 0x7e) as u8);
+                adaptation.put_u8(0);
+            }
+
+            let remain = pes.len() - offset;
+            let header_len = 4 + if adaptation.is_empty() { 0 } else { 1 + adaptation.len() };
+            let space = 188 - header_len;
+            if remain < space {
+                //不足一包 用自适应字段填充
+                let stuffing = space - remain;
+                if adaptation.is_empty() {
+                    if stuffing == 1 {
+                        out.put_u8(0x30 | cc);
+                        out.put_u8(0);
+                    } else {
+                        out.put_u8(0x30 | cc);
+                        out.put_u8((stuffing - 1) as u8);
+                        out.put_u8(0);
+                        out.resize(out.len() + stuffing - 2, 0xff);
+                    }
+                } else {
+                    out.put_u8(0x30 | cc);
+                    out.put_u8((adaptation.len() + stuffing) as u8);
+                    out.put_slice(&adaptation);
+                    out.resize(out.len() + stuffing, 0xff);
+                }
+            } else if adaptation.is_empty() {
+                out.put_u8(0x10 | cc);
+            } else {
+                out.put_u8(0x30 | cc);
+                out.put_u8(adaptation.len() as u8);
+                out.put_slice(&adaptation);
+            }
+
+            let n = 188 - (out.len() - start);
+            out.put_slice(&pes[offset..offset + n]);
+            offset += n;
+            first = false;
+        }
+    }
+}
+
+/// MPEG-2 CRC32
+fn crc32(data:&[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in data {
+        crc ^= (*b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 > 0 { (crc << 1) ^ 0x04c11db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+
+#[test]
+fn test_ts_mux()
+{
+    //ffmpeg默认PAT
+    assert_eq!(crc32(&[0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00]), 0x2ab104b2);
+
+    let mut muxer = TsMuxer::new(false, false);
+    let mut out = BytesMut::new();
+    muxer.write_psi(&mut out);
+    for len in [1, 100, 170, 171, 172, 183, 184, 1000] {
+        muxer.write_video(&mut out, 40, true, &vec![0x65; len]);
+    }
+    assert_eq!(out.len() % 188, 0);
+    assert!(out.chunks(188).all(|t| t[0] == 0x47));
+}
@@ -0,0 +1,510 @@
+use std::{collections::HashMap, sync::{Arc, atomic::{AtomicU32, Ordering}}, time::Duration};
+
+use bytes::{BufMut, BytesMut};
+use serde::Serialize;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::{mpsc, watch}, time::timeout};
+
+use crate::{config_model::Jt809Config, service_event, session808::jt808_models::JtLocation, session809::{jt809_models::*, jt809_parse::{self, Jt809Cipher, Jt809Frame, Jt809Package}}};
+
+//JT/T 809上级平台(政府监管平台)对接
+//主链路: 本平台连接上级 0x1001登录 0x1005心跳 上传0x1200车辆定位(0x1202实时 0x1203补报)和0x1400报警(0x1402)
+//从链路: 上级连接本平台 0x9001携带登录应答中的校验码 0x9005心跳
+//车牌号和颜色取自配置的vehicles 没有时取终端注册(0x0100)信息 注册信息保存在vehicles_path 都没有的车辆不上传
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+const QUEUE_SIZE: usize = 4096;
+const VERSION: [u8; 3] = [1, 0, 0];
+
+static GLOBAL_809: std::sync::Mutex<Option<Jt809Store>> = std::sync::Mutex::new(None);
+static NEXT_INFO_ID: AtomicU32 = AtomicU32::new(1);
+
+struct Jt809Store {
+    package: Arc<Jt809Package>,
+    sender: mpsc::Sender<Vec<u8>>,
+    //sim -> (车牌号, 车牌颜色)
+    vehicles: HashMap<String, (String, u8)>,
+    //终端注册得到的车牌 配置的车辆优先
+    learned: HashMap<String, (String, u8)>,
+    //learned的文件内容 由后台任务写入
+    saver: watch::Sender<Vec<u8>>,
+    verify_code: Option<u32>,
+    //当前从链路会话 旧会话结束时不改变状态
+    slave_session: u64,
+    status: Jt809Status,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Jt809Status {
+    /// 主链路已登录
+    pub main_link: bool,
+    /// 从链路已连接并校验
+    pub slave_link: bool,
+    pub locations: u64,
+    pub alarms: u64,
+    /// 链路未登录或队列满时丢弃的消息数
+    pub dropped: u64,
+}
+
+pub fn init(config:&Jt809Config) {
+    let cipher = config.encrypt.then_some(Jt809Cipher { m1: config.m1, ia1: config.ia1, ic1: config.ic1 });
+    let package = Arc::new(Jt809Package::new(config.center_id, VERSION, cipher));
+    let (sender, queue) = mpsc::channel(QUEUE_SIZE);
+    let learned = match std::fs::read(&config.vehicles_path) {
+        Ok(bts) => serde_json::from_slice(&bts).unwrap_or_else(|err| {
+            log::warn!("[service-809]vehicles file invalid:{}", err);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    };
+    let (saver, receiver) = watch::channel(Vec::new());
+    tokio::spawn(run_saver(config.vehicles_path.clone(), receiver));
+    *GLOBAL_809.lock().unwrap() = Some(Jt809Store {
+        package: package.clone(),
+        sender,
+        vehicles: config.get_vehicles(),
+        learned,
+        saver,
+        verify_code: None,
+        slave_session: 0,
+        status: Jt809Status::default(),
+    });
+    log::info!("[service-809]main link:{} center_id:{} encrypt:{}", config.address, config.center_id, config.encrypt);
+
+    tokio::spawn(run_main_link(config.clone(), package.clone(), cipher, queue));
+    let address_down = config.address_down.clone();
+    tokio::spawn(async move {
+        if let Err(err) = run_slave_server(&address_down, package, cipher).await {
+            log::error!("[service-809]slave link listen failed:{} err:{}", address_down, err);
+        }
+    });
+    tokio::spawn(run_events());
+}
+
+pub fn status() -> Option<Jt809Status> {
+    GLOBAL_809.lock().unwrap().as_ref().map(|t| t.status.clone())
+}
+
+/// 终端上报0x0200时调用 上传0x1202
+pub fn update(sim:&str, location:&JtLocation) {
+    let mut gnss = BytesMut::with_capacity(36);
+    write_gnss(&mut gnss, location);
+    send_vehicle(sim, UP_EXG_MSG, UP_EXG_MSG_REAL_LOCATION, &gnss);
+}
+
+/// 0x0704批量上传 按每条最多5个定位上传0x1203
+pub fn backfill(sim:&str, locations:&[JtLocation]) {
+    for chunk in locations.chunks(HISTORY_MAX) {
+        let mut data = BytesMut::with_capacity(1 + 36 * chunk.len());
+        data.put_u8(chunk.len() as u8);
+        for location in chunk {
+            write_gnss(&mut data, location);
+        }
+        send_vehicle(sim, UP_EXG_MSG, UP_EXG_MSG_HISTORY_LOCATION, &data);
+    }
+}
+
+fn send_vehicle(sim:&str, id:u16, data_type:u16, data:&[u8]) {
+    let mut global = GLOBAL_809.lock().unwrap();
+    let store = match global.as_mut() {
+        Some(store) => store,
+        None => return,
+    };
+    let (plate, color) = match store.vehicles.get(sim).or_else(|| store.learned.get(sim)) {
+        Some(vehicle) => vehicle,
+        None => return,
+    };
+    if !store.status.main_link {
+        store.status.dropped += 1;
+        return;
+    }
+    let mut body = BytesMut::with_capacity(28 + data.len());
+    Jt809VehicleMsg { plate, color: *color, data_type, data }.write(&mut body);
+    if store.sender.try_send(store.package.serialize(id, &body)).is_err() {
+        store.status.dropped += 1;
+        return;
+    }
+    match id {
+        UP_WARN_MSG => store.status.alarms += 1,
+        _ => store.status.locations += 1,
+    }
+}
+
+//订阅终端注册和报警事件
+async fn run_events() {
+    let mut receiver = match service_event::subscribe() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    let filter = service_event::EventFilter::new("", "register,alarm,geofence_enter,geofence_exit,route_deviation");
+    while let Some(event) = service_event::recv(&mut receiver, &filter).await {
+        if event.kind == "register" {
+            let register = &event.data["register"];
+            //注册信息中的数字以字符串序列化
+            let color = register["color"].as_u64().or_else(|| register["color"].as_str()?.parse().ok());
+            if let (Some(plate), Some(color)) = (register["name"].as_str().filter(|t| !t.is_empty()), color) {
+                if let Some(store) = GLOBAL_809.lock().unwrap().as_mut() {
+                    let vehicle = (plate.to_string(), color as u8);
+                    if store.learned.get(&event.sim) != Some(&vehicle) {
+                        log::info!("[service-809]learned sim:{} plate:{} color:{}", event.sim, vehicle.0, vehicle.1);
+                        store.learned.insert(event.sim.clone(), vehicle);
+                        if let Ok(bts) = serde_json::to_vec_pretty(&store.learned) {
+                            store.saver.send_replace(bts);
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+
+        //报警使用报警名称 区域/线路事件使用事件类型
+        let kind = event.data["kind"].as_str().filter(|_| event.kind == "alarm").unwrap_or(&event.kind);
+        let warn_type = match warn_type(kind) {
+            Some(warn_type) => warn_type,
+            None => continue,
+        };
+        let src = match event.data["source"].as_str() {
+            Some("server") => 2,
+            _ => 1,
+        };
+        let time = event.data["start"].as_i64().or(event.data["time"].as_i64()).filter(|t| *t > 0).unwrap_or(event.time as i64);
+        let content = match event.data["name"].as_str() {
+            Some(name) => format!("{} {}", kind, name),
+            None => kind.to_string(),
+        };
+        let mut data = BytesMut::new();
+        Jt809WarnInfo { src, warn_type, time, info_id: NEXT_INFO_ID.fetch_add(1, Ordering::Relaxed), content }.write(&mut data);
+        log::info!("[service-809]warn sim:{} kind:{} type:{:04x}", event.sim, kind, warn_type);
+        send_vehicle(&event.sim, UP_WARN_MSG, UP_WARN_MSG_ADPT_INFO, &data);
+    }
+}
+
+//只写最新内容
+async fn run_saver(path:String, mut receiver:watch::Receiver<Vec<u8>>) {
+    while receiver.changed().await.is_ok() {
+        let bts = receiver.borrow_and_update().clone();
+        if let Err(err) = tokio::fs::write(&path, bts).await {
+            log::warn!("[service-809]save vehicles failed:{} err:{}", path, err);
+        }
+    }
+}
+
+fn set_status(f:impl FnOnce(&mut Jt809Store)) {
+    if let Some(store) = GLOBAL_809.lock().unwrap().as_mut() {
+        f(store);
+    }
+}
+
+//主链路 断线后重连
+async fn run_main_link(config:Jt809Config, package:Arc<Jt809Package>, cipher:Option<Jt809Cipher>, mut queue:mpsc::Receiver<Vec<u8>>) {
+    loop {
+        match TcpStream::connect(&config.address).await {
+            Ok(stream) => {
+                log::info!("[service-809]main link connected:{}", config.address);
+                main_link_session(stream, &config, &package, cipher, &mut queue).await;
+                set_status(|t| {
+                    t.status.main_link = false;
+                    t.verify_code = None;
+                });
+                log::info!("[service-809]main link disconnected");
+            },
+            Err(err) => {
+                log::info!("[service-809]main link connect failed:{} err:{}", config.address, err);
+            },
+        }
+        //未登录期间积压的定位不再上传
+        while queue.try_recv().is_ok() {}
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn main_link_session(stream:TcpStream, config:&Jt809Config, package:&Jt809Package, cipher:Option<Jt809Cipher>, queue:&mut mpsc::Receiver<Vec<u8>>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (down_link_ip, down_link_port) = config.get_down_public();
+    let mut body = BytesMut::new();
+    Jt809Login { user_id: config.user_id, password: config.password.clone(), down_link_ip, down_link_port }.write(&mut body);
+    if writer.write_all(&package.serialize(UP_CONNECT_REQ, &body)).await.is_err() {
+        return;
+    }
+
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut logged_in = false;
+    let login_deadline = tokio::time::sleep(LOGIN_TIMEOUT);
+    tokio::pin!(login_deadline);
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(config.heartbeat.max(1)));
+    heartbeat.tick().await;
+    //心跳间隔内没有任何数据时断开
+    let mut last_recv = tokio::time::Instant::now();
+
+    loop {
+        tokio::select! {
+            result = reader.read_buf(&mut buffer) => {
+                match result {
+                    Ok(n) if n > 0 => {},
+                    _ => return,
+                }
+                last_recv = tokio::time::Instant::now();
+                loop {
+                    let frame = match jt809_parse::deserialize(&mut buffer, cipher.as_ref()) {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(err) => {
+                            log::info!("[service-809]main link invalid frame:{}", err);
+                            continue;
+                        },
+                    };
+                    match frame.id {
+                        UP_CONNECT_RSP => {
+                            let answer = match Jt809LoginAnswer::read(&frame.body) {
+                                Some(answer) => answer,
+                                None => return,
+                            };
+                            log::info!("[service-809]login result:{} verify_code:{}", answer.result, answer.verify_code);
+                            if answer.result != 0 {
+                                return;
+                            }
+                            logged_in = true;
+                            set_status(|t| {
+                                t.status.main_link = true;
+                                t.verify_code = Some(answer.verify_code);
+                            });
+                        },
+                        UP_LINKTEST_RSP => {},
+                        id => log::info!("[service-809]main link recv:{:04x} len:{}", id, frame.body.len()),
+                    }
+                }
+            },
+            data = queue.recv(), if logged_in => {
+                let data = match data {
+                    Some(data) => data,
+                    None => return,
+                };
+                if writer.write_all(&data).await.is_err() {
+                    return;
+                }
+            },
+            _ = &mut login_deadline, if !logged_in => {
+                log::info!("[service-809]login timeout");
+                return;
+            },
+            _ = heartbeat.tick() => {
+                if last_recv.elapsed() > Duration::from_secs(config.heartbeat * 3) {
+                    log::info!("[service-809]main link timeout");
+                    return;
+                }
+                if logged_in && writer.write_all(&package.serialize(UP_LINKTEST_REQ, &[])).await.is_err() {
+                    return;
+                }
+            },
+        }
+    }
+}
+
+//从链路服务 同一时间只保留最后连接的一个 新连接时关闭之前的会话
+async fn run_slave_server(addr:&str, package:Arc<Jt809Package>, cipher:Option<Jt809Cipher>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("[service-809]slave link listen:{}", addr);
+    let (current, _) = watch::channel(0u64);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let session = *current.borrow() + 1;
+        log::info!("[service-809]slave link connect:{} session:{}", addr, session);
+        current.send_replace(session);
+        set_status(|t| {
+            t.slave_session = session;
+            t.status.slave_link = false;
+        });
+        let package = package.clone();
+        let closed = current.subscribe();
+        tokio::spawn(async move {
+            slave_link_session(stream, &package, cipher, closed).await;
+            set_status(|t| {
+                if t.slave_session == session {
+                    t.status.slave_link = false;
+                }
+            });
+            log::info!("[service-809]slave link disconnect:{} session:{}", addr, session);
+        });
+    }
+}
+
+async fn slave_link_session(mut stream:TcpStream, package:&Jt809Package, cipher:Option<Jt809Cipher>, mut closed:watch::Receiver<u64>) {
+    let mut buffer = BytesMut::with_capacity(4096);
+    loop {
+        tokio::select! {
+            result = timeout(Duration::from_secs(180), stream.read_buf(&mut buffer)) => {
+                match result {
+                    Ok(Ok(n)) if n > 0 => {},
+                    _ => return,
+                }
+            },
+            //有新的从链路连接
+            _ = closed.changed() => return,
+        }
+        loop {
+            let frame: Jt809Frame = match jt809_parse::deserialize(&mut buffer, cipher.as_ref()) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    log::info!("[service-809]slave link invalid frame:{}", err);
+                    continue;
+                },
+            };
+            let answer = match frame.id {
+                DOWN_CONNECT_REQ => {
+                    let code = (frame.body.len() >= 4).then(|| u32::from_be_bytes([frame.body[0], frame.body[1], frame.body[2], frame.body[3]]));
+                    let verify_code = GLOBAL_809.lock().unwrap().as_ref().and_then(|t| t.verify_code);
+                    //0:成功 1:校验码错误
+                    let result = if code.is_some() && code == verify_code { 0 } else { 1 };
+                    log::info!("[service-809]slave link login code:{:?} result:{}", code, result);
+                    set_status(|t| t.status.slave_link = result == 0);
+                    Some((DOWN_CONNECT_RSP, vec![result]))
+                },
+                DOWN_LINKTEST_REQ => Some((DOWN_LINKTEST_RSP, Vec::new())),
+                DOWN_DISCONNECT_REQ => {
+                    let _ = stream.write_all(&package.serialize(DOWN_DISCONNECT_RSP, &[])).await;
+                    return;
+                },
+                id => {
+                    log::info!("[service-809]slave link recv:{:04x} len:{}", id, frame.body.len());
+                    None
+                },
+            };
+            if let Some((id, body)) = answer {
+                if stream.write_all(&package.serialize(id, &body)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_heartbeat_config()
+{
+    let path = std::env::temp_dir().join(format!("config-809-{}.xml", std::process::id()));
+    let xml = |heartbeat:u64| format!("<config><address_device>0.0.0.0:808</address_device><address_http>0.0.0.0:80</address_http><address_forward>0.0.0.0:809</address_forward>\
+        <jt809><address>1.2.3.4:9000</address><user_id>1</user_id><password>1</password><center_id>1</center_id><address_down>0.0.0.0:20809</address_down>\
+        <heartbeat>{}</heartbeat></jt809></config>", heartbeat);
+    std::fs::write(&path, xml(0)).unwrap();
+    let err = crate::config_model::ConfigModel::read(path.to_str().unwrap().to_string()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    std::fs::write(&path, xml(30)).unwrap();
+    let config = crate::config_model::ConfigModel::read(path.to_str().unwrap().to_string()).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(config.jt809.map(|t| (t.heartbeat, t.vehicles_path)), Some((30, "Jt809Vehicles.json".to_string())));
+}
+
+#[tokio::test]
+async fn test_jt809_links()
+{
+    //上级平台 应答登录 收到的消息转给测试
+    let cipher = Jt809Cipher { m1: 10000000, ia1: 20000000, ic1: 30000000 };
+    let upper = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = upper.local_addr().unwrap().to_string();
+    let (tx, mut frames) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = upper.accept().await.unwrap();
+        let upper_package = Jt809Package::new(1001, VERSION, Some(cipher));
+        let mut buffer = BytesMut::new();
+        while stream.read_buf(&mut buffer).await.unwrap_or(0) > 0 {
+            //本平台发出的消息都是加密的
+            while let Some(end) = buffer.iter().position(|t| *t == jt809_parse::TAIL_FLAG).map(|t| t + 1) {
+                assert_eq!(jt809_parse::deserialize(&mut BytesMut::from(&buffer[..end]), None).err(), Some("encrypted without cipher"));
+                let frame = jt809_parse::deserialize(&mut buffer, Some(&cipher)).unwrap().unwrap();
+                if frame.id == UP_CONNECT_REQ {
+                    stream.write_all(&upper_package.serialize(UP_CONNECT_RSP, &[0, 0x01, 0x02, 0x03, 0x04])).await.unwrap();
+                }
+                let _ = tx.send(frame);
+            }
+        }
+    });
+    async fn recv_frame(frames:&mut mpsc::UnboundedReceiver<Jt809Frame>, id:u16) -> Jt809Frame {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let frame = frames.recv().await.unwrap();
+                if frame.id == id {
+                    return frame;
+                }
+            }
+        }).await.unwrap()
+    }
+
+    let address_down = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let vehicles_path = std::env::temp_dir().join(format!("vehicles-809-{}.json", std::process::id()));
+    let vehicles_path = vehicles_path.to_str().unwrap().to_string();
+    crate::service_event::test_init();
+    init(&Jt809Config {
+        address,
+        user_id: 10001,
+        password: "12345678".into(),
+        center_id: 1001,
+        address_down: address_down.clone(),
+        address_down_public: String::new(),
+        encrypt: true,
+        m1: cipher.m1,
+        ia1: cipher.ia1,
+        ic1: cipher.ic1,
+        heartbeat: 1,
+        vehicles: "013800001001=粤B12345:2".into(),
+        vehicles_path: vehicles_path.clone(),
+    });
+
+    let login = recv_frame(&mut frames, UP_CONNECT_REQ).await;
+    assert_eq!((login.body.len(), &login.body[..4]), (46, &10001u32.to_be_bytes()[..]));
+    timeout(Duration::from_secs(5), async {
+        while !status().is_some_and(|t| t.main_link) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await.unwrap();
+
+    //配置的车辆上传实时定位
+    let location = JtLocation { lat: 22.5431, lng: 114.0579, speed: 60.0, time: 1729339200, state: 0x02, ..Default::default() };
+    update("013800001001", &location);
+    update("013800001002", &location);
+    let exg = recv_frame(&mut frames, UP_EXG_MSG).await;
+    assert_eq!((&exg.body[..2], exg.body[21], &exg.body[22..24]), (&[0xd4, 0xc1][..], 2, &[0x12, 0x02][..]));
+    recv_frame(&mut frames, UP_LINKTEST_REQ).await;
+
+    //终端注册得到的车牌保存到文件
+    let mut learned = HashMap::new();
+    for _ in 0..100 {
+        crate::service_event::publish(crate::service_event::DeviceEvent::new("013800001002", "register", &serde_json::json!({"register": {"name": "粤B54321", "color": "1"}})));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        learned = std::fs::read(&vehicles_path).ok().and_then(|t| serde_json::from_slice::<HashMap<String, (String, u8)>>(&t).ok()).unwrap_or_default();
+        if !learned.is_empty() {
+            break;
+        }
+    }
+    let _ = std::fs::remove_file(&vehicles_path);
+    assert_eq!(learned.get("013800001002"), Some(&("粤B54321".to_string(), 1)));
+    update("013800001002", &location);
+    let exg = recv_frame(&mut frames, UP_EXG_MSG).await;
+    assert_eq!((exg.body[21], &exg.body[22..24]), (1, &[0x12, 0x02][..]));
+
+    //从链路使用登录应答中的校验码 新的连接替换旧的
+    let lower_package = Jt809Package::new(1001, VERSION, Some(cipher));
+    async fn slave_login(address:&str, package:&Jt809Package, cipher:&Jt809Cipher, code:u32) -> (TcpStream, u8) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&package.serialize(DOWN_CONNECT_REQ, &code.to_be_bytes())).await.unwrap();
+        let mut buffer = BytesMut::new();
+        loop {
+            assert!(stream.read_buf(&mut buffer).await.unwrap() > 0);
+            if let Some(frame) = jt809_parse::deserialize(&mut buffer, Some(cipher)).unwrap() {
+                assert_eq!(frame.id, DOWN_CONNECT_RSP);
+                return (stream, frame.body[0]);
+            }
+        }
+    }
+    assert_eq!(slave_login(&address_down, &lower_package, &cipher, 0x04030201).await.1, 1);
+    let (mut first, result) = slave_login(&address_down, &lower_package, &cipher, 0x01020304).await;
+    assert_eq!(result, 0);
+    assert!(status().unwrap().slave_link);
+    let (_second, result) = slave_login(&address_down, &lower_package, &cipher, 0x01020304).await;
+    assert_eq!(result, 0);
+    let mut buf = [0u8; 64];
+    assert_eq!(timeout(Duration::from_secs(5), first.read(&mut buf)).await.unwrap().unwrap_or(0), 0);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(status().unwrap().slave_link);
+}
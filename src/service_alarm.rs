@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{service_device, service_event::{self, DeviceEvent}, session808::jt808_models::{Jt0x8203, JtLocation}};
+
+//0x0200报警标志 按SIM保存当前报警 只在标志位变化时发布事件 连续汇报的相同报警不重复
+//平台侧检测的报警(如超速/疲劳驾驶)通过raise/clear加入 与终端报警分开判断
+//可人工确认的报警(紧急/危险预警/进出区域/进出路线/路段行驶时间/非法点火/非法位移)由0x8203确认
+
+//可人工确认的标志位
+const CONFIRMABLE: u32 = 1 | 1 << 3 | 1 << 20 | 1 << 21 | 1 << 22 | 1 << 27 | 1 << 28;
+
+pub const SOURCE_TERMINAL: &str = "terminal";
+pub const SOURCE_SERVER: &str = "server";
+
+static GLOBAL_ALARM: std::sync::Mutex<Option<HashMap<String, Vec<ActiveAlarm>>>> = std::sync::Mutex::new(None);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveAlarm {
+    pub sim: String,
+    /// terminal:终端上报 server:平台检测
+    pub source: &'static str,
+    pub bit: u8,
+    pub kind: &'static str,
+    /// 报警开始时0x0200的流水号 0x8203确认时使用
+    pub sn: u16,
+    /// 终端时间 unix秒
+    pub start: i64,
+    pub lat: f64,
+    pub lng: f64,
+    pub speed: f64,
+    /// 最后一次汇报的终端时间
+    pub last: i64,
+    /// 持续汇报的次数
+    pub reports: u32,
+    pub confirmable: bool,
+    pub acknowledged: bool,
+}
+
+/// 报警标志位名称 保留位返回None
+pub fn alarm_kind(bit:u8) -> Option<&'static str> {
+    let kind = match bit {
+        0 => "emergency",
+        1 => "overspeed",
+        2 => "fatigue",
+        3 => "danger_warning",
+        4 => "gnss_fault",
+        5 => "gnss_antenna_open",
+        6 => "gnss_antenna_short",
+        7 => "power_low",
+        8 => "power_off",
+        9 => "lcd_fault",
+        10 => "tts_fault",
+        11 => "camera_fault",
+        12 => "ic_card_fault",
+        13 => "overspeed_warning",
+        14 => "fatigue_warning",
+        15 => "illegal_driving",
+        16 => "tire_pressure_warning",
+        17 => "right_turn_blind_zone",
+        18 => "driving_timeout",
+        19 => "parking_timeout",
+        20 => "area_in_out",
+        21 => "route_in_out",
+        22 => "route_time",
+        23 => "route_deviation",
+        24 => "vss_fault",
+        25 => "fuel_abnormal",
+        26 => "stolen",
+        27 => "illegal_ignition",
+        28 => "illegal_move",
+        29 => "collision",
+        30 => "rollover",
+        31 => "illegal_door",
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// 报警名称或标志位数字转为标志位
+pub fn parse_kind(kind:&str) -> Option<u8> {
+    match kind.parse::<u8>() {
+        Ok(bit) => alarm_kind(bit).map(|_| bit),
+        Err(_) => (0..32).find(|t| alarm_kind(*t) == Some(kind)),
+    }
+}
+
+pub fn init() {
+    *GLOBAL_ALARM.lock().unwrap() = Some(HashMap::new());
+}
+
+//测试共用全局状态 只初始化一次 避免清掉其它测试的报警
+#[cfg(test)]
+pub fn test_init() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(init);
+}
+
+/// 终端上报0x0200时更新 sn为该消息的流水号
+pub fn update(sim:&str, sn:u16, location:&JtLocation) {
+    let (fired, ended) = {
+        let mut global = GLOBAL_ALARM.lock().unwrap();
+        let map = match global.as_mut() {
+            Some(map) => map,
+            None => return,
+        };
+        let active = map.entry(sim.to_string()).or_default();
+        let last = active.iter().filter(|t| t.source == SOURCE_TERMINAL).fold(0u32, |bits, t| bits | 1 << t.bit);
+        if location.alarm == 0 && last == 0 {
+            if active.is_empty() {
+                map.remove(sim);
+            }
+            return;
+        }
+
+        let mut ended = Vec::new();
+        active.retain(|t| {
+            if t.source != SOURCE_TERMINAL || location.alarm & 1 << t.bit > 0 {
+                return true;
+            }
+            ended.push(t.clone());
+            false
+        });
+        for alarm in active.iter_mut().filter(|t| t.source == SOURCE_TERMINAL) {
+            alarm.last = location.time;
+            alarm.reports += 1;
+        }
+
+        let mut fired = Vec::new();
+        for bit in 0..32u8 {
+            if location.alarm & 1 << bit == 0 || last & 1 << bit > 0 {
+                continue;
+            }
+            let kind = match alarm_kind(bit) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let alarm = ActiveAlarm {
+                sim: sim.to_string(),
+                source: SOURCE_TERMINAL,
+                bit,
+                kind,
+                sn,
+                start: location.time,
+                lat: location.lat,
+                lng: location.lng,
+                speed: location.speed,
+                last: location.time,
+                reports: 1,
+                confirmable: CONFIRMABLE & 1 << bit > 0,
+                acknowledged: false,
+            };
+            active.push(alarm.clone());
+            fired.push(alarm);
+        }
+        if active.is_empty() {
+            map.remove(sim);
+        }
+        (fired, ended)
+    };
+
+    for alarm in &fired {
+        service_event::publish(DeviceEvent::new(sim, "alarm", alarm));
+    }
+    for alarm in &ended {
+        service_event::publish(DeviceEvent::new(sim, "alarm_end", alarm));
+    }
+}
+
+/// 平台检测到报警 已存在时只更新最后时间 返回是否为新报警
+pub fn raise(sim:&str, bit:u8, location:&JtLocation) -> bool {
+    let kind = match alarm_kind(bit) {
+        Some(kind) => kind,
+        None => return false,
+    };
+    let alarm = {
+        let mut global = GLOBAL_ALARM.lock().unwrap();
+        let active = match global.as_mut() {
+            Some(map) => map.entry(sim.to_string()).or_default(),
+            None => return false,
+        };
+        if let Some(alarm) = active.iter_mut().find(|t| t.source == SOURCE_SERVER && t.bit == bit) {
+            alarm.last = location.time;
+            alarm.reports += 1;
+            return false;
+        }
+        let alarm = ActiveAlarm {
+            sim: sim.to_string(),
+            source: SOURCE_SERVER,
+            bit,
+            kind,
+            sn: 0,
+            start: location.time,
+            lat: location.lat,
+            lng: location.lng,
+            speed: location.speed,
+            last: location.time,
+            reports: 1,
+            confirmable: false,
+            acknowledged: false,
+        };
+        active.push(alarm.clone());
+        alarm
+    };
+    service_event::publish(DeviceEvent::new(sim, "alarm", &alarm));
+    true
+}
+
+/// 平台检测的报警结束
+pub fn clear(sim:&str, bit:u8) {
+    let alarm = {
+        let mut global = GLOBAL_ALARM.lock().unwrap();
+        let map = match global.as_mut() {
+            Some(map) => map,
+            None => return,
+        };
+        let active = match map.get_mut(sim) {
+            Some(active) => active,
+            None => return,
+        };
+        let alarm = match active.iter().position(|t| t.source == SOURCE_SERVER && t.bit == bit) {
+            Some(i) => active.remove(i),
+            None => return,
+        };
+        if active.is_empty() {
+            map.remove(sim);
+        }
+        alarm
+    };
+    service_event::publish(DeviceEvent::new(sim, "alarm_end", &alarm));
+}
+
+/// 终端当前报警
+pub fn get(sim:&str) -> Vec<ActiveAlarm> {
+    GLOBAL_ALARM.lock().unwrap().as_ref().and_then(|t| t.get(sim).cloned()).unwrap_or_default()
+}
+
+/// 全部终端的当前报警
+pub fn all() -> Vec<ActiveAlarm> {
+    GLOBAL_ALARM.lock().unwrap().as_ref().map_or(Vec::new(), |t| t.values().flatten().cloned().collect())
+}
+
+/// 下发0x8203人工确认 bit为None时确认全部可确认的当前报警
+/// 返回终端通用应答结果 -1:超时或不在线 -2:没有可确认的报警
+pub async fn acknowledge(sim:&str, bit:Option<u8>) -> i32 {
+    let targets: Vec<ActiveAlarm> = get(sim).into_iter().filter(|t| t.confirmable && bit.is_none_or(|b| b == t.bit)).collect();
+    if targets.is_empty() {
+        return -2;
+    }
+    let sender = match service_device::get_sender(sim).await {
+        Some(sender) => sender,
+        None => return -1,
+    };
+
+    //单个报警按开始时的流水号确认 多个时流水号为0
+    let mut jt8203 = Jt0x8203 {
+        alarm_sn: if targets.len() == 1 { targets[0].sn } else { 0 },
+        alarm_type: targets.iter().fold(0, |bits, t| bits | 1 << t.bit),
+    };
+    let result = sender.send_cmd(0x8203, &mut jt8203).await;
+    log::info!("[service-alarm]acknowledge sim:{} sn:{} type:{:08x} result:{}", sim, jt8203.alarm_sn, jt8203.alarm_type, result);
+    if result != 0 {
+        return result;
+    }
+
+    if let Some(active) = GLOBAL_ALARM.lock().unwrap().as_mut().and_then(|t| t.get_mut(sim)) {
+        for alarm in active.iter_mut().filter(|t| t.confirmable && jt8203.alarm_type & 1 << t.bit > 0) {
+            alarm.acknowledged = true;
+        }
+    }
+    for mut alarm in targets {
+        alarm.acknowledged = true;
+        service_event::publish(DeviceEvent::new(sim, "alarm_ack", &alarm));
+    }
+    result
+}
+
+
+#[test]
+fn test_alarm_edges()
+{
+    test_init();
+    let mut location = JtLocation { alarm: 1 | 1 << 1, time: 100, ..Default::default() };
+    update("test_alarm", 5, &location);
+    location.time = 110;
+    update("test_alarm", 6, &location);
+    let active = get("test_alarm");
+    assert_eq!(active.len(), 2);
+    assert_eq!((active[0].kind, active[0].sn, active[0].start, active[0].last, active[0].reports), ("emergency", 5, 100, 110, 2));
+    assert!(active[0].confirmable && !active[1].confirmable);
+
+    location.alarm = 1 << 1 | 1 << 20;
+    update("test_alarm", 7, &location);
+    let active = get("test_alarm");
+    assert_eq!(active.iter().map(|t| (t.bit, t.sn)).collect::<Vec<_>>(), vec![(1, 5), (20, 7)]);
+
+    location.alarm = 0;
+    update("test_alarm", 8, &location);
+    assert!(get("test_alarm").is_empty());
+
+    //平台检测的报警不受终端标志位影响
+    assert!(raise("test_alarm", 1, &location));
+    assert!(!raise("test_alarm", 1, &location));
+    update("test_alarm", 9, &location);
+    assert_eq!(get("test_alarm").iter().map(|t| t.source).collect::<Vec<_>>(), vec![SOURCE_SERVER]);
+    clear("test_alarm", 1);
+    assert!(get("test_alarm").is_empty());
+    assert_eq!(parse_kind("illegal_move"), Some(28));
+    assert_eq!(parse_kind("3"), Some(3));
+    assert_eq!(parse_kind("unknown"), None);
+}
@@ -0,0 +1,425 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use bytes::{Buf, BytesMut};
+use jt808::{models::{Jt0x0001, Jt808BodySerialize}, JtPackage, JtSubMerger};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}, net::{TcpListener, TcpStream, tcp::OwnedWriteHalf}, time::timeout};
+
+use crate::{service_safety, session808::{jt808_jsatl12::{Jt0x1210, Jt0x9212, JtAttachmentFile}, jt808_parse::Jt808DeserializeAndPackUp}};
+
+//主动安全报警附件服务 终端收到0x9208后连接
+//0x1210附件信息 0x1211文件信息 码流包(0x30316364) 0x1212上传完成 应答0x9212补传列表
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+//码流包头 帧头4 文件名称50 数据偏移量4 数据长度4
+const STREAM_HEAD: [u8; 4] = [0x30, 0x31, 0x63, 0x64];
+const STREAM_HEAD_LEN: usize = 62;
+//单个码流包数据上限
+const STREAM_MAX_DATA: usize = 1024 * 1024;
+//808消息(含转义)长度上限 消息体最长1023字节
+const FRAME_MAX_LEN: usize = 4096;
+
+pub async fn start(addr:&str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("[service-attachment]listen addr:{}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    log::info!("[service-attachment]new connect addr:{}", addr);
+                    tokio::spawn(run_session(stream));
+                },
+                Err(err) => {
+                    log::warn!("[service-attachment]accept failed:{}", err);
+                },
+            }
+        }
+    });
+    Ok(())
+}
+
+struct FileRecv {
+    size: u32,
+    file_type: u8,
+    file: Option<File>,
+    //已接收的(偏移, 长度)
+    ranges: Vec<(u32, u32)>,
+}
+
+struct AttachmentSession {
+    writer: OwnedWriteHalf,
+    parse: Jt808DeserializeAndPackUp,
+    //应答使用首条消息的终端号和版本
+    package: Option<JtPackage>,
+    number: String,
+    dir: Option<PathBuf>,
+    files: HashMap<String, FileRecv>,
+}
+
+async fn run_session(stream:TcpStream) {
+    let (mut reader, writer) = stream.into_split();
+    let mut session = AttachmentSession {
+        writer,
+        parse: Jt808DeserializeAndPackUp::new(),
+        package: None,
+        number: String::new(),
+        dir: None,
+        files: HashMap::new(),
+    };
+    let mut buffer = BytesMut::with_capacity(8096);
+    loop {
+        match timeout(IDLE_TIMEOUT, reader.read_buf(&mut buffer)).await {
+            Ok(Ok(n)) if n > 0 => {},
+            _ => break,
+        }
+        if !session.handle_buffer(&mut buffer).await {
+            break;
+        }
+    }
+    log::info!("[service-attachment]disconnect number:{}", session.number);
+}
+
+impl AttachmentSession {
+    //一次读取可能包含多条消息和码流包 返回false时断开
+    async fn handle_buffer(&mut self, buffer:&mut BytesMut) -> bool {
+        loop {
+            if buffer.is_empty() {
+                return true;
+            }
+            if buffer[0] == 0x7e {
+                let end = match buffer.iter().skip(1).take(FRAME_MAX_LEN).position(|t| *t == 0x7e) {
+                    Some(end) => end + 2,
+                    None if buffer.len() <= FRAME_MAX_LEN => return true,
+                    None => {
+                        log::info!("[service-attachment]disconnect(frame too long)");
+                        return false;
+                    },
+                };
+                let mut frame = buffer.split_to(end);
+                match self.parse.deserialize(&mut frame) {
+                    Ok(Some(mut jtsub)) => {
+                        if !self.handle_808(&mut jtsub).await {
+                            return false;
+                        }
+                    },
+                    Ok(None) => {},
+                    Err(_) => {
+                        log::info!("[service-attachment]disconnect(protocol)");
+                        return false;
+                    },
+                }
+            } else if buffer.len() < STREAM_HEAD.len() && STREAM_HEAD.starts_with(buffer) {
+                //码流包头不完整 等待后续数据
+                return true;
+            } else if buffer.starts_with(&STREAM_HEAD) {
+                if buffer.len() < STREAM_HEAD_LEN {
+                    return true;
+                }
+                let len = u32::from_be_bytes([buffer[58], buffer[59], buffer[60], buffer[61]]) as usize;
+                if len > STREAM_MAX_DATA {
+                    log::info!("[service-attachment]disconnect(stream len:{})", len);
+                    return false;
+                }
+                if buffer.len() < STREAM_HEAD_LEN + len {
+                    return true;
+                }
+                let name = String::from_utf8_lossy(&buffer[4..54]).trim_end_matches('\0').to_string();
+                let offset = u32::from_be_bytes([buffer[54], buffer[55], buffer[56], buffer[57]]);
+                buffer.advance(STREAM_HEAD_LEN);
+                let data = buffer.split_to(len);
+                self.handle_stream(&name, offset, &data).await;
+            } else {
+                //不可识别 跳到下一个可能的头
+                let next = buffer.iter().skip(1).position(|t| *t == 0x7e || *t == STREAM_HEAD[0]).map_or(buffer.len(), |t| t + 1);
+                buffer.advance(next);
+            }
+        }
+    }
+
+    async fn handle_808(&mut self, jtsub:&mut JtSubMerger) -> bool {
+        let (id, sn) = match jtsub.get_first_jt() {
+            Some(jt) => {
+                if self.package.is_none() {
+                    self.package = Some(JtPackage::new(jt.sim.clone(), jt.v19, jt.ver, 1023));
+                }
+                (jt.id, jt.sn)
+            },
+            None => return true,
+        };
+
+        match id {
+            0x1210 => {
+                let tt = jtsub.trans_body::<Jt0x1210>();
+                log::info!("[service-attachment]recv 0x1210 number:{} files:{:?}", tt.alarm_number, tt.files);
+                self.dir = service_safety::on_files(&tt).await;
+                if self.dir.is_none() {
+                    //报警编号不是本平台分配的
+                    self.answer(sn, id, 1).await;
+                    return false;
+                }
+                self.number = tt.alarm_number;
+                self.answer(sn, id, 0).await;
+            },
+            0x1211 => {
+                let tt = jtsub.trans_body::<JtAttachmentFile>();
+                log::info!("[service-attachment]recv 0x1211 number:{} file:{:?}", self.number, tt);
+                let result = match self.open_file(&tt).await {
+                    true => 0,
+                    false => 1,
+                };
+                self.answer(sn, id, result).await;
+            },
+            0x1212 => {
+                let tt = jtsub.trans_body::<JtAttachmentFile>();
+                //没有0x1211时按0x1212的文件信息接收 要求整个文件补传
+                if !self.open_file(&tt).await {
+                    self.answer(sn, id, 1).await;
+                    return true;
+                }
+                let missing = match self.files.get_mut(&tt.name) {
+                    Some(recv) => {
+                        let missing = missing_ranges(&recv.ranges, recv.size);
+                        if missing.is_empty() {
+                            if let Some(mut file) = recv.file.take() {
+                                let _ = file.flush().await;
+                            }
+                        }
+                        missing
+                    },
+                    None => vec![(0, tt.size)],
+                };
+                log::info!("[service-attachment]recv 0x1212 number:{} file:{} missing:{:?}", self.number, tt.name, missing);
+                if missing.is_empty() {
+                    self.files.remove(&tt.name);
+                    service_safety::on_file_complete(&self.number, &tt.name).await;
+                }
+                let mut jt9212 = Jt0x9212 {
+                    result: if missing.is_empty() { 0 } else { 1 },
+                    name: tt.name,
+                    file_type: tt.file_type,
+                    missing,
+                };
+                self.send(0x9212, &mut jt9212).await;
+            },
+            _ => {
+                self.answer(sn, id, 0).await;
+            },
+        }
+        true
+    }
+
+    async fn open_file(&mut self, info:&JtAttachmentFile) -> bool {
+        if self.files.contains_key(&info.name) {
+            return true;
+        }
+        let path = match (&self.dir, service_safety::file_name(&info.name)) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => return false,
+        };
+        let file = match OpenOptions::new().create(true).write(true).truncate(true).open(&path).await {
+            Ok(file) => file,
+            Err(err) => {
+                log::warn!("[service-attachment]open file failed:{:?} err:{}", path, err);
+                return false;
+            },
+        };
+        service_safety::on_file_info(&self.number, &info.name, info.file_type);
+        self.files.insert(info.name.clone(), FileRecv { size: info.size, file_type: info.file_type, file: Some(file), ranges: Vec::new() });
+        true
+    }
+
+    async fn handle_stream(&mut self, name:&str, offset:u32, data:&[u8]) {
+        let recv = match self.files.get_mut(name) {
+            Some(recv) => recv,
+            None => {
+                log::info!("[service-attachment]stream of unknown file:{}", name);
+                return;
+            },
+        };
+        if offset as u64 + data.len() as u64 > recv.size as u64 {
+            log::info!("[service-attachment]stream out of range file:{} type:{} offset:{} len:{}", name, recv.file_type, offset, data.len());
+            return;
+        }
+        let file = match recv.file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+        if file.seek(std::io::SeekFrom::Start(offset as u64)).await.is_err() || file.write_all(data).await.is_err() {
+            log::warn!("[service-attachment]write failed file:{}", name);
+            return;
+        }
+        recv.ranges.push((offset, data.len() as u32));
+    }
+
+    async fn answer(&mut self, sn:u16, id:u16, result:u8) {
+        let mut jt8001 = Jt0x0001 { answer_sn: sn, answer_id: id, result };
+        self.send(0x8001, &mut jt8001).await;
+    }
+
+    async fn send<T: Jt808BodySerialize>(&mut self, id:u16, body:&mut T) {
+        let package = match &self.package {
+            Some(package) => package,
+            None => return,
+        };
+        let buf = package.serialize(id, 0, body);
+        let _ = self.writer.write_all(&buf).await;
+    }
+}
+
+/// 按已接收的(偏移, 长度)计算[0, size)中缺少的区间
+fn missing_ranges(ranges:&[(u32, u32)], size:u32) -> Vec<(u32, u32)> {
+    let mut ranges = ranges.to_vec();
+    ranges.sort();
+    let mut missing = Vec::new();
+    let mut pos = 0u32;
+    for (offset, len) in ranges {
+        if offset > pos {
+            missing.push((pos, offset - pos));
+        }
+        pos = pos.max(offset.saturating_add(len));
+    }
+    if pos < size {
+        missing.push((pos, size - pos));
+    }
+    missing
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use jt808::models::{Jt808, Ver808};
+    use jt_util::{bytes::IBuffWrite, bytes_bcd::BytesBCD};
+    use crate::{service_device, session808::{jt808_models::JtLocation, jt808_parse::Jt808Deserialize}};
+
+    #[test]
+    fn test_missing_ranges()
+    {
+        assert_eq!(missing_ranges(&[], 100), vec![(0, 100)]);
+        assert_eq!(missing_ranges(&[(0, 100)], 100), vec![]);
+        assert_eq!(missing_ranges(&[(60, 40), (0, 30)], 100), vec![(30, 30)]);
+        assert_eq!(missing_ranges(&[(0, 50), (20, 20), (70, 10)], 100), vec![(50, 20), (80, 20)]);
+    }
+
+    //原样写入的消息体
+    struct RawBody(Vec<u8>);
+
+    impl Jt808BodySerialize for RawBody {
+        fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+            buf.put_slice(&self.0);
+        }
+
+        fn len(&self, _ver:&Ver808) -> usize {
+            self.0.len()
+        }
+    }
+
+    fn file_body(name:&str, file_type:u8, size:u32) -> RawBody {
+        let mut body = vec![name.len() as u8];
+        body.extend_from_slice(name.as_bytes());
+        body.push(file_type);
+        body.extend_from_slice(&size.to_be_bytes());
+        RawBody(body)
+    }
+
+    fn stream_package(name:&str, offset:u32, data:&[u8]) -> Vec<u8> {
+        let mut buf = STREAM_HEAD.to_vec();
+        let mut field = name.as_bytes().to_vec();
+        field.resize(50, 0);
+        buf.extend_from_slice(&field);
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    async fn recv_jt(stream:&mut TcpStream, parse:&mut Jt808Deserialize, buffer:&mut BytesMut) -> Jt808 {
+        loop {
+            if let Ok(Some(jt)) = parse.deserialize(buffer) {
+                return jt;
+            }
+            assert!(stream.read_buf(buffer).await.unwrap() > 0);
+        }
+    }
+
+    async fn start_server() -> String {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        start(&addr).await.unwrap();
+        addr
+    }
+
+    //0x1210->0x1211->码流->0x1212->0x9212 码流包头分两次到达
+    #[tokio::test]
+    async fn test_attachment_upload()
+    {
+        let sim = "013800000451";
+        //终端不在线 0x9208下发失败
+        service_device::test_init();
+        service_safety::init("127.0.0.1:0", "attachment");
+        let mut adas = 7u32.to_be_bytes().to_vec();
+        adas.extend_from_slice(&[1, 0x02, 1, 0, 0, 1, 0, 0, 60, 0x00, 0x10]);
+        adas.extend_from_slice(&22_543_100u32.to_be_bytes());
+        adas.extend_from_slice(&114_057_900u32.to_be_bytes());
+        adas.extend_from_slice(&[0x24, 0x01, 0x02, 0x03, 0x04, 0x05, 0x00, 0x01]);
+        let identifier = [b"T000001".as_slice(), &[0x24, 0x01, 0x02, 0x03, 0x04, 0x05, 1, 1, 0]].concat();
+        adas.extend_from_slice(&identifier);
+        let mut location = JtLocation::default();
+        location.extras.insert(0x64, Bytes::from(adas));
+        service_safety::update(sim, &location);
+        let number = service_safety::list(sim)[0].number.clone();
+
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let mut sim_bcd = BytesBCD::new();
+        sim_bcd.set_bytes(Bytes::from(hex::decode(sim).unwrap()));
+        let package = JtPackage::new(sim_bcd, false, 0, 1023);
+        let mut parse = Jt808Deserialize::new();
+        let mut buffer = BytesMut::new();
+        let name = "00_64_6401_0_test.jpg";
+        let data: Vec<u8> = (0..200u8).collect();
+
+        let mut jt1210 = b"T000001".to_vec();
+        jt1210.extend_from_slice(&identifier);
+        jt1210.extend_from_slice(number.as_bytes());
+        jt1210.extend_from_slice(&[0, 1]);
+        jt1210.extend_from_slice(&file_body(name, 0, data.len() as u32).0[..1 + name.len()]);
+        jt1210.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        for (id, mut body) in [(0x1210, RawBody(jt1210)), (0x1211, file_body(name, 0, data.len() as u32))] {
+            stream.write_all(&package.serialize(id, 0, &mut body)).await.unwrap();
+            let jt = recv_jt(&mut stream, &mut parse, &mut buffer).await;
+            let answer = jt.get_body();
+            assert_eq!((jt.id, u16::from_be_bytes([answer[2], answer[3]]), answer[4]), (0x8001, id, 0));
+        }
+
+        let first = stream_package(name, 0, &data[..120]);
+        stream.write_all(&first[..2]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream.write_all(&first[2..]).await.unwrap();
+        stream.write_all(&stream_package(name, 120, &data[120..])).await.unwrap();
+
+        stream.write_all(&package.serialize(0x1212, 0, &mut file_body(name, 0, data.len() as u32))).await.unwrap();
+        let jt = recv_jt(&mut stream, &mut parse, &mut buffer).await;
+        assert_eq!(jt.id, 0x9212);
+        //文件名 类型 结果0 补传数0
+        assert_eq!(&jt.get_body()[1 + name.len()..], &[0, 0, 0]);
+
+        let path = service_safety::file_path(&number, name).unwrap();
+        assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+        assert!(service_safety::get(&number).unwrap().files.iter().all(|t| t.completed));
+    }
+
+    //没有结束标识的超长808消息断开
+    #[tokio::test]
+    async fn test_attachment_frame_limit()
+    {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let mut frame = vec![0x7e];
+        frame.resize(FRAME_MAX_LEN + 2, 0x01);
+        stream.write_all(&frame).await.unwrap();
+        let mut buf = [0u8; 16];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+}
@@ -0,0 +1,125 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::{service_device, session1078::extend808::{Jt0x1003, JtEmpty}};
+
+//终端音视频属性 0x9003查询 0x1003应答按SIM缓存
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+static GLOBAL_AVINFO: std::sync::Mutex<Option<HashMap<String, Jt0x1003>>> = std::sync::Mutex::new(None);
+
+pub fn init() {
+    *GLOBAL_AVINFO.lock().unwrap() = Some(HashMap::new());
+}
+
+/// 终端上报0x1003时更新缓存 消息体不完整时忽略
+pub fn update(sim:&str, attr:Jt0x1003) {
+    if !attr.valid {
+        log::warn!("[service-avinfo]sim:{} invalid 0x1003", sim);
+        return;
+    }
+    if let Some(map) = GLOBAL_AVINFO.lock().unwrap().as_mut() {
+        map.insert(sim.to_string(), attr);
+    }
+}
+
+pub fn get_cached(sim:&str) -> Option<Jt0x1003> {
+    GLOBAL_AVINFO.lock().unwrap().as_ref()?.get(sim).cloned()
+}
+
+/// 取音视频属性 没有缓存或refresh时下发0x9003
+pub async fn query(sim:&str, refresh:bool) -> Option<Jt0x1003> {
+    if !refresh {
+        if let Some(attr) = get_cached(sim) {
+            return Some(attr);
+        }
+    }
+
+    let sender = service_device::get_sender(sim).await?;
+    match sender.request::<_, Jt0x1003>(0x9003, &mut JtEmpty {}, 0x1003, false, QUERY_TIMEOUT).await {
+        Ok(attr) if attr.valid => {
+            log::info!("[service-avinfo]sim:{} {:?}", sim, attr);
+            update(sim, attr.clone());
+            Some(attr)
+        },
+        //消息体不完整 不缓存
+        Ok(_) => {
+            log::warn!("[service-avinfo]query sim:{} invalid 0x1003", sim);
+            None
+        },
+        Err(ret) => {
+            log::info!("[service-avinfo]query sim:{} ret:{}", sim, ret);
+            None
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use bytes::{Bytes, BytesMut};
+    use jt808::{JtPackage, models::Jt0x0001};
+    use jt_util::bytes_bcd::BytesBCD;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc};
+    use crate::{service_device, service_forward::ServiceForward, service_passthrough::ServicePassthrough, session808::jt808_parse::Jt808Deserialize};
+
+    //模拟终端 上线后记录平台下发的(消息ID, 消息体) 并以result通用应答
+    async fn device(sim:&str, result:u8) -> mpsc::UnboundedReceiver<(u16, Bytes)> {
+        service_device::test_init();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let fw_service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+        let pt_service = Arc::new(ServicePassthrough::new(Vec::new(), String::new()));
+        service_device::start(&addr, fw_service, pt_service).await.unwrap();
+
+        let mut sim_bcd = BytesBCD::new();
+        sim_bcd.set_bytes(Bytes::from(hex::decode(sim).unwrap()));
+        let package = JtPackage::new(sim_bcd, false, 0, 1023);
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        //jt808库空消息体的长度写错 用通用应答上线
+        stream.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: 0, answer_id: 0x0002, result: 0 })).await.unwrap();
+        while service_device::get_sender(sim).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut parse = Jt808Deserialize::new();
+            let mut buffer = BytesMut::new();
+            while let Ok(n) = stream.read_buf(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                while let Ok(Some(jt)) = parse.deserialize(&mut buffer) {
+                    //平台通用应答不需要回复
+                    if jt.id == 0x8001 {
+                        continue;
+                    }
+                    let mut answer = Jt0x0001 { answer_sn: jt.sn, answer_id: jt.id, result };
+                    if stream.write_all(&package.serialize(0x0001, 0, &mut answer)).await.is_err() || tx.send((jt.id, jt.get_body())).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_query_cache()
+    {
+        init();
+        let sim = "013800000371";
+        update(sim, Jt0x1003::default());
+        assert!(get_cached(sim).is_none());
+
+        //终端拒绝0x9003时不缓存
+        let _device = device(sim, 1).await;
+        assert!(query(sim, false).await.is_none());
+        assert!(get_cached(sim).is_none());
+
+        update(sim, Jt0x1003 { audio_codec: 6, valid: true, ..Default::default() });
+        assert_eq!(query(sim, false).await.unwrap().audio_codec, 6);
+    }
+}
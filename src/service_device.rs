@@ -1,21 +1,24 @@
 
 
-use axum::async_trait;
 use jt808::JtPackage;
+use serde::Serialize;
 use tokio::{io::{self, AsyncReadExt}, net::TcpListener, sync::Mutex, time::timeout};
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use crate::{session808::{jt808_session::{Jt808SessionShared, Jt808Session}, jt808_parse::Jt808DeserializeAndPackUp}, service_forward::ServiceForward};
+use crate::{session808::{jt808_session::{Jt808SessionShared, Jt808Session}, jt808_parse::Jt808DeserializeAndPackUp}, service_forward::ServiceForward, service_passthrough::ServicePassthrough, service_event::{self, DeviceEvent}, service_video_alarm};
 
 static GLOBAL_DATA: std::sync::Mutex<Option<HashMap<String, Arc<Jt808SessionShared>>>> = std::sync::Mutex::new(None);
 
 pub trait GetSender {
+    #[allow(clippy::ptr_arg)]
     fn get_sender(&self, sim:&String) -> Option<Arc<Jt808SessionShared>>;
 }
+
 pub struct ServiceJt808 {
     m_map_sessions: Mutex<HashMap<String, Arc<Jt808SessionShared>>>,
 }
 
+#[allow(clippy::ptr_arg)]
 impl ServiceJt808 {
     pub fn new() ->Self {
         let m_map_sessions = Mutex::new(HashMap::default());
@@ -24,95 +27,9 @@ impl ServiceJt808 {
         }
     }
 
+    //会话仍由全局表管理 与模块start一致 不建立透传连接
     pub async fn start(&self, addr:&String, fw_service:Arc<ServiceForward>) -> io::Result<()> {
-        let listener: TcpListener = TcpListener::bind(addr).await.expect("service device listen failed");
-
-        log::info!("[service-device]listen addr:{}", addr);
-
-        let _ = tokio::spawn(async move{
-            loop {
-                let (socket, _) = listener.accept().await.unwrap();
-
-                log::info!("[service-device]new connect addr:{:?}", socket.peer_addr());
-
-                let fw_service = fw_service.clone();
-                //todo: linux use tokio::uring 
-                tokio::spawn(async move{
-
-                    let (mut reader, writer) = socket.into_split();
-                    let mut jt808_parse = Jt808DeserializeAndPackUp::new();
-
-                    let sender_arc = Arc::new(Mutex::new(writer));
-                    let mut buffer = bytes::BytesMut::with_capacity(8096);
-                    let mut sessions: HashMap<String, Jt808Session> = HashMap::new();
-
-                    loop {
-                        match timeout(Duration::from_secs(60), reader.read_buf(&mut buffer)).await {
-                            Ok(result) => {
-                            let n = result.expect("[service-device]conn faield read");
-                            if n > 0 {
-                                    match jt808_parse.deserialize(&mut buffer) {
-                                        Ok(package) => {
-                                            if let Some(mut jtsub) = package {
-                                                if let Some(jt808) = jtsub.get_first_jt() {
-                                                    let sim = jt808.sim.to_string();
-
-                                                    match sessions.get_mut(&sim) {
-                                                        Some(session) => {
-                                                            //是否已经closed
-                                                            if session.is_closed() {
-                                                                log::info!("[service-device]disconnect(session closed)");
-                                                                break;
-                                                            }
-                                                            session.handle(&mut jtsub).await;
-                                                        },
-                                                        None => {
-                                                            //获得转发列表
-                                                            let fw_sender = ServiceForward::get_forward_sender(&fw_service, &sim).await;
-
-                                                            let session_common: Arc<Jt808SessionShared> = Arc::new(Jt808SessionShared::new(
-                                                                sender_arc.clone(),
-                                                                JtPackage::new(jt808.sim.clone(), jt808.v19, jt808.ver, 1023),
-                                                            ));
-            
-                                                            let mut session = Jt808Session::new(session_common.clone(),  fw_sender).await;
-                                                            
-                                                            session.handle(&mut jtsub).await;
-            
-                                                            sessions.insert(sim.clone(), session);
-                                                            map_insert(sim.clone(), session_common);
-                                                        },
-                                                    }
-                                                }
-                                            }
-                                        },
-                                        Err(_err) => {
-                                            log::info!("[service-device]disconnect(protocol)");
-                                            break;
-                                        },
-                                    };
-                                } else {
-                                    log::info!("[service-device]disconnect(reason:net)");
-                                    break;
-                                }
-                            },
-                            Err(_) => {
-                                log::info!("[service-device]disconnect(timeout)");
-                                break;
-                            },
-                        };
-                    }
-                
-                    for (sim, session) in sessions {
-                        map_remove(&sim, session.session_shared);
-                    }
-
-                });
-            }
-        });
-
-        Ok(())
-
+        start(addr, fw_service, Arc::new(ServicePassthrough::new(Vec::new(), String::new()))).await
     }
     
     pub async fn map_insert(&mut self, sim : String, value :Arc<Jt808SessionShared>) {        
@@ -144,16 +61,15 @@ impl ServiceJt808 {
 
 }
 
+impl Default for ServiceJt808 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GetSender for ServiceJt808  {
     fn get_sender(&self, sim:&String) -> Option<Arc<Jt808SessionShared>> {
-        match map_get(sim) {
-            Some(client_sender) => {
-                Some(client_sender)
-            },
-            None => {
-                None
-            },
-        }
+        map_get(sim)
     }
 }
 
@@ -161,19 +77,20 @@ pub fn init() {
     *GLOBAL_DATA.lock().unwrap() = Some(HashMap::default());
 }
 
-pub async fn start(addr:&String, fw_service:Arc<ServiceForward>) -> io::Result<()> {
+pub async fn start(addr:&String, fw_service:Arc<ServiceForward>, pt_service:Arc<ServicePassthrough>) -> io::Result<()> {
     
     let listener: TcpListener = TcpListener::bind(addr).await.expect("service device listen failed");
 
     log::info!("[service-device]listen addr:{}", addr);
 
-    let _ = tokio::spawn(async move{
+    tokio::spawn(async move{
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
+            let (socket, addr) = listener.accept().await.unwrap();
 
-            log::info!("[service-device]new connect addr:{:?}", socket.peer_addr());
+            log::info!("[service-device]new connect addr:{:?}", addr);
 
             let fw_service = fw_service.clone();
+            let pt_service = pt_service.clone();
             //todo: linux use tokio::uring 
             tokio::spawn(async move{
 
@@ -184,14 +101,19 @@ pub async fn start(addr:&String, fw_service:Arc<ServiceForward>) -> io::Result<(
                 let mut buffer = bytes::BytesMut::with_capacity(8096);
                 let mut sessions: HashMap<String, Jt808Session> = HashMap::new();
 
+                let mut is_err = false;
+                //下线原因
+                let mut reason = "";
                 loop {
                     match timeout(Duration::from_secs(60), reader.read_buf(&mut buffer)).await {
                         Ok(result) => {
-                           let n = result.expect("[service-device]conn faield read");
+                           let n = result.unwrap_or(0);
                            if n > 0 {
-                                match jt808_parse.deserialize(&mut buffer) {
-                                    Ok(package) => {
-                                        if let Some(mut jtsub) = package {
+                                //一次读取可能包含多条消息
+                                loop {
+                                    let len = buffer.len();
+                                    match jt808_parse.deserialize(&mut buffer) {
+                                        Ok(Some(mut jtsub)) => {
                                             if let Some(jt808) = jtsub.get_first_jt() {
                                                 let sim = jt808.sim.to_string();
 
@@ -200,48 +122,76 @@ pub async fn start(addr:&String, fw_service:Arc<ServiceForward>) -> io::Result<(
                                                         //是否已经closed
                                                         if session.is_closed() {
                                                             log::info!("[service-device]disconnect(session closed)");
+                                                            reason = "replaced";
+                                                            is_err = true;
                                                             break;
                                                         }
                                                         session.handle(&mut jtsub).await;
                                                     },
                                                     None => {
                                                         //获得转发列表
-                                                        let fw_sender = ServiceForward::get_forward_sender(&fw_service, &sim).await;
+                                                        let fw_sender = ServiceForward::get_forward_sender(&fw_service, &sim);
 
                                                         let session_common: Arc<Jt808SessionShared> = Arc::new(Jt808SessionShared::new(
                                                             sender_arc.clone(),
                                                             JtPackage::new(jt808.sim.clone(), jt808.v19, jt808.ver, 1023),
                                                         ));
-        
-                                                        let mut session = Jt808Session::new(session_common.clone(),  fw_sender).await;
-                                                        
+
+                                                        //透传上级平台
+                                                        let pt_links = ServicePassthrough::open_links(&pt_service, jt808, session_common.clone());
+
+                                                        let mut session = Jt808Session::new(session_common.clone(), fw_sender, pt_links).await;
+
+                                                        //先上线 首条消息的事件在online之后
+                                                        let replaced = map_insert(sim.clone(), session_common);
+                                                        service_event::publish(DeviceEvent::new(&sim, "online", &OnlineEvent { addr: addr.to_string(), replaced }));
+
                                                         session.handle(&mut jtsub).await;
-        
+
                                                         sessions.insert(sim.clone(), session);
-                                                        map_insert(sim.clone(), session_common);
                                                     },
                                                 }
                                             }
-                                        }
-                                    },
-                                    Err(_err) => {
-                                        log::info!("[service-device]disconnect(protocol)");
-                                        break;
-                                    },
-                                };
+                                        },
+                                        Ok(None) => {
+                                            if buffer.is_empty() || buffer.len() == len {
+                                                break;
+                                            }
+                                        },
+                                        Err(_err) => {
+                                            log::info!("[service-device]disconnect(protocol)");
+                                            reason = "protocol";
+                                            is_err = true;
+                                            break;
+                                        },
+                                    };
+                                }
                             } else {
                                 log::info!("[service-device]disconnect(reason:net)");
-                                break;
+                                reason = "net";
+                                is_err = true;
                             }
                         },
                         Err(_) => {
                             log::info!("[service-device]disconnect(timeout)");
-                            break;
+                            reason = "timeout";
+                            is_err = true;
                         },
                     };
+
+                    if is_err {
+                        break;
+                    }
                 }
             
                 for (sim, session) in sessions {
+                    //被新连接替换的会话不发布下线
+                    if !session.is_closed() {
+                        service_event::publish(DeviceEvent::new(&sim, "offline", &OfflineEvent { addr: addr.to_string(), reason }));
+                        service_video_alarm::remove(&sim);
+                    }
+                    session.close();
+                    fw_service.unbind_device(&sim, &session.session_shared);
                     map_remove(&sim, session.session_shared);
                 }
 
@@ -252,24 +202,34 @@ pub async fn start(addr:&String, fw_service:Arc<ServiceForward>) -> io::Result<(
     Ok(())
 }
 
-pub async fn get_sender(sim:&String) -> Option<Arc<Jt808SessionShared>> {
-    match map_get(sim) {
-        Some(client_sender) => {
-            Some(client_sender)
-        },
-        None => {
-            None
-        },
-    }
+#[derive(Serialize)]
+struct OnlineEvent {
+    addr: String,
+    //替换了同一终端的旧连接
+    replaced: bool,
 }
 
-fn map_insert(sim : String, value :Arc<Jt808SessionShared>) {
+#[derive(Serialize)]
+struct OfflineEvent {
+    addr: String,
+    //net:连接断开 timeout:超时 protocol:协议错误 replaced:同一连接上的其他终端被新连接替换
+    reason: &'static str,
+}
+
+pub async fn get_sender(sim:&str) -> Option<Arc<Jt808SessionShared>> {
+    map_get(sim)
+}
+
+//返回是否替换了旧会话
+fn map_insert(sim : String, value :Arc<Jt808SessionShared>) -> bool {
     let mut binding = GLOBAL_DATA.lock().unwrap();
     let map_senders = binding.as_mut().unwrap();
     
     if let Some(session_shared) = map_senders.insert(sim, value) {
         session_shared.close();
+        return true;
     }
+    false
 }
 
 fn map_remove(sim:&String, value:Arc<Jt808SessionShared>) {
@@ -289,10 +249,17 @@ fn map_remove(sim:&String, value:Arc<Jt808SessionShared>) {
     }
 }
 
-fn map_get(sim : &String) -> Option<Arc<Jt808SessionShared>> {
+fn map_get(sim : &str) -> Option<Arc<Jt808SessionShared>> {
     let mut binding = GLOBAL_DATA.lock().unwrap();
     let map_senders = binding.as_mut().unwrap();
 
     let t = map_senders.get_mut(sim)?;
     Some(t.clone())
-}
\ No newline at end of file
+}
+
+//测试共用会话表 只初始化一次 避免清掉其它测试的终端
+#[cfg(test)]
+pub fn test_init() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(init);
+}
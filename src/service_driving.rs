@@ -0,0 +1,159 @@
+use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+
+use jt_util::bytes_gbk::BytesGBK;
+
+use crate::{config_model::DrivingRuleConfig, service_alarm, service_device, session808::jt808_models::{Jt0x8300, JtLocation}};
+
+//平台侧超速/疲劳驾驶检测 按0x0200速度和终端时间判断 结果加入当前报警(service_alarm)
+//规则按sim匹配 没有匹配时使用sims为空的默认规则
+
+const BIT_OVERSPEED: u8 = 1;
+const BIT_FATIGUE: u8 = 2;
+//高于该速度(km/h)视为行驶
+const DRIVING_SPEED: f64 = 5.0;
+
+static GLOBAL_DRIVING: std::sync::Mutex<Option<DrivingStore>> = std::sync::Mutex::new(None);
+
+struct DrivingStore {
+    rules: Vec<DrivingRuleConfig>,
+    states: HashMap<String, DrivingState>,
+}
+
+#[derive(Default)]
+struct DrivingState {
+    //开始超速的时间
+    overspeed_since: Option<i64>,
+    //本次连续驾驶开始时间
+    driving_since: Option<i64>,
+    //停车开始时间
+    stopped_since: Option<i64>,
+}
+
+pub fn init(rules:Vec<DrivingRuleConfig>) {
+    *GLOBAL_DRIVING.lock().unwrap() = Some(DrivingStore { rules, states: HashMap::new() });
+}
+
+/// 终端上报0x0200时调用
+pub fn update(sim:&str, location:&JtLocation) {
+    let time = if location.time > 0 { location.time } else { unix_now() };
+    let mut raise = Vec::new();
+    let mut clear = Vec::new();
+    let text_warning = {
+        let mut global = GLOBAL_DRIVING.lock().unwrap();
+        let store = match global.as_mut() {
+            Some(store) => store,
+            None => return,
+        };
+        let rule = match store.rules.iter().find(|t| t.get_sims().iter().any(|s| s == sim)).or_else(|| store.rules.iter().find(|t| t.sims.trim().is_empty())) {
+            Some(rule) => rule,
+            None => return,
+        };
+        let state = store.states.entry(sim.to_string()).or_default();
+
+        if rule.speed_limit > 0 {
+            if location.speed > rule.speed_limit as f64 {
+                let since = *state.overspeed_since.get_or_insert(time);
+                if time - since >= rule.overspeed_seconds as i64 {
+                    raise.push(BIT_OVERSPEED);
+                }
+            } else if state.overspeed_since.take().is_some() {
+                clear.push(BIT_OVERSPEED);
+            }
+        }
+
+        if rule.max_driving_minutes > 0 {
+            let min_rest = rule.min_rest_minutes as i64 * 60;
+            if location.speed > DRIVING_SPEED {
+                //休息足够后重新计算
+                if state.stopped_since.take().is_some_and(|t| time - t >= min_rest) {
+                    state.driving_since = None;
+                }
+                let since = *state.driving_since.get_or_insert(time);
+                if time - since >= rule.max_driving_minutes as i64 * 60 {
+                    raise.push(BIT_FATIGUE);
+                }
+            } else {
+                let since = *state.stopped_since.get_or_insert(time);
+                if time - since >= min_rest && state.driving_since.take().is_some() {
+                    clear.push(BIT_FATIGUE);
+                }
+            }
+        }
+        rule.text_warning.then_some(rule.max_driving_minutes)
+    };
+
+    for bit in clear {
+        service_alarm::clear(sim, bit);
+    }
+    for bit in raise {
+        if !service_alarm::raise(sim, bit, location) {
+            continue;
+        }
+        log::info!("[service-driving]alarm sim:{} bit:{} speed:{}", sim, bit, location.speed);
+        if let Some(max_driving_minutes) = text_warning {
+            let text = match bit {
+                BIT_OVERSPEED => "您已超速，请减速慢行".to_string(),
+                _ => format!("您已连续驾驶超过{}分钟，请停车休息", max_driving_minutes),
+            };
+            tokio::spawn(send_text(sim.to_string(), text));
+        }
+    }
+}
+
+//0x8300 终端显示并TTS播读
+async fn send_text(sim:String, text:String) {
+    let sender = match service_device::get_sender(&sim).await {
+        Some(sender) => sender,
+        None => return,
+    };
+    let mut gbk = BytesGBK::new();
+    gbk.set_val(&text);
+    let mut jt8300 = Jt0x8300 { flag: 1 << 2 | 1 << 3, text_type: 1, text: gbk };
+    let result = sender.send_cmd(0x8300, &mut jt8300).await;
+    log::info!("[service-driving]text sim:{} text:{} result:{}", sim, text, result);
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs() as i64)
+}
+
+
+#[test]
+fn test_driving_rules()
+{
+    service_alarm::test_init();
+    init(vec![DrivingRuleConfig {
+        sims: String::new(),
+        speed_limit: 80,
+        overspeed_seconds: 10,
+        max_driving_minutes: 60,
+        min_rest_minutes: 20,
+        text_warning: false,
+    }]);
+    let sim = "test_driving";
+    let report = |time:i64, speed:f64| update(sim, &JtLocation { time, speed, ..Default::default() });
+    let kinds = || service_alarm::get(sim).iter().filter(|t| t.source == service_alarm::SOURCE_SERVER).map(|t| t.kind).collect::<Vec<_>>();
+
+    report(1000, 90.0);
+    report(1005, 90.0);
+    assert!(kinds().is_empty());
+    report(1010, 90.0);
+    assert_eq!(kinds(), vec!["overspeed"]);
+    report(1020, 60.0);
+    assert!(kinds().is_empty());
+
+    //短暂停车不算休息
+    report(2000, 60.0);
+    report(2600, 0.0);
+    report(3000, 60.0);
+    assert!(kinds().is_empty());
+    report(4700, 60.0);
+    assert_eq!(kinds(), vec!["fatigue"]);
+    report(4800, 0.0);
+    report(5000, 0.0);
+    assert_eq!(kinds(), vec!["fatigue"]);
+    report(6000, 0.0);
+    assert!(kinds().is_empty());
+    report(6100, 60.0);
+    assert!(kinds().is_empty());
+}
@@ -0,0 +1,113 @@
+use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+//设备事件 终端上报解析后的结果发布到这里 供http等订阅
+//上下线(online/offline) 注册(register) 鉴权(auth) 位置(position) 报警(alarm...) 指令应答(command_answer) 多媒体上传(media_upload...)
+
+const EVENT_CAPACITY: usize = 4096;
+//高频事件不记录日志
+const QUIET_KINDS: [&str; 1] = ["position"];
+
+static GLOBAL_EVENTS: std::sync::Mutex<Option<broadcast::Sender<Arc<DeviceEvent>>>> = std::sync::Mutex::new(None);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceEvent {
+    pub sim: String,
+    /// 事件类型 如passenger_flow
+    pub kind: String,
+    /// unix秒
+    pub time: u64,
+    pub data: serde_json::Value,
+}
+
+impl DeviceEvent {
+    pub fn new<T: Serialize>(sim:&str, kind:&str, data:&T) -> Self {
+        DeviceEvent {
+            sim: sim.to_string(),
+            kind: kind.to_string(),
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs()),
+            data: serde_json::to_value(data).unwrap_or_default(),
+        }
+    }
+}
+
+pub fn init() {
+    let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+    *GLOBAL_EVENTS.lock().unwrap() = Some(sender);
+}
+
+/// 发布事件 没有订阅者时丢弃
+pub fn publish(event:DeviceEvent) {
+    if !QUIET_KINDS.contains(&event.kind.as_str()) {
+        log::info!("[service-event]sim:{} kind:{} data:{}", event.sim, event.kind, event.data);
+    }
+    if let Some(sender) = GLOBAL_EVENTS.lock().unwrap().as_ref() {
+        let _ = sender.send(Arc::new(event));
+    }
+}
+
+pub fn subscribe() -> Option<broadcast::Receiver<Arc<DeviceEvent>>> {
+    GLOBAL_EVENTS.lock().unwrap().as_ref().map(|t| t.subscribe())
+}
+
+/// 是否有订阅者 高频事件没有订阅者时不必生成
+pub fn subscribed() -> bool {
+    GLOBAL_EVENTS.lock().unwrap().as_ref().is_some_and(|t| t.receiver_count() > 0)
+}
+
+//测试共用事件总线 只初始化一次 避免关闭其它测试的订阅
+#[cfg(test)]
+pub fn test_init() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(init);
+}
+
+/// 按sim和事件类型过滤 为空时不过滤
+#[derive(Debug, Default, Clone)]
+pub struct EventFilter {
+    sims: Vec<String>,
+    kinds: Vec<String>,
+}
+
+impl EventFilter {
+    /// sims kinds均为逗号分隔
+    pub fn new(sims:&str, kinds:&str) -> Self {
+        let split = |s:&str| s.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect();
+        EventFilter { sims: split(sims), kinds: split(kinds) }
+    }
+
+    pub fn matches(&self, event:&DeviceEvent) -> bool {
+        (self.sims.is_empty() || self.sims.contains(&event.sim)) && (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+    }
+}
+
+/// 接收下一个符合过滤条件的事件 落后时丢弃未接收的事件 总线关闭时返回None
+pub async fn recv(receiver:&mut broadcast::Receiver<Arc<DeviceEvent>>, filter:&EventFilter) -> Option<Arc<DeviceEvent>> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if filter.matches(&event) {
+                    return Some(event);
+                }
+            },
+            Err(RecvError::Lagged(n)) => {
+                log::info!("[service-event]subscriber lagged:{}", n);
+            },
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+
+#[test]
+fn test_event_filter()
+{
+    let event = DeviceEvent::new("013800000000", "alarm", &0);
+    assert!(EventFilter::new("", "").matches(&event));
+    assert!(EventFilter::new("013800000001, 013800000000", "").matches(&event));
+    assert!(EventFilter::new("", "position,alarm").matches(&event));
+    assert!(!EventFilter::new("013800000000", "position").matches(&event));
+    assert!(!EventFilter::new("013800000001", "").matches(&event));
+}
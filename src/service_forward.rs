@@ -1,30 +1,52 @@
 
 
 
-use std::sync::{Arc, atomic::Ordering};
+use std::{sync::{Arc, atomic::{Ordering, AtomicU32}}, collections::{VecDeque, HashMap}, time::Duration};
 
-use bytes::Bytes;
-use tokio::{io::{self, AsyncReadExt}, net::TcpListener, sync::RwLock};
+use jt808::models::Jt808;
+use tokio::{io::{self, AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}}};
 
-use crate::{session_forward::{forward_parse::{ForwardParse, ReturnType}, forward_session::{ForwardSession, UPDATE}, forward_item::ForwardItem}, session808::jt808_session::Jt808SessionShared};
+use crate::{session_forward::{forward_parse::{ForwardParse, ReturnType, ALL_SIMS}, forward_session::ForwardSession, forward_item::ForwardItem, forward_filter::{ForwardFilter, FilterMode}}, session808::jt808_session::Jt808SessionShared, config_model::ForwardTargetConfig};
 
 
 //2字节body总长度
 //控制指令0xffffff开头
-//0xffffff00  登录
+//0xffffff00  登录 客户端ID和密码需与配置一致 否则断开
+//[2字节长度][客户端ID][2字节长度][密码]
 //0xffffff01  重置sim表
 //0xffffff02  添加sim表
 //0xffffff03  删除sim表
-//[bcdsim 10字节20位]
+//[bcdsim 10字节20位] 长度为0表示全部sim(通配订阅)
+//0xffffff04  设置消息过滤
+//[1字节模式 0:不过滤 1:包含 2:排除][1字节 0x0200仅转发报警][2字节消息ID]...
+
+//断线缓存: 开启后按客户端ID保留订阅 断线期间的数据写入缓存
+//同一客户端ID重新登录后先补发缓存再转发实时数据 超过保存时长未重连则取消订阅
 
 pub struct ServiceForward {
-    pub forward_session:RwLock<Vec<Arc<ForwardSession>>>,
+    //sim->订阅列表
+    subscribers:std::sync::RwLock<HashMap<String, Arc<ForwardSimSubscribers>>>,
+    //通配订阅 所有sim都转发
+    wildcard:ForwardSimSubscribers,
+    //客户端ID->会话 仅开启缓存时记录
+    clients:std::sync::Mutex<HashMap<String, Arc<ForwardSession>>>,
+    //客户端ID->密码
+    credentials:HashMap<String, String>,
+    //缓存最大字节数 0:不缓存
+    buffer_size:usize,
+    //缓存保存时长
+    buffer_age:Duration,
 }
 
 impl ServiceForward {
-    pub fn new() -> Self {
+    pub fn new(buffer_size:usize, buffer_age:Duration, credentials:HashMap<String, String>) -> Self {
         ServiceForward {
-            forward_session:RwLock::new(Vec::new()),
+            subscribers:std::sync::RwLock::new(HashMap::new()),
+            wildcard:ForwardSimSubscribers::new(),
+            clients:std::sync::Mutex::new(HashMap::new()),
+            credentials,
+            buffer_size,
+            buffer_age,
         }
     }
 
@@ -34,127 +56,468 @@ impl ServiceForward {
         let listener: TcpListener = TcpListener::bind(addr).await.expect("service device listen failed");
     
        
-        let _ = tokio::spawn(async move{
-    
-            let (socket, _) = listener.accept().await.unwrap();
-    
-            log::info!("[service-forward]new connect addr:{:?}", socket.peer_addr());
-    
-            let service = service.clone();
-            tokio::spawn(async move{
+        tokio::spawn(async move{
     
-                let (mut reader, writer) = socket.into_split();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+        
+                log::info!("[service-forward]new connect addr:{:?}", socket.peer_addr());
+        
+                let service = service.clone();
+                tokio::spawn(async move{
+                    let (reader, writer) = socket.into_split();
+
+                    //todo:验证流程
+                    let forward_session = Arc::new(ForwardSession::new(writer));
+                    ServiceForward::run_session(service, forward_session, reader, 0).await;
+                });
+            }
     
-                //todo:验证流程
-                
-                let forward_session = Arc::new(ForwardSession::new(writer));
-                let mut forward_parse = ForwardParse::new();
-                let mut buffer = bytes::BytesMut::with_capacity(8096);
+        });
     
-                service.forward_session.write().await.push(forward_session.clone());
+        Ok(())
     
-                let mut is_err= false; 
+    }
+
+    //主动连接上级平台 断线后退避重连
+    pub fn start_targets(service:Arc<ServiceForward>, targets:&[ForwardTargetConfig]) {
+        for target in targets {
+            let service = service.clone();
+            let target = target.clone();
+            tokio::spawn(async move {
+                //同一上级平台重连复用会话 断线期间的数据缓存
+                let forward_session = Arc::new(ForwardSession::new_with(None));
+                forward_session.set_client_id(&target.client_id);
+                forward_session.output().enable_buffer(service.buffer_size, service.buffer_age);
+
+                let mut backoff = 1;
+                loop {
+                    match TcpStream::connect(&target.address).await {
+                        Ok(socket) => {
+                            log::info!("[service-forward]connected target:{}", target.address);
+                            backoff = 1;
+
+                            let (reader, writer) = socket.into_split();
+                            match ServiceForward::resend_subscription(&service, &forward_session, &target, writer).await {
+                                Ok(generation) => {
+                                    ServiceForward::run_session(service.clone(), forward_session.clone(), reader, generation).await;
+                                },
+                                Err(err) => {
+                                    log::warn!("[service-forward]target:{} send failed:{}", target.address, err);
+                                },
+                            }
+                        },
+                        Err(err) => {
+                            log::warn!("[service-forward]connect target:{} failed:{}", target.address, err);
+                        },
+                    }
+
+                    tokio::time::sleep(Duration::from_secs(backoff)).await;
+                    backoff = std::cmp::min(backoff * 2, 60);
+                }
+            });
+        }
+    }
+
+    //登录并发送订阅 本地同时按配置订阅 完成后补发缓存
+    async fn resend_subscription(service:&ServiceForward, forward_session:&ForwardSession, target:&ForwardTargetConfig, mut writer:OwnedWriteHalf) -> io::Result<u64> {
+        let mut filter = ForwardFilter::new();
+        filter.mode = match target.filter_mode {
+            1 => FilterMode::Include,
+            2 => FilterMode::Exclude,
+            _ => FilterMode::All,
+        };
+        filter.ids = target.get_filter_ids().into_iter().collect();
+        filter.alarm_only = target.alarm_only;
+        let sims = target.get_sims();
+
+        let invalid = |_| io::Error::from(io::ErrorKind::InvalidInput);
+        writer.write_all(&ForwardParse::pack_login(&target.client_id, &target.password).map_err(invalid)?).await?;
+        writer.write_all(&ForwardParse::pack_filter(&filter).map_err(invalid)?).await?;
+        writer.write_all(&ForwardParse::pack_sims(0x01, &sims).map_err(invalid)?).await?;
+
+        forward_session.handle_filter(filter);
+        forward_session.handle_cmd(service, 0x01, sims).await;
+        forward_session.output().attach(writer).await
+    }
+
+    //转发连接处理 被动接入和主动连接共用
+    async fn run_session(service:Arc<ServiceForward>, mut forward_session:Arc<ForwardSession>, mut reader:OwnedReadHalf, mut generation:u64) {
+        let mut forward_parse = ForwardParse::new();
+        let mut buffer = bytes::BytesMut::with_capacity(8096);
+
+        let mut is_err= false; 
+        loop {
+            let n = reader.read_buf(&mut buffer).await.unwrap_or(0);
+            if n > 0{
                 loop {
-                    let n = reader.read_buf(&mut buffer).await.expect("");
-                    if n > 0{
-                        loop {
-                            match forward_parse.parse(&mut buffer) {
-                                Ok(ret) => {
-                                    match ret {
-                                        Some(rt) => {
-                                            match rt {
-                                                ReturnType::Cmd(t, sims) => {
-                                                    forward_session.handle_cmd(t, sims).await;
-                                                },
-                                                ReturnType::Data(jtsub) => {
-                                                    forward_session.handle_data(jtsub).await;
-                                                }
-                                            }
+                    match forward_parse.parse(&mut buffer) {
+                        Ok(Some(rt)) => {
+                            match rt {
+                                ReturnType::Login(client_id, password) => {
+                                    log::info!("[service-forward]login client:{}", client_id);
+                                    match ServiceForward::login(&service, forward_session.clone(), &client_id, &password).await {
+                                        Ok((session, g)) => {
+                                            forward_session = session;
+                                            generation = g;
                                         },
-                                        None => {
+                                        Err(err) => {
+                                            log::info!("[service-forward]login failed client:{} err:{}", client_id, err);
                                             is_err = true;
                                             break;
                                         },
                                     }
                                 },
-                                Err(_) => {
-                                    break;
+                                ReturnType::Cmd(t, sims) => {
+                                    forward_session.handle_cmd(&service, t, sims).await;
                                 },
+                                ReturnType::Filter(filter) => {
+                                    forward_session.handle_filter(filter);
+                                },
+                                ReturnType::Data(jtsub) => {
+                                    forward_session.handle_data(jtsub).await;
+                                }
                             }
-                        }
-    
-                    } else {
-                        is_err = true;
-                    }
-    
-                    if is_err {
-                        break;
+                        },
+                        Ok(None) => {
+                            break;
+                        },
+                        Err(_) => {
+                            is_err = true;
+                            break;
+                        },
                     }
-    
                 }
-    
-            });
-    
-    
-        });
-    
-        Ok(())
-    
+
+            } else {
+                is_err = true;
+            }
+
+            if is_err {
+                log::info!("[service-forward]disconnect");
+                ServiceForward::disconnect(&service, &forward_session, generation).await;
+                break;
+            }
+
+        }
     }
-    
-    pub async fn get_forward_sender(service:&Arc<ServiceForward>, sim:&String) -> ForwardSimSender {
-        let map_senders = service.forward_session.read().await;
-    
-        let mut senders:Vec<(Arc<ForwardItem>, i32)> = Vec::new();
-        for (_, sender) in map_senders.iter().enumerate() {
-            let item = sender.get_item(sim).await;
-            match item {
-                Some((forward, update)) => {
-                    senders.push((forward, update));
-                },
+
+    //客户端登录 校验密码后 开启缓存时该客户端ID已有会话则接管 返回会话及连接代数
+    async fn login(service:&Arc<ServiceForward>, forward_session:Arc<ForwardSession>, client_id:&str, password:&str) -> io::Result<(Arc<ForwardSession>, u64)> {
+        if !service.credentials.get(client_id).is_some_and(|t| password_eq(t, password)) {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+
+        let generation = forward_session.output().generation();
+        if service.buffer_size == 0 || client_id.is_empty() {
+            return Ok((forward_session, generation));
+        }
+
+        forward_session.set_client_id(client_id);
+        let old = {
+            let mut clients = service.clients.lock().unwrap();
+            match clients.get(client_id) {
+                Some(old) if !Arc::ptr_eq(old, &forward_session) => Some(old.clone()),
+                Some(_) => None,
                 None => {
-    
-                },
+                    clients.insert(client_id.to_string(), forward_session.clone());
+                    None
+                }
             }
+        };
+
+        match old {
+            Some(old) => {
+                //登录前的订阅作废 连接转交给原会话
+                forward_session.close(service).await;
+                match forward_session.output().take_writer().await {
+                    Some(writer) => {
+                        let generation = old.output().attach(writer).await?;
+                        Ok((old, generation))
+                    },
+                    None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+                }
+            },
+            None => {
+                forward_session.output().enable_buffer(service.buffer_size, service.buffer_age);
+                Ok((forward_session, generation))
+            },
         }
-        
-        ForwardSimSender{sim: sim.to_string(), service:service.clone(), update_num: UPDATE.load(Ordering::Relaxed), senders }
+    }
+
+    //连接断开 开启缓存时保留订阅 超过保存时长未重连再取消
+    async fn disconnect(service:&Arc<ServiceForward>, forward_session:&Arc<ForwardSession>, generation:u64) {
+        //已被新连接接管
+        if !forward_session.output().detach(generation).await {
+            return;
+        }
+
+        if !forward_session.output().is_buffered() {
+            forward_session.close(service).await;
+            return;
+        }
+
+        let service = service.clone();
+        let forward_session = forward_session.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(service.buffer_age).await;
+            if forward_session.output().is_detached(generation).await {
+                log::info!("[service-forward]buffer expired client:{}", forward_session.get_client_id());
+                service.clients.lock().unwrap().retain(|_, t| !Arc::ptr_eq(t, &forward_session));
+                forward_session.close(&service).await;
+            }
+        });
     }
     
+    //订阅 只通知该sim对应的设备会话
+    pub async fn subscribe(&self, sim:&str, item:Arc<ForwardItem>) {
+        if sim == ALL_SIMS {
+            self.wildcard.items.write().unwrap().push(item);
+            self.wildcard.update.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let device = {
+            let mut map_subscribers = self.subscribers.write().unwrap();
+            let subscribers = map_subscribers.entry(sim.to_string()).or_insert_with(|| Arc::new(ForwardSimSubscribers::new()));
+            subscribers.items.write().unwrap().push(item.clone());
+            subscribers.update.fetch_add(1, Ordering::Relaxed);
+            let device = subscribers.device.read().unwrap().clone();
+            device
+        };
+
+        if let Some(device) = device {
+            item.bind_device(device).await;
+        }
+    }
+
+    pub fn unsubscribe(&self, sim:&str, item:&Arc<ForwardItem>) {
+        if sim == ALL_SIMS {
+            self.wildcard.items.write().unwrap().retain(|t| !Arc::ptr_eq(t, item));
+            self.wildcard.update.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let mut map_subscribers = self.subscribers.write().unwrap();
+        if let Some(subscribers) = map_subscribers.get(sim) {
+            subscribers.items.write().unwrap().retain(|t| !Arc::ptr_eq(t, item));
+            subscribers.update.fetch_add(1, Ordering::Relaxed);
+            if subscribers.is_empty() {
+                map_subscribers.remove(sim);
+            }
+        }
+    }
+
+    //设备下线
+    pub fn unbind_device(&self, sim:&str, device:&Arc<Jt808SessionShared>) {
+        let mut map_subscribers = self.subscribers.write().unwrap();
+        if let Some(subscribers) = map_subscribers.get(sim) {
+            let mut dw = subscribers.device.write().unwrap();
+            if dw.as_ref().is_some_and(|t| Arc::ptr_eq(t, device)) {
+                *dw = None;
+            }
+            drop(dw);
+            if subscribers.is_empty() {
+                map_subscribers.remove(sim);
+            }
+        }
+    }
+
+    pub fn get_forward_sender(service:&Arc<ServiceForward>, sim:&str) -> ForwardSimSender {
+        let subscribers = service.subscribers.write().unwrap()
+            .entry(sim.to_string())
+            .or_insert_with(|| Arc::new(ForwardSimSubscribers::new()))
+            .clone();
+
+        let mut sender = ForwardSimSender{ service:service.clone(), subscribers, update_num:0, wildcard_num:0, senders:Vec::new() };
+        sender.refresh();
+        sender
+    }
+
+}
+
+//逐字节比较 耗时与内容无关
+fn password_eq(a:&str, b:&str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+//单个sim的订阅列表
+pub struct ForwardSimSubscribers {
+    //订阅变化时加1
+    update: AtomicU32,
+    items: std::sync::RwLock<Vec<Arc<ForwardItem>>>,
+    device: std::sync::RwLock<Option<Arc<Jt808SessionShared>>>,
+}
 
+impl ForwardSimSubscribers {
+    fn new() -> Self {
+        ForwardSimSubscribers {
+            update: AtomicU32::new(0),
+            items: std::sync::RwLock::new(Vec::new()),
+            device: std::sync::RwLock::new(None),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.items.read().unwrap().is_empty() && self.device.read().unwrap().is_none()
+    }
 }
 
 pub struct ForwardSimSender {
-    sim:String,
     service:Arc<ServiceForward>,
-    update_num: i32,
-    senders: Vec<(Arc<ForwardItem>, i32)>
+    subscribers:Arc<ForwardSimSubscribers>,
+    update_num: u32,
+    wildcard_num: u32,
+    senders: Vec<Arc<ForwardItem>>
 }
 
 impl ForwardSimSender {
-    
-    pub async fn forward_send(&mut self, buf: &Bytes)
-    {
-        let u = UPDATE.load(Ordering::Relaxed);
-        if self.update_num != u {
 
-            let fs = ServiceForward::get_forward_sender(&self.service, &self.sim).await;
-            self.senders = fs.senders;
-            self.update_num = fs.update_num;
+    //订阅变化时重建转发列表 同一连接同时按sim和通配订阅时只转发一次
+    fn refresh(&mut self) {
+        self.update_num = self.subscribers.update.load(Ordering::Relaxed);
+        self.wildcard_num = self.service.wildcard.update.load(Ordering::Relaxed);
+        let mut senders = self.subscribers.items.read().unwrap().clone();
+        for item in self.service.wildcard.items.read().unwrap().iter() {
+            if !senders.iter().any(|t| t.same_output(item)) {
+                senders.push(item.clone());
+            }
+        }
+        self.senders = senders;
+    }
+
+    pub async fn forward_send(&mut self, id:u16, alarm:u32, packages:&VecDeque<Jt808>)
+    {
+        if self.update_num != self.subscribers.update.load(Ordering::Relaxed) || self.wildcard_num != self.service.wildcard.update.load(Ordering::Relaxed) {
+            self.refresh();
         }
 
-        for (sender, _) in &self.senders {
-            sender.forward_send_bytes(buf).await;
+        for sender in &self.senders {
+            if !sender.accept(id, alarm) {
+                continue;
+            }
+            for item in packages {
+                sender.forward_send_bytes(&item.get_bytes()).await;
+            }
         }
     }
 
     pub async fn bind_device(&mut self, dw_session:Arc<Jt808SessionShared>)
     {
-        for (forward, _update) in &self.senders {
+        let senders = {
+            //持有写锁 防止与subscribe/unsubscribe交错
+            let _map_subscribers = self.service.subscribers.write().unwrap();
+            *self.subscribers.device.write().unwrap() = Some(dw_session.clone());
+            self.subscribers.items.read().unwrap().clone()
+        };
+
+        for forward in &senders {
             forward.bind_device(dw_session.clone()).await;
         }
+    }
+
+}
+
+
+#[tokio::test]
+async fn test_subscription_index()
+{
+    use crate::session_forward::forward_buffer::ForwardOutput;
+
+    let service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+    let output1 = Arc::new(ForwardOutput::new(None));
+    let output2 = Arc::new(ForwardOutput::new(None));
+    let new_item = |output:&Arc<ForwardOutput>| Arc::new(ForwardItem::new(output.clone(), Arc::new(std::sync::RwLock::new(ForwardFilter::new()))));
+    let contains = |sender:&ForwardSimSender, item:&Arc<ForwardItem>| sender.senders.iter().any(|t| Arc::ptr_eq(t, item));
+    let empty = VecDeque::new();
+
+    //按sim订阅 只影响该sim
+    let item = new_item(&output1);
+    service.subscribe("013800000000", item.clone()).await;
+    let mut sender = ServiceForward::get_forward_sender(&service, "013800000000");
+    let mut other = ServiceForward::get_forward_sender(&service, "013800000001");
+    assert_eq!(sender.senders.len(), 1);
+    assert!(contains(&sender, &item));
+    assert!(other.senders.is_empty());
+
+    //通配订阅 所有sim可见 同一连接不重复
+    let wildcard1 = new_item(&output1);
+    let wildcard2 = new_item(&output2);
+    service.subscribe(ALL_SIMS, wildcard1.clone()).await;
+    service.subscribe(ALL_SIMS, wildcard2.clone()).await;
+    sender.forward_send(0x0200, 0, &empty).await;
+    other.forward_send(0x0200, 0, &empty).await;
+    assert_eq!(sender.senders.len(), 2);
+    assert!(contains(&sender, &item) && contains(&sender, &wildcard2));
+    assert_eq!(other.senders.len(), 2);
+    assert!(contains(&other, &wildcard1) && contains(&other, &wildcard2));
+
+    //取消订阅
+    service.unsubscribe("013800000000", &item);
+    sender.forward_send(0x0200, 0, &empty).await;
+    assert_eq!(sender.senders.len(), 2);
+    assert!(contains(&sender, &wildcard1));
+    service.unsubscribe(ALL_SIMS, &wildcard1);
+    service.unsubscribe(ALL_SIMS, &wildcard2);
+    sender.forward_send(0x0200, 0, &empty).await;
+    other.forward_send(0x0200, 0, &empty).await;
+    assert!(sender.senders.is_empty() && other.senders.is_empty());
+
+    //没有设备和订阅的sim从索引移除
+    let item = new_item(&output2);
+    service.subscribe("013800000002", item.clone()).await;
+    assert!(service.subscribers.read().unwrap().contains_key("013800000002"));
+    service.unsubscribe("013800000002", &item);
+    assert!(!service.subscribers.read().unwrap().contains_key("013800000002"));
+}
+
+#[tokio::test]
+async fn test_session_subscription()
+{
+    let service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+    let session = ForwardSession::new_with(None);
+    let count = |sim:&str| service.subscribers.read().unwrap().get(sim).map_or(0, |t| t.items.read().unwrap().len());
+
+    session.handle_cmd(&service, 0x02, vec!["013800000000".to_string(), ALL_SIMS.to_string()]).await;
+    assert_eq!(count("013800000000"), 1);
+    assert_eq!(service.wildcard.items.read().unwrap().len(), 1);
+
+    //重置sim表 原订阅全部取消
+    session.handle_cmd(&service, 0x01, vec!["013800000001".to_string()]).await;
+    assert_eq!(count("013800000000"), 0);
+    assert_eq!(count("013800000001"), 1);
+    assert!(service.wildcard.items.read().unwrap().is_empty());
+
+    session.handle_cmd(&service, 0x02, vec![ALL_SIMS.to_string()]).await;
+    session.handle_cmd(&service, 0x03, vec![ALL_SIMS.to_string(), "013800000001".to_string()]).await;
+    assert_eq!(count("013800000001"), 0);
+    assert!(service.wildcard.items.read().unwrap().is_empty());
+
+    session.handle_cmd(&service, 0x02, vec!["013800000002".to_string()]).await;
+    session.close(&service).await;
+    assert_eq!(count("013800000002"), 0);
+}
+
+#[tokio::test]
+async fn test_login_password()
+{
+    let credentials = HashMap::from([("platform1".to_string(), "123456".to_string())]);
+    let service = Arc::new(ServiceForward::new(1024, Duration::from_secs(60), credentials));
 
+    //密码错误或未配置的客户端ID拒绝 不接管已有会话
+    for (client_id, password) in [("platform1", "654321"), ("platform1", ""), ("platform2", "123456"), ("", "")] {
+        let result = ServiceForward::login(&service, Arc::new(ForwardSession::new_with(None)), client_id, password).await;
+        assert_eq!(result.err().map(|t| t.kind()), Some(io::ErrorKind::PermissionDenied));
     }
+    assert!(service.clients.lock().unwrap().is_empty());
 
+    let session = Arc::new(ForwardSession::new_with(None));
+    let (logged, _) = ServiceForward::login(&service, session.clone(), "platform1", "123456").await.unwrap();
+    assert!(Arc::ptr_eq(&logged, &session));
+    assert!(session.output().is_buffered());
 
+    //同一客户端ID密码错误 不能接管订阅和缓存
+    let intruder = Arc::new(ForwardSession::new_with(None));
+    assert!(ServiceForward::login(&service, intruder, "platform1", "12345").await.is_err());
+    assert!(Arc::ptr_eq(service.clients.lock().unwrap().get("platform1").unwrap(), &session));
 }
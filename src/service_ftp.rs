@@ -0,0 +1,499 @@
+use std::{net::IpAddr, path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU32, Ordering}}, time::Duration};
+
+use tokio::{fs::OpenOptions, io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream, tcp::OwnedWriteHalf}, time::timeout};
+
+use crate::service_upload::ServiceUpload;
+
+//终端文件上传使用的FTP服务 仅支持被动模式和上传
+//账号由0x9206任务生成 每个账号只能访问自己的任务目录
+
+const DATA_TIMEOUT: Duration = Duration::from_secs(30);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub struct ServiceFtp {
+    upload: Arc<ServiceUpload>,
+    //PASV应答中的地址 为空或0.0.0.0时使用控制连接的本地地址
+    public_ip: String,
+    passive_ports: Option<(u16, u16)>,
+    next_port: AtomicU32,
+}
+
+impl ServiceFtp {
+    pub fn new(upload:Arc<ServiceUpload>, address_public:&str, passive_ports:Option<(u16, u16)>) -> Self {
+        let public_ip = address_public.rsplit_once(':').map_or(address_public, |t| t.0).to_string();
+        ServiceFtp {
+            upload,
+            public_ip,
+            passive_ports,
+            next_port: AtomicU32::new(0),
+        }
+    }
+
+    pub async fn start(service:Arc<ServiceFtp>, addr:&str) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("[service-ftp]listen addr:{}", addr);
+
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        log::info!("[service-ftp]new connect addr:{}", addr);
+                        tokio::spawn(ServiceFtp::run_session(service.clone(), stream));
+                    },
+                    Err(err) => {
+                        log::warn!("[service-ftp]accept failed:{}", err);
+                    },
+                }
+            }
+        });
+        Ok(())
+    }
+
+    //被动模式监听 有端口范围时轮流尝试
+    async fn bind_passive(&self, ip:IpAddr) -> Option<TcpListener> {
+        let (start, end) = match self.passive_ports {
+            Some(range) => range,
+            None => return TcpListener::bind((ip, 0)).await.ok(),
+        };
+        //0-65535共65536个 按u32计算
+        let count = end as u32 - start as u32 + 1;
+        for _ in 0..count {
+            let port = (start as u32 + self.next_port.fetch_add(1, Ordering::Relaxed) % count) as u16;
+            if let Ok(listener) = TcpListener::bind((ip, port)).await {
+                return Some(listener);
+            }
+        }
+        None
+    }
+
+    async fn run_session(service:Arc<ServiceFtp>, stream:TcpStream) {
+        let (local_ip, peer_ip) = match (stream.local_addr(), stream.peer_addr()) {
+            (Ok(local), Ok(peer)) => (local.ip(), peer.ip()),
+            _ => return,
+        };
+        let pasv_ip = match service.public_ip.parse::<IpAddr>() {
+            Ok(ip) if !ip.is_unspecified() => ip,
+            _ => local_ip,
+        };
+
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut session = FtpSession::new(peer_ip);
+
+        if reply(&mut writer, "220 gw808 ftp ready").await.is_err() {
+            return;
+        }
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match timeout(IDLE_TIMEOUT, reader.read_line(&mut line)).await {
+                Ok(Ok(n)) if n > 0 => {},
+                _ => break,
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let (cmd, arg) = match line.split_once(' ') {
+                Some((cmd, arg)) => (cmd.to_ascii_uppercase(), arg),
+                None => (line.to_ascii_uppercase(), ""),
+            };
+            if cmd != "PASS" {
+                log::debug!("[service-ftp]recv {}", line);
+            }
+
+            let result = match cmd.as_str() {
+                "USER" => {
+                    session.user = arg.to_string();
+                    reply(&mut writer, "331 Password required").await
+                },
+                "PASS" => {
+                    match service.upload.login(&session.user, arg) {
+                        Some((task, root)) => {
+                            log::info!("[service-ftp]login user:{} task:{}", session.user, task);
+                            session.task = Some((task, root));
+                            reply(&mut writer, "230 Login successful").await
+                        },
+                        None => {
+                            log::info!("[service-ftp]login failed user:{}", session.user);
+                            reply(&mut writer, "530 Login incorrect").await
+                        },
+                    }
+                },
+                "QUIT" => {
+                    let _ = reply(&mut writer, "221 Bye").await;
+                    break;
+                },
+                "NOOP" => reply(&mut writer, "200 OK").await,
+                "SYST" => reply(&mut writer, "215 UNIX Type: L8").await,
+                "FEAT" => reply(&mut writer, "211-Features:\r\n PASV\r\n EPSV\r\n SIZE\r\n REST STREAM\r\n UTF8\r\n211 End").await,
+                _ if session.task.is_none() => reply(&mut writer, "530 Please login with USER and PASS").await,
+                _ => ServiceFtp::handle(&service, &mut session, &mut writer, &cmd, arg, local_ip, pasv_ip).await,
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        log::info!("[service-ftp]disconnect user:{}", session.user);
+    }
+
+    async fn handle(service:&Arc<ServiceFtp>, session:&mut FtpSession, writer:&mut OwnedWriteHalf, cmd:&str, arg:&str, local_ip:IpAddr, pasv_ip:IpAddr) -> std::io::Result<()> {
+        let (task, root) = match &session.task {
+            Some((task, root)) => (*task, root.clone()),
+            None => return Ok(()),
+        };
+
+        match cmd {
+            "OPTS" | "MODE" | "STRU" | "ALLO" => reply(writer, "200 OK").await,
+            "TYPE" => reply(writer, "200 Type set").await,
+            "PWD" | "XPWD" => reply(writer, &format!("257 \"{}\" is current directory", session.cwd)).await,
+            "CWD" | "XCWD" => {
+                let path = resolve(&session.cwd, arg);
+                if real_path(&root, &path).is_dir() {
+                    session.cwd = path;
+                    reply(writer, "250 Directory changed").await
+                } else {
+                    reply(writer, "550 No such directory").await
+                }
+            },
+            "CDUP" => {
+                session.cwd = resolve(&session.cwd, "..");
+                reply(writer, "250 Directory changed").await
+            },
+            "MKD" | "XMKD" => {
+                let path = resolve(&session.cwd, arg);
+                match tokio::fs::create_dir_all(real_path(&root, &path)).await {
+                    Ok(_) => reply(writer, &format!("257 \"{}\" created", path)).await,
+                    Err(_) => reply(writer, "550 Create directory failed").await,
+                }
+            },
+            "PASV" | "EPSV" => {
+                let listener = match service.bind_passive(local_ip).await {
+                    Some(listener) => listener,
+                    None => return reply(writer, "425 Can't open data connection").await,
+                };
+                let port = listener.local_addr()?.port();
+                session.passive = Some(listener);
+                match (cmd, pasv_ip) {
+                    ("PASV", IpAddr::V4(ip)) => {
+                        let o = ip.octets();
+                        reply(writer, &format!("227 Entering Passive Mode ({},{},{},{},{},{})", o[0], o[1], o[2], o[3], port >> 8, port & 0xff)).await
+                    },
+                    _ => reply(writer, &format!("229 Entering Extended Passive Mode (|||{}|)", port)).await,
+                }
+            },
+            "REST" => {
+                session.rest = arg.trim().parse().unwrap_or(0);
+                reply(writer, &format!("350 Restarting at {}", session.rest)).await
+            },
+            "SIZE" => {
+                match tokio::fs::metadata(real_path(&root, &resolve(&session.cwd, arg))).await {
+                    Ok(meta) if meta.is_file() => reply(writer, &format!("213 {}", meta.len())).await,
+                    _ => reply(writer, "550 No such file").await,
+                }
+            },
+            "STOR" | "APPE" => {
+                let path = resolve(&session.cwd, arg);
+                let rest = std::mem::take(&mut session.rest);
+                let mut data = match session.accept_data().await {
+                    Some(data) => data,
+                    None => return reply(writer, "425 Use PASV first").await,
+                };
+
+                let file_path = real_path(&root, &path);
+                if let Some(parent) = file_path.parent() {
+                    let _ = tokio::fs::create_dir_all(parent).await;
+                }
+                let mut options = OpenOptions::new();
+                options.create(true).write(true);
+                if cmd == "APPE" {
+                    options.append(true);
+                } else if rest == 0 {
+                    options.truncate(true);
+                }
+                let mut file = match options.open(&file_path).await {
+                    Ok(file) => file,
+                    Err(_) => return reply(writer, "550 Can't create file").await,
+                };
+                if rest > 0 && cmd == "STOR" {
+                    file.seek(std::io::SeekFrom::Start(rest)).await?;
+                }
+
+                reply(writer, "150 Ok to send data").await?;
+                service.upload.on_file(task, &path);
+                log::info!("[service-ftp]upload task:{} file:{} offset:{}", task, path, rest);
+
+                let mut buf = vec![0u8; 64 * 1024];
+                let ok = loop {
+                    match timeout(IDLE_TIMEOUT, data.read(&mut buf)).await {
+                        Ok(Ok(0)) => break true,
+                        Ok(Ok(n)) => {
+                            if file.write_all(&buf[..n]).await.is_err() {
+                                break false;
+                            }
+                            service.upload.on_data(task, n);
+                        },
+                        _ => break false,
+                    }
+                };
+                let _ = file.flush().await;
+                if ok {
+                    reply(writer, "226 Transfer complete").await
+                } else {
+                    reply(writer, "426 Transfer aborted").await
+                }
+            },
+            "LIST" | "NLST" => {
+                let mut data = match session.accept_data().await {
+                    Some(data) => data,
+                    None => return reply(writer, "425 Use PASV first").await,
+                };
+                reply(writer, "150 Here comes the directory listing").await?;
+                let mut listing = String::new();
+                if let Ok(mut dir) = tokio::fs::read_dir(real_path(&root, &session.cwd)).await {
+                    while let Ok(Some(entry)) = dir.next_entry().await {
+                        let name = entry.file_name().to_string_lossy().to_string();
+                        if cmd == "NLST" {
+                            listing.push_str(&format!("{}\r\n", name));
+                        } else {
+                            let meta = entry.metadata().await.ok();
+                            let is_dir = meta.as_ref().is_some_and(|t| t.is_dir());
+                            let len = meta.map_or(0, |t| t.len());
+                            listing.push_str(&format!("{} 1 ftp ftp {} Jan 01 00:00 {}\r\n", if is_dir { "drwxr-xr-x" } else { "-rw-r--r--" }, len, name));
+                        }
+                    }
+                }
+                let _ = data.write_all(listing.as_bytes()).await;
+                let _ = data.shutdown().await;
+                reply(writer, "226 Directory send OK").await
+            },
+            _ => reply(writer, "502 Command not implemented").await,
+        }
+    }
+}
+
+struct FtpSession {
+    user: String,
+    //任务ID 任务目录
+    task: Option<(u64, PathBuf)>,
+    cwd: String,
+    rest: u64,
+    passive: Option<TcpListener>,
+    //控制连接的对端地址 数据连接只接受该地址
+    peer_ip: IpAddr,
+}
+
+impl FtpSession {
+    fn new(peer_ip:IpAddr) -> Self {
+        FtpSession {
+            user: String::new(),
+            task: None,
+            cwd: "/".to_string(),
+            rest: 0,
+            passive: None,
+            peer_ip: peer_ip.to_canonical(),
+        }
+    }
+
+    async fn accept_data(&mut self) -> Option<TcpStream> {
+        let listener = self.passive.take()?;
+        let peer_ip = self.peer_ip;
+        let accept = async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) if addr.ip().to_canonical() == peer_ip => return Some(stream),
+                    Ok((_, addr)) => log::warn!("[service-ftp]reject data connect addr:{} expect:{}", addr, peer_ip),
+                    Err(_) => return None,
+                }
+            }
+        };
+        timeout(DATA_TIMEOUT, accept).await.ok().flatten()
+    }
+}
+
+async fn reply(writer:&mut OwnedWriteHalf, msg:&str) -> std::io::Result<()> {
+    writer.write_all(format!("{}\r\n", msg).as_bytes()).await
+}
+
+/// 按当前目录解析FTP路径 结果以/开头 不会超出根目录
+fn resolve(cwd:&str, arg:&str) -> String {
+    let mut parts: Vec<&str> = if arg.starts_with('/') { Vec::new() } else { cwd.split('/').filter(|t| !t.is_empty()).collect() };
+    for part in arg.split(['/', '\\']) {
+        match part {
+            "" | "." => {},
+            ".." => {
+                parts.pop();
+            },
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+fn real_path(root:&Path, path:&str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use bytes::{Buf, Bytes, BytesMut};
+    use jt808::{JtPackage, models::{Jt0x0001, Jt808, Jt808BodySerialize, Ver808}};
+    use jt_util::{bytes::IBuffWrite, bytes_bcd::BytesBCD};
+    use tokio::net::tcp::OwnedReadHalf;
+    use crate::{service_device, service_forward::ServiceForward, service_passthrough::ServicePassthrough, service_upload::{UploadRequest, UploadState}, session808::jt808_parse::Jt808Deserialize};
+
+    #[test]
+    fn test_resolve()
+    {
+        assert_eq!(resolve("/", "a.mp4"), "/a.mp4");
+        assert_eq!(resolve("/a/b", "../c"), "/a/c");
+        assert_eq!(resolve("/a", "/x/./y"), "/x/y");
+        assert_eq!(resolve("/", "../../etc/passwd"), "/etc/passwd");
+        assert_eq!(resolve("/a", "..\\..\\b"), "/b");
+    }
+
+    #[tokio::test]
+    async fn test_accept_data_peer()
+    {
+        let mut session = FtpSession::new("127.0.0.1".parse().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        session.passive = Some(listener);
+
+        let other = tokio::net::TcpSocket::new_v4().unwrap();
+        other.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut other = other.connect(addr).await.unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+
+        let data = session.accept_data().await.unwrap();
+        assert_eq!(data.peer_addr().unwrap().ip().to_string(), "127.0.0.1");
+        //其它地址的连接被关闭
+        let mut buf = [0u8; 1];
+        assert!(matches!(other.read(&mut buf).await, Ok(0) | Err(_)));
+    }
+
+    //上传完成通知
+    struct Jt0x1206Body(u16, u8);
+
+    impl Jt808BodySerialize for Jt0x1206Body {
+        fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+            buf.put_u16(self.0);
+            buf.put_u8(self.1);
+        }
+
+        fn len(&self, _ver:&Ver808) -> usize {
+            3
+        }
+    }
+
+    async fn ftp_reply(reader:&mut BufReader<OwnedReadHalf>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    async fn recv_jt(terminal:&mut TcpStream, parse:&mut Jt808Deserialize, buffer:&mut BytesMut) -> Jt808 {
+        loop {
+            if let Ok(Some(jt)) = parse.deserialize(buffer) {
+                return jt;
+            }
+            assert!(terminal.read_buf(buffer).await.unwrap() > 0);
+        }
+    }
+
+    //0x9206下发账号 终端登录FTP上传文件 0x1206通知完成
+    #[tokio::test]
+    async fn test_upload_end_to_end()
+    {
+        let sim = "013800000361";
+        service_device::test_init();
+        let device_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let fw_service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+        let pt_service = Arc::new(ServicePassthrough::new(Vec::new(), String::new()));
+        service_device::start(&device_addr, fw_service, pt_service).await.unwrap();
+
+        //被动端口范围覆盖全部端口
+        let ftp_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let upload = Arc::new(ServiceUpload::new(&ftp_addr, "upload"));
+        ServiceFtp::start(Arc::new(ServiceFtp::new(upload.clone(), &ftp_addr, Some((0, 65535)))), &ftp_addr).await.unwrap();
+
+        let mut sim_bcd = BytesBCD::new();
+        sim_bcd.set_bytes(Bytes::from(hex::decode(sim).unwrap()));
+        let package = JtPackage::new(sim_bcd, false, 0, 1023);
+        let mut terminal = TcpStream::connect(&device_addr).await.unwrap();
+        terminal.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: 0, answer_id: 0x0002, result: 0 })).await.unwrap();
+        while service_device::get_sender(sim).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let request = || tokio::spawn({
+            let upload = upload.clone();
+            async move { ServiceUpload::request(&upload, sim, &UploadRequest { channel: 1, ..Default::default() }).await }
+        });
+        let mut parse = Jt808Deserialize::new();
+        let mut buffer = BytesMut::new();
+
+        //终端拒绝时不登记任务
+        let refused = request();
+        let jt9206 = recv_jt(&mut terminal, &mut parse, &mut buffer).await;
+        terminal.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: jt9206.sn, answer_id: 0x9206, result: 1 })).await.unwrap();
+        assert!(refused.await.unwrap().is_none());
+        assert!(upload.list(sim).is_empty());
+
+        //终端收到0x9206 取出FTP账号
+        let request = request();
+        let jt9206 = recv_jt(&mut terminal, &mut parse, &mut buffer).await;
+        assert_eq!(jt9206.id, 0x9206);
+        let mut body = jt9206.get_body();
+        let get_str = |body:&mut Bytes| {
+            let n = body.get_u8() as usize;
+            String::from_utf8(body.split_to(n).to_vec()).unwrap()
+        };
+        assert_eq!(get_str(&mut body), "127.0.0.1");
+        assert_eq!(body.get_u16().to_string(), ftp_addr.rsplit_once(':').unwrap().1);
+        let user = get_str(&mut body);
+        let password = get_str(&mut body);
+        assert_eq!(password.len(), 16);
+        terminal.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: jt9206.sn, answer_id: 0x9206, result: 0 })).await.unwrap();
+        let task = request.await.unwrap().unwrap();
+        assert_eq!(task.state, UploadState::Requested);
+
+        let (reader, mut writer) = TcpStream::connect(&ftp_addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        assert!(ftp_reply(&mut reader).await.starts_with("220"));
+        writer.write_all(format!("USER {}\r\nPASS wrong\r\n", user).as_bytes()).await.unwrap();
+        assert!(ftp_reply(&mut reader).await.starts_with("331"));
+        assert!(ftp_reply(&mut reader).await.starts_with("530"));
+        writer.write_all(format!("USER {}\r\nPASS {}\r\nPASV\r\n", user, password).as_bytes()).await.unwrap();
+        assert!(ftp_reply(&mut reader).await.starts_with("331"));
+        assert!(ftp_reply(&mut reader).await.starts_with("230"));
+        let pasv = ftp_reply(&mut reader).await;
+        let numbers: Vec<u16> = pasv[pasv.find('(').unwrap() + 1..pasv.find(')').unwrap()].split(',').map(|t| t.parse().unwrap()).collect();
+        let mut data = TcpStream::connect(("127.0.0.1", numbers[4] << 8 | numbers[5])).await.unwrap();
+
+        writer.write_all(b"STOR ch1/a.mp4\r\n").await.unwrap();
+        assert!(ftp_reply(&mut reader).await.starts_with("150"));
+        data.write_all(b"0123456789").await.unwrap();
+        drop(data);
+        assert!(ftp_reply(&mut reader).await.starts_with("226"));
+
+        let task = upload.get(task.id).unwrap();
+        assert_eq!((task.state, task.bytes, task.files.clone()), (UploadState::Uploading, 10, vec!["/ch1/a.mp4".to_string()]));
+        let file = std::path::Path::new("upload").join(sim).join(task.id.to_string()).join("ch1/a.mp4");
+        assert_eq!(tokio::fs::read(&file).await.unwrap(), b"0123456789");
+
+        terminal.write_all(&package.serialize(0x1206, 0, &mut Jt0x1206Body(jt9206.sn, 0))).await.unwrap();
+        for _ in 0..100 {
+            if upload.get(task.id).unwrap().state == UploadState::Completed {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(upload.get(task.id).unwrap().state, UploadState::Completed);
+        //结束的任务不能再登录
+        writer.write_all(format!("USER {}\r\nPASS {}\r\n", user, password).as_bytes()).await.unwrap();
+        assert!(ftp_reply(&mut reader).await.starts_with("331"));
+        assert!(ftp_reply(&mut reader).await.starts_with("530"));
+    }
+}
@@ -0,0 +1,459 @@
+use std::{collections::HashMap, fs, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{service_device, service_event::{self, DeviceEvent}, session808::{jt808_area::{area_type_name, Area, AreaShape, GeoPoint, Jt0x0608, Jt0x8608, JtAreaDelete, JtAreaSet}, jt808_models::JtLocation}};
+
+//区域/线路 定义保存在geofence_path文件 下发终端0x8600~0x8607 查询0x8608
+//绑定的终端在平台侧按0x0200判断进出区域和偏离线路 终端不支持区域报警时也能产生事件
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+//线路宽度为0时使用的宽度(米)
+const DEFAULT_ROUTE_WIDTH: f64 = 50.0;
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+static GLOBAL_GEOFENCE: std::sync::Mutex<Option<GeofenceStore>> = std::sync::Mutex::new(None);
+
+struct GeofenceStore {
+    geofences: HashMap<u32, Geofence>,
+    //sim->区域ID->是否在区域内(线路为是否在线路上)
+    states: HashMap<String, HashMap<u32, bool>>,
+    //最新的文件内容 由后台任务写入 不在锁内写文件
+    saver: watch::Sender<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Geofence {
+    #[serde(flatten)]
+    pub area: Area,
+    /// 平台侧判断的终端 下发成功后自动加入
+    #[serde(default)]
+    pub sims: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GeofenceState {
+    pub area_id: u32,
+    pub name: String,
+    /// 区域为是否在区域内 线路为是否在线路上
+    pub inside: bool,
+}
+
+#[derive(Serialize)]
+struct GeofenceEvent<'a> {
+    area_id: u32,
+    name: &'a str,
+    #[serde(rename = "type")]
+    area_type: &'static str,
+    lat: f64,
+    lng: f64,
+    speed: f64,
+    time: i64,
+    //偏离线路的距离(米)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<f64>,
+}
+
+pub fn init(path:&str) {
+    let geofences: Vec<Geofence> = match fs::read(path) {
+        Ok(bts) => serde_json::from_slice(&bts).unwrap_or_else(|err| {
+            log::warn!("[service-geofence]file invalid:{}", err);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    };
+    let (saver, receiver) = watch::channel(Vec::new());
+    tokio::spawn(run_saver(path.to_string(), receiver));
+    *GLOBAL_GEOFENCE.lock().unwrap() = Some(GeofenceStore {
+        geofences: geofences.into_iter().map(|t| (t.area.id, t)).collect(),
+        states: HashMap::new(),
+        saver,
+    });
+}
+
+impl GeofenceStore {
+    fn save(&self) {
+        let mut geofences: Vec<&Geofence> = self.geofences.values().collect();
+        geofences.sort_by_key(|t| t.area.id);
+        if let Ok(bts) = serde_json::to_vec_pretty(&geofences) {
+            self.saver.send_replace(bts);
+        }
+    }
+}
+
+//只写最新内容
+async fn run_saver(path:String, mut receiver:watch::Receiver<Vec<u8>>) {
+    while receiver.changed().await.is_ok() {
+        let bts = receiver.borrow_and_update().clone();
+        if let Err(err) = tokio::fs::write(&path, bts).await {
+            log::warn!("[service-geofence]save failed:{} err:{}", path, err);
+        }
+    }
+}
+
+fn modify<R>(f:impl FnOnce(&mut GeofenceStore) -> R) -> Option<R> {
+    let mut global = GLOBAL_GEOFENCE.lock().unwrap();
+    let store = global.as_mut()?;
+    let result = f(store);
+    store.save();
+    Some(result)
+}
+
+pub fn list() -> Vec<Geofence> {
+    let global = GLOBAL_GEOFENCE.lock().unwrap();
+    let mut geofences: Vec<Geofence> = global.as_ref().map_or(Vec::new(), |t| t.geofences.values().cloned().collect());
+    geofences.sort_by_key(|t| t.area.id);
+    geofences
+}
+
+pub fn get(id:u32) -> Option<Geofence> {
+    GLOBAL_GEOFENCE.lock().unwrap().as_ref()?.geofences.get(&id).cloned()
+}
+
+/// 新增或替换 保留已下发的终端 已下发的终端需要重新下发
+pub fn put(mut geofence:Geofence) -> Result<Geofence, &'static str> {
+    geofence.area.shape.validate()?;
+    modify(|store| {
+        for states in store.states.values_mut() {
+            states.remove(&geofence.area.id);
+        }
+        if let Some(old) = store.geofences.remove(&geofence.area.id) {
+            let sims = std::mem::take(&mut geofence.sims);
+            geofence.sims = old.sims;
+            for sim in sims {
+                if !geofence.sims.contains(&sim) {
+                    geofence.sims.push(sim);
+                }
+            }
+        }
+        store.geofences.insert(geofence.area.id, geofence.clone());
+        geofence
+    }).ok_or("uninitialized")
+}
+
+pub fn remove(id:u32) -> Option<Geofence> {
+    modify(|store| {
+        for states in store.states.values_mut() {
+            states.remove(&id);
+        }
+        store.geofences.remove(&id)
+    })?
+}
+
+/// 下发到终端 成功后加入平台侧判断
+/// 返回终端通用应答结果 -1:超时或不在线 -2:区域不存在
+pub async fn push(sim:&str, id:u32) -> i32 {
+    let geofence = match get(id) {
+        Some(geofence) => geofence,
+        None => return -2,
+    };
+    let sender = match service_device::get_sender(sim).await {
+        Some(sender) => sender,
+        None => return -1,
+    };
+    let cmd = geofence.area.shape.set_id();
+    let result = sender.send_cmd(cmd, &mut JtAreaSet { area: geofence.area }).await;
+    log::info!("[service-geofence]push sim:{} id:{} cmd:{:04x} result:{}", sim, id, cmd, result);
+    if result == 0 {
+        modify(|store| {
+            if let Some(geofence) = store.geofences.get_mut(&id) {
+                if !geofence.sims.iter().any(|t| t == sim) {
+                    geofence.sims.push(sim.to_string());
+                }
+            }
+        });
+    }
+    result
+}
+
+/// 从终端删除 终端应答成功后停止平台侧判断
+pub async fn delete(sim:&str, id:u32) -> i32 {
+    let geofence = match get(id) {
+        Some(geofence) => geofence,
+        None => return -2,
+    };
+    let sender = match service_device::get_sender(sim).await {
+        Some(sender) => sender,
+        None => return -1,
+    };
+    let cmd = geofence.area.shape.set_id() + 1;
+    let result = sender.send_cmd(cmd, &mut JtAreaDelete { ids: vec![id] }).await;
+    log::info!("[service-geofence]delete sim:{} id:{} cmd:{:04x} result:{}", sim, id, cmd, result);
+    if result == 0 {
+        modify(|store| {
+            if let Some(geofence) = store.geofences.get_mut(&id) {
+                geofence.sims.retain(|t| t != sim);
+            }
+            if let Some(states) = store.states.get_mut(sim) {
+                states.remove(&id);
+            }
+        });
+    }
+    result
+}
+
+/// 0x8608查询终端上的区域 ids为空时查询该类型全部
+pub async fn query(sim:&str, query_type:u8, ids:Vec<u32>) -> Result<Vec<Area>, i32> {
+    let sender = service_device::get_sender(sim).await.ok_or(-1)?;
+    let mut jt8608 = Jt0x8608 { query_type, ids };
+    let jt0608 = sender.request::<_, Jt0x0608>(0x8608, &mut jt8608, 0x0608, false, QUERY_TIMEOUT).await?;
+    Ok(jt0608.areas)
+}
+
+/// 平台侧判断的当前状态
+pub fn states(sim:&str) -> Vec<GeofenceState> {
+    let global = GLOBAL_GEOFENCE.lock().unwrap();
+    let store = match global.as_ref() {
+        Some(store) => store,
+        None => return Vec::new(),
+    };
+    let mut states: Vec<GeofenceState> = store.states.get(sim).map_or(Vec::new(), |states| {
+        states.iter().filter_map(|(id, inside)| {
+            let geofence = store.geofences.get(id)?;
+            Some(GeofenceState { area_id: *id, name: geofence.area.name.clone(), inside: *inside })
+        }).collect()
+    });
+    states.sort_by_key(|t| t.area_id);
+    states
+}
+
+/// 终端上报0x0200时调用 状态变化时发布事件 首次判断只记录状态
+pub fn update(sim:&str, location:&JtLocation) {
+    //未定位
+    if location.state & 0x02 == 0 {
+        return;
+    }
+    let point = GeoPoint { lat: location.lat, lng: location.lng };
+    let mut events = Vec::new();
+    {
+        let mut global = GLOBAL_GEOFENCE.lock().unwrap();
+        let store = match global.as_mut() {
+            Some(store) => store,
+            None => return,
+        };
+        for geofence in store.geofences.values() {
+            if !geofence.sims.iter().any(|t| t == sim) || !geofence.area.is_active(location.time) {
+                continue;
+            }
+            let (inside, distance) = match &geofence.area.shape {
+                AreaShape::Route { .. } => {
+                    let (distance, width) = route_distance(&geofence.area.shape, &point);
+                    (distance <= width / 2.0, Some(distance))
+                },
+                shape => (contains(shape, &point), None),
+            };
+            let states = store.states.entry(sim.to_string()).or_default();
+            let last = states.insert(geofence.area.id, inside);
+            if last.is_none_or(|t| t == inside) {
+                continue;
+            }
+            let kind = match (&geofence.area.shape, inside) {
+                (AreaShape::Route { .. }, true) => "route_return",
+                (AreaShape::Route { .. }, false) => "route_deviation",
+                (_, true) => "geofence_enter",
+                (_, false) => "geofence_exit",
+            };
+            events.push((kind, geofence.area.id, geofence.area.name.clone(), geofence.area.shape.query_type(), distance));
+        }
+    }
+
+    for (kind, area_id, name, query_type, distance) in events {
+        let event = GeofenceEvent {
+            area_id,
+            name: &name,
+            area_type: area_type_name(query_type).unwrap_or_default(),
+            lat: location.lat,
+            lng: location.lng,
+            speed: location.speed,
+            time: location.time,
+            distance,
+        };
+        service_event::publish(DeviceEvent::new(sim, kind, &event));
+    }
+}
+
+/// 两点间球面距离(米)
+pub fn haversine(a:&GeoPoint, b:&GeoPoint) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dlat = lat2 - lat1;
+    let dlng = (b.lng - a.lng).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// 点是否在圆形/矩形/多边形内 线路返回false
+pub fn contains(shape:&AreaShape, point:&GeoPoint) -> bool {
+    match shape {
+        AreaShape::Circle { center, radius } => haversine(center, point) <= *radius as f64,
+        AreaShape::Rectangle { top_left, bottom_right } => {
+            point.lat <= top_left.lat && point.lat >= bottom_right.lat && point.lng >= top_left.lng && point.lng <= bottom_right.lng
+        },
+        AreaShape::Polygon { points } => {
+            //射线法
+            let mut inside = false;
+            let mut j = points.len().wrapping_sub(1);
+            for (i, a) in points.iter().enumerate() {
+                let b = &points[j];
+                if (a.lat > point.lat) != (b.lat > point.lat) && point.lng < (b.lng - a.lng) * (point.lat - a.lat) / (b.lat - a.lat) + a.lng {
+                    inside = !inside;
+                }
+                j = i;
+            }
+            inside
+        },
+        AreaShape::Route { .. } => false,
+    }
+}
+
+/// 点到线路的最近距离(米)和该路段宽度
+pub fn route_distance(shape:&AreaShape, point:&GeoPoint) -> (f64, f64) {
+    let points = match shape {
+        AreaShape::Route { points } if !points.is_empty() => points,
+        _ => return (f64::MAX, 0.0),
+    };
+    //以当前点为原点的平面坐标(米)
+    let scale = point.lat.to_radians().cos();
+    let project = |lat:f64, lng:f64| -> (f64, f64) {
+        ((lng - point.lng).to_radians() * EARTH_RADIUS * scale, (lat - point.lat).to_radians() * EARTH_RADIUS)
+    };
+    let width = |w:u8| if w == 0 { DEFAULT_ROUTE_WIDTH } else { w as f64 };
+
+    let mut best = (f64::MAX, width(points[0].width));
+    for (i, start) in points.iter().enumerate() {
+        let a = project(start.lat, start.lng);
+        let b = points.get(i + 1).map_or(a, |t| project(t.lat, t.lng));
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let len2 = dx * dx + dy * dy;
+        let t = if len2 > 0.0 { (-(a.0 * dx + a.1 * dy) / len2).clamp(0.0, 1.0) } else { 0.0 };
+        let distance = (a.0 + t * dx).hypot(a.1 + t * dy);
+        if distance < best.0 {
+            best = (distance, width(start.width));
+        }
+    }
+    best
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use bytes::{Bytes, BytesMut};
+    use jt808::{JtPackage, models::Jt0x0001};
+    use jt_util::bytes_bcd::BytesBCD;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc};
+    use crate::{service_device, service_forward::ServiceForward, service_passthrough::ServicePassthrough, session808::jt808_parse::Jt808Deserialize};
+
+    //模拟终端 上线后记录平台下发的(消息ID, 消息体) 并以result通用应答
+    async fn device(sim:&str, result:u8) -> mpsc::UnboundedReceiver<(u16, Bytes)> {
+        service_device::test_init();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let fw_service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+        let pt_service = Arc::new(ServicePassthrough::new(Vec::new(), String::new()));
+        service_device::start(&addr, fw_service, pt_service).await.unwrap();
+
+        let mut sim_bcd = BytesBCD::new();
+        sim_bcd.set_bytes(Bytes::from(hex::decode(sim).unwrap()));
+        let package = JtPackage::new(sim_bcd, false, 0, 1023);
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        //jt808库空消息体的长度写错 用通用应答上线
+        stream.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: 0, answer_id: 0x0002, result: 0 })).await.unwrap();
+        while service_device::get_sender(sim).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut parse = Jt808Deserialize::new();
+            let mut buffer = BytesMut::new();
+            while let Ok(n) = stream.read_buf(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                while let Ok(Some(jt)) = parse.deserialize(&mut buffer) {
+                    //平台通用应答不需要回复
+                    if jt.id == 0x8001 {
+                        continue;
+                    }
+                    let mut answer = Jt0x0001 { answer_sn: jt.sn, answer_id: jt.id, result };
+                    if stream.write_all(&package.serialize(0x0001, 0, &mut answer)).await.is_err() || tx.send((jt.id, jt.get_body())).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    #[test]
+    fn test_geometry()
+    {
+        use crate::session808::jt808_area::RoutePoint;
+
+        let center = GeoPoint { lat: 22.5431, lng: 114.0579 };
+        let circle = AreaShape::Circle { center, radius: 500 };
+        assert!(contains(&circle, &GeoPoint { lat: 22.5461, lng: 114.0579 }));
+        assert!(!contains(&circle, &GeoPoint { lat: 22.5481, lng: 114.0579 }));
+
+        let rect = AreaShape::Rectangle { top_left: GeoPoint { lat: 23.0, lng: 114.0 }, bottom_right: GeoPoint { lat: 22.0, lng: 115.0 } };
+        assert!(contains(&rect, &center));
+        assert!(!contains(&rect, &GeoPoint { lat: 23.1, lng: 114.5 }));
+
+        let polygon = AreaShape::Polygon { points: vec![GeoPoint { lat: 0.0, lng: 0.0 }, GeoPoint { lat: 0.0, lng: 2.0 }, GeoPoint { lat: 2.0, lng: 1.0 }] };
+        assert!(contains(&polygon, &GeoPoint { lat: 0.5, lng: 1.0 }));
+        assert!(!contains(&polygon, &GeoPoint { lat: 1.5, lng: 0.2 }));
+
+        let route = AreaShape::Route { points: vec![
+            RoutePoint { lat: 22.5, lng: 114.0, width: 40, ..Default::default() },
+            RoutePoint { lat: 22.5, lng: 114.1, width: 40, ..Default::default() },
+        ] };
+        //纬度0.0001约11米
+        let (distance, width) = route_distance(&route, &GeoPoint { lat: 22.5001, lng: 114.05 });
+        assert!((distance - 11.1).abs() < 0.5, "{}", distance);
+        assert_eq!(width, 40.0);
+        assert!(route_distance(&route, &GeoPoint { lat: 22.5, lng: 114.2 }).0 > 10_000.0);
+    }
+
+
+    #[tokio::test]
+    async fn test_geofence_store()
+    {
+        let path = std::env::temp_dir().join(format!("geofence-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        init(&path);
+        let geofence = |json:&str| serde_json::from_str::<Geofence>(json).unwrap();
+
+        assert_eq!(put(geofence(r#"{"id":7,"type":"polygon","points":[{"lat":1,"lng":1},{"lat":1,"lng":2}]}"#)).err(), Some("polygon"));
+        assert_eq!(put(geofence(r#"{"id":7,"type":"route","points":[]}"#)).err(), Some("route"));
+        assert!(get(7).is_none());
+
+        //下发成功的终端加入 终端应答失败的删除不改变本地状态
+        let mut accept = device("013800000501", 0).await;
+        let _refuse = device("013800000502", 1).await;
+        put(geofence(r#"{"id":7,"type":"circle","center":{"lat":22.5,"lng":114.0},"radius":500,"sims":["013800000502"]}"#)).unwrap();
+        assert_eq!(push("013800000501", 7).await, 0);
+        assert_eq!(accept.recv().await.unwrap().0, 0x8600);
+        assert_eq!(delete("013800000502", 7).await, 1);
+        assert_eq!(get(7).unwrap().sims, vec!["013800000502", "013800000501"]);
+
+        //替换定义时保留已下发的终端
+        let replaced = put(geofence(r#"{"id":7,"type":"circle","center":{"lat":22.5,"lng":114.0},"radius":800}"#)).unwrap();
+        assert_eq!(replaced.sims, vec!["013800000502", "013800000501"]);
+        assert_eq!(delete("013800000501", 7).await, 0);
+        assert_eq!(get(7).unwrap().sims, vec!["013800000502"]);
+
+        //后台写入文件
+        let mut saved = Vec::new();
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            saved = fs::read(&path).ok().and_then(|t| serde_json::from_slice::<Vec<Geofence>>(&t).ok()).unwrap_or_default();
+            if saved.first().is_some_and(|t| t.sims.len() == 1) {
+                break;
+            }
+        }
+        let _ = fs::remove_file(&path);
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].sims, vec!["013800000502"]);
+    }
+}
@@ -0,0 +1,447 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, atomic::{AtomicBool, AtomicU32, Ordering}}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use bytes::{Bytes, BytesMut};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{media::{audio::PT_AAC, ts::TsMuxer}, service_live::{LiveViewer, ServiceLive}, service_media::MediaKey, session1078::jt1078_parse::MediaFrame};
+
+//HLS输出 按关键帧切TS分片 内存保留最近的分片
+//开启录像缓存时保留最近N分钟 保存时写入一个TS文件
+
+//分片目标时长(毫秒)
+const SEGMENT_DURATION: u64 = 2000;
+//无关键帧时单个分片上限 超过后强制切片并等待下一个关键帧
+const MAX_SEGMENT_DURATION: u64 = SEGMENT_DURATION * 5;
+const MAX_SEGMENT_SIZE: usize = 8 * 1024 * 1024;
+//播放列表分片数
+const PLAYLIST_SIZE: usize = 6;
+//无访问且未录像时关闭
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct HlsSegment {
+    pub seq: u64,
+    //秒
+    pub duration: f64,
+    //开始时间 unix秒
+    pub time: u64,
+    pub data: Bytes,
+}
+
+pub struct HlsChannel {
+    segments: std::sync::Mutex<VecDeque<Arc<HlsSegment>>>,
+    last_access: std::sync::Mutex<Instant>,
+    //录像缓存分钟数 0:不录像
+    record_minutes: AtomicU32,
+    //http接口开启的录像 报警联动结束时不停止
+    record_manual: AtomicBool,
+}
+
+impl HlsChannel {
+    fn new() -> Self {
+        HlsChannel {
+            segments: std::sync::Mutex::new(VecDeque::new()),
+            last_access: std::sync::Mutex::new(Instant::now()),
+            record_minutes: AtomicU32::new(0),
+            record_manual: AtomicBool::new(false),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_access.lock().unwrap() = Instant::now();
+    }
+
+    fn is_idle(&self) -> bool {
+        self.record_minutes.load(Ordering::Relaxed) == 0 && self.last_access.lock().unwrap().elapsed() > IDLE_TIMEOUT
+    }
+
+    fn push(&self, segment:HlsSegment) {
+        let record_secs = self.record_minutes.load(Ordering::Relaxed) as u64 * 60;
+        let mut segments = self.segments.lock().unwrap();
+        segments.push_back(Arc::new(segment));
+        while segments.len() > PLAYLIST_SIZE {
+            let oldest = segments.front().map_or(0, |t| t.time);
+            if record_secs > 0 && unix_now().saturating_sub(oldest) <= record_secs {
+                break;
+            }
+            segments.pop_front();
+        }
+    }
+
+    /// 滚动播放列表 还没有分片时返回None
+    pub fn playlist(&self) -> Option<String> {
+        self.touch();
+        let segments = self.segments.lock().unwrap();
+        if segments.is_empty() {
+            return None;
+        }
+        let skip = segments.len().saturating_sub(PLAYLIST_SIZE);
+        let items: Vec<&Arc<HlsSegment>> = segments.iter().skip(skip).collect();
+        let target = items.iter().map(|t| t.duration.ceil() as u64).max().unwrap_or(2).max(1);
+
+        let mut m3u8 = format!("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n", target, items[0].seq);
+        for item in items {
+            m3u8.push_str(&format!("#EXTINF:{:.3},\n{}.ts\n", item.duration, item.seq));
+        }
+        Some(m3u8)
+    }
+
+    pub fn segment(&self, seq:u64) -> Option<Bytes> {
+        self.touch();
+        self.segments.lock().unwrap().iter().find(|t| t.seq == seq).map(|t| t.data.clone())
+    }
+}
+
+//按关键帧切片
+struct HlsSegmenter {
+    muxer: Option<TsMuxer>,
+    has_audio: bool,
+    seq: u64,
+    data: BytesMut,
+    start_ts: u64,
+    start_time: u64,
+}
+
+impl HlsSegmenter {
+    fn new() -> Self {
+        HlsSegmenter {
+            muxer: None,
+            has_audio: false,
+            seq: 0,
+            data: BytesMut::new(),
+            start_ts: 0,
+            start_time: 0,
+        }
+    }
+
+    //返回完成的分片
+    fn push(&mut self, frame:&MediaFrame) -> Option<HlsSegment> {
+        if frame.is_audio() {
+            if frame.pt != PT_AAC {
+                return None;
+            }
+            self.has_audio = true;
+            if self.is_overflow(frame.time_stamp) {
+                return self.cut(frame.time_stamp);
+            }
+            if let Some(muxer) = self.muxer.as_mut() {
+                muxer.write_audio(&mut self.data, frame.time_stamp, &frame.data);
+            }
+            return None;
+        }
+        if !frame.is_video() {
+            return None;
+        }
+
+        let mut segment = None;
+        if frame.is_key() && (self.muxer.is_none() || frame.time_stamp.saturating_sub(self.start_ts) >= SEGMENT_DURATION) {
+            segment = self.cut(frame.time_stamp);
+            //新分片 重写PAT/PMT
+            let mut muxer = TsMuxer::new(frame.pt == 99, self.has_audio);
+            muxer.write_psi(&mut self.data);
+            self.muxer = Some(muxer);
+            self.start_ts = frame.time_stamp;
+            self.start_time = unix_now();
+        } else if self.is_overflow(frame.time_stamp) {
+            return self.cut(frame.time_stamp);
+        }
+
+        if let Some(muxer) = self.muxer.as_mut() {
+            muxer.write_video(&mut self.data, frame.time_stamp, frame.is_key(), &frame.data);
+        }
+        segment
+    }
+
+    //长时间收不到关键帧
+    fn is_overflow(&self, time_stamp:u64) -> bool {
+        self.muxer.is_some() && (self.data.len() >= MAX_SEGMENT_SIZE || time_stamp.saturating_sub(self.start_ts) >= MAX_SEGMENT_DURATION)
+    }
+
+    //结束当前分片 之后的帧丢弃到下一个关键帧
+    fn cut(&mut self, time_stamp:u64) -> Option<HlsSegment> {
+        if self.muxer.take().is_none() || self.data.is_empty() {
+            self.data.clear();
+            return None;
+        }
+        let segment = HlsSegment {
+            seq: self.seq,
+            duration: time_stamp.saturating_sub(self.start_ts) as f64 / 1000.0,
+            time: self.start_time,
+            data: self.data.split().freeze(),
+        };
+        self.seq += 1;
+        Some(segment)
+    }
+}
+
+pub struct ServiceHls {
+    live: Arc<ServiceLive>,
+    record_path: String,
+    //录像缓存最长分钟数
+    record_max_minutes: u32,
+    channels: std::sync::Mutex<HashMap<MediaKey, Arc<HlsChannel>>>,
+}
+
+impl ServiceHls {
+    pub fn new(live:Arc<ServiceLive>, record_path:String, record_max_minutes:u32) -> Self {
+        ServiceHls {
+            live,
+            record_path,
+            record_max_minutes,
+            channels: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    //获取或开启HLS输出 设备不在线或打开失败时返回None
+    pub async fn get_channel(service:&Arc<ServiceHls>, sim:&str, channel:u8) -> Option<Arc<HlsChannel>> {
+        let key = (sim.to_string(), channel);
+        if let Some(hls) = service.channels.lock().unwrap().get(&key) {
+            return Some(hls.clone());
+        }
+
+        let viewer = ServiceLive::open(&service.live, sim, channel, 0).await.ok()?;
+        let hls = {
+            let mut channels = service.channels.lock().unwrap();
+            if let Some(hls) = channels.get(&key) {
+                return Some(hls.clone());
+            }
+            let hls = Arc::new(HlsChannel::new());
+            channels.insert(key.clone(), hls.clone());
+            hls
+        };
+
+        log::info!("[service-hls]open sim:{} channel:{}", sim, channel);
+        tokio::spawn(ServiceHls::run(service.clone(), key, hls.clone(), viewer));
+        Some(hls)
+    }
+
+    async fn run(service:Arc<ServiceHls>, key:MediaKey, hls:Arc<HlsChannel>, mut viewer:LiveViewer) {
+        let mut segmenter = HlsSegmenter::new();
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                frame = viewer.receiver.recv() => {
+                    match frame {
+                        Ok(frame) => {
+                            if let Some(segment) = segmenter.push(&frame) {
+                                hls.push(segment);
+                            }
+                        },
+                        Err(RecvError::Lagged(_)) => {},
+                        Err(RecvError::Closed) => break,
+                    }
+                },
+                _ = interval.tick() => {
+                    if hls.is_idle() {
+                        break;
+                    }
+                },
+            }
+        }
+
+        log::info!("[service-hls]close sim:{} channel:{}", key.0, key.1);
+        let mut channels = service.channels.lock().unwrap();
+        if channels.get(&key).is_some_and(|t| Arc::ptr_eq(t, &hls)) {
+            channels.remove(&key);
+        }
+    }
+
+    /// 开始缓存最近minutes分钟 minutes为0时停止 超过配置的最长分钟数时按最长缓存
+    pub async fn record(service:&Arc<ServiceHls>, sim:&str, channel:u8, minutes:u32) -> bool {
+        let minutes = minutes.min(service.record_max_minutes);
+        if minutes == 0 {
+            if let Some(hls) = service.channels.lock().unwrap().get(&(sim.to_string(), channel)) {
+                hls.record_manual.store(false, Ordering::Relaxed);
+                hls.record_minutes.store(0, Ordering::Relaxed);
+                hls.touch();
+            }
+            return true;
+        }
+        match ServiceHls::get_channel(service, sim, channel).await {
+            Some(hls) => {
+                hls.record_manual.store(true, Ordering::Relaxed);
+                hls.record_minutes.store(minutes, Ordering::Relaxed);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// 报警联动开始缓存 已在录像时不缩短缓存分钟数
+    pub async fn record_alarm(service:&Arc<ServiceHls>, sim:&str, channel:u8, minutes:u32) -> bool {
+        let minutes = minutes.min(service.record_max_minutes);
+        match ServiceHls::get_channel(service, sim, channel).await {
+            Some(hls) => {
+                hls.record_minutes.fetch_max(minutes, Ordering::Relaxed);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// 报警联动结束缓存 http接口开启的录像继续
+    pub fn stop_alarm(&self, sim:&str, channel:u8) {
+        if let Some(hls) = self.channels.lock().unwrap().get(&(sim.to_string(), channel)) {
+            if !hls.record_manual.load(Ordering::Relaxed) {
+                hls.record_minutes.store(0, Ordering::Relaxed);
+                hls.touch();
+            }
+        }
+    }
+
+    /// 冻结当前缓存并保存为TS文件 返回文件路径
+    pub async fn save(&self, sim:&str, channel:u8) -> Option<String> {
+        let segments: Vec<Arc<HlsSegment>> = {
+            let channels = self.channels.lock().unwrap();
+            let hls = channels.get(&(sim.to_string(), channel))?;
+            let segments = hls.segments.lock().unwrap();
+            segments.iter().cloned().collect()
+        };
+        if segments.is_empty() {
+            return None;
+        }
+
+        let path = format!("{}/{}_{}_{}.ts", self.record_path, sim, channel, segments[0].time);
+        let mut data = BytesMut::new();
+        for segment in &segments {
+            data.extend_from_slice(&segment.data);
+        }
+
+        if let Err(err) = tokio::fs::create_dir_all(&self.record_path).await {
+            log::warn!("[service-hls]create dir failed:{}", err);
+            return None;
+        }
+        match tokio::fs::write(&path, data).await {
+            Ok(_) => {
+                log::info!("[service-hls]save sim:{} channel:{} file:{}", sim, channel, path);
+                Some(path)
+            },
+            Err(err) => {
+                log::warn!("[service-hls]save failed:{}", err);
+                None
+            },
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use bytes::{Bytes, BytesMut};
+    use jt808::{JtPackage, models::Jt0x0001};
+    use jt_util::bytes_bcd::BytesBCD;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc};
+    use crate::{service_device, service_forward::ServiceForward, service_passthrough::ServicePassthrough, session808::jt808_parse::Jt808Deserialize};
+
+    //模拟终端 上线后记录平台下发的(消息ID, 消息体) 并以result通用应答
+    async fn device(sim:&str, result:u8) -> mpsc::UnboundedReceiver<(u16, Bytes)> {
+        service_device::test_init();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let fw_service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+        let pt_service = Arc::new(ServicePassthrough::new(Vec::new(), String::new()));
+        service_device::start(&addr, fw_service, pt_service).await.unwrap();
+
+        let mut sim_bcd = BytesBCD::new();
+        sim_bcd.set_bytes(Bytes::from(hex::decode(sim).unwrap()));
+        let package = JtPackage::new(sim_bcd, false, 0, 1023);
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        //jt808库空消息体的长度写错 用通用应答上线
+        stream.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: 0, answer_id: 0x0002, result: 0 })).await.unwrap();
+        while service_device::get_sender(sim).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut parse = Jt808Deserialize::new();
+            let mut buffer = BytesMut::new();
+            while let Ok(n) = stream.read_buf(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                while let Ok(Some(jt)) = parse.deserialize(&mut buffer) {
+                    //平台通用应答不需要回复
+                    if jt.id == 0x8001 {
+                        continue;
+                    }
+                    let mut answer = Jt0x0001 { answer_sn: jt.sn, answer_id: jt.id, result };
+                    if stream.write_all(&package.serialize(0x0001, 0, &mut answer)).await.is_err() || tx.send((jt.id, jt.get_body())).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    #[test]
+    fn test_segment_without_key()
+    {
+        let frame = |data_type:u8, time_stamp:u64| MediaFrame {
+            sim: "013800000332".to_string(),
+            channel: 1,
+            pt: 98,
+            data_type,
+            time_stamp,
+            last_i_interval: 0,
+            last_interval: 0,
+            data: Bytes::from_static(&[0, 0, 0, 1, 0x65, 0x88, 0x84]),
+        };
+        let mut segmenter = HlsSegmenter::new();
+        assert!(segmenter.push(&frame(0, 0)).is_none());
+        for time_stamp in (40..MAX_SEGMENT_DURATION).step_by(40) {
+            assert!(segmenter.push(&frame(1, time_stamp)).is_none());
+        }
+        //一直没有关键帧 到上限强制切片
+        let segment = segmenter.push(&frame(1, MAX_SEGMENT_DURATION)).unwrap();
+        assert_eq!(segment.seq, 0);
+        assert_eq!(segment.duration, MAX_SEGMENT_DURATION as f64 / 1000.0);
+
+        //之后的帧丢弃到下一个关键帧
+        assert!(segmenter.push(&frame(1, MAX_SEGMENT_DURATION + 40)).is_none());
+        assert!(segmenter.data.is_empty());
+        assert!(segmenter.push(&frame(0, MAX_SEGMENT_DURATION + 80)).is_none());
+        assert!(!segmenter.data.is_empty());
+        assert_eq!(segmenter.push(&frame(0, MAX_SEGMENT_DURATION + 80 + SEGMENT_DURATION)).unwrap().seq, 1);
+    }
+
+    #[tokio::test]
+    async fn test_record_max_minutes()
+    {
+        let live = Arc::new(ServiceLive::new(Arc::new(crate::service_media::ServiceMedia::new()), "127.0.0.1:1078", Duration::from_secs(1)));
+        let service = Arc::new(ServiceHls::new(live, "record".to_string(), 30));
+        let _device = device("013800000331", 0).await;
+
+        assert!(ServiceHls::record(&service, "013800000331", 1, 100000).await);
+        let hls = service.channels.lock().unwrap().get(&("013800000331".to_string(), 1)).unwrap().clone();
+        assert_eq!(hls.record_minutes.load(Ordering::Relaxed), 30);
+        assert!(ServiceHls::record(&service, "013800000331", 1, 10).await);
+        assert_eq!(hls.record_minutes.load(Ordering::Relaxed), 10);
+    }
+
+    #[tokio::test]
+    async fn test_record_alarm_keeps_manual()
+    {
+        let live = Arc::new(ServiceLive::new(Arc::new(crate::service_media::ServiceMedia::new()), "127.0.0.1:1078", Duration::from_secs(1)));
+        let service = Arc::new(ServiceHls::new(live, "record".to_string(), 30));
+        let _device = device("013800000401", 0).await;
+        let minutes = |channel:u8| service.channels.lock().unwrap().get(&("013800000401".to_string(), channel)).unwrap().record_minutes.load(Ordering::Relaxed);
+
+        //联动开启的录像联动结束时停止
+        assert!(ServiceHls::record_alarm(&service, "013800000401", 1, 1).await);
+        assert_eq!(minutes(1), 1);
+        service.stop_alarm("013800000401", 1);
+        assert_eq!(minutes(1), 0);
+
+        //手动开启的录像不被联动缩短或停止
+        assert!(ServiceHls::record(&service, "013800000401", 2, 10).await);
+        assert!(ServiceHls::record_alarm(&service, "013800000401", 2, 1).await);
+        service.stop_alarm("013800000401", 2);
+        assert_eq!(minutes(2), 10);
+    }
+}
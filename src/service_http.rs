@@ -1,22 +1,35 @@
 use std::{collections::HashMap, num::ParseIntError, sync::Arc};
 
 use axum::{
-    routing::get,
-    Router, extract::Query,
+    routing::{get, post}, Json,
+    Router, extract::{Query, Path, State, ws::{WebSocketUpgrade, WebSocket, Message}},
+    response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}}, body::StreamBody, http::{StatusCode, header},
 };
 use bytes::Bytes;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
 use jt1078::extend808::{Jt0x9101, Jt0x9102, Jt0x9201, Jt0x9202, Jt0x9205};
 use jt808::{models::{Jt808, Jt808BodyTrans, Jt808BodySerialize}, bytes::JtBytes};
 
-use crate::{service_device::{self, GetSender}, session808::jt808_session::Jt808SessionShared};
+use crate::{session1078::extend808::{codec_name, VideoAlarm}, service_alarm::{self, ActiveAlarm}, service_geofence::{self, Geofence, GeofenceState}, session808::jt808_area::Area, service_video_alarm, service_event::{self, EventFilter}, service_webhook::{self, WebhookStatus}, service_809, service_track, service_trip, service_safety::{self, SafetyRecord}, service_device, service_avinfo, service_ptz::{self, PtzRequest}, service_live::{ServiceLive, LiveViewer, StreamInfo}, service_hls::ServiceHls, service_intercom::{self, IntercomMode, IntercomSession, ServiceIntercom}, service_playback::{ServicePlayback, RecordingQuery, Recording, PlaybackRequest, PlaybackControl, Jt0x9202Bcd, Jt0x9205Bcd}, service_upload::{ServiceUpload, UploadRequest, UploadTask}, media::flv::FlvMuxer};
 
+//http接口使用的服务
+pub struct HttpContext {
+    pub live: Arc<ServiceLive>,
+    pub hls: Arc<ServiceHls>,
+    pub playback: Arc<ServicePlayback>,
+    pub upload: Arc<ServiceUpload>,
+    pub intercom: Arc<ServiceIntercom>,
+}
 
+#[allow(dead_code)]
 struct ServiceHttp {
-    sender : Box<dyn GetSender>,
+    sender : Box<dyn service_device::GetSender>,
 }
 
+#[allow(dead_code)]
 impl ServiceHttp {
-    pub fn new(sender:Box<dyn GetSender>) ->Self {
+    pub fn new(sender:Box<dyn service_device::GetSender>) ->Self {
         Self{
             sender
         }
@@ -25,7 +38,7 @@ impl ServiceHttp {
     pub async fn start(addr:&String) {
     
         let app = Router::new()
-        .route("/api/VideoControl", get(root));
+        .route("/api/VideoControl", get(Self::root));
     
         log::info!("[service-http]listen addr:{}", addr);
     
@@ -42,54 +55,54 @@ impl ServiceHttp {
             Some(value) => {
                 match decode_hex(value) {
                     Ok(mut result) => {
-                        let mut jt808 = Jt808::from_http(&result);
+                        let jt808 = Jt808::from_http(&result);
                         let sim = jt808.sim.to_string();
                         let body = result.split_off(jt808.get_head_len() - 1);
                         match jt808.id {
                             0x9101 => {
-                                let mut jt9101 = Jt0x9101::fill_new(&mut JtBytes::from(body), &mut jt808);
+                                let mut jt9101 = Jt0x9101::fill_new(&mut JtBytes::from(body), &jt808);
                                 log::info!("[service-http]Control Jt9101:{:?}", jt9101);
     
                                 return send_cmd(&sim, 0x9101, &mut jt9101).await;
                             },
                             0x9102 => {
-                                let mut jt9102 = Jt0x9102::fill_new(&mut JtBytes::from(body), &mut jt808);
+                                let mut jt9102 = Jt0x9102::fill_new(&mut JtBytes::from(body), &jt808);
                                 log::info!("[service-http]Control Jt9101:{:?}", jt9102);
     
                                 return send_cmd(&sim, 0x9102, &mut jt9102).await;
                             },
                             0x9201 => {
-                                let mut jt9201 = Jt0x9201::fill_new(&mut JtBytes::from(body), &mut jt808);
+                                let mut jt9201 = Jt0x9201::fill_new(&mut JtBytes::from(body), &jt808);
                                 log::info!("[service-http]Control Jt9201:{:?}", jt9201);
     
                                 return send_cmd(&sim, 0x9201, &mut jt9201).await;
                             },
                             0x9202 => {
-                                let mut jt9202 = Jt0x9202::fill_new(&mut JtBytes::from(body), &mut jt808);
+                                let mut jt9202 = Jt0x9202::fill_new(&mut JtBytes::from(body), &jt808);
                                 log::info!("[service-http]Control Jt9202:{:?}", jt9202);
     
                                 return send_cmd(&sim, 0x9202, &mut jt9202).await;
                             },
                             0x9205 => {
-                                let mut jt9205 = Jt0x9205::fill_new(&mut JtBytes::from(body), &mut jt808);
+                                let mut jt9205 = Jt0x9205::fill_new(&mut JtBytes::from(body), &jt808);
                                 log::info!("[service-http]Control Jt9101:{:?}", jt9205);
     
                                 return send_cmd(&sim, 0x9205, &mut jt9205).await;
                             },
                             _ => {
-                                return "0"
+                                "0"
                             }
                         }
     
                         
                     }
                     Err(_) => {
-                        return "0";
+                        "0"
                     },
                 }
             },
             None => {
-                return "0";
+                "0"
             },
         }
     }
@@ -99,10 +112,10 @@ impl ServiceHttp {
         match self.sender.get_sender(sim) {
             Some(sender) => {
                 sender.send_cmd(id, cmd).await;
-                return "1";
+                "1"
             },
             None => {
-                return "0";
+                "0"
             },
         }
     }
@@ -117,10 +130,45 @@ impl ServiceHttp {
 }
 
 
-pub async fn start(addr:&String) {
+pub async fn start(addr:&String, context:Arc<HttpContext>) {
     
     let app = Router::new()
-    .route("/api/VideoControl", get(root));
+    .route("/api/VideoControl", get(root))
+    .route("/api/streams", get(stream_list))
+    .route("/live/:sim/:file", get(live_flv))
+    .route("/ws/live/:sim/:file", get(live_ws_flv))
+    .route("/hls/:sim/:channel/:file", get(hls_file))
+    .route("/api/record/:sim/:channel/:action", post(record_control))
+    .route("/api/devices/:sim/recordings", get(recordings))
+    .route("/api/devices/:sim/playback", post(playback_start))
+    .route("/api/devices/:sim/playback/:channel/control", post(playback_control))
+    .route("/playback/:sim/:file", get(playback_flv))
+    .route("/api/devices/:sim/uploads", get(upload_list).post(upload_request))
+    .route("/api/uploads/:id", get(upload_get))
+    .route("/api/devices/:sim/av-attributes", get(av_attributes))
+    .route("/api/devices/:sim/ptz/:channel/:command", post(ptz_control))
+    .route("/api/uploads/:id/:action", post(upload_control))
+    .route("/ws/intercom/:sim/:channel", get(intercom_ws))
+    .route("/api/events", get(events_sse))
+    .route("/ws/events", get(events_ws))
+    .route("/api/webhooks", get(webhook_status))
+    .route("/api/webhooks/dead-letters", get(webhook_dead_letters))
+    .route("/api/jt809", get(jt809_status))
+    .route("/api/alarms", get(alarm_all))
+    .route("/api/devices/:sim/alarms", get(alarm_list))
+    .route("/api/devices/:sim/alarms/:kind/ack", post(alarm_ack))
+    .route("/api/geofences", get(geofence_list).post(geofence_put))
+    .route("/api/geofences/:id", get(geofence_get).delete(geofence_remove))
+    .route("/api/devices/:sim/geofences", get(geofence_query))
+    .route("/api/devices/:sim/geofences/:id", post(geofence_push).delete(geofence_delete))
+    .route("/api/devices/:sim/geofence-states", get(geofence_states))
+    .route("/api/devices/:sim/track", get(track))
+    .route("/api/devices/:sim/trips", get(trip_summary))
+    .route("/api/devices/:sim/video-alarms", get(video_alarms))
+    .route("/api/devices/:sim/safety-alarms", get(safety_alarms))
+    .route("/api/safety-alarms/:number", get(safety_alarm_get))
+    .route("/api/safety-alarms/:number/:file", get(safety_attachment))
+    .with_state(context);
 
     log::info!("[service-http]listen addr:{}", addr);
 
@@ -130,80 +178,732 @@ pub async fn start(addr:&String) {
     .unwrap();
 }
 
-async fn root(Query(args): Query<HashMap<String, String>>) -> &'static str {
+async fn root(State(context):State<Arc<HttpContext>>, Query(args): Query<HashMap<String, String>>) -> &'static str {
     log::info!("[service-http]Control args:{:?}", args);
     
     match args.get("Content") {
         Some(value) => {
             match decode_hex(value) {
                 Ok(mut result) => {
-                    let mut jt808 = Jt808::from_http(&result);
+                    let jt808 = Jt808::from_http(&result);
                     let sim = jt808.sim.to_string();
                     let body = result.split_off(jt808.get_head_len() - 1);
                     match jt808.id {
                         0x9101 => {
-                            let mut jt9101 = Jt0x9101::fill_new(&mut JtBytes::from(body), &mut jt808);
+                            let jt9101 = Jt0x9101::fill_new(&mut JtBytes::from(body), &jt808);
                             log::info!("[service-http]Control Jt9101:{:?}", jt9101);
 
-                            return send_cmd(&sim, 0x9101, &mut jt9101).await;
+                            //同一码流只下发一次
+                            return if ServiceLive::hold(&context.live, &sim, jt9101).await { "1" } else { "0" };
                         },
                         0x9102 => {
-                            let mut jt9102 = Jt0x9102::fill_new(&mut JtBytes::from(body), &mut jt808);
-                            log::info!("[service-http]Control Jt9101:{:?}", jt9102);
+                            let mut jt9102 = Jt0x9102::fill_new(&mut JtBytes::from(body), &jt808);
+                            log::info!("[service-http]Control Jt9102:{:?}", jt9102);
 
+                            //关闭时只释放接口占用 没有观看者后由码流管理关闭
+                            if jt9102.cmd == 0 && (ServiceLive::unhold(&context.live, &sim, jt9102.channel) || context.live.is_open(&sim, jt9102.channel)) {
+                                return "1";
+                            }
                             return send_cmd(&sim, 0x9102, &mut jt9102).await;
                         },
                         0x9201 => {
-                            let mut jt9201 = Jt0x9201::fill_new(&mut JtBytes::from(body), &mut jt808);
+                            let mut jt9201 = Jt0x9201::fill_new(&mut JtBytes::from(body), &jt808);
                             log::info!("[service-http]Control Jt9201:{:?}", jt9201);
 
                             return send_cmd(&sim, 0x9201, &mut jt9201).await;
                         },
                         0x9202 => {
-                            let mut jt9202 = Jt0x9202::fill_new(&mut JtBytes::from(body), &mut jt808);
+                            let jt9202 = Jt0x9202::fill_new(&mut JtBytes::from(body), &jt808);
                             log::info!("[service-http]Control Jt9202:{:?}", jt9202);
 
-                            return send_cmd(&sim, 0x9202, &mut jt9202).await;
+                            return send_cmd(&sim, 0x9202, &mut Jt0x9202Bcd(jt9202)).await;
                         },
                         0x9205 => {
-                            let mut jt9205 = Jt0x9205::fill_new(&mut JtBytes::from(body), &mut jt808);
-                            log::info!("[service-http]Control Jt9101:{:?}", jt9205);
+                            let jt9205 = Jt0x9205::fill_new(&mut JtBytes::from(body), &jt808);
+                            log::info!("[service-http]Control Jt9205:{:?}", jt9205);
 
-                            return send_cmd(&sim, 0x9205, &mut jt9205).await;
+                            return send_cmd(&sim, 0x9205, &mut Jt0x9205Bcd(jt9205)).await;
                         },
                         _ => {
-                            return "0"
+                            "0"
                         }
                     }
 
                     
                 }
                 Err(_) => {
-                    return "0";
+                    "0"
                 },
             }
         },
         None => {
-            return "0";
+            "0"
         },
     }
 }
 
-async fn send_cmd<T:Jt808BodySerialize>(sim:&String, id:u16, cmd:&mut T) -> &'static str {
-    match service_device::get_sender(&sim).await {
+async fn send_cmd<T:Jt808BodySerialize>(sim:&str, id:u16, cmd:&mut T) -> &'static str {
+    match service_device::get_sender(sim).await {
         Some(sender) => {
             sender.send_cmd(id, cmd).await;
-            return "1";
+            "1"
         },
         None => {
-            return "0";
+            "0"
         },
     }
 }
 
+//当前码流列表
+async fn stream_list(State(context):State<Arc<HttpContext>>) -> Json<Vec<StreamInfo>> {
+    Json(context.live.streams())
+}
+
+//码流类型 ?stream=1 子码流 默认主码流
+fn parse_stream_type(args:&HashMap<String, String>) -> u8 {
+    args.get("stream").and_then(|t| t.parse().ok()).unwrap_or(0)
+}
+
+//文件名 {channel}.flv
+fn parse_channel(file:&str) -> Option<u8> {
+    file.strip_suffix(".flv")?.parse().ok()
+}
+
+//HTTP-FLV /live/{sim}/{channel}.flv
+async fn live_flv(State(context):State<Arc<HttpContext>>, Path((sim, file)):Path<(String, String)>, Query(args):Query<HashMap<String, String>>) -> Response {
+    let channel = match parse_channel(&file) {
+        Some(channel) => channel,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let viewer = match ServiceLive::open(&context.live, &sim, channel, parse_stream_type(&args)).await {
+        Ok(viewer) => viewer,
+        Err("offline") => return StatusCode::NOT_FOUND.into_response(),
+        Err("busy") => return StatusCode::CONFLICT.into_response(),
+        Err(_) => return StatusCode::BAD_GATEWAY.into_response(),
+    };
+    log::info!("[service-http]live flv sim:{} channel:{}", sim, channel);
+    flv_response(viewer)
+}
+
+fn flv_response(viewer:LiveViewer) -> Response {
+    let first = futures::stream::once(async { Ok::<Bytes, std::io::Error>(FlvMuxer::header()) });
+    let tags = futures::stream::unfold((viewer, FlvMuxer::new()), |(mut viewer, mut muxer)| async move {
+        let tags = next_tags(&mut viewer, &mut muxer).await?;
+        let mut buf = bytes::BytesMut::new();
+        for tag in tags {
+            buf.extend_from_slice(&tag);
+        }
+        Some((Ok(buf.freeze()), (viewer, muxer)))
+    });
+
+    (
+        [(header::CONTENT_TYPE, "video/x-flv"), (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")],
+        StreamBody::new(futures::StreamExt::chain(first, tags)),
+    ).into_response()
+}
+
+//WebSocket-FLV /ws/live/{sim}/{channel}.flv
+async fn live_ws_flv(State(context):State<Arc<HttpContext>>, Path((sim, file)):Path<(String, String)>, Query(args):Query<HashMap<String, String>>, ws:WebSocketUpgrade) -> Response {
+    let channel = match parse_channel(&file) {
+        Some(channel) => channel,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let viewer = match ServiceLive::open(&context.live, &sim, channel, parse_stream_type(&args)).await {
+        Ok(viewer) => viewer,
+        Err("offline") => return StatusCode::NOT_FOUND.into_response(),
+        Err("busy") => return StatusCode::CONFLICT.into_response(),
+        Err(_) => return StatusCode::BAD_GATEWAY.into_response(),
+    };
+    log::info!("[service-http]live ws-flv sim:{} channel:{}", sim, channel);
+
+    ws.on_upgrade(move |socket| live_ws_send(socket, viewer))
+}
+
+async fn live_ws_send(mut socket:WebSocket, mut viewer:LiveViewer) {
+    let mut muxer = FlvMuxer::new();
+    if socket.send(Message::Binary(FlvMuxer::header().to_vec())).await.is_err() {
+        return;
+    }
+    loop {
+        tokio::select! {
+            tags = next_tags(&mut viewer, &mut muxer) => {
+                let tags = match tags {
+                    Some(tags) => tags,
+                    None => break,
+                };
+                for tag in tags {
+                    if socket.send(Message::Binary(tag.to_vec())).await.is_err() {
+                        return;
+                    }
+                }
+            },
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {},
+                }
+            },
+        }
+    }
+}
+
+//下一批FLV tag 流结束时返回None
+async fn next_tags(viewer:&mut LiveViewer, muxer:&mut FlvMuxer) -> Option<Vec<Bytes>> {
+    loop {
+        match viewer.receiver.recv().await {
+            Ok(frame) => {
+                let tags = muxer.mux(&frame);
+                if !tags.is_empty() {
+                    return Some(tags);
+                }
+            },
+            Err(RecvError::Lagged(n)) => {
+                log::info!("[service-http]live lagged:{}", n);
+                muxer.reset_key();
+            },
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+//HLS /hls/{sim}/{channel}/index.m3u8 分片 /hls/{sim}/{channel}/{seq}.ts
+async fn hls_file(State(context):State<Arc<HttpContext>>, Path((sim, channel, file)):Path<(String, u8, String)>) -> Response {
+    let hls = match ServiceHls::get_channel(&context.hls, &sim, channel).await {
+        Some(hls) => hls,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    if file == "index.m3u8" {
+        //首个分片生成前等待
+        for _ in 0..100 {
+            if let Some(m3u8) = hls.playlist() {
+                return ([(header::CONTENT_TYPE, "application/vnd.apple.mpegurl"), (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], m3u8).into_response();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match file.strip_suffix(".ts").and_then(|t| t.parse().ok()).and_then(|seq| hls.segment(seq)) {
+        Some(data) => ([(header::CONTENT_TYPE, "video/mp2t"), (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], data).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct RecordResult {
+    result: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+}
+
+//录像缓存 /api/record/{sim}/{channel}/start?minutes=N stop save
+async fn record_control(State(context):State<Arc<HttpContext>>, Path((sim, channel, action)):Path<(String, u8, String)>, Query(args):Query<HashMap<String, String>>) -> Json<RecordResult> {
+    log::info!("[service-http]record sim:{} channel:{} action:{} args:{:?}", sim, channel, action, args);
+    match action.as_str() {
+        "start" => {
+            let minutes = args.get("minutes").and_then(|t| t.parse().ok()).unwrap_or(5);
+            let result = ServiceHls::record(&context.hls, &sim, channel, minutes).await;
+            Json(RecordResult { result, file: None })
+        },
+        "stop" => {
+            let result = ServiceHls::record(&context.hls, &sim, channel, 0).await;
+            Json(RecordResult { result, file: None })
+        },
+        "save" => {
+            let file = context.hls.save(&sim, channel).await;
+            Json(RecordResult { result: file.is_some(), file })
+        },
+        _ => Json(RecordResult { result: false, file: None }),
+    }
+}
+
+#[derive(Serialize)]
+struct RecordingsResult {
+    result: bool,
+    items: Vec<Recording>,
+}
+
+//录像资源列表 /api/devices/{sim}/recordings?channel=1&from=&to=&alarm=&av_type=
+async fn recordings(State(context):State<Arc<HttpContext>>, Path(sim):Path<String>, Query(query):Query<RecordingQuery>) -> Json<RecordingsResult> {
+    log::info!("[service-http]recordings sim:{} query:{:?}", sim, query);
+    match context.playback.recordings(&sim, &query).await {
+        Ok(items) => Json(RecordingsResult { result: true, items }),
+        Err(_) => Json(RecordingsResult { result: false, items: Vec::new() }),
+    }
+}
+
+#[derive(Serialize)]
+struct PlaybackResult {
+    result: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+//开始回放 返回FLV播放地址
+async fn playback_start(State(context):State<Arc<HttpContext>>, Path(sim):Path<String>, Json(req):Json<PlaybackRequest>) -> Json<PlaybackResult> {
+    log::info!("[service-http]playback sim:{} req:{:?}", sim, req);
+    if context.playback.start(&sim, &req).await {
+        Json(PlaybackResult { result: true, url: Some(format!("/playback/{}/{}.flv", sim, req.channel)) })
+    } else {
+        Json(PlaybackResult { result: false, url: None })
+    }
+}
+
+//回放控制 {"action":"pause|resume|stop|forward|rewind|seek|keyframe", "multiple":1, "position":unix秒}
+async fn playback_control(State(context):State<Arc<HttpContext>>, Path((sim, channel)):Path<(String, u8)>, Json(control):Json<PlaybackControl>) -> Json<PlaybackResult> {
+    let result = context.playback.control(&sim, channel, &control).await;
+    Json(PlaybackResult { result, url: None })
+}
+
+//回放HTTP-FLV /playback/{sim}/{channel}.flv 码流由0x9201打开
+async fn playback_flv(State(context):State<Arc<HttpContext>>, Path((sim, file)):Path<(String, String)>) -> Response {
+    let channel = match parse_channel(&file) {
+        Some(channel) => channel,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    log::info!("[service-http]playback flv sim:{} channel:{}", sim, channel);
+    flv_response(ServiceLive::watch(&context.live, &sim, channel))
+}
+
+#[derive(Serialize)]
+struct UploadResult {
+    result: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task: Option<UploadTask>,
+}
+
+//请求终端上传录像文件(0x9206)
+async fn upload_request(State(context):State<Arc<HttpContext>>, Path(sim):Path<String>, Json(req):Json<UploadRequest>) -> Json<UploadResult> {
+    log::info!("[service-http]upload sim:{} req:{:?}", sim, req);
+    let task = ServiceUpload::request(&context.upload, &sim, &req).await;
+    Json(UploadResult { result: task.is_some(), task })
+}
+
+async fn upload_list(State(context):State<Arc<HttpContext>>, Path(sim):Path<String>) -> Json<Vec<UploadTask>> {
+    Json(context.upload.list(&sim))
+}
+
+async fn upload_get(State(context):State<Arc<HttpContext>>, Path(id):Path<u64>) -> Response {
+    match context.upload.get(id) {
+        Some(task) => Json(task).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+//上传控制(0x9207) pause resume cancel
+async fn upload_control(State(context):State<Arc<HttpContext>>, Path((id, action)):Path<(u64, String)>) -> Json<UploadResult> {
+    let result = context.upload.control(id, &action).await;
+    Json(UploadResult { result, task: context.upload.get(id) })
+}
+
+//音视频属性(0x9003/0x1003) ?refresh=1 重新查询
+async fn av_attributes(Path(sim):Path<String>, Query(args):Query<HashMap<String, String>>) -> Response {
+    let refresh = args.get("refresh").is_some_and(|t| t == "1" || t == "true");
+    match service_avinfo::query(&sim, refresh).await {
+        Some(attr) => Json(attr).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+//当前视频报警(0x0200附加信息0x14~0x18)
+async fn video_alarms(Path(sim):Path<String>) -> Json<Vec<VideoAlarm>> {
+    Json(service_video_alarm::get(&sim))
+}
+
+//主动安全报警(0x0200附加信息0x64~0x67) 新的在前
+async fn safety_alarms(Path(sim):Path<String>) -> Json<Vec<SafetyRecord>> {
+    Json(service_safety::list(&sim))
+}
+
+async fn safety_alarm_get(Path(number):Path<String>) -> Response {
+    match service_safety::get(&number) {
+        Some(record) => Json(record).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+//报警附件 只提供已接收完整的文件
+async fn safety_attachment(Path((number, file)):Path<(String, String)>) -> Response {
+    let path = match service_safety::file_path(&number, &file) {
+        Some(path) => path,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let content_type = match path.extension().and_then(|t| t.to_str()).map(|t| t.to_ascii_lowercase()).as_deref() {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("mp4") => "video/mp4",
+        Some("wav") => "audio/wav",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    };
+    //按块读取 大文件不整个读入内存
+    match tokio::fs::File::open(&path).await {
+        Ok(file) => {
+            let len = file.metadata().await.map_or(0, |t| t.len());
+            (
+                [(header::CONTENT_TYPE, content_type.to_string()), (header::CONTENT_LENGTH, len.to_string()), (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string())],
+                StreamBody::new(tokio_util::io::ReaderStream::new(file)),
+            ).into_response()
+        },
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct AnswerResult {
+    //终端通用应答 0:成功 1:失败 2:消息有误 3:不支持 -1:超时或不在线 -2:参数错误
+    result: i32,
+}
+
+//设备事件推送 ?sim=逗号分隔&type=逗号分隔 为空时不过滤
+fn event_filter(args:&HashMap<String, String>) -> EventFilter {
+    EventFilter::new(args.get("sim").map_or("", |t| t.as_str()), args.get("type").map_or("", |t| t.as_str()))
+}
+
+//Server-Sent Events 事件名为事件类型 数据为事件JSON
+async fn events_sse(Query(args):Query<HashMap<String, String>>) -> Response {
+    let receiver = match service_event::subscribe() {
+        Some(receiver) => receiver,
+        None => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    };
+    let filter = event_filter(&args);
+    log::info!("[service-http]events sse filter:{:?}", filter);
+    let stream = futures::stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        let event = service_event::recv(&mut receiver, &filter).await?;
+        let data = serde_json::to_string(event.as_ref()).unwrap_or_default();
+        Some((Ok::<_, std::convert::Infallible>(Event::default().event(event.kind.as_str()).data(data)), (receiver, filter)))
+    });
+    ([(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], Sse::new(stream).keep_alive(KeepAlive::default())).into_response()
+}
+
+//WebSocket 每个事件一条文本消息
+async fn events_ws(Query(args):Query<HashMap<String, String>>, ws:WebSocketUpgrade) -> Response {
+    let receiver = match service_event::subscribe() {
+        Some(receiver) => receiver,
+        None => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    };
+    let filter = event_filter(&args);
+    log::info!("[service-http]events ws filter:{:?}", filter);
+    ws.on_upgrade(move |socket| events_ws_send(socket, receiver, filter))
+}
+
+async fn events_ws_send(mut socket:WebSocket, mut receiver:tokio::sync::broadcast::Receiver<Arc<service_event::DeviceEvent>>, filter:EventFilter) {
+    loop {
+        tokio::select! {
+            event = service_event::recv(&mut receiver, &filter) => {
+                let event = match event {
+                    Some(event) => event,
+                    None => break,
+                };
+                let data = serde_json::to_string(event.as_ref()).unwrap_or_default();
+                if socket.send(Message::Text(data)).await.is_err() {
+                    break;
+                }
+            },
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {},
+                }
+            },
+        }
+    }
+}
+
+//事件推送地址状态
+async fn webhook_status() -> Json<Vec<WebhookStatus>> {
+    Json(service_webhook::status())
+}
+
+//最近的死信 ?limit=默认100
+async fn webhook_dead_letters(Query(args):Query<HashMap<String, String>>) -> Json<Vec<serde_json::Value>> {
+    let limit = args.get("limit").and_then(|t| t.parse().ok()).unwrap_or(100);
+    Json(service_webhook::dead_letters(limit).await)
+}
+
+//809上级平台链路状态
+async fn jt809_status() -> Response {
+    match service_809::status() {
+        Some(status) => Json(status).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+//当前报警(0x0200报警标志)
+async fn alarm_all() -> Json<Vec<ActiveAlarm>> {
+    Json(service_alarm::all())
+}
+
+async fn alarm_list(Path(sim):Path<String>) -> Json<Vec<ActiveAlarm>> {
+    Json(service_alarm::get(&sim))
+}
+
+//人工确认报警(0x8203) kind:报警名称或标志位 all:全部可确认的报警
+async fn alarm_ack(Path((sim, kind)):Path<(String, String)>) -> Json<AnswerResult> {
+    let bit = match kind.as_str() {
+        "all" => None,
+        kind => match service_alarm::parse_kind(kind) {
+            Some(bit) => Some(bit),
+            None => return Json(AnswerResult { result: -2 }),
+        },
+    };
+    Json(AnswerResult { result: service_alarm::acknowledge(&sim, bit).await })
+}
+
+//区域/线路定义
+async fn geofence_list() -> Json<Vec<Geofence>> {
+    Json(service_geofence::list())
+}
+
+async fn geofence_put(Json(geofence):Json<Geofence>) -> Response {
+    log::info!("[service-http]geofence put id:{}", geofence.area.id);
+    match service_geofence::put(geofence) {
+        Ok(geofence) => Json(geofence).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err).into_response(),
+    }
+}
+
+async fn geofence_get(Path(id):Path<u32>) -> Response {
+    match service_geofence::get(id) {
+        Some(geofence) => Json(geofence).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn geofence_remove(Path(id):Path<u32>) -> Response {
+    match service_geofence::remove(id) {
+        Some(geofence) => Json(geofence).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+//下发区域到终端(0x8600/0x8602/0x8604/0x8606)
+async fn geofence_push(Path((sim, id)):Path<(String, u32)>) -> Json<AnswerResult> {
+    Json(AnswerResult { result: service_geofence::push(&sim, id).await })
+}
+
+//从终端删除区域(0x8601/0x8603/0x8605/0x8607)
+async fn geofence_delete(Path((sim, id)):Path<(String, u32)>) -> Json<AnswerResult> {
+    Json(AnswerResult { result: service_geofence::delete(&sim, id).await })
+}
+
+#[derive(Serialize)]
+struct GeofenceQueryResult {
+    //-1:超时或不在线 其它为终端通用应答结果
+    result: i32,
+    areas: Vec<Area>,
+}
+
+//查询终端上的区域(0x8608) ?type=circle|rectangle|polygon|route&ids=1,2
+async fn geofence_query(Path(sim):Path<String>, Query(args):Query<HashMap<String, String>>) -> Response {
+    let query_type = match args.get("type").map_or("circle", |t| t.as_str()) {
+        "circle" => 1,
+        "rectangle" => 2,
+        "polygon" => 3,
+        "route" => 4,
+        _ => return (StatusCode::BAD_REQUEST, "type").into_response(),
+    };
+    let ids: Vec<u32> = args.get("ids").map_or(Vec::new(), |t| t.split(',').filter_map(|t| t.trim().parse().ok()).collect());
+    let result = match service_geofence::query(&sim, query_type, ids).await {
+        Ok(areas) => GeofenceQueryResult { result: 0, areas },
+        Err(result) => GeofenceQueryResult { result, areas: Vec::new() },
+    };
+    Json(result).into_response()
+}
+
+//平台侧进出区域/偏离线路状态
+async fn geofence_states(Path(sim):Path<String>) -> Json<Vec<GeofenceState>> {
+    Json(service_geofence::states(&sim))
+}
+
+//历史轨迹 ?from=&to=(unix秒 默认最近24小时)&format=json|geojson&interval=抽稀间隔(秒)&limit=最多点数
+async fn track(Path(sim):Path<String>, Query(args):Query<HashMap<String, String>>) -> Response {
+    let arg = |name:&str| args.get(name).and_then(|t| t.parse::<i64>().ok());
+    let to = arg("to").unwrap_or_else(|| chrono::Local::now().timestamp());
+    let from = arg("from").unwrap_or(to - 86400);
+    if from > to || to - from > service_track::MAX_QUERY_DAYS * 86400 {
+        return (StatusCode::BAD_REQUEST, "from/to").into_response();
+    }
+    let points = service_track::query(&sim, from, to).await;
+    let points = service_track::downsample(points, arg("interval").unwrap_or(0), arg("limit").unwrap_or(0).max(0) as usize);
+    match args.get("format").map_or("json", |t| t.as_str()) {
+        "json" => Json(points).into_response(),
+        "geojson" => Json(serde_json::json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": points.iter().map(|t| [t.lng, t.lat]).collect::<Vec<_>>(),
+            },
+            "properties": {
+                "sim": sim,
+                "from": from,
+                "to": to,
+                "times": points.iter().map(|t| t.time).collect::<Vec<_>>(),
+                "speeds": points.iter().map(|t| t.speed).collect::<Vec<_>>(),
+            },
+        })).into_response(),
+        _ => (StatusCode::BAD_REQUEST, "format").into_response(),
+    }
+}
+
+//每日行程/停车汇总 ?date=YYYY-MM-DD 默认当天
+async fn trip_summary(Path(sim):Path<String>, Query(args):Query<HashMap<String, String>>) -> Response {
+    let date = match args.get("date") {
+        Some(date) => match chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) => return (StatusCode::BAD_REQUEST, "date").into_response(),
+        },
+        None => chrono::Local::now().date_naive(),
+    };
+    let summary = service_trip::daily_summary(&sim, date).await;
+    match summary {
+        Some(summary) => Json(summary).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+//云台控制 command:rotate focus iris wiper ir zoom
+async fn ptz_control(Path((sim, channel, command)):Path<(String, u8, String)>, Json(req):Json<PtzRequest>) -> Json<AnswerResult> {
+    log::info!("[service-http]ptz sim:{} channel:{} command:{} req:{:?}", sim, channel, command, req);
+    Json(AnswerResult { result: service_ptz::control(&sim, channel, &command, &req).await })
+}
+
+//对讲 /ws/intercom/{sim}/{channel}?mode=talk|listen|broadcast&codec=g711a|g711u|g726|adpcm
+//&format=opus,pcm 客户端可发送的上行格式 按顺序选第一个支持的 目前只有pcm
+//二进制消息为8kHz 16bit单声道PCM(小端) 双向相同 连接后先发送一条编码及上行格式的文本消息 之后仍收到Opus时回复错误并关闭
+async fn intercom_ws(State(context):State<Arc<HttpContext>>, Path((sim, channel)):Path<(String, u8)>, Query(args):Query<HashMap<String, String>>, ws:WebSocketUpgrade) -> Response {
+    let format = service_intercom::negotiate_format(args.get("format").map_or("pcm", |t| t.as_str()));
+    let mode = match IntercomMode::parse(args.get("mode").map_or("talk", |t| t.as_str())) {
+        Some(mode) => mode,
+        None => return (StatusCode::BAD_REQUEST, "mode").into_response(),
+    };
+    let codec = match args.get("codec") {
+        Some(codec) => match service_intercom::parse_codec(codec) {
+            Some(pt) => Some(pt),
+            None => return (StatusCode::BAD_REQUEST, "codec").into_response(),
+        },
+        None => None,
+    };
+    let session = match ServiceIntercom::open(&context.intercom, &sim, channel, mode, codec).await {
+        Ok(session) => session,
+        Err(err) => return (StatusCode::SERVICE_UNAVAILABLE, err).into_response(),
+    };
+    log::info!("[service-http]intercom sim:{} channel:{} mode:{:?} format:{}", sim, channel, mode, format);
+
+    ws.on_upgrade(move |socket| intercom_ws_run(socket, session, format))
+}
+
+async fn intercom_ws_run(mut socket:WebSocket, mut session:IntercomSession, format:&'static str) {
+    let info = serde_json::json!({ "pt": session.pt(), "codec": codec_name(session.pt()), "sample_rate": 8000, "format": format });
+    if socket.send(Message::Text(info.to_string())).await.is_err() {
+        return;
+    }
+    //终端未建立媒体连接前的音频丢弃
+    let mut linked = false;
+    loop {
+        tokio::select! {
+            frame = session.receiver.recv() => {
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if let Some(pcm) = session.decode(&frame) {
+                    let data: Vec<u8> = pcm.iter().flat_map(|t| t.to_le_bytes()).collect();
+                    if socket.send(Message::Binary(data)).await.is_err() {
+                        break;
+                    }
+                }
+            },
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Binary(data))) => {
+                        match session.send_audio(&data).await {
+                            Ok(sent) => {
+                                if sent != linked {
+                                    log::info!("[service-http]intercom linked:{}", sent);
+                                    linked = sent;
+                                }
+                            },
+                            Err(err) => {
+                                let error = serde_json::json!({ "error": err, "format": format });
+                                let _ = socket.send(Message::Text(error.to_string())).await;
+                                break;
+                            },
+                        }
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {},
+                }
+            },
+        }
+    }
+}
+
 fn decode_hex(s: &str) -> Result<Bytes, ParseIntError> {
     (0..s.len())
         .step_by(2)
         .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
         .collect()
 }
+
+
+#[tokio::test]
+async fn test_events_subscribe()
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    service_event::test_init();
+    let app = Router::new()
+    .route("/api/events", get(events_sse))
+    .route("/ws/events", get(events_ws));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+
+    //读到应答头结束 返回应答头和已读到的消息体
+    async fn request(addr:std::net::SocketAddr, head:&str) -> (tokio::net::TcpStream, String, Vec<u8>) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut buf = Vec::new();
+        loop {
+            assert!(stream.read_buf(&mut buf).await.unwrap() > 0);
+            if let Some(end) = buf.windows(4).position(|t| t == b"\r\n\r\n") {
+                let body = buf.split_off(end + 4);
+                return (stream, String::from_utf8_lossy(&buf).to_string(), body);
+            }
+        }
+    }
+
+    let sim = "013800000701";
+    let (mut sse, head, mut body) = request(addr, &format!("GET /api/events?sim={} HTTP/1.1\r\nHost: localhost\r\n\r\n", sim)).await;
+    assert!(head.starts_with("HTTP/1.1 200") && head.contains("text/event-stream"), "{}", head);
+    let (mut ws, head, mut frame) = request(addr, &format!("GET /ws/events?sim={}&type=alarm HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", sim)).await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+    //过滤掉的事件不推送
+    service_event::publish(service_event::DeviceEvent::new(sim, "position", &serde_json::json!({"lat": 22.5})));
+    service_event::publish(service_event::DeviceEvent::new("013800000702", "alarm", &1));
+    service_event::publish(service_event::DeviceEvent::new(sim, "alarm", &serde_json::json!({"kind": "emergency"})));
+
+    let text = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while !String::from_utf8_lossy(&body).contains("emergency") {
+            sse.read_buf(&mut body).await.unwrap();
+        }
+        String::from_utf8_lossy(&body).to_string()
+    }).await.unwrap();
+    assert!(text.contains("event:position") && text.contains("event:alarm") && !text.contains("013800000702"), "{}", text);
+
+    //服务端文本帧不加掩码
+    let text = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while frame.len() < 2 || frame.len() < 2 + frame[1] as usize {
+            ws.read_buf(&mut frame).await.unwrap();
+        }
+        assert_eq!(frame[0], 0x81);
+        String::from_utf8_lossy(&frame[2..2 + frame[1] as usize]).to_string()
+    }).await.unwrap();
+    let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!((event["sim"].as_str(), event["kind"].as_str(), event["data"]["kind"].as_str()), (Some(sim), Some("alarm"), Some("emergency")));
+}
@@ -0,0 +1,359 @@
+use std::{collections::HashSet, sync::Arc};
+
+use bytes::Bytes;
+use jt1078::extend808::Jt0x9102;
+use tokio::sync::broadcast;
+
+use crate::{media::audio::{AudioTranscoder, PT_ADPCMA, PT_G711A, PT_G711U, PT_G726}, service_avinfo, service_device, service_live::ServiceLive, session1078::jt1078_parse::{Jt1078Package, MediaFrame}};
+
+//双向对讲/监听/中心广播 0x9101数据类型2/3/4
+//浏览器与本服务之间为8kHz 16bit单声道PCM(小端) 下发时转为终端声明的音频编码
+//上行格式在握手时协商 没有Opus解码库 请求opus时告知改用pcm 浏览器用AudioWorklet采集PCM后发送
+
+//每个下发包的采样数(40ms)
+const FRAME_SAMPLES: usize = 320;
+//能解码的上行格式 第一个为默认
+const FORMATS: [&str; 1] = ["pcm"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntercomMode {
+    //双向对讲
+    Talk,
+    //监听
+    Listen,
+    //中心广播
+    Broadcast,
+}
+
+impl IntercomMode {
+    pub fn parse(mode:&str) -> Option<Self> {
+        match mode {
+            "talk" => Some(IntercomMode::Talk),
+            "listen" => Some(IntercomMode::Listen),
+            "broadcast" => Some(IntercomMode::Broadcast),
+            _ => None,
+        }
+    }
+
+    //0x9101数据类型
+    fn data_type(&self) -> u8 {
+        match self {
+            IntercomMode::Talk => 2,
+            IntercomMode::Listen => 3,
+            IntercomMode::Broadcast => 4,
+        }
+    }
+
+    fn can_send(&self) -> bool {
+        *self != IntercomMode::Listen
+    }
+}
+
+pub struct ServiceIntercom {
+    live: Arc<ServiceLive>,
+    //正在下发音频的(sim,通道) 同一通道只允许一路
+    talking: std::sync::Mutex<HashSet<(String, u8)>>,
+}
+
+impl ServiceIntercom {
+    pub fn new(live:Arc<ServiceLive>) -> Self {
+        ServiceIntercom {
+            live,
+            talking: std::sync::Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// 下发0x9101打开对讲 codec为空时使用终端0x1003声明的音频编码
+    /// 设备不在线/通道占用/编码不支持/终端拒绝时返回Err
+    pub async fn open(service:&Arc<ServiceIntercom>, sim:&str, channel:u8, mode:IntercomMode, codec:Option<u8>) -> Result<IntercomSession, &'static str> {
+        let sender = service_device::get_sender(sim).await.ok_or("offline")?;
+
+        let pt = match codec {
+            Some(pt) => pt,
+            None => service_avinfo::query(sim, false).await.map_or(PT_G711A, |t| t.audio_codec),
+        };
+        let transcoder = AudioTranscoder::new(pt).ok_or("codec not supported")?;
+
+        let key = (sim.to_string(), channel);
+        if mode.can_send() && !service.talking.lock().unwrap().insert(key.clone()) {
+            return Err("busy");
+        }
+        //先订阅 避免错过终端首帧
+        let receiver = service.live.media().subscribe(sim, channel);
+
+        let mut jt9101 = service.live.new_9101(channel, 0);
+        jt9101.data_type = mode.data_type();
+        let ret = sender.send_cmd(0x9101, &mut jt9101).await;
+        log::info!("[service-intercom]open sim:{} channel:{} mode:{:?} pt:{} ret:{}", sim, channel, mode, pt, ret);
+        if ret != 0 {
+            if mode.can_send() {
+                service.talking.lock().unwrap().remove(&key);
+            }
+            return Err("refused");
+        }
+        Ok(IntercomSession {
+            service: service.clone(),
+            sim: sim.to_string(),
+            channel,
+            mode,
+            transcoder,
+            sn: 0,
+            time_stamp: 0,
+            receiver,
+        })
+    }
+}
+
+/// 对讲会话 释放时下发0x9102关闭
+pub struct IntercomSession {
+    service: Arc<ServiceIntercom>,
+    sim: String,
+    channel: u8,
+    mode: IntercomMode,
+    transcoder: AudioTranscoder,
+    sn: u16,
+    //下发音频的时间戳(毫秒) 按采样数累计
+    time_stamp: u64,
+    pub receiver: broadcast::Receiver<Arc<MediaFrame>>,
+}
+
+impl IntercomSession {
+    pub fn pt(&self) -> u8 {
+        self.transcoder.pt
+    }
+
+    /// 编码后按1078音频帧下发 监听模式或终端还未建立媒体连接时返回false
+    pub async fn send_pcm(&mut self, pcm:&[i16]) -> bool {
+        if !self.mode.can_send() {
+            return false;
+        }
+        for chunk in pcm.chunks(FRAME_SAMPLES) {
+            let package = Jt1078Package {
+                v19: false,
+                m: true,
+                pt: self.transcoder.pt,
+                sn: self.sn,
+                sim: self.sim.clone(),
+                channel: self.channel,
+                data_type: 3,
+                sub_flag: 0,
+                time_stamp: self.time_stamp,
+                last_i_interval: 0,
+                last_interval: 0,
+                body: Bytes::from(self.transcoder.encode(chunk)),
+            };
+            self.sn = self.sn.wrapping_add(1);
+            self.time_stamp += (chunk.len() / 8) as u64;
+            if !self.service.live.media().send(package).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// 浏览器上行的音频 按协商的pcm下发 收到Opus(Ogg/WebM封装)时返回Err
+    pub async fn send_audio(&mut self, data:&[u8]) -> Result<bool, &'static str> {
+        if is_opus(data) {
+            return Err("opus not supported");
+        }
+        let pcm: Vec<i16> = data.chunks_exact(2).map(|t| i16::from_le_bytes([t[0], t[1]])).collect();
+        Ok(self.send_pcm(&pcm).await)
+    }
+
+    /// 终端上行的音频帧转为PCM 非音频帧返回None
+    pub fn decode(&mut self, frame:&MediaFrame) -> Option<Vec<i16>> {
+        if !frame.is_audio() {
+            return None;
+        }
+        Some(self.transcoder.decode(frame.pt, &frame.data))
+    }
+}
+
+impl Drop for IntercomSession {
+    fn drop(&mut self) {
+        let key = (self.sim.clone(), self.channel);
+        if self.mode.can_send() {
+            self.service.talking.lock().unwrap().remove(&key);
+        }
+
+        //对讲用4关闭 监听/广播只关闭音频 通道上还有视频时不影响
+        let mut jt9102 = match self.mode {
+            IntercomMode::Talk => Jt0x9102 { channel: self.channel, cmd: 4, close_type: 0, switch_type: 0 },
+            _ => Jt0x9102 { channel: self.channel, cmd: 0, close_type: if self.service.live.is_open(&key.0, key.1) { 1 } else { 0 }, switch_type: 0 },
+        };
+        let mode = self.mode;
+        let media = self.service.live.media().clone();
+        tokio::spawn(async move {
+            media.unlink(&key.0, key.1);
+            if let Some(sender) = service_device::get_sender(&key.0).await {
+                let ret = sender.send_cmd(0x9102, &mut jt9102).await;
+                log::info!("[service-intercom]close sim:{} channel:{} mode:{:?} ret:{}", key.0, key.1, mode, ret);
+            }
+        });
+    }
+}
+
+/// 按客户端给出的顺序(逗号分隔)选第一个能解码的上行格式 都不支持时使用pcm
+pub fn negotiate_format(formats:&str) -> &'static str {
+    formats.split(',')
+    .find_map(|t| FORMATS.iter().find(|f| f.eq_ignore_ascii_case(t.trim())))
+    .unwrap_or(&FORMATS[0])
+}
+
+//MediaRecorder输出的Opus 以Ogg页或WebM(EBML)头开始
+fn is_opus(data:&[u8]) -> bool {
+    data.starts_with(b"OggS") || data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3])
+}
+
+/// 编码名称或负载类型数字转为负载类型
+pub fn parse_codec(codec:&str) -> Option<u8> {
+    match codec.to_ascii_lowercase().as_str() {
+        "g711a" | "pcma" => Some(PT_G711A),
+        "g711u" | "pcmu" => Some(PT_G711U),
+        "g726" => Some(PT_G726),
+        "adpcm" | "adpcma" => Some(PT_ADPCMA),
+        t => t.parse().ok(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use bytes::{Bytes, BytesMut};
+    use jt808::{JtPackage, models::Jt0x0001};
+    use jt_util::bytes_bcd::BytesBCD;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc};
+    use crate::{service_device, service_forward::ServiceForward, service_passthrough::ServicePassthrough, session808::jt808_parse::Jt808Deserialize};
+
+    //模拟终端 上线后记录平台下发的(消息ID, 消息体) 并以result通用应答
+    async fn device(sim:&str, result:u8) -> mpsc::UnboundedReceiver<(u16, Bytes)> {
+        service_device::test_init();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let fw_service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+        let pt_service = Arc::new(ServicePassthrough::new(Vec::new(), String::new()));
+        service_device::start(&addr, fw_service, pt_service).await.unwrap();
+
+        let mut sim_bcd = BytesBCD::new();
+        sim_bcd.set_bytes(Bytes::from(hex::decode(sim).unwrap()));
+        let package = JtPackage::new(sim_bcd, false, 0, 1023);
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        //jt808库空消息体的长度写错 用通用应答上线
+        stream.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: 0, answer_id: 0x0002, result: 0 })).await.unwrap();
+        while service_device::get_sender(sim).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut parse = Jt808Deserialize::new();
+            let mut buffer = BytesMut::new();
+            while let Ok(n) = stream.read_buf(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                while let Ok(Some(jt)) = parse.deserialize(&mut buffer) {
+                    //平台通用应答不需要回复
+                    if jt.id == 0x8001 {
+                        continue;
+                    }
+                    let mut answer = Jt0x0001 { answer_sn: jt.sn, answer_id: jt.id, result };
+                    if stream.write_all(&package.serialize(0x0001, 0, &mut answer)).await.is_err() || tx.send((jt.id, jt.get_body())).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_intercom_commands()
+    {
+        use crate::service_media::ServiceMedia;
+
+        let live = Arc::new(ServiceLive::new(Arc::new(ServiceMedia::new()), "127.0.0.1:1078", std::time::Duration::from_secs(1)));
+        let service = Arc::new(ServiceIntercom::new(live));
+        let mut device = device("013800000392", 0).await;
+
+        //0x9101 数据类型在通道号之后 最后一字节为码流类型
+        for (channel, mode, data_type, cmd) in [(1, IntercomMode::Talk, 2, 4), (2, IntercomMode::Listen, 3, 0), (3, IntercomMode::Broadcast, 4, 0)] {
+            let session = ServiceIntercom::open(&service, "013800000392", channel, mode, Some(PT_G711A)).await.unwrap();
+            let (id, body) = device.recv().await.unwrap();
+            assert_eq!((id, body[body.len() - 3], body[body.len() - 2]), (0x9101, channel, data_type));
+
+            if mode == IntercomMode::Talk {
+                assert_eq!(ServiceIntercom::open(&service, "013800000392", channel, mode, Some(PT_G711A)).await.err(), Some("busy"));
+            }
+
+            drop(session);
+            let (id, body) = device.recv().await.unwrap();
+            assert_eq!((id, body[0], body[1], body[2]), (0x9102, channel, cmd, 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_intercom_opus()
+    {
+        use crate::{service_media::ServiceMedia, session1078::jt1078_parse::Jt1078Deserialize};
+
+        assert_eq!(negotiate_format("opus, PCM"), "pcm");
+        assert_eq!(negotiate_format("opus"), "pcm");
+
+        let sim = "013800000393";
+        let media = Arc::new(ServiceMedia::new());
+        let media_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        ServiceMedia::start(media.clone(), &media_addr).await.unwrap();
+        let live = Arc::new(ServiceLive::new(media.clone(), &media_addr, Duration::from_secs(1)));
+        let service = Arc::new(ServiceIntercom::new(live));
+        let _device = device(sim, 0).await;
+        let mut session = ServiceIntercom::open(&service, sim, 1, IntercomMode::Talk, Some(PT_G711A)).await.unwrap();
+
+        //终端建立媒体连接
+        let mut terminal = TcpStream::connect(&media_addr).await.unwrap();
+        let up = Jt1078Package {
+            v19: false,
+            m: true,
+            pt: PT_G711A,
+            sn: 0,
+            sim: sim.to_string(),
+            channel: 1,
+            data_type: 3,
+            sub_flag: 0,
+            time_stamp: 0,
+            last_i_interval: 0,
+            last_interval: 0,
+            body: Bytes::from_static(&[0xd5; 160]),
+        };
+        //两包 服务端按下一个包头判断版本
+        terminal.write_all(&[up.serialize(), up.serialize()].concat()).await.unwrap();
+        while !media.is_linked(sim, 1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        //Ogg/WebM封装的Opus不当作PCM下发
+        let mut ogg = b"OggS".to_vec();
+        ogg.resize(64, 0);
+        assert_eq!(session.send_audio(&ogg).await, Err("opus not supported"));
+        let mut webm = vec![0x1a, 0x45, 0xdf, 0xa3];
+        webm.resize(64, 0);
+        assert_eq!(session.send_audio(&webm).await, Err("opus not supported"));
+
+        //两包 解析时按下一个包头判断版本
+        let pcm: Vec<u8> = [0i16; FRAME_SAMPLES * 2].iter().flat_map(|t| t.to_le_bytes()).collect();
+        assert_eq!(session.send_audio(&pcm).await, Ok(true));
+        let mut parse = Jt1078Deserialize::new();
+        let mut buffer = BytesMut::new();
+        let down = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(Some(package)) = parse.deserialize(&mut buffer, false) {
+                    return package;
+                }
+                assert!(terminal.read_buf(&mut buffer).await.unwrap() > 0);
+            }
+        }).await.unwrap();
+        //收到的第一包就是PCM编码后的G.711A
+        assert_eq!((down.pt, down.body.len()), (PT_G711A, FRAME_SAMPLES));
+    }
+}
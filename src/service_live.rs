@@ -0,0 +1,419 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use jt1078::extend808::{Jt0x9101, Jt0x9102};
+use jt_util::bytes_gbk::BytesGBK;
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+
+use crate::{service_device, service_media::ServiceMedia, session1078::jt1078_parse::MediaFrame};
+
+//实时音视频流管理 按(SIM,通道,码流类型)计数
+//第一个观看者下发0x9101 之后的观看者等待其结果 最后一个离开后延迟关闭 期间有新观看者则继续使用
+//媒体数据只按(SIM,通道)区分 同一通道同时只能使用一种码流类型
+
+/// (SIM, 逻辑通道, 码流类型 0:主码流 1:子码流)
+pub type StreamKey = (String, u8, u8);
+
+struct StreamState {
+    viewers: u32,
+    //http控制接口打开的占用 0x9102关闭时释放
+    api_hold: bool,
+    //开始时间 unix秒
+    since: u64,
+    //延迟关闭任务代数 重新打开后作废
+    generation: u64,
+    //首次0x9101的结果 下发中为None
+    opened: watch::Receiver<Option<bool>>,
+}
+
+//登记结果 第一个使用者负责下发0x9101并通知结果 其余等待
+enum Acquired {
+    First(watch::Sender<Option<bool>>),
+    Joined(watch::Receiver<Option<bool>>),
+}
+
+#[derive(Serialize)]
+pub struct StreamInfo {
+    pub sim: String,
+    pub channel: u8,
+    pub stream_type: u8,
+    pub viewers: u32,
+    pub api_hold: bool,
+    pub since: u64,
+    //等待延迟关闭
+    pub closing: bool,
+}
+
+pub struct ServiceLive {
+    media: Arc<ServiceMedia>,
+    //下发给终端的音视频服务器地址
+    media_ip: String,
+    media_port: u16,
+    //最后一个观看者离开后等待多久下发0x9102
+    grace: Duration,
+    streams: std::sync::Mutex<HashMap<StreamKey, StreamState>>,
+}
+
+impl ServiceLive {
+    pub fn new(media:Arc<ServiceMedia>, address_public:&str, grace:Duration) -> Self {
+        let (media_ip, media_port) = match address_public.rsplit_once(':') {
+            Some((ip, port)) => (ip.to_string(), port.parse().unwrap_or(0)),
+            None => (address_public.to_string(), 0),
+        };
+
+        ServiceLive {
+            media,
+            media_ip,
+            media_port,
+            grace,
+            streams: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 开始观看 设备不在线/通道正在使用其它码流/终端拒绝0x9101时返回Err 不登记观看者
+    pub async fn open(service:&Arc<ServiceLive>, sim:&str, channel:u8, stream_type:u8) -> Result<LiveViewer, &'static str> {
+        service_device::get_sender(sim).await.ok_or("offline")?;
+
+        //先订阅 避免错过首帧
+        let receiver = service.media.subscribe(sim, channel);
+        let key = (sim.to_string(), channel, stream_type);
+        let acquired = service.acquire(&key, false)?;
+        let mut viewer = LiveViewer { service: service.clone(), key: Some(key.clone()), receiver };
+
+        if !service.wait_open(sim, &key, acquired, service.new_9101(channel, stream_type)).await {
+            viewer.key = None;
+            return Err("refused");
+        }
+        Ok(viewer)
+    }
+
+    /// 只订阅媒体 不下发0x9101 用于回放等已由其它指令打开的码流
+    pub fn watch(service:&Arc<ServiceLive>, sim:&str, channel:u8) -> LiveViewer {
+        let receiver = service.media.subscribe(sim, channel);
+        LiveViewer { service: service.clone(), key: None, receiver }
+    }
+
+    /// http控制接口下发的0x9101 同一码流已打开时不再下发 下发失败时移除登记
+    pub async fn hold(service:&Arc<ServiceLive>, sim:&str, jt9101:Jt0x9101) -> bool {
+        if service_device::get_sender(sim).await.is_none() {
+            return false;
+        }
+        let key = (sim.to_string(), jt9101.channel, jt9101.stream_type);
+        match service.acquire(&key, true) {
+            Ok(acquired) => {
+                if let Acquired::Joined(_) = acquired {
+                    log::info!("[service-live]hold sim:{} channel:{} stream:{} already open", sim, key.1, key.2);
+                }
+                service.wait_open(sim, &key, acquired, jt9101).await
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// http控制接口下发的0x9102关闭 释放该通道的接口占用
+    pub fn unhold(service:&Arc<ServiceLive>, sim:&str, channel:u8) -> bool {
+        let keys: Vec<StreamKey> = {
+            let mut streams = service.streams.lock().unwrap();
+            streams.iter_mut()
+            .filter(|(key, state)| key.0 == sim && key.1 == channel && state.api_hold)
+            .map(|(key, state)| {
+                state.api_hold = false;
+                key.clone()
+            })
+            .collect()
+        };
+        for key in &keys {
+            ServiceLive::release(service, key, false);
+        }
+        !keys.is_empty()
+    }
+
+    /// 下发给终端的音视频服务器地址
+    pub fn media_address(&self) -> (&str, u16) {
+        (&self.media_ip, self.media_port)
+    }
+
+    pub fn media(&self) -> &Arc<ServiceMedia> {
+        &self.media
+    }
+
+    /// 通道是否有正在使用的码流
+    pub fn is_open(&self, sim:&str, channel:u8) -> bool {
+        self.streams.lock().unwrap().keys().any(|t| t.0 == sim && t.1 == channel)
+    }
+
+    /// 当前所有码流
+    pub fn streams(&self) -> Vec<StreamInfo> {
+        let streams = self.streams.lock().unwrap();
+        let mut list: Vec<StreamInfo> = streams.iter().map(|(key, state)| StreamInfo {
+            sim: key.0.clone(),
+            channel: key.1,
+            stream_type: key.2,
+            viewers: state.viewers,
+            api_hold: state.api_hold,
+            since: state.since,
+            closing: state.viewers == 0 && !state.api_hold,
+        }).collect();
+        list.sort_by(|a, b| (&a.sim, a.channel, a.stream_type).cmp(&(&b.sim, b.channel, b.stream_type)));
+        list
+    }
+
+    //增加计数 同一通道的其它码流正在使用时返回Err
+    fn acquire(&self, key:&StreamKey, api:bool) -> Result<Acquired, &'static str> {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(state) = streams.get_mut(key) {
+            if api {
+                state.api_hold = true;
+            } else {
+                state.viewers += 1;
+            }
+            //取消延迟关闭
+            state.generation += 1;
+            return Ok(Acquired::Joined(state.opened.clone()));
+        }
+
+        let other = streams.iter()
+            .find(|(t, _)| t.0 == key.0 && t.1 == key.1)
+            .map(|(t, state)| (t.clone(), state.viewers > 0 || state.api_hold));
+        if let Some((other, in_use)) = other {
+            if in_use {
+                log::info!("[service-live]sim:{} channel:{} stream:{} busy with stream:{}", key.0, key.1, key.2, other.2);
+                return Err("busy");
+            }
+            //等待延迟关闭的码流直接由新的0x9101替换
+            streams.remove(&other);
+        }
+        let (opened_tx, opened) = watch::channel(None);
+        streams.insert(key.clone(), StreamState {
+            viewers: if api { 0 } else { 1 },
+            api_hold: api,
+            since: unix_now(),
+            generation: 0,
+            opened,
+        });
+        Ok(Acquired::First(opened_tx))
+    }
+
+    //第一个使用者下发0x9101 其余等待同一结果 失败时移除登记
+    async fn wait_open(&self, sim:&str, key:&StreamKey, acquired:Acquired, jt9101:Jt0x9101) -> bool {
+        let mut opened = match acquired {
+            Acquired::First(opened_tx) => {
+                let ok = ServiceLive::send_open(sim, jt9101).await;
+                opened_tx.send_replace(Some(ok));
+                opened_tx.subscribe()
+            },
+            Acquired::Joined(opened) => opened,
+        };
+        //第一个使用者中途取消时按失败处理
+        let ok = matches!(opened.wait_for(|t| t.is_some()).await.as_deref(), Ok(Some(true)));
+        if !ok {
+            self.rollback(key, &opened);
+        }
+        ok
+    }
+
+    //0x9101下发失败 移除登记 已被重新打开的不动
+    fn rollback(&self, key:&StreamKey, opened:&watch::Receiver<Option<bool>>) {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.get(key).is_some_and(|state| state.opened.same_channel(opened)) {
+            streams.remove(key);
+        }
+    }
+
+    /// 推送到本服务的0x9101 数据类型为音视频
+    pub fn new_9101(&self, channel:u8, stream_type:u8) -> Jt0x9101 {
+        Jt0x9101 {
+            ipaddress: BytesGBK::new_with_bytes(bytes::Bytes::from(self.media_ip.clone())),
+            tcp_port: self.media_port,
+            udp_port: self.media_port,
+            channel,
+            data_type: 0,
+            stream_type,
+        }
+    }
+
+    async fn send_open(sim:&str, mut jt9101:Jt0x9101) -> bool {
+        match service_device::get_sender(sim).await {
+            Some(sender) => {
+                let ret = sender.send_cmd(0x9101, &mut jt9101).await;
+                log::info!("[service-live]open sim:{} channel:{} stream:{} ret:{}", sim, jt9101.channel, jt9101.stream_type, ret);
+                ret == 0
+            },
+            None => false,
+        }
+    }
+
+    //减少计数 没有使用者时延迟关闭
+    fn release(service:&Arc<ServiceLive>, key:&StreamKey, viewer:bool) {
+        let generation = {
+            let mut streams = service.streams.lock().unwrap();
+            let state = match streams.get_mut(key) {
+                Some(state) => state,
+                None => return,
+            };
+            if viewer {
+                state.viewers = state.viewers.saturating_sub(1);
+            }
+            if state.viewers > 0 || state.api_hold {
+                return;
+            }
+            state.generation += 1;
+            state.generation
+        };
+
+        let service = service.clone();
+        let key = key.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(service.grace).await;
+            {
+                let mut streams = service.streams.lock().unwrap();
+                match streams.get(&key) {
+                    Some(state) if state.generation == generation => {},
+                    _ => return,
+                }
+                streams.remove(&key);
+            }
+
+            let (sim, channel, stream_type) = key;
+            if let Some(sender) = service_device::get_sender(&sim).await {
+                let mut jt9102 = Jt0x9102 { channel, cmd: 0, close_type: 0, switch_type: 0 };
+                let ret = sender.send_cmd(0x9102, &mut jt9102).await;
+                log::info!("[service-live]close sim:{} channel:{} stream:{} ret:{}", sim, channel, stream_type, ret);
+            }
+        });
+    }
+}
+
+//观看者 释放时减少计数
+pub struct LiveViewer {
+    service: Arc<ServiceLive>,
+    key: Option<StreamKey>,
+    pub receiver: broadcast::Receiver<Arc<MediaFrame>>,
+}
+
+impl Drop for LiveViewer {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key {
+            ServiceLive::release(&self.service, key, true);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use bytes::{Bytes, BytesMut};
+    use jt808::{JtPackage, models::Jt0x0001};
+    use jt_util::bytes_bcd::BytesBCD;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc};
+    use crate::{service_device, service_forward::ServiceForward, service_passthrough::ServicePassthrough, session808::jt808_parse::Jt808Deserialize};
+
+    //模拟终端 上线后记录平台下发的(消息ID, 消息体) 并以result通用应答
+    async fn device(sim:&str, result:u8) -> mpsc::UnboundedReceiver<(u16, Bytes)> {
+        service_device::test_init();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let fw_service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+        let pt_service = Arc::new(ServicePassthrough::new(Vec::new(), String::new()));
+        service_device::start(&addr, fw_service, pt_service).await.unwrap();
+
+        let mut sim_bcd = BytesBCD::new();
+        sim_bcd.set_bytes(Bytes::from(hex::decode(sim).unwrap()));
+        let package = JtPackage::new(sim_bcd, false, 0, 1023);
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        //jt808库空消息体的长度写错 用通用应答上线
+        stream.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: 0, answer_id: 0x0002, result: 0 })).await.unwrap();
+        while service_device::get_sender(sim).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut parse = Jt808Deserialize::new();
+            let mut buffer = BytesMut::new();
+            while let Ok(n) = stream.read_buf(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                while let Ok(Some(jt)) = parse.deserialize(&mut buffer) {
+                    //平台通用应答不需要回复
+                    if jt.id == 0x8001 {
+                        continue;
+                    }
+                    let mut answer = Jt0x0001 { answer_sn: jt.sn, answer_id: jt.id, result };
+                    if stream.write_all(&package.serialize(0x0001, 0, &mut answer)).await.is_err() || tx.send((jt.id, jt.get_body())).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_open_refused()
+    {
+        let live = Arc::new(ServiceLive::new(Arc::new(ServiceMedia::new()), "127.0.0.1:1078", Duration::from_secs(1)));
+
+        let mut refused = device("013800000321", 1).await;
+        assert_eq!(ServiceLive::open(&live, "013800000321", 1, 0).await.err(), Some("refused"));
+        assert_eq!(refused.recv().await.unwrap().0, 0x9101);
+        assert!(live.streams().is_empty());
+
+        //同时打开时只下发一次0x9101 终端拒绝后都失败
+        let (first, second) = tokio::join!(ServiceLive::open(&live, "013800000321", 1, 0), ServiceLive::open(&live, "013800000321", 1, 0));
+        assert_eq!(first.err(), Some("refused"));
+        assert_eq!(second.err(), Some("refused"));
+        assert_eq!(refused.recv().await.unwrap().0, 0x9101);
+        assert!(refused.try_recv().is_err());
+        assert!(live.streams().is_empty());
+
+        let mut accepted = device("013800000322", 0).await;
+        let viewer = ServiceLive::open(&live, "013800000322", 1, 0).await.unwrap();
+        assert_eq!(accepted.recv().await.unwrap().0, 0x9101);
+        assert_eq!(live.streams()[0].viewers, 1);
+        drop(viewer);
+
+        let (first, second) = tokio::join!(ServiceLive::open(&live, "013800000322", 2, 0), ServiceLive::open(&live, "013800000322", 2, 0));
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(accepted.recv().await.unwrap().0, 0x9101);
+        assert!(accepted.try_recv().is_err());
+        assert!(live.streams().iter().any(|t| t.channel == 2 && t.viewers == 2));
+        drop((first, second));
+
+        assert_eq!(ServiceLive::open(&live, "013800000323", 1, 0).await.err(), Some("offline"));
+    }
+
+
+    #[tokio::test]
+    async fn test_stream_rollback()
+    {
+        let live = Arc::new(ServiceLive::new(Arc::new(ServiceMedia::new()), "127.0.0.1:1078", Duration::from_secs(1)));
+
+        //接口打开失败时移除登记
+        let _refused = device("013800000341", 1).await;
+        assert!(!ServiceLive::hold(&live, "013800000341", live.new_9101(1, 0)).await);
+        assert!(live.streams().is_empty());
+
+        //同一通道不能同时使用主码流和子码流
+        let _accepted = device("013800000342", 0).await;
+        let main = ServiceLive::open(&live, "013800000342", 1, 0).await.unwrap();
+        assert_eq!(ServiceLive::open(&live, "013800000342", 1, 1).await.err(), Some("busy"));
+        assert!(!ServiceLive::hold(&live, "013800000342", live.new_9101(1, 1)).await);
+        let other = ServiceLive::open(&live, "013800000342", 2, 1).await.unwrap();
+        assert_eq!(live.streams().len(), 2);
+
+        //等待延迟关闭的码流可以被替换
+        drop(main);
+        let sub = ServiceLive::open(&live, "013800000342", 1, 1).await.unwrap();
+        let streams = live.streams();
+        assert_eq!(streams.len(), 2);
+        assert!(streams.iter().all(|t| t.stream_type == 1 && t.viewers == 1));
+        drop((sub, other));
+    }
+}
@@ -0,0 +1,297 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+
+use bytes::Bytes;
+use tokio::{io::{self, AsyncReadExt, AsyncWriteExt}, net::{TcpListener, UdpSocket}, sync::{broadcast, mpsc}, time::timeout};
+
+use crate::session1078::jt1078_parse::{Jt1078Deserialize, Jt1078FrameMerger, Jt1078Package, MediaFrame};
+
+//1078实时音视频接收 TCP/UDP同一端口
+//按(sim,通道)合并分包后广播 没有订阅时直接丢弃
+//记录每个(sim,通道)第一个上行的连接 用于对讲/广播下发音频 该连接断开/空闲/对讲结束后才允许其它连接登记
+
+pub type MediaKey = (String, u8);
+
+//UDP对端超过该时间没有数据则清理
+const UDP_IDLE: Duration = Duration::from_secs(60);
+//TCP下发队列长度 满时丢弃
+const TCP_QUEUE: usize = 64;
+
+//终端媒体连接
+#[derive(Clone)]
+enum MediaWriter {
+    Tcp(mpsc::Sender<Bytes>),
+    Udp(Arc<UdpSocket>, SocketAddr),
+}
+
+//UDP对端的解析状态
+struct UdpPeer {
+    conn: u64,
+    parse: Jt1078Deserialize,
+    mergers: HashMap<MediaKey, Jt1078FrameMerger>,
+    last: Instant,
+}
+
+#[derive(Clone)]
+struct MediaLink {
+    //连接编号 断开时只移除自己登记的
+    conn: u64,
+    v19: bool,
+    writer: MediaWriter,
+}
+
+pub struct ServiceMedia {
+    channels: std::sync::Mutex<HashMap<MediaKey, broadcast::Sender<Arc<MediaFrame>>>>,
+    links: std::sync::Mutex<HashMap<MediaKey, MediaLink>>,
+    next_conn: AtomicU64,
+}
+
+impl ServiceMedia {
+    pub fn new() -> Self {
+        ServiceMedia {
+            channels: std::sync::Mutex::new(HashMap::new()),
+            links: std::sync::Mutex::new(HashMap::new()),
+            next_conn: AtomicU64::new(1),
+        }
+    }
+
+    pub async fn start(service:Arc<ServiceMedia>, addr:&String) -> io::Result<()> {
+
+        log::info!("[service-media]listen addr:{}", addr);
+        let listener = TcpListener::bind(addr).await?;
+        let udp = Arc::new(UdpSocket::bind(addr).await?);
+
+        let service_tcp = service.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, peer) = match listener.accept().await {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+                log::info!("[service-media]new connect addr:{:?}", peer);
+
+                let service = service_tcp.clone();
+                tokio::spawn(async move {
+                    let (mut socket, mut write) = socket.into_split();
+                    let (tx, mut rx) = mpsc::channel::<Bytes>(TCP_QUEUE);
+                    tokio::spawn(async move {
+                        while let Some(data) = rx.recv().await {
+                            if write.write_all(&data).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                    let conn = service.next_conn.fetch_add(1, Ordering::Relaxed);
+                    let writer = MediaWriter::Tcp(tx);
+
+                    let mut parse = Jt1078Deserialize::new();
+                    let mut mergers: HashMap<MediaKey, Jt1078FrameMerger> = HashMap::new();
+                    let mut buffer = bytes::BytesMut::with_capacity(8096);
+
+                    loop {
+                        let n = match timeout(std::time::Duration::from_secs(60), socket.read_buf(&mut buffer)).await {
+                            Ok(Ok(n)) => n,
+                            _ => 0,
+                        };
+                        if n == 0 {
+                            break;
+                        }
+                        if service.handle_buffer(&mut parse, &mut mergers, &mut buffer, false, conn, &writer).is_err() {
+                            log::info!("[service-media]disconnect(protocol) addr:{:?}", peer);
+                            break;
+                        }
+                    }
+                    service.links.lock().unwrap().retain(|_, link| link.conn != conn);
+                    log::info!("[service-media]disconnect addr:{:?}", peer);
+                });
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut peers: HashMap<SocketAddr, UdpPeer> = HashMap::new();
+            let mut buf = vec![0u8; 65536];
+            let mut check = tokio::time::interval(UDP_IDLE);
+            loop {
+                let (n, peer) = tokio::select! {
+                    ret = udp.recv_from(&mut buf) => match ret {
+                        Ok(t) => t,
+                        Err(_) => continue,
+                    },
+                    _ = check.tick() => {
+                        service.evict_udp(&mut peers);
+                        continue;
+                    },
+                };
+                let mut buffer = bytes::BytesMut::from(&buf[..n]);
+                let state = peers.entry(peer).or_insert_with(|| UdpPeer {
+                    conn: service.next_conn.fetch_add(1, Ordering::Relaxed),
+                    parse: Jt1078Deserialize::new(),
+                    mergers: HashMap::new(),
+                    last: Instant::now(),
+                });
+                state.last = Instant::now();
+                let writer = MediaWriter::Udp(udp.clone(), peer);
+                let _ = service.handle_buffer(&mut state.parse, &mut state.mergers, &mut buffer, true, state.conn, &writer);
+            }
+        });
+
+        Ok(())
+    }
+
+    //清理空闲的UDP对端及其登记的连接
+    fn evict_udp(&self, peers:&mut HashMap<SocketAddr, UdpPeer>) {
+        let now = Instant::now();
+        let mut links = self.links.lock().unwrap();
+        peers.retain(|peer, state| {
+            if now.duration_since(state.last) < UDP_IDLE {
+                return true;
+            }
+            log::info!("[service-media]udp idle addr:{:?}", peer);
+            links.retain(|_, link| link.conn != state.conn);
+            false
+        });
+    }
+
+    fn handle_buffer(&self, parse:&mut Jt1078Deserialize, mergers:&mut HashMap<MediaKey, Jt1078FrameMerger>, buffer:&mut bytes::BytesMut, is_end:bool, conn:u64, writer:&MediaWriter) -> Result<(), ()> {
+        loop {
+            match parse.deserialize(buffer, is_end) {
+                Ok(Some(package)) => {
+                    let key = (package.sim.clone(), package.channel);
+                    //已被其它连接登记时不替换 避免伪造的上行抢占下发
+                    self.links.lock().unwrap()
+                        .entry(key.clone())
+                        .or_insert_with(|| MediaLink { conn, v19: package.v19, writer: writer.clone() });
+                    let merger = mergers.entry(key).or_default();
+                    if let Some(frame) = merger.merge(package) {
+                        self.publish(frame);
+                    }
+                },
+                Ok(None) => return Ok(()),
+                Err(_) => return Err(()),
+            }
+        }
+    }
+
+    /// 终端是否已建立(sim,通道)的媒体连接
+    pub fn is_linked(&self, sim:&str, channel:u8) -> bool {
+        self.links.lock().unwrap().contains_key(&(sim.to_string(), channel))
+    }
+
+    /// 移除UDP的下发登记 对讲结束后由下次上行重新登记 TCP连接断开时自动移除
+    pub fn unlink(&self, sim:&str, channel:u8) {
+        let key = (sim.to_string(), channel);
+        let mut links = self.links.lock().unwrap();
+        if links.get(&key).is_some_and(|t| matches!(t.writer, MediaWriter::Udp(..))) {
+            links.remove(&key);
+        }
+    }
+
+    /// 通过终端的媒体连接下发 版本按终端上行的包头 没有连接时返回false 下发队列满时丢弃
+    pub async fn send(&self, mut package:Jt1078Package) -> bool {
+        let link = match self.links.lock().unwrap().get(&(package.sim.clone(), package.channel)) {
+            Some(link) => link.clone(),
+            None => return false,
+        };
+        package.v19 = link.v19;
+        let data = package.serialize();
+        match link.writer {
+            MediaWriter::Tcp(tx) => match tx.try_send(data) {
+                Ok(_) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    log::debug!("[service-media]queue full sim:{} channel:{}", package.sim, package.channel);
+                    true
+                },
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            },
+            MediaWriter::Udp(udp, peer) => udp.send_to(&data, peer).await.is_ok(),
+        }
+    }
+
+    //订阅(sim,通道)的完整帧
+    pub fn subscribe(&self, sim:&str, channel:u8) -> broadcast::Receiver<Arc<MediaFrame>> {
+        self.channels.lock().unwrap()
+            .entry((sim.to_string(), channel))
+            .or_insert_with(|| broadcast::channel(256).0)
+            .subscribe()
+    }
+
+    pub fn publish(&self, frame:MediaFrame) {
+        let mut channels = self.channels.lock().unwrap();
+        let key = (frame.sim.clone(), frame.channel);
+        if let Some(sender) = channels.get(&key) {
+            //没有订阅者 移除
+            if sender.send(Arc::new(frame)).is_err() {
+                channels.remove(&key);
+            }
+        }
+    }
+}
+
+impl Default for ServiceMedia {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[test]
+fn test_evict_udp()
+{
+    let service = ServiceMedia::new();
+    let (tx, _rx) = mpsc::channel(1);
+    let old: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    let new: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+    let mut peers = HashMap::new();
+    for (conn, addr, idle) in [(1, old, UDP_IDLE * 2), (2, new, Duration::ZERO)] {
+        peers.insert(addr, UdpPeer { conn, parse: Jt1078Deserialize::new(), mergers: HashMap::new(), last: Instant::now() - idle });
+        service.links.lock().unwrap().insert(("013800000000".to_string(), conn as u8), MediaLink { conn, v19: false, writer: MediaWriter::Tcp(tx.clone()) });
+    }
+    service.evict_udp(&mut peers);
+    assert!(!peers.contains_key(&old) && peers.contains_key(&new));
+    assert!(!service.is_linked("013800000000", 1));
+    assert!(service.is_linked("013800000000", 2));
+}
+
+#[tokio::test]
+async fn test_link_owner()
+{
+    let service = ServiceMedia::new();
+    let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let terminal = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let spoofer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let package = |body:&'static [u8]| Jt1078Package {
+        v19: false,
+        m: true,
+        pt: 6,
+        sn: 0,
+        sim: "013800000391".to_string(),
+        channel: 1,
+        data_type: 3,
+        sub_flag: 0,
+        time_stamp: 0,
+        last_i_interval: 0,
+        last_interval: 0,
+        body: Bytes::from_static(body),
+    };
+
+    //先上行的对端登记 其它对端不能替换
+    for (conn, peer) in [(1, terminal.local_addr().unwrap()), (2, spoofer)] {
+        let mut buffer = bytes::BytesMut::from(&package(b"up").serialize()[..]);
+        service.handle_buffer(&mut Jt1078Deserialize::new(), &mut HashMap::new(), &mut buffer, true, conn, &MediaWriter::Udp(udp.clone(), peer)).unwrap();
+    }
+    assert!(service.send(package(b"down")).await);
+    let mut buf = [0u8; 256];
+    let n = terminal.recv(&mut buf).await.unwrap();
+    assert!(buf[..n].ends_with(b"down"));
+    assert_eq!(service.links.lock().unwrap().values().next().unwrap().conn, 1);
+
+    service.unlink("013800000391", 1);
+    assert!(!service.is_linked("013800000391", 1));
+
+    //TCP下发队列满时丢弃
+    let (tx, _rx) = mpsc::channel(1);
+    service.links.lock().unwrap().insert(("013800000391".to_string(), 1), MediaLink { conn: 3, v19: false, writer: MediaWriter::Tcp(tx) });
+    assert!(service.send(package(b"1")).await);
+    assert!(service.send(package(b"2")).await);
+    drop(_rx);
+    assert!(!service.send(package(b"3")).await);
+}
@@ -0,0 +1,357 @@
+use std::{collections::VecDeque, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use bytes::Bytes;
+use jt808::{bytes::JtBytes, models::{Jt808, Jt808BodyTrans}};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+use crate::{config_model::MqttConfig, service_device, service_event::{self, EventFilter}, session808::jt808_models::JtLocation, session_passthrough::passthrough_link::RawBody};
+
+//MQTT桥接(feature mqtt)
+//终端上行原始消息发布到{prefix}/{sim}/{消息ID} 如jt808/013800000000/0200 格式json或hex json格式的0x0200带解析后的位置
+//设备事件发布到{prefix}/{sim}/event/{事件类型}
+//订阅{prefix}/+/cmd 下行指令经send_cmd下发 终端通用应答结果发布到{prefix}/{sim}/cmd/reply
+//同时等待应答的指令数有上限 超过时直接回复-3
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+const CLIENT_CAPACITY: usize = 1024;
+const COMMAND_LIMIT: usize = 64;
+
+static GLOBAL_MQTT: std::sync::Mutex<Option<MqttStore>> = std::sync::Mutex::new(None);
+
+struct MqttStore {
+    client: AsyncClient,
+    config: MqttConfig,
+    frame_ids: Vec<u16>,
+}
+
+/// 下行指令 {"id":"8103","body":"hex","request_id":任意}
+#[derive(Debug, Deserialize)]
+struct MqttCommand {
+    id: String,
+    #[serde(default)]
+    body: String,
+    #[serde(default)]
+    request_id: serde_json::Value,
+}
+
+pub fn init(config:&MqttConfig) {
+    let (host, port) = match config.address.rsplit_once(':').and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?))) {
+        Some(address) => address,
+        None => {
+            log::error!("[service-mqtt]invalid address:{}", config.address);
+            return;
+        },
+    };
+    let mut options = MqttOptions::new(config.client_id.clone(), host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if !config.username.is_empty() {
+        options.set_credentials(config.username.clone(), config.password.clone());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, CLIENT_CAPACITY);
+    *GLOBAL_MQTT.lock().unwrap() = Some(MqttStore { client: client.clone(), config: config.clone(), frame_ids: config.get_frame_ids() });
+    log::info!("[service-mqtt]connect address:{} client_id:{}", config.address, config.client_id);
+
+    let qos = qos(config.qos);
+    let cmd_topic = format!("{}/+/cmd", config.topic_prefix);
+    let prefix = config.topic_prefix.clone();
+    let client_sub = client.clone();
+    let commands = Arc::new(Semaphore::new(COMMAND_LIMIT));
+    tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    log::info!("[service-mqtt]connected subscribe:{}", cmd_topic);
+                    let _ = client_sub.subscribe(cmd_topic.clone(), qos).await;
+                },
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if let Some(sim) = cmd_sim(&prefix, &publish.topic) {
+                        match commands.clone().try_acquire_owned() {
+                            Ok(permit) => {
+                                let (client, prefix) = (client_sub.clone(), prefix.clone());
+                                tokio::spawn(async move {
+                                    handle_command(client, prefix, qos, sim, publish.payload).await;
+                                    drop(permit);
+                                });
+                            },
+                            Err(_) => {
+                                log::warn!("[service-mqtt]command busy sim:{}", sim);
+                                let request_id = parse_command(&publish.payload).map_or(serde_json::Value::Null, |t| t.0.request_id);
+                                let reply = serde_json::json!({ "request_id": request_id, "result": -3 });
+                                let _ = client_sub.try_publish(format!("{}/{}/cmd/reply", prefix, sim), qos, false, serde_json::to_vec(&reply).unwrap_or_default());
+                            },
+                        }
+                    }
+                },
+                Ok(_) => {},
+                Err(err) => {
+                    log::warn!("[service-mqtt]connection error:{}", err);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                },
+            }
+        }
+    });
+
+    //设备事件
+    if let Some(mut receiver) = service_event::subscribe() {
+        let filter = EventFilter::new("", &config.events);
+        let prefix = config.topic_prefix.clone();
+        tokio::spawn(async move {
+            while let Some(event) = service_event::recv(&mut receiver, &filter).await {
+                let topic = format!("{}/{}/event/{}", prefix, event.sim, event.kind);
+                let payload = serde_json::to_vec(event.as_ref()).unwrap_or_default();
+                if let Err(err) = client.try_publish(topic, qos, false, payload) {
+                    log::info!("[service-mqtt]publish event failed:{}", err);
+                }
+            }
+        });
+    }
+}
+
+/// 终端上行的原始消息
+pub fn publish_frames(packages:&VecDeque<Jt808>) {
+    let global = GLOBAL_MQTT.lock().unwrap();
+    let store = match global.as_ref() {
+        Some(store) => store,
+        None => return,
+    };
+    for jt808 in packages {
+        if !store.frame_ids.is_empty() && !store.frame_ids.contains(&jt808.id) {
+            continue;
+        }
+        let sim = jt808.sim.to_string();
+        let frame = hex::encode(jt808.get_bytes());
+        let payload = match store.config.format.as_str() {
+            "hex" => frame.into_bytes(),
+            _ => {
+                let mut json = serde_json::json!({
+                    "sim": sim,
+                    "id": format!("{:04x}", jt808.id),
+                    "sn": jt808.sn,
+                    "time": SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs()),
+                    "frame": frame,
+                });
+                if jt808.id == 0x0200 {
+                    let location = JtLocation::fill_new(&mut JtBytes::from(jt808.get_body()), jt808);
+                    if location.valid {
+                        json["location"] = serde_json::to_value(&location).unwrap_or_default();
+                    }
+                }
+                serde_json::to_vec(&json).unwrap_or_default()
+            },
+        };
+        let topic = format!("{}/{}/{:04x}", store.config.topic_prefix, sim, jt808.id);
+        if let Err(err) = store.client.try_publish(topic, qos(store.config.qos), false, payload) {
+            log::info!("[service-mqtt]publish frame failed:{}", err);
+        }
+    }
+}
+
+//{prefix}/{sim}/cmd
+fn cmd_sim(prefix:&str, topic:&str) -> Option<String> {
+    let sim = topic.strip_prefix(prefix)?.strip_prefix('/')?.strip_suffix("/cmd")?;
+    (!sim.is_empty() && !sim.contains('/')).then(|| sim.to_string())
+}
+
+//指令结果 0~3:终端通用应答 -1:超时或不在线 -2:指令格式错误 -3:等待应答的指令过多
+async fn handle_command(client:AsyncClient, prefix:String, qos:QoS, sim:String, payload:Bytes) {
+    let (request_id, id, result) = match parse_command(&payload) {
+        Some((cmd, id, body)) => {
+            let result = match service_device::get_sender(&sim).await {
+                Some(sender) => sender.send_cmd(id, &mut RawBody(body)).await,
+                None => -1,
+            };
+            (cmd.request_id, format!("{:04x}", id), result)
+        },
+        None => (serde_json::Value::Null, String::new(), -2),
+    };
+    log::info!("[service-mqtt]command sim:{} id:{} result:{}", sim, id, result);
+    let reply = serde_json::json!({ "request_id": request_id, "id": id, "result": result });
+    let _ = client.publish(format!("{}/{}/cmd/reply", prefix, sim), qos, false, serde_json::to_vec(&reply).unwrap_or_default()).await;
+}
+
+fn parse_command(payload:&[u8]) -> Option<(MqttCommand, u16, Bytes)> {
+    let cmd: MqttCommand = serde_json::from_slice(payload).ok()?;
+    let id = u16::from_str_radix(cmd.id.trim_start_matches("0x"), 16).ok()?;
+    let body = hex::decode(cmd.body.replace(' ', "")).ok()?;
+    Some((cmd, id, Bytes::from(body)))
+}
+
+fn qos(qos:u8) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use bytes::{Bytes, BytesMut};
+    use jt808::{JtPackage, models::Jt0x0001};
+    use jt_util::bytes_bcd::BytesBCD;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc};
+    use crate::{service_device, service_forward::ServiceForward, service_passthrough::ServicePassthrough, session808::jt808_parse::Jt808Deserialize};
+
+    //模拟终端 上线后记录平台下发的(消息ID, 消息体) 并以result通用应答
+    async fn device(sim:&str, result:u8) -> mpsc::UnboundedReceiver<(u16, Bytes)> {
+        service_device::test_init();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let fw_service = Arc::new(ServiceForward::new(0, Duration::ZERO, HashMap::new()));
+        let pt_service = Arc::new(ServicePassthrough::new(Vec::new(), String::new()));
+        service_device::start(&addr, fw_service, pt_service).await.unwrap();
+
+        let mut sim_bcd = BytesBCD::new();
+        sim_bcd.set_bytes(Bytes::from(hex::decode(sim).unwrap()));
+        let package = JtPackage::new(sim_bcd, false, 0, 1023);
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        //jt808库空消息体的长度写错 用通用应答上线
+        stream.write_all(&package.serialize(0x0001, 0, &mut Jt0x0001 { answer_sn: 0, answer_id: 0x0002, result: 0 })).await.unwrap();
+        while service_device::get_sender(sim).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut parse = Jt808Deserialize::new();
+            let mut buffer = BytesMut::new();
+            while let Ok(n) = stream.read_buf(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                while let Ok(Some(jt)) = parse.deserialize(&mut buffer) {
+                    //平台通用应答不需要回复
+                    if jt.id == 0x8001 {
+                        continue;
+                    }
+                    let mut answer = Jt0x0001 { answer_sn: jt.sn, answer_id: jt.id, result };
+                    if stream.write_all(&package.serialize(0x0001, 0, &mut answer)).await.is_err() || tx.send((jt.id, jt.get_body())).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    #[test]
+    fn test_mqtt_command()
+    {
+        assert_eq!(cmd_sim("jt808", "jt808/013800000000/cmd"), Some("013800000000".to_string()));
+        assert_eq!(cmd_sim("jt808", "jt808/013800000000/0200"), None);
+        assert_eq!(cmd_sim("jt808", "jt808//cmd"), None);
+        let (cmd, id, body) = parse_command(br#"{"id":"8103","body":"01 00000001 04 0000001e","request_id":7}"#).unwrap();
+        assert_eq!((id, body.len(), cmd.request_id), (0x8103, 10, serde_json::json!(7)));
+        assert!(parse_command(br#"{"id":"zz"}"#).is_none());
+    }
+
+
+    #[tokio::test]
+    async fn test_mqtt_bridge()
+    {
+        use bytes::BytesMut;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use crate::session808::jt808_parse::{jt808_escape, Jt808Deserialize};
+
+        //代替broker 应答连接/订阅 订阅后下发一条指令 收到的发布转给测试
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let sim = "013800000901";
+        let (tx, mut published) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            loop {
+                let packet = match rumqttc::mqttbytes::v4::read(&mut buf, 1 << 20) {
+                    Ok(packet) => packet,
+                    Err(rumqttc::mqttbytes::Error::InsufficientBytes(_)) => {
+                        if stream.read_buf(&mut buf).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        continue;
+                    },
+                    Err(err) => panic!("{:?}", err),
+                };
+                let mut out = BytesMut::new();
+                match packet {
+                    Packet::Connect(_) => {
+                        rumqttc::ConnAck::new(rumqttc::ConnectReturnCode::Success, false).write(&mut out).unwrap();
+                    },
+                    Packet::Subscribe(subscribe) => {
+                        assert_eq!(subscribe.filters[0].path, "jt808/+/cmd");
+                        rumqttc::SubAck::new(subscribe.pkid, vec![rumqttc::SubscribeReasonCode::Success(QoS::AtLeastOnce)]).write(&mut out).unwrap();
+                        let cmd = br#"{"id":"8103","body":"01 00000001 04 0000001e","request_id":"r1"}"#.to_vec();
+                        rumqttc::Publish::new(format!("jt808/{}/cmd", sim), QoS::AtMostOnce, cmd).write(&mut out).unwrap();
+                    },
+                    Packet::Publish(publish) => {
+                        if publish.qos != QoS::AtMostOnce {
+                            rumqttc::PubAck::new(publish.pkid).write(&mut out).unwrap();
+                        }
+                        let _ = tx.send((publish.topic, publish.payload));
+                    },
+                    Packet::PingReq => {
+                        rumqttc::PingResp.write(&mut out).unwrap();
+                    },
+                    _ => {},
+                }
+                stream.write_all(&out).await.unwrap();
+            }
+        });
+
+        let mut device = device(sim, 0).await;
+        service_event::test_init();
+        init(&MqttConfig {
+            address,
+            client_id: "gw808-test".into(),
+            username: String::new(),
+            password: String::new(),
+            qos: 1,
+            format: "json".into(),
+            topic_prefix: "jt808".into(),
+            frame_ids: "0200".into(),
+            events: String::new(),
+        });
+
+        //下行指令经终端应答后回复
+        let (id, body) = device.recv().await.unwrap();
+        assert_eq!((id, body.len()), (0x8103, 10));
+        async fn recv(published:&mut tokio::sync::mpsc::UnboundedReceiver<(String, Bytes)>, topic:String) -> serde_json::Value {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let (t, payload) = published.recv().await.unwrap();
+                    if t == topic {
+                        return serde_json::from_slice(&payload).unwrap();
+                    }
+                }
+            }).await.unwrap()
+        }
+        let reply = recv(&mut published, format!("jt808/{}/cmd/reply", sim)).await;
+        assert_eq!((reply["request_id"].as_str(), reply["id"].as_str(), reply["result"].as_i64()), (Some("r1"), Some("8103"), Some(0)));
+
+        //上行0x0200发布解析后的位置 不在frame_ids中的消息不发布
+        let mut body = vec![0x02, 0x00, 0x00, 0x1c, 0x01, 0x38, 0x00, 0x00, 0x09, 0x01, 0x00, 0x05];
+        body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        body.extend_from_slice(&22_543_100u32.to_be_bytes());
+        body.extend_from_slice(&114_057_900u32.to_be_bytes());
+        body.extend_from_slice(&[0, 10, 0x01, 0xf4, 0, 90, 0x24, 0x10, 0x19, 0x08, 0x00, 0x00]);
+        let mut heartbeat = vec![0x00, 0x02, 0x00, 0x00, 0x01, 0x38, 0x00, 0x00, 0x09, 0x01, 0x00, 0x06];
+        let mut packages = VecDeque::new();
+        for content in [&mut heartbeat, &mut body] {
+            let mut buf = BytesMut::from(&jt808_escape(content)[..]);
+            packages.push_back(Jt808Deserialize::new().deserialize(&mut buf).unwrap().unwrap());
+        }
+        publish_frames(&packages);
+        let frame = recv(&mut published, format!("jt808/{}/0200", sim)).await;
+        assert_eq!((frame["sim"].as_str(), frame["sn"].as_u64()), (Some(sim), Some(5)));
+        assert_eq!((frame["location"]["lat"].as_f64(), frame["location"]["speed"].as_f64(), frame["location"]["alarm"].as_u64()), (Some(22.5431), Some(50.0), Some(1)));
+
+        //设备事件
+        service_event::publish(service_event::DeviceEvent::new(sim, "alarm", &1));
+        let event = recv(&mut published, format!("jt808/{}/event/alarm", sim)).await;
+        assert_eq!(event["kind"], "alarm");
+    }
+}
@@ -0,0 +1,108 @@
+use std::{collections::HashMap, fs, sync::Arc};
+
+use jt808::{JtPackage, models::Jt808};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::watch};
+
+use crate::{config_model::PassthroughTargetConfig, session808::jt808_session::Jt808SessionShared, session_passthrough::passthrough_link::PassthroughLink};
+
+//按标准808协议把终端透传到第三方平台 每个sim每个平台一条上级连接
+//网关作为终端向上级注册/鉴权 鉴权码保存在本地文件(仅所有者可读写) 后台写入
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PassthroughCredential {
+    //上级平台下发的鉴权码
+    #[serde(default)]
+    pub auth_code: String,
+    //终端0x0100消息体(16进制)
+    #[serde(default)]
+    pub register: String,
+}
+
+pub struct ServicePassthrough {
+    targets: Vec<PassthroughTargetConfig>,
+    //sim@address -> 鉴权信息
+    credentials: std::sync::Mutex<HashMap<String, PassthroughCredential>>,
+    //最新的文件内容 由后台任务写入
+    saver: watch::Sender<Vec<u8>>,
+}
+
+impl ServicePassthrough {
+    pub fn new(targets:Vec<PassthroughTargetConfig>, credentials_path:String) -> Self {
+        let credentials = match fs::read(&credentials_path) {
+            Ok(bts) => serde_json::from_slice(&bts).unwrap_or_else(|err| {
+                log::warn!("[service-passthrough]credentials file invalid:{}", err);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        let (saver, receiver) = watch::channel(Vec::new());
+        tokio::spawn(run_saver(credentials_path, receiver));
+
+        ServicePassthrough {
+            targets,
+            credentials: std::sync::Mutex::new(credentials),
+            saver,
+        }
+    }
+
+    //设备上线 建立该sim所有透传连接
+    pub fn open_links(service:&Arc<ServicePassthrough>, jt808:&Jt808, device:Arc<Jt808SessionShared>) -> Vec<Arc<PassthroughLink>> {
+        let sim = jt808.sim.to_string();
+        let mut links = Vec::new();
+        for target in &service.targets {
+            if !target.get_sims().contains(&sim) {
+                continue;
+            }
+            let package = JtPackage::new(jt808.sim.clone(), jt808.v19, jt808.ver, 1023);
+            let link = Arc::new(PassthroughLink::new(service.clone(), target.address.clone(), sim.clone(), package, device.clone()));
+            PassthroughLink::start(link.clone());
+            links.push(link);
+        }
+        links
+    }
+
+    pub fn get_credential(&self, key:&str) -> PassthroughCredential {
+        self.credentials.lock().unwrap().get(key).cloned().unwrap_or_default()
+    }
+
+    pub fn set_auth_code(&self, key:&str, auth_code:&str) {
+        self.update_credential(key, |t| t.auth_code = auth_code.to_string());
+    }
+
+    pub fn set_register(&self, key:&str, register:&str) {
+        self.update_credential(key, |t| t.register = register.to_string());
+    }
+
+    fn update_credential(&self, key:&str, f:impl FnOnce(&mut PassthroughCredential)) {
+        let mut credentials = self.credentials.lock().unwrap();
+        f(credentials.entry(key.to_string()).or_default());
+
+        if let Ok(bts) = serde_json::to_vec_pretty(&*credentials) {
+            self.saver.send_replace(bts);
+        }
+    }
+}
+
+//只写最新内容 先写临时文件再替换
+async fn run_saver(path:String, mut receiver:watch::Receiver<Vec<u8>>) {
+    while receiver.changed().await.is_ok() {
+        let bts = receiver.borrow_and_update().clone();
+        if let Err(err) = save_file(&path, &bts).await {
+            log::warn!("[service-passthrough]save credentials failed:{} err:{}", path, err);
+        }
+    }
+}
+
+async fn save_file(path:&str, bts:&[u8]) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    file.write_all(bts).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
@@ -0,0 +1,224 @@
+use std::{sync::Arc, time::Duration};
+
+use jt1078::extend808::{Jt0x1205, Jt0x9201, Jt0x9202, Jt0x9205};
+use jt808::models::{Jt808BodySerialize, Ver808};
+use jt_util::{bytes::IBuffWrite, bytes_gbk::BytesGBK};
+use serde::{Deserialize, Serialize};
+
+use crate::{service_device, service_live::ServiceLive, session1078::extend808::put_time_bcd6};
+
+//历史音视频 0x9205查询资源列表(0x1205应答) 0x9201回放 0x9202回放控制
+
+//资源列表可能分包上传 等待时间长一些
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+const PLAYBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 查询条件 时间为unix秒 0表示不限
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RecordingQuery {
+    pub channel: u8,
+    pub from: i64,
+    pub to: i64,
+    pub alarm: u64,
+    //0:音视频 1:音频 2:视频 3:视频或音视频
+    pub av_type: u8,
+    //0:所有码流 1:主码流 2:子码流
+    pub stream_type: u8,
+    //0:所有存储器 1:主存储器 2:灾备存储器
+    pub storage_type: u8,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Recording {
+    pub channel: u8,
+    pub from: i64,
+    pub to: i64,
+    pub alarm: u64,
+    pub av_type: u8,
+    pub stream_type: u8,
+    pub storage_type: u8,
+    pub file_size: u32,
+}
+
+/// 回放请求 一般取自资源列表中的一项
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PlaybackRequest {
+    pub channel: u8,
+    pub from: i64,
+    pub to: i64,
+    pub av_type: u8,
+    pub stream_type: u8,
+    pub storage_type: u8,
+    //0:正常 1:快进 2:关键帧快退 3:关键帧播放 4:单帧上传
+    pub mode: u8,
+    //快进快退倍数 0:无效 1:1倍 2:2倍 3:4倍 4:8倍 5:16倍
+    pub multiple: u8,
+}
+
+/// 回放控制
+#[derive(Debug, Deserialize)]
+pub struct PlaybackControl {
+    //pause resume stop forward rewind seek keyframe
+    pub action: String,
+    #[serde(default)]
+    pub multiple: u8,
+    //拖动位置 unix秒
+    #[serde(default)]
+    pub position: i64,
+}
+
+pub struct ServicePlayback {
+    live: Arc<ServiceLive>,
+}
+
+impl ServicePlayback {
+    pub fn new(live:Arc<ServiceLive>) -> Self {
+        ServicePlayback { live }
+    }
+
+    /// 查询录像资源列表 设备不在线或无应答时返回Err
+    pub async fn recordings(&self, sim:&str, query:&RecordingQuery) -> Result<Vec<Recording>, i32> {
+        let sender = service_device::get_sender(sim).await.ok_or(-1)?;
+        let jt9205 = Jt0x9205 {
+            channel: query.channel,
+            starttime: query.from,
+            endtime: query.to,
+            alarm: query.alarm,
+            media_type: query.av_type,
+            stream_type: query.stream_type,
+            storage_type: query.storage_type,
+        };
+        let jt1205 = sender.request::<_, Jt0x1205>(0x9205, &mut Jt0x9205Bcd(jt9205), 0x1205, true, QUERY_TIMEOUT).await?;
+        log::info!("[service-playback]recordings sim:{} channel:{} count:{}", sim, query.channel, jt1205.file_list.len());
+
+        Ok(jt1205.file_list.iter().map(|t| Recording {
+            channel: t.channel,
+            from: t.starttime,
+            to: t.endtime,
+            alarm: t.alarm,
+            av_type: t.media_type,
+            stream_type: t.stream_type,
+            storage_type: t.storage_type,
+            file_size: t.file_size,
+        }).collect())
+    }
+
+    /// 下发0x9201 码流推送到本服务的音视频端口
+    pub async fn start(&self, sim:&str, req:&PlaybackRequest) -> bool {
+        let sender = match service_device::get_sender(sim).await {
+            Some(sender) => sender,
+            None => return false,
+        };
+        let (media_ip, media_port) = self.live.media_address();
+        let mut jt9201 = Jt0x9201 {
+            ipaddress: BytesGBK::new_with_bytes(bytes::Bytes::from(media_ip.to_string())),
+            tcp_port: media_port,
+            udp_port: media_port,
+            channel: req.channel,
+            media_type: req.av_type,
+            stream_type: req.stream_type,
+            storage_type: req.storage_type,
+            playback_mode: req.mode,
+            multiple: req.multiple,
+            starttime: req.from,
+            endtime: req.to,
+        };
+        //终端应答0x1205 部分终端应答0x0001
+        let ret = match sender.request::<_, Jt0x1205>(0x9201, &mut jt9201, 0x1205, true, PLAYBACK_TIMEOUT).await {
+            Ok(_) => 0,
+            Err(ret) => ret,
+        };
+        log::info!("[service-playback]start sim:{} channel:{} from:{} to:{} ret:{}", sim, req.channel, req.from, req.to, ret);
+        ret == 0
+    }
+
+    /// 下发0x9202 未知的动作返回false
+    pub async fn control(&self, sim:&str, channel:u8, control:&PlaybackControl) -> bool {
+        let playback_control = match control.action.as_str() {
+            "resume" => 0,
+            "pause" => 1,
+            "stop" => 2,
+            "forward" => 3,
+            "rewind" => 4,
+            "seek" => 5,
+            "keyframe" => 6,
+            _ => return false,
+        };
+        let sender = match service_device::get_sender(sim).await {
+            Some(sender) => sender,
+            None => return false,
+        };
+
+        let jt9202 = Jt0x9202 {
+            channel,
+            playback_control,
+            multiple: if playback_control == 3 || playback_control == 4 { control.multiple } else { 0 },
+            drag_playback_position: if playback_control == 5 { control.position } else { 0 },
+        };
+        let ret = sender.send_cmd(0x9202, &mut Jt0x9202Bcd(jt9202)).await;
+        log::info!("[service-playback]control sim:{} channel:{} action:{} ret:{}", sim, channel, control.action, ret);
+        ret == 0
+    }
+}
+
+//jt1078库的0x9205/0x9202时间用datetime_to_bcd6 每个字段按二进制写入(24年写成0x18) 0x9202的0时间还会写成1970年
+//这里按BCD重写 0写全0
+pub struct Jt0x9205Bcd(pub Jt0x9205);
+
+impl Jt808BodySerialize for Jt0x9205Bcd {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u8(self.0.channel);
+        put_time_bcd6(buf, self.0.starttime);
+        put_time_bcd6(buf, self.0.endtime);
+        buf.put_u64(self.0.alarm);
+        buf.put_u8(self.0.media_type);
+        buf.put_u8(self.0.stream_type);
+        buf.put_u8(self.0.storage_type);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        24
+    }
+}
+
+pub struct Jt0x9202Bcd(pub Jt0x9202);
+
+impl Jt808BodySerialize for Jt0x9202Bcd {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u8(self.0.channel);
+        buf.put_u8(self.0.playback_control);
+        buf.put_u8(self.0.multiple);
+        put_time_bcd6(buf, self.0.drag_playback_position);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        9
+    }
+}
+
+
+#[test]
+fn test_time_bcd()
+{
+    use chrono::{Local, TimeZone};
+
+    let body = |jt:&mut dyn Jt808BodySerialize| {
+        let mut sim = jt_util::bytes_bcd::BytesBCD::new();
+        sim.set_bytes(bytes::Bytes::from_static(&[0x01, 0x38, 0x00, 0x00, 0x03, 0x51]));
+        let package = jt808::JtPackage::new(sim, false, 0, 1023);
+        let buf = package.serialize_box(0x9202, 0, jt);
+        buf[13..buf.len() - 2].to_vec()
+    };
+    let time = Local.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap().timestamp();
+    let jt9202 = |drag_playback_position:i64| Jt0x9202 { channel: 1, playback_control: 5, multiple: 0, drag_playback_position };
+
+    assert_eq!(body(&mut Jt0x9202Bcd(jt9202(time))), [1, 5, 0, 0x24, 0x05, 0x06, 0x07, 0x08, 0x09]);
+    assert_eq!(body(&mut jt9202(time)), [1, 5, 0, 24, 5, 6, 7, 8, 9]);
+    assert_eq!(body(&mut Jt0x9202Bcd(jt9202(0))), [1, 5, 0, 0, 0, 0, 0, 0, 0]);
+    assert_ne!(body(&mut jt9202(0))[3..], [0; 6]);
+
+    let jt9205 = Jt0x9205 { channel: 1, starttime: time, endtime: 0, alarm: 0, media_type: 0, stream_type: 0, storage_type: 0 };
+    assert_eq!(body(&mut Jt0x9205Bcd(jt9205))[1..13], [0x24, 0x05, 0x06, 0x07, 0x08, 0x09, 0, 0, 0, 0, 0, 0]);
+}
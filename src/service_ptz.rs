@@ -0,0 +1,86 @@
+use serde::Deserialize;
+
+use crate::{service_device, session1078::extend808::{Jt0x9301, JtPtzControl}};
+
+//云台控制 0x9301旋转 0x9302焦距 0x9303光圈 0x9304雨刷 0x9305红外补光 0x9306变倍
+
+/// 云台指令参数
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PtzRequest {
+    /// rotate:stop up down left right
+    /// focus/iris/zoom:increase decrease
+    /// wiper/ir:on off
+    pub action: String,
+    /// 旋转速度 0~255
+    pub speed: u8,
+}
+
+/// 指令名及动作转换为消息ID和控制值
+pub fn ptz_command(command:&str, action:&str) -> Option<(u16, u8)> {
+    match command {
+        "rotate" => {
+            let direction = match action {
+                "stop" => 0,
+                "up" => 1,
+                "down" => 2,
+                "left" => 3,
+                "right" => 4,
+                _ => return None,
+            };
+            Some((0x9301, direction))
+        },
+        "focus" | "iris" | "zoom" => {
+            let id = match command {
+                "focus" => 0x9302,
+                "iris" => 0x9303,
+                _ => 0x9306,
+            };
+            match action {
+                "increase" => Some((id, 0)),
+                "decrease" => Some((id, 1)),
+                _ => None,
+            }
+        },
+        "wiper" | "ir" => {
+            let id = if command == "wiper" { 0x9304 } else { 0x9305 };
+            match action {
+                "off" => Some((id, 0)),
+                "on" => Some((id, 1)),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// 下发云台指令 返回终端通用应答结果 -1:超时或不在线 -2:参数错误
+pub async fn control(sim:&str, channel:u8, command:&str, req:&PtzRequest) -> i32 {
+    let (id, control) = match ptz_command(command, &req.action) {
+        Some(t) => t,
+        None => return -2,
+    };
+    let sender = match service_device::get_sender(sim).await {
+        Some(sender) => sender,
+        None => return -1,
+    };
+
+    let ret = if id == 0x9301 {
+        sender.send_cmd(id, &mut Jt0x9301 { channel, direction: control, speed: req.speed }).await
+    } else {
+        sender.send_cmd(id, &mut JtPtzControl { channel, control }).await
+    };
+    log::info!("[service-ptz]sim:{} channel:{} id:0x{:04X} control:{} ret:{}", sim, channel, id, control, ret);
+    ret
+}
+
+
+#[test]
+fn test_ptz_command()
+{
+    assert_eq!(ptz_command("rotate", "left"), Some((0x9301, 3)));
+    assert_eq!(ptz_command("zoom", "decrease"), Some((0x9306, 1)));
+    assert_eq!(ptz_command("ir", "on"), Some((0x9305, 1)));
+    assert_eq!(ptz_command("wiper", "up"), None);
+    assert_eq!(ptz_command("pan", "left"), None);
+}
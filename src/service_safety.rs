@@ -0,0 +1,258 @@
+use std::{collections::{HashMap, VecDeque}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::Serialize;
+
+use crate::{service_device, service_event::{self, DeviceEvent}, session808::{jt808_jsatl12::{safety_alarms, Jt0x1210, Jt0x9208, SafetyAlarm}, jt808_models::JtLocation}};
+
+//主动安全报警(ADAS/DSM/TPMS/BSD) 每条报警分配32位报警编号
+//有附件的报警下发0x9208 附件由service_attachment接收 写入{attachment_path}/{sim}/{报警编号} 同目录保存alarm.json
+
+//每个终端保留的报警记录数
+const MAX_RECORDS: usize = 200;
+
+static GLOBAL_SAFETY: std::sync::Mutex<Option<SafetyStore>> = std::sync::Mutex::new(None);
+
+struct SafetyStore {
+    //0x9208下发给终端的附件服务地址
+    ip: String,
+    port: u16,
+    path: PathBuf,
+    records: HashMap<String, SafetyRecord>,
+    //sim->报警编号 按时间顺序
+    recent: HashMap<String, VecDeque<String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentFile {
+    pub name: String,
+    pub size: u32,
+    //0x1211上报的文件类型
+    pub file_type: Option<u8>,
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SafetyRecord {
+    pub number: String,
+    pub sim: String,
+    pub alarm: SafetyAlarm,
+    //0x9208应答 -1:超时或不在线 没有附件时为None
+    pub request_result: Option<i32>,
+    pub files: Vec<AttachmentFile>,
+    pub created: u64,
+    pub updated: u64,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+pub fn init(address_public:&str, attachment_path:&str) {
+    let (ip, port) = match address_public.rsplit_once(':') {
+        Some((ip, port)) => (ip.to_string(), port.parse().unwrap_or(0)),
+        None => (address_public.to_string(), 0),
+    };
+    *GLOBAL_SAFETY.lock().unwrap() = Some(SafetyStore {
+        ip,
+        port,
+        path: PathBuf::from(attachment_path),
+        records: HashMap::new(),
+        recent: HashMap::new(),
+    });
+}
+
+/// 终端上报0x0200时调用 新报警登记并发布事件 有附件的下发0x9208
+/// 终端补报的相同报警(报警标识号相同)忽略
+pub fn update(sim:&str, location:&JtLocation) {
+    let alarms = safety_alarms(location);
+    if alarms.is_empty() {
+        return;
+    }
+
+    let mut global = GLOBAL_SAFETY.lock().unwrap();
+    let store = match global.as_mut() {
+        Some(store) => store,
+        None => return,
+    };
+    for alarm in alarms {
+        let recent = store.recent.entry(sim.to_string()).or_default();
+        let exists = recent.iter().filter_map(|t| store.records.get(t)).any(|t| {
+            t.alarm.source == alarm.source && t.alarm.alarm_id == alarm.alarm_id && t.alarm.flag == alarm.flag && t.alarm.identifier.raw == alarm.identifier.raw
+        });
+        if exists {
+            continue;
+        }
+
+        let number = new_number();
+        recent.push_back(number.clone());
+        if recent.len() > MAX_RECORDS {
+            if let Some(old) = recent.pop_front() {
+                store.records.remove(&old);
+            }
+        }
+
+        let now = unix_now();
+        let record = SafetyRecord {
+            number: number.clone(),
+            sim: sim.to_string(),
+            request_result: None,
+            files: Vec::new(),
+            created: now,
+            updated: now,
+            dir: store.path.join(sim).join(&number),
+            alarm,
+        };
+        log::info!("[service-safety]alarm sim:{} number:{} source:{} kind:{} attachments:{}", sim, number, record.alarm.source, record.alarm.kind, record.alarm.identifier.attachments);
+        service_event::publish(DeviceEvent::new(sim, "safety_alarm", &record));
+
+        if record.alarm.identifier.attachments > 0 {
+            let jt9208 = Jt0x9208 {
+                ipaddress: store.ip.clone(),
+                tcp_port: store.port,
+                udp_port: 0,
+                identifier: record.alarm.identifier.raw.clone(),
+                alarm_number: number.clone(),
+            };
+            tokio::spawn(request_attachment(record.clone(), jt9208));
+        }
+        store.records.insert(number, record);
+    }
+}
+
+//下发0x9208 保存alarm.json
+async fn request_attachment(record:SafetyRecord, mut jt9208:Jt0x9208) {
+    if let Err(err) = tokio::fs::create_dir_all(&record.dir).await {
+        log::warn!("[service-safety]create dir failed:{}", err);
+    }
+    save(&record).await;
+
+    let result = match service_device::get_sender(&record.sim).await {
+        Some(sender) => sender.send_cmd(0x9208, &mut jt9208).await,
+        None => -1,
+    };
+    log::info!("[service-safety]request 0x9208 sim:{} number:{} result:{}", record.sim, record.number, result);
+    if let Some(record) = modify(&record.number, |t| t.request_result = Some(result)) {
+        save(&record).await;
+    }
+}
+
+/// 附件连接收到0x1210 登记文件列表 返回附件目录 报警编号不存在时返回None
+pub async fn on_files(jt1210:&Jt0x1210) -> Option<PathBuf> {
+    let record = modify(&jt1210.alarm_number, |record| {
+        for (name, size) in &jt1210.files {
+            match record.files.iter_mut().find(|t| &t.name == name) {
+                Some(file) => file.size = *size,
+                None => record.files.push(AttachmentFile { name: name.clone(), size: *size, file_type: None, completed: false }),
+            }
+        }
+    })?;
+    save(&record).await;
+    Some(record.dir)
+}
+
+/// 0x1211文件信息
+pub fn on_file_info(number:&str, name:&str, file_type:u8) {
+    modify(number, |record| {
+        if let Some(file) = record.files.iter_mut().find(|t| t.name == name) {
+            file.file_type = Some(file_type);
+        }
+    });
+}
+
+/// 文件接收完整
+pub async fn on_file_complete(number:&str, name:&str) {
+    let record = match modify(number, |record| {
+        if let Some(file) = record.files.iter_mut().find(|t| t.name == name) {
+            file.completed = true;
+        }
+    }) {
+        Some(record) => record,
+        None => return,
+    };
+    save(&record).await;
+    log::info!("[service-safety]attachment sim:{} number:{} file:{}", record.sim, number, name);
+
+    #[derive(Serialize)]
+    struct AttachmentEvent<'a> {
+        number: &'a str,
+        name: &'a str,
+        kind: &'a str,
+        completed: usize,
+        total: usize,
+    }
+    let event = AttachmentEvent {
+        number,
+        name,
+        kind: record.alarm.kind,
+        completed: record.files.iter().filter(|t| t.completed).count(),
+        total: record.files.len(),
+    };
+    service_event::publish(DeviceEvent::new(&record.sim, "safety_attachment", &event));
+}
+
+/// 终端最近的报警 新的在前
+pub fn list(sim:&str) -> Vec<SafetyRecord> {
+    let global = GLOBAL_SAFETY.lock().unwrap();
+    let store = match global.as_ref() {
+        Some(store) => store,
+        None => return Vec::new(),
+    };
+    store.recent.get(sim).map_or(Vec::new(), |recent| recent.iter().rev().filter_map(|t| store.records.get(t).cloned()).collect())
+}
+
+pub fn get(number:&str) -> Option<SafetyRecord> {
+    GLOBAL_SAFETY.lock().unwrap().as_ref()?.records.get(number).cloned()
+}
+
+/// 已接收完整的附件路径
+pub fn file_path(number:&str, name:&str) -> Option<PathBuf> {
+    let record = get(number)?;
+    record.files.iter().find(|t| t.name == name && t.completed)?;
+    Some(record.dir.join(file_name(name)?))
+}
+
+/// 附件名只取文件名部分 防止写到目录外
+pub fn file_name(name:&str) -> Option<&str> {
+    Path::new(name).file_name()?.to_str()
+}
+
+fn modify<F>(number:&str, f:F) -> Option<SafetyRecord>
+where
+    F: FnOnce(&mut SafetyRecord),
+{
+    let mut global = GLOBAL_SAFETY.lock().unwrap();
+    let record = global.as_mut()?.records.get_mut(number)?;
+    f(record);
+    record.updated = unix_now();
+    Some(record.clone())
+}
+
+async fn save(record:&SafetyRecord) {
+    let data = match serde_json::to_vec_pretty(record) {
+        Ok(data) => data,
+        Err(_) => return,
+    };
+    if let Err(err) = tokio::fs::write(record.dir.join("alarm.json"), data).await {
+        log::warn!("[service-safety]save failed number:{} err:{}", record.number, err);
+    }
+}
+
+//报警编号同时是附件服务的凭证 使用系统随机数
+fn new_number() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("system random unavailable");
+    hex::encode(bytes)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs())
+}
+
+
+#[test]
+fn test_new_number()
+{
+    let a = new_number();
+    let b = new_number();
+    assert_eq!(a.len(), 32);
+    assert!(a.chars().all(|t| t.is_ascii_hexdigit()));
+    assert_ne!(a, b);
+}
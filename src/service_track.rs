@@ -0,0 +1,286 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use chrono::{Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::{File, OpenOptions}, io::AsyncWriteExt, sync::{mpsc, oneshot}};
+
+use crate::session808::jt808_models::JtLocation;
+
+//历史轨迹 0x0200/0x0704的每个点按天追加写入 {path}/{YYYYMMDD}/{sim}.jsonl
+//按终端时间(本地日期)分段 超过保存天数或总大小的整天目录被删除
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+//同时打开的分段文件上限 超过时全部关闭
+const MAX_OPEN_FILES: usize = 1024;
+//单次查询最多跨越的天数
+pub const MAX_QUERY_DAYS: i64 = 31;
+//待写入的点上限 写满时汇报的会话等待
+const WRITE_QUEUE: usize = 4096;
+
+static GLOBAL_TRACK: std::sync::Mutex<Option<TrackStore>> = std::sync::Mutex::new(None);
+
+struct TrackStore {
+    path: PathBuf,
+    sender: mpsc::Sender<TrackWrite>,
+}
+
+enum TrackWrite {
+    Point(String, TrackPoint),
+    //之前的点都已写入后应答
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackPoint {
+    /// 终端时间 unix秒
+    pub time: i64,
+    pub lat: f64,
+    pub lng: f64,
+    pub speed: f64,
+    pub direction: u16,
+    pub altitude: u16,
+    pub alarm: u32,
+    pub state: u32,
+    /// 附加信息0x01里程(km)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mileage: Option<f64>,
+    /// 0x0704盲区补报
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub backfill: bool,
+}
+
+impl TrackPoint {
+    pub fn new(location:&JtLocation, backfill:bool) -> Self {
+        TrackPoint {
+            time: if location.time > 0 { location.time } else { unix_now() },
+            lat: location.lat,
+            lng: location.lng,
+            speed: location.speed,
+            direction: location.direction,
+            altitude: location.altitude,
+            alarm: location.alarm,
+            state: location.state,
+            mileage: location.extra_u32(0x01).map(|t| t as f64 / 10.0),
+            backfill,
+        }
+    }
+}
+
+/// days:保存天数 max_size:总大小上限(字节) 0为不限制
+pub fn init(path:&str, days:u32, max_size:u64) {
+    let path = PathBuf::from(path);
+    let (sender, receiver) = mpsc::channel(WRITE_QUEUE);
+    *GLOBAL_TRACK.lock().unwrap() = Some(TrackStore { path: path.clone(), sender });
+    tokio::spawn(run_writer(path.clone(), receiver));
+    if days > 0 || max_size > 0 {
+        tokio::spawn(async move {
+            loop {
+                let path = path.clone();
+                let _ = tokio::task::spawn_blocking(move || cleanup(&path, days, max_size)).await;
+                tokio::time::sleep(CLEANUP_INTERVAL).await;
+            }
+        });
+    }
+}
+
+fn get_sender() -> Option<mpsc::Sender<TrackWrite>> {
+    GLOBAL_TRACK.lock().unwrap().as_ref().map(|t| t.sender.clone())
+}
+
+/// 记录一个位置点 写入在后台进行 队列满时等待
+pub async fn record(sim:&str, location:&JtLocation, backfill:bool) {
+    if let Some(sender) = get_sender() {
+        let _ = sender.send(TrackWrite::Point(sim.to_string(), TrackPoint::new(location, backfill))).await;
+    }
+}
+
+/// 等待已记录的点写入文件
+pub async fn flush() {
+    if let Some(sender) = get_sender() {
+        let (tx, rx) = oneshot::channel();
+        if sender.send(TrackWrite::Flush(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+/// 查询[from, to]内的点 按时间排序 相同时间只保留一个
+pub async fn query(sim:&str, from:i64, to:i64) -> Vec<TrackPoint> {
+    let path = match GLOBAL_TRACK.lock().unwrap().as_ref() {
+        Some(store) => store.path.clone(),
+        None => return Vec::new(),
+    };
+    flush().await;
+    if sim.is_empty() || !sim.chars().all(|t| t.is_ascii_alphanumeric()) {
+        return Vec::new();
+    }
+    let mut points = Vec::new();
+    let (first, last) = (local_date(from), local_date(to));
+    let mut date = first;
+    while date <= last {
+        let file = path.join(date.format("%Y%m%d").to_string()).join(format!("{}.jsonl", sim));
+        if let Ok(text) = tokio::fs::read_to_string(&file).await {
+            points.extend(text.lines()
+                .filter_map(|line| serde_json::from_str::<TrackPoint>(line).ok())
+                .filter(|t| t.time >= from && t.time <= to));
+        }
+        date = match date.succ_opt() {
+            Some(date) => date,
+            None => break,
+        };
+    }
+    points.sort_by_key(|t| t.time);
+    points.dedup_by_key(|t| t.time);
+    points
+}
+
+/// 抽稀 interval:每interval秒最多保留一个点 limit:最多保留的点数(均匀抽取) 0为不限制
+/// 首尾点总是保留
+pub fn downsample(points:Vec<TrackPoint>, interval:i64, limit:usize) -> Vec<TrackPoint> {
+    let mut points = points;
+    if interval > 0 && points.len() > 2 {
+        let last = points.pop();
+        let mut next = i64::MIN;
+        points.retain(|t| {
+            if t.time < next {
+                return false;
+            }
+            next = t.time + interval;
+            true
+        });
+        points.extend(last);
+    }
+    if limit >= 2 && points.len() > limit {
+        let step = (points.len() - 1) as f64 / (limit - 1) as f64;
+        points = (0..limit).map(|i| points[(i as f64 * step).round() as usize].clone()).collect();
+    }
+    points
+}
+
+async fn run_writer(path:PathBuf, mut receiver:mpsc::Receiver<TrackWrite>) {
+    let mut files: HashMap<(NaiveDate, String), File> = HashMap::new();
+    while let Some(write) = receiver.recv().await {
+        let (sim, point) = match write {
+            TrackWrite::Point(sim, point) => (sim, point),
+            TrackWrite::Flush(tx) => {
+                let _ = tx.send(());
+                continue;
+            },
+        };
+        let date = local_date(point.time);
+        let key = (date, sim);
+        if !files.contains_key(&key) {
+            if files.len() >= MAX_OPEN_FILES {
+                files.clear();
+            }
+            let dir = path.join(date.format("%Y%m%d").to_string());
+            let _ = tokio::fs::create_dir_all(&dir).await;
+            let file_path = dir.join(format!("{}.jsonl", key.1));
+            match OpenOptions::new().create(true).append(true).open(&file_path).await {
+                Ok(file) => {
+                    files.insert(key.clone(), file);
+                },
+                Err(err) => {
+                    log::warn!("[service-track]open failed:{:?} err:{}", file_path, err);
+                    continue;
+                },
+            }
+        }
+        let file = match files.get_mut(&key) {
+            Some(file) => file,
+            None => continue,
+        };
+        let mut line = serde_json::to_vec(&point).unwrap_or_default();
+        line.push(b'\n');
+        if file.write_all(&line).await.is_err() || file.flush().await.is_err() {
+            log::warn!("[service-track]write failed sim:{} date:{}", key.1, date);
+            files.remove(&key);
+        }
+    }
+}
+
+//删除超过保存天数的目录 总大小超限时从最早的一天开始删除 当天不删除
+fn cleanup(path:&Path, days:u32, max_size:u64) {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut dirs: Vec<(NaiveDate, PathBuf)> = entries.filter_map(|t| t.ok())
+        .filter_map(|t| Some((NaiveDate::parse_from_str(t.file_name().to_str()?, "%Y%m%d").ok()?, t.path())))
+        .collect();
+    dirs.sort();
+    let today = Local::now().date_naive();
+
+    if days > 0 {
+        let oldest = today - chrono::Duration::days(days as i64 - 1);
+        dirs.retain(|(date, dir)| {
+            if *date >= oldest {
+                return true;
+            }
+            log::info!("[service-track]remove expired:{:?}", dir);
+            let _ = std::fs::remove_dir_all(dir);
+            false
+        });
+    }
+
+    if max_size > 0 {
+        let sizes: Vec<u64> = dirs.iter().map(|(_, dir)| dir_size(dir)).collect();
+        let mut total: u64 = sizes.iter().sum();
+        for ((date, dir), size) in dirs.iter().zip(sizes) {
+            if total <= max_size || *date >= today {
+                break;
+            }
+            log::info!("[service-track]remove for size:{:?} total:{}", dir, total);
+            let _ = std::fs::remove_dir_all(dir);
+            total -= size;
+        }
+    }
+}
+
+fn dir_size(dir:&Path) -> u64 {
+    std::fs::read_dir(dir).map_or(0, |entries| entries.filter_map(|t| t.ok()?.metadata().ok()).map(|t| t.len()).sum())
+}
+
+fn local_date(time:i64) -> NaiveDate {
+    Local.timestamp_opt(time, 0).single().map_or_else(|| Local::now().date_naive(), |t| t.date_naive())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs() as i64)
+}
+
+
+#[test]
+fn test_downsample()
+{
+    let points = |times:&[i64]| times.iter().map(|time| TrackPoint::new(&JtLocation { time: 1000 + *time, ..Default::default() }, false)).collect::<Vec<_>>();
+    let times = |points:Vec<TrackPoint>| points.iter().map(|t| t.time - 1000).collect::<Vec<_>>();
+
+    assert_eq!(times(downsample(points(&[0, 10, 20, 30]), 0, 0)), vec![0, 10, 20, 30]);
+    assert_eq!(times(downsample(points(&[0, 10, 20, 30, 40, 45]), 30, 0)), vec![0, 30, 45]);
+    assert_eq!(times(downsample(points(&[0, 1, 2, 3, 4, 5, 6, 7, 8]), 0, 3)), vec![0, 4, 8]);
+    assert_eq!(times(downsample(points(&[0, 1]), 10, 1)), vec![0, 1]);
+}
+
+
+#[tokio::test]
+async fn test_record_query()
+{
+    let path = std::env::temp_dir().join(format!("track-{}", std::process::id()));
+    init(path.to_str().unwrap(), 0, 0);
+    let sim = "013800000601";
+    let location = |time:i64, lat:f64| JtLocation { time, lat, lng: 114.0, state: 0x02, ..Default::default() };
+    record(sim, &location(1_700_000_000, 22.1), false).await;
+    record(sim, &location(1_700_000_060, 22.2), false).await;
+    //隔天的盲区补报
+    record(sim, &location(1_700_090_000, 22.3), true).await;
+    record("013800000602", &location(1_700_000_030, 22.4), false).await;
+    flush().await;
+
+    let points = query(sim, 1_700_000_000, 1_700_100_000).await;
+    assert_eq!(points.iter().map(|t| (t.time, t.lat, t.backfill)).collect::<Vec<_>>(),
+        vec![(1_700_000_000, 22.1, false), (1_700_000_060, 22.2, false), (1_700_090_000, 22.3, true)]);
+    assert_eq!(query(sim, 1_700_000_030, 1_700_000_060).await.len(), 1);
+    assert!(query(sim, 1_800_000_000, 1_800_000_060).await.is_empty());
+    let _ = std::fs::remove_dir_all(&path);
+}
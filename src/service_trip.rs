@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use chrono::{Local, NaiveDate, TimeZone};
+use serde::Serialize;
+
+use crate::{service_event::{self, DeviceEvent}, service_geofence::haversine, service_track::{self, TrackPoint}, session808::{jt808_area::GeoPoint, jt808_models::JtLocation}};
+
+//行程/停车分段 按ACC状态(状态位0)、速度和停留时间判断
+//行驶中停留(ACC关或低于速度阈值)超过stop_seconds时行程结束 结束时间为开始停留的时间
+//里程分别按终端附加信息0x01和GPS点间距离累计
+//实时检测发布trip_start/trip_end/stop_start/stop_end事件 每日汇总用历史轨迹重放计算
+
+//相邻两点推算速度超过该值(km/h)视为漂移 不计入GPS里程
+const MAX_JUMP_SPEED: f64 = 300.0;
+
+static GLOBAL_TRIP: std::sync::Mutex<Option<TripStore>> = std::sync::Mutex::new(None);
+
+struct TripStore {
+    rule: TripRule,
+    detectors: HashMap<String, TripDetector>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TripRule {
+    /// 行驶速度阈值(km/h)
+    pub min_speed: f64,
+    /// 停留超过该时间(秒)行程结束
+    pub stop_seconds: i64,
+    /// 使用ACC状态 ACC关时立即结束行程
+    pub use_acc: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trip {
+    /// unix秒
+    pub start: i64,
+    pub end: i64,
+    pub start_lat: f64,
+    pub start_lng: f64,
+    pub end_lat: f64,
+    pub end_lng: f64,
+    /// 终端里程(附加信息0x01)差值(km) 没有里程时为None
+    pub mileage_distance: Option<f64>,
+    /// GPS点间距离累计(km)
+    pub gps_distance: f64,
+    pub max_speed: f64,
+    /// 平均速度(km/h) 按GPS里程计算
+    pub avg_speed: f64,
+    /// 未结束
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub ongoing: bool,
+    #[serde(skip)]
+    start_mileage: Option<f64>,
+    #[serde(skip)]
+    end_mileage: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stop {
+    pub start: i64,
+    /// 未结束时为None
+    pub end: Option<i64>,
+    pub lat: f64,
+    pub lng: f64,
+    /// 停留时长(秒)
+    pub duration: i64,
+}
+
+#[derive(Debug, Clone)]
+pub enum TripEvent {
+    TripStart(Trip),
+    TripEnd(Trip),
+    StopStart(Stop),
+    StopEnd(Stop),
+}
+
+/// 单个终端的分段状态 点需要按时间顺序输入
+#[derive(Debug, Default)]
+pub struct TripDetector {
+    trip: Option<Trip>,
+    stop: Option<Stop>,
+    //行程中开始停留的点
+    pause: Option<TrackPoint>,
+    last: Option<TrackPoint>,
+}
+
+impl TripDetector {
+    pub fn update(&mut self, rule:&TripRule, point:&TrackPoint) -> Vec<TripEvent> {
+        let mut events = Vec::new();
+        if self.last.as_ref().is_some_and(|t| point.time <= t.time) {
+            return events;
+        }
+
+        //长时间没有数据(离线) 按最后一个点结束行程
+        if let (Some(_), Some(last)) = (&self.trip, self.last.clone()) {
+            if point.time - last.time >= rule.stop_seconds {
+                let pause = self.pause.take().unwrap_or(last);
+                self.end_trip(&pause, &mut events);
+            }
+        }
+
+        let acc_off = rule.use_acc && point.state & 0x01 == 0;
+        let moving = !acc_off && point.speed >= rule.min_speed;
+        if self.trip.is_some() {
+            self.add_distance(point);
+            if moving {
+                self.pause = None;
+            } else {
+                let pause = self.pause.get_or_insert_with(|| point.clone()).clone();
+                if acc_off || point.time - pause.time >= rule.stop_seconds {
+                    self.pause = None;
+                    self.end_trip(&pause, &mut events);
+                }
+            }
+        } else if moving {
+            if let Some(mut stop) = self.stop.take() {
+                stop.end = Some(point.time);
+                stop.duration = point.time - stop.start;
+                events.push(TripEvent::StopEnd(stop));
+            }
+            let trip = Trip {
+                start: point.time,
+                end: point.time,
+                start_lat: point.lat,
+                start_lng: point.lng,
+                end_lat: point.lat,
+                end_lng: point.lng,
+                mileage_distance: None,
+                gps_distance: 0.0,
+                max_speed: point.speed,
+                avg_speed: 0.0,
+                ongoing: true,
+                start_mileage: point.mileage,
+                end_mileage: point.mileage,
+            };
+            events.push(TripEvent::TripStart(trip.clone()));
+            self.trip = Some(trip);
+        }
+        self.last = Some(point.clone());
+        events
+    }
+
+    /// 当前行程(未结束)
+    pub fn current_trip(&self) -> Option<Trip> {
+        self.trip.clone()
+    }
+
+    /// 当前停车(未结束) 时长计算到time
+    pub fn current_stop(&self, time:i64) -> Option<Stop> {
+        self.stop.clone().map(|mut t| {
+            t.duration = time.max(t.start) - t.start;
+            t
+        })
+    }
+
+    fn add_distance(&mut self, point:&TrackPoint) {
+        let (trip, last) = match (self.trip.as_mut(), self.last.as_ref()) {
+            (Some(trip), Some(last)) => (trip, last),
+            _ => return,
+        };
+        //未定位的点不计入GPS里程
+        if point.state & 0x02 > 0 && last.state & 0x02 > 0 {
+            let meters = haversine(&GeoPoint { lat: last.lat, lng: last.lng }, &GeoPoint { lat: point.lat, lng: point.lng });
+            let seconds = (point.time - last.time).max(1) as f64;
+            if meters / seconds * 3.6 <= MAX_JUMP_SPEED {
+                trip.gps_distance += meters / 1000.0;
+            }
+        }
+        if point.mileage.is_some() {
+            trip.start_mileage = trip.start_mileage.or(point.mileage);
+            trip.end_mileage = point.mileage;
+        }
+        trip.max_speed = trip.max_speed.max(point.speed);
+    }
+
+    fn end_trip(&mut self, point:&TrackPoint, events:&mut Vec<TripEvent>) {
+        let mut trip = match self.trip.take() {
+            Some(trip) => trip,
+            None => return,
+        };
+        trip.end = point.time;
+        trip.end_lat = point.lat;
+        trip.end_lng = point.lng;
+        trip.ongoing = false;
+        finish(&mut trip);
+        events.push(TripEvent::TripEnd(trip));
+
+        let stop = Stop { start: point.time, end: None, lat: point.lat, lng: point.lng, duration: 0 };
+        events.push(TripEvent::StopStart(stop.clone()));
+        self.stop = Some(stop);
+    }
+}
+
+fn finish(trip:&mut Trip) {
+    if let (Some(start), Some(end)) = (trip.start_mileage, trip.end_mileage) {
+        trip.mileage_distance = (end >= start).then_some(((end - start) * 10.0).round() / 10.0);
+    }
+    let hours = (trip.end - trip.start) as f64 / 3600.0;
+    trip.avg_speed = if hours > 0.0 { trip.gps_distance / hours } else { 0.0 };
+}
+
+pub fn init(rule:TripRule) {
+    *GLOBAL_TRIP.lock().unwrap() = Some(TripStore { rule, detectors: HashMap::new() });
+}
+
+/// 终端上报0x0200时调用
+pub fn update(sim:&str, location:&JtLocation) {
+    let point = TrackPoint::new(location, false);
+    let events = {
+        let mut global = GLOBAL_TRIP.lock().unwrap();
+        let store = match global.as_mut() {
+            Some(store) => store,
+            None => return,
+        };
+        let rule = store.rule;
+        store.detectors.entry(sim.to_string()).or_default().update(&rule, &point)
+    };
+    for event in events {
+        let event = match event {
+            TripEvent::TripStart(trip) => DeviceEvent::new(sim, "trip_start", &trip),
+            TripEvent::TripEnd(trip) => DeviceEvent::new(sim, "trip_end", &trip),
+            TripEvent::StopStart(stop) => DeviceEvent::new(sim, "stop_start", &stop),
+            TripEvent::StopEnd(stop) => DeviceEvent::new(sim, "stop_end", &stop),
+        };
+        service_event::publish(event);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DailySummary {
+    pub sim: String,
+    pub date: String,
+    pub trip_count: usize,
+    /// 行驶时长(秒)
+    pub driving_seconds: i64,
+    /// 行程的终端里程之和(km) 没有终端里程时为None
+    pub mileage_distance: Option<f64>,
+    /// 行程的GPS里程之和(km)
+    pub gps_distance: f64,
+    pub max_speed: f64,
+    pub trips: Vec<Trip>,
+    pub stops: Vec<Stop>,
+}
+
+/// 按历史轨迹重放计算某天的行程和停车 当天未结束的行程/停车计算到最后一个点
+pub async fn daily_summary(sim:&str, date:NaiveDate) -> Option<DailySummary> {
+    let rule = GLOBAL_TRIP.lock().unwrap().as_ref()?.rule;
+    let from = Local.from_local_datetime(&date.and_hms_opt(0, 0, 0)?).earliest()?.timestamp();
+    let to = Local.from_local_datetime(&date.succ_opt()?.and_hms_opt(0, 0, 0)?).earliest()?.timestamp() - 1;
+    let points = service_track::query(sim, from, to).await;
+    Some(summarize(sim, date, &rule, &points))
+}
+
+fn summarize(sim:&str, date:NaiveDate, rule:&TripRule, points:&[TrackPoint]) -> DailySummary {
+    let mut detector = TripDetector::default();
+    let mut trips = Vec::new();
+    let mut stops = Vec::new();
+    for point in points {
+        for event in detector.update(rule, point) {
+            match event {
+                TripEvent::TripEnd(trip) => trips.push(trip),
+                TripEvent::StopEnd(stop) => stops.push(stop),
+                _ => {},
+            }
+        }
+    }
+    if let Some(last) = points.last() {
+        if let Some(mut trip) = detector.current_trip() {
+            trip.end = last.time;
+            trip.end_lat = last.lat;
+            trip.end_lng = last.lng;
+            finish(&mut trip);
+            trips.push(trip);
+        }
+        stops.extend(detector.current_stop(last.time));
+    }
+
+    let mileage: Vec<f64> = trips.iter().filter_map(|t| t.mileage_distance).collect();
+    DailySummary {
+        sim: sim.to_string(),
+        date: date.format("%Y-%m-%d").to_string(),
+        trip_count: trips.len(),
+        driving_seconds: trips.iter().map(|t| t.end - t.start).sum(),
+        mileage_distance: (!mileage.is_empty()).then(|| (mileage.iter().sum::<f64>() * 10.0).round() / 10.0),
+        gps_distance: trips.iter().map(|t| t.gps_distance).sum(),
+        max_speed: trips.iter().map(|t| t.max_speed).fold(0.0, f64::max),
+        trips,
+        stops,
+    }
+}
+
+
+#[test]
+fn test_trip_segments()
+{
+    let rule = TripRule { min_speed: 5.0, stop_seconds: 180, use_acc: true };
+    //ACC开 已定位 纬度每10秒增加0.001度(约111米)
+    let point = |time:i64, speed:f64, acc:bool, mileage:f64| TrackPoint::new(&JtLocation {
+        time,
+        speed,
+        state: if acc { 0x03 } else { 0x02 },
+        lat: 22.5 + (time - 1000) as f64 / 10000.0,
+        lng: 114.0,
+        extras: [(0x01u8, bytes::Bytes::from(((mileage * 10.0) as u32).to_be_bytes().to_vec()))].into_iter().collect(),
+        ..Default::default()
+    }, false);
+    let points = vec![
+        point(1000, 40.0, true, 100.0),
+        point(1010, 40.0, true, 100.1),
+        point(1020, 0.0, true, 100.2),
+        //短暂停留不结束
+        point(1100, 40.0, true, 100.3),
+        point(1110, 0.0, false, 100.5),
+        //熄火后重新出发
+        point(1500, 40.0, true, 100.5),
+        point(1510, 40.0, true, 100.6),
+    ];
+    let summary = summarize("test", NaiveDate::from_ymd_opt(2024, 10, 19).unwrap(), &rule, &points);
+    assert_eq!(summary.trips.iter().map(|t| (t.start, t.end, t.ongoing)).collect::<Vec<_>>(), vec![(1000, 1110, false), (1500, 1510, true)]);
+    assert_eq!(summary.stops.iter().map(|t| (t.start, t.end, t.duration)).collect::<Vec<_>>(), vec![(1110, Some(1500), 390)]);
+    assert_eq!(summary.trips[0].mileage_distance, Some(0.5));
+    assert!((summary.trips[0].gps_distance - 1.223).abs() < 0.01);
+    assert_eq!(summary.driving_seconds, 120);
+
+    //停留超过stop_seconds 行程在开始停留时结束
+    let points = vec![point(1000, 40.0, true, 0.0), point(1010, 0.0, true, 0.0), point(1100, 0.0, true, 0.0), point(1200, 0.0, true, 0.0)];
+    let summary = summarize("test", NaiveDate::from_ymd_opt(2024, 10, 19).unwrap(), &rule, &points);
+    assert_eq!(summary.trips.iter().map(|t| (t.start, t.end)).collect::<Vec<_>>(), vec![(1000, 1010)]);
+    assert_eq!(summary.stops.iter().map(|t| (t.start, t.end, t.duration)).collect::<Vec<_>>(), vec![(1010, None, 190)]);
+}
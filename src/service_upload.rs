@@ -0,0 +1,308 @@
+use std::{collections::HashMap, path::PathBuf, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{service_device, service_event::{self, DeviceEvent}, session1078::extend808::{gbk, Jt0x1206, Jt0x9206, Jt0x9207}};
+
+//终端文件上传 0x9206下发每个任务独立的FTP账号 文件写入{upload_path}/{sim}/{任务ID}
+//0x9207暂停/继续/取消 0x1206上传完成通知
+
+//等待0x1206的最长时间
+const COMPLETE_TIMEOUT: Duration = Duration::from_secs(6 * 3600);
+//结束的任务保留时间 之后从任务表移除
+const FINISHED_KEEP: u64 = 24 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    //已下发 等待终端连接
+    Requested,
+    Uploading,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadTask {
+    pub id: u64,
+    pub sim: String,
+    //0x9206流水号
+    pub sn: u16,
+    pub channel: u8,
+    pub from: i64,
+    pub to: i64,
+    pub state: UploadState,
+    pub files: Vec<String>,
+    //已接收字节数
+    pub bytes: u64,
+    pub created: u64,
+    pub updated: u64,
+    #[serde(skip)]
+    user: String,
+    #[serde(skip)]
+    password: String,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl UploadTask {
+    fn is_finished(&self) -> bool {
+        matches!(self.state, UploadState::Completed | UploadState::Failed | UploadState::Cancelled)
+    }
+}
+
+/// 上传请求 时间为unix秒
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct UploadRequest {
+    pub channel: u8,
+    pub from: i64,
+    pub to: i64,
+    pub alarm: u64,
+    pub av_type: u8,
+    pub stream_type: u8,
+    pub storage_type: u8,
+    //任务执行条件 bit0:WIFI bit1:LAN bit2:3G/4G 0表示不限
+    pub condition: u8,
+}
+
+pub struct ServiceUpload {
+    //下发给终端的FTP地址
+    ftp_ip: String,
+    ftp_port: u16,
+    upload_path: PathBuf,
+    next_id: AtomicU64,
+    tasks: std::sync::Mutex<HashMap<u64, UploadTask>>,
+}
+
+impl ServiceUpload {
+    pub fn new(address_public:&str, upload_path:&str) -> Self {
+        let (ftp_ip, ftp_port) = match address_public.rsplit_once(':') {
+            Some((ip, port)) => (ip.to_string(), port.parse().unwrap_or(21)),
+            None => (address_public.to_string(), 21),
+        };
+
+        ServiceUpload {
+            ftp_ip,
+            ftp_port,
+            upload_path: PathBuf::from(upload_path),
+            next_id: AtomicU64::new(unix_now()),
+            tasks: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 下发0x9206 返回任务 设备不在线或拒绝时返回None
+    pub async fn request(service:&Arc<ServiceUpload>, sim:&str, req:&UploadRequest) -> Option<UploadTask> {
+        let sender = service_device::get_sender(sim).await?;
+        service.remove_finished(unix_now());
+
+        let id = service.next_id.fetch_add(1, Ordering::Relaxed);
+        let user = format!("u{}", id);
+        let password = new_password();
+        let dir = service.upload_path.join(sim).join(id.to_string());
+        if let Err(err) = tokio::fs::create_dir_all(&dir).await {
+            log::warn!("[service-upload]create dir failed:{}", err);
+            return None;
+        }
+
+        let mut jt9206 = Jt0x9206 {
+            ipaddress: gbk(&service.ftp_ip),
+            port: service.ftp_port,
+            user: gbk(&user),
+            password: gbk(&password),
+            path: gbk("/"),
+            channel: req.channel,
+            starttime: req.from,
+            endtime: req.to,
+            alarm: req.alarm,
+            media_type: req.av_type,
+            stream_type: req.stream_type,
+            storage_type: req.storage_type,
+            condition: req.condition,
+        };
+
+        //先登记任务 终端可能在应答前就连接FTP
+        let now = unix_now();
+        service.tasks.lock().unwrap().insert(id, UploadTask {
+            id,
+            sim: sim.to_string(),
+            sn: 0,
+            channel: req.channel,
+            from: req.from,
+            to: req.to,
+            state: UploadState::Requested,
+            files: Vec::new(),
+            bytes: 0,
+            created: now,
+            updated: now,
+            user,
+            password,
+            dir: dir.clone(),
+        });
+
+        let (sn, ret, rx) = sender.send_cmd_answer::<_, Jt0x1206>(0x9206, &mut jt9206, 0x1206).await;
+        log::info!("[service-upload]request sim:{} task:{} sn:{} ret:{}", sim, id, sn, ret);
+        if ret != 0 {
+            sender.remove_request(0x1206, sn).await;
+            service.tasks.lock().unwrap().remove(&id);
+            let _ = tokio::fs::remove_dir(&dir).await;
+            return None;
+        }
+        if let Some(task) = service.tasks.lock().unwrap().get_mut(&id) {
+            task.sn = sn;
+        }
+
+        //等待上传完成通知
+        let service_wait = service.clone();
+        let sim = sim.to_string();
+        tokio::spawn(async move {
+            let state = match tokio::time::timeout(COMPLETE_TIMEOUT, rx).await {
+                Ok(Ok(jt1206)) => {
+                    log::info!("[service-upload]complete task:{} result:{}", id, jt1206.result);
+                    service_event::publish(DeviceEvent::new(&sim, "upload_complete", &serde_json::json!({ "id": id, "result": jt1206.result })));
+                    if jt1206.result == 0 { UploadState::Completed } else { UploadState::Failed }
+                },
+                //设备下线
+                Ok(Err(_)) => UploadState::Failed,
+                Err(_) => {
+                    sender.remove_request(0x1206, sn).await;
+                    UploadState::Failed
+                },
+            };
+            //取消的任务保持取消状态
+            if service_wait.get(id).is_some_and(|t| t.state != UploadState::Cancelled) {
+                service_wait.set_state(id, state);
+            }
+        });
+
+        service.get(id)
+    }
+
+    /// 下发0x9207 action:pause resume cancel
+    pub async fn control(&self, id:u64, action:&str) -> bool {
+        let (control, state) = match action {
+            "pause" => (0, UploadState::Paused),
+            "resume" => (1, UploadState::Uploading),
+            "cancel" => (2, UploadState::Cancelled),
+            _ => return false,
+        };
+        let task = match self.get(id) {
+            Some(task) => task,
+            None => return false,
+        };
+        if task.is_finished() {
+            return false;
+        }
+        let sender = match service_device::get_sender(&task.sim).await {
+            Some(sender) => sender,
+            None => return false,
+        };
+
+        let mut jt9207 = Jt0x9207 { answer_sn: task.sn, control };
+        let ret = sender.send_cmd(0x9207, &mut jt9207).await;
+        log::info!("[service-upload]control sim:{} task:{} action:{} ret:{}", task.sim, id, action, ret);
+        if ret != 0 {
+            return false;
+        }
+        if state == UploadState::Cancelled {
+            sender.remove_request(0x1206, task.sn).await;
+        }
+        self.set_state(id, state);
+        true
+    }
+
+    pub fn get(&self, id:u64) -> Option<UploadTask> {
+        self.tasks.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn list(&self, sim:&str) -> Vec<UploadTask> {
+        let mut list: Vec<UploadTask> = self.tasks.lock().unwrap().values().filter(|t| t.sim == sim).cloned().collect();
+        list.sort_by_key(|t| t.id);
+        list
+    }
+
+    /// FTP登录 返回任务ID及上传目录
+    pub fn login(&self, user:&str, password:&str) -> Option<(u64, PathBuf)> {
+        let tasks = self.tasks.lock().unwrap();
+        let task = tasks.values().find(|t| t.user == user && t.password == password)?;
+        if task.is_finished() {
+            return None;
+        }
+        Some((task.id, task.dir.clone()))
+    }
+
+    /// FTP开始接收文件
+    pub fn on_file(&self, id:u64, name:&str) {
+        if let Some(task) = self.tasks.lock().unwrap().get_mut(&id) {
+            if !task.files.iter().any(|t| t == name) {
+                task.files.push(name.to_string());
+            }
+            if task.state == UploadState::Requested {
+                task.state = UploadState::Uploading;
+            }
+            task.updated = unix_now();
+        }
+    }
+
+    /// FTP接收进度
+    pub fn on_data(&self, id:u64, n:usize) {
+        if let Some(task) = self.tasks.lock().unwrap().get_mut(&id) {
+            task.bytes += n as u64;
+            task.updated = unix_now();
+        }
+    }
+
+    //移除结束超过保留时间的任务
+    fn remove_finished(&self, now:u64) {
+        self.tasks.lock().unwrap().retain(|_, t| !t.is_finished() || now.saturating_sub(t.updated) < FINISHED_KEEP);
+    }
+
+    fn set_state(&self, id:u64, state:UploadState) {
+        if let Some(task) = self.tasks.lock().unwrap().get_mut(&id) {
+            task.state = state;
+            task.updated = unix_now();
+        }
+    }
+}
+
+//每个任务的FTP密码 使用系统随机数
+fn new_password() -> String {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("system random unavailable");
+    hex::encode(bytes)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs())
+}
+
+
+#[test]
+fn test_remove_finished()
+{
+    let service = ServiceUpload::new("127.0.0.1:21", "upload");
+    for (id, state, updated) in [(1, UploadState::Completed, 0), (2, UploadState::Uploading, 0), (3, UploadState::Failed, FINISHED_KEEP)] {
+        service.tasks.lock().unwrap().insert(id, UploadTask {
+            id,
+            sim: "013800000000".to_string(),
+            sn: 0,
+            channel: 1,
+            from: 0,
+            to: 0,
+            state,
+            files: Vec::new(),
+            bytes: 0,
+            created: 0,
+            updated,
+            user: format!("u{}", id),
+            password: String::new(),
+            dir: PathBuf::new(),
+        });
+    }
+    service.remove_finished(FINISHED_KEEP + 1);
+    let mut ids: Vec<u64> = service.tasks.lock().unwrap().keys().copied().collect();
+    ids.sort();
+    assert_eq!(ids, vec![2, 3]);
+}
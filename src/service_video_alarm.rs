@@ -0,0 +1,188 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+
+use serde::Serialize;
+
+use crate::{service_device, service_event::{self, DeviceEvent}, service_hls::ServiceHls, session1078::extend808::{video_alarms, VideoAlarm}, session808::jt808_models::{Jt0x0805, Jt0x8801, JtLocation}};
+
+//1078视频报警 0x0200附加信息0x14~0x18 按SIM保存当前报警
+//新出现的报警发布事件 配置的报警类型联动录像(1078接收缓存)或拍照(0x8801)
+
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static GLOBAL_VIDEO_ALARM: std::sync::Mutex<Option<HashMap<String, Vec<VideoAlarm>>>> = std::sync::Mutex::new(None);
+static GLOBAL_TRIGGER: std::sync::Mutex<Option<Arc<VideoAlarmTrigger>>> = std::sync::Mutex::new(None);
+
+/// 报警联动
+pub struct VideoAlarmTrigger {
+    hls: Arc<ServiceHls>,
+    //联动的报警类型
+    kinds: Vec<String>,
+    record: bool,
+    snapshot: bool,
+    //录像时长
+    seconds: u64,
+    //报警没有通道时使用
+    channel: u8,
+    //正在联动录像的(sim,通道) 期间不重复触发
+    recording: std::sync::Mutex<HashSet<(String, u8)>>,
+}
+
+#[derive(Serialize)]
+struct TriggerResult<'a> {
+    alarm: &'a VideoAlarm,
+    channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    //0x0805结果 0:成功 1:失败 2:通道不支持 -1:超时或不在线
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<i32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    media_ids: Vec<u32>,
+}
+
+impl VideoAlarmTrigger {
+    /// actions: record snapshot
+    pub fn new(hls:Arc<ServiceHls>, kinds:Vec<String>, actions:&[String], seconds:u64, channel:u8) -> Self {
+        VideoAlarmTrigger {
+            hls,
+            kinds,
+            record: actions.iter().any(|t| t == "record"),
+            snapshot: actions.iter().any(|t| t == "snapshot"),
+            seconds,
+            channel,
+            recording: std::sync::Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn fire(trigger:&Arc<VideoAlarmTrigger>, sim:&str, alarm:&VideoAlarm) {
+        if !trigger.kinds.iter().any(|t| t == alarm.kind) {
+            return;
+        }
+        let channel = alarm.channel.unwrap_or(trigger.channel);
+
+        if trigger.record && trigger.recording.lock().unwrap().insert((sim.to_string(), channel)) {
+            tokio::spawn(VideoAlarmTrigger::record(trigger.clone(), sim.to_string(), channel, alarm.clone()));
+        }
+        if trigger.snapshot {
+            tokio::spawn(VideoAlarmTrigger::snapshot(sim.to_string(), channel, alarm.clone()));
+        }
+    }
+
+    //缓存报警后seconds秒的码流并保存 手动开启的录像不停止
+    async fn record(trigger:Arc<VideoAlarmTrigger>, sim:String, channel:u8, alarm:VideoAlarm) {
+        let minutes = trigger.seconds.div_ceil(60).max(1) as u32;
+        let mut file = None;
+        if ServiceHls::record_alarm(&trigger.hls, &sim, channel, minutes).await {
+            tokio::time::sleep(Duration::from_secs(trigger.seconds)).await;
+            file = trigger.hls.save(&sim, channel).await;
+            trigger.hls.stop_alarm(&sim, channel);
+        }
+        log::info!("[service-video-alarm]record sim:{} channel:{} kind:{} file:{:?}", sim, channel, alarm.kind, file);
+        trigger.recording.lock().unwrap().remove(&(sim.clone(), channel));
+
+        let result = TriggerResult { alarm: &alarm, channel, file, result: None, media_ids: Vec::new() };
+        service_event::publish(DeviceEvent::new(&sim, "video_alarm_record", &result));
+    }
+
+    //0x8801拍一张实时上传 图片由终端0x0801上传
+    async fn snapshot(sim:String, channel:u8, alarm:VideoAlarm) {
+        let sender = match service_device::get_sender(&sim).await {
+            Some(sender) => sender,
+            None => return,
+        };
+        let mut jt8801 = Jt0x8801 {
+            channel,
+            command: 1,
+            interval: 0,
+            save: 0,
+            resolution: 2,
+            quality: 5,
+            brightness: 128,
+            contrast: 64,
+            saturation: 64,
+            chroma: 128,
+        };
+        let (result, media_ids) = match sender.request::<_, Jt0x0805>(0x8801, &mut jt8801, 0x0805, true, SNAPSHOT_TIMEOUT).await {
+            Ok(jt0805) => (jt0805.result as i32, jt0805.ids),
+            Err(ret) => (ret, Vec::new()),
+        };
+        log::info!("[service-video-alarm]snapshot sim:{} channel:{} kind:{} result:{} ids:{:?}", sim, channel, alarm.kind, result, media_ids);
+
+        let result = TriggerResult { alarm: &alarm, channel, file: None, result: Some(result), media_ids };
+        service_event::publish(DeviceEvent::new(&sim, "video_alarm_snapshot", &result));
+    }
+}
+
+pub fn init() {
+    *GLOBAL_VIDEO_ALARM.lock().unwrap() = Some(HashMap::new());
+}
+
+/// 设置报警联动 未设置时只记录报警
+pub fn set_trigger(trigger:VideoAlarmTrigger) {
+    *GLOBAL_TRIGGER.lock().unwrap() = Some(Arc::new(trigger));
+}
+
+/// 终端上报0x0200时更新 新出现的报警发布事件并联动 消失的报警发布结束事件
+pub fn update(sim:&str, location:&JtLocation) {
+    let alarms = video_alarms(location);
+    let (fired, ended) = {
+        let mut global = GLOBAL_VIDEO_ALARM.lock().unwrap();
+        let map = match global.as_mut() {
+            Some(map) => map,
+            None => return,
+        };
+        let last = map.get(sim).map_or(&[][..], |t| t.as_slice());
+        if alarms.is_empty() && last.is_empty() {
+            return;
+        }
+        let fired: Vec<VideoAlarm> = alarms.iter().filter(|t| !last.iter().any(|l| same_alarm(l, t))).cloned().collect();
+        let ended: Vec<VideoAlarm> = last.iter().filter(|l| !alarms.iter().any(|t| same_alarm(l, t))).cloned().collect();
+        if alarms.is_empty() {
+            map.remove(sim);
+        } else {
+            map.insert(sim.to_string(), alarms);
+        }
+        (fired, ended)
+    };
+
+    let trigger = GLOBAL_TRIGGER.lock().unwrap().clone();
+    for alarm in &fired {
+        service_event::publish(DeviceEvent::new(sim, "video_alarm", alarm));
+        if let Some(trigger) = &trigger {
+            VideoAlarmTrigger::fire(trigger, sim, alarm);
+        }
+    }
+    for alarm in &ended {
+        service_event::publish(DeviceEvent::new(sim, "video_alarm_end", alarm));
+    }
+}
+
+/// 终端下线时清除当前报警
+pub fn remove(sim:&str) {
+    if let Some(map) = GLOBAL_VIDEO_ALARM.lock().unwrap().as_mut() {
+        map.remove(sim);
+    }
+}
+
+/// 当前报警
+pub fn get(sim:&str) -> Vec<VideoAlarm> {
+    GLOBAL_VIDEO_ALARM.lock().unwrap().as_ref().and_then(|t| t.get(sim).cloned()).unwrap_or_default()
+}
+
+//疲劳程度变化不算新报警
+fn same_alarm(a:&VideoAlarm, b:&VideoAlarm) -> bool {
+    a.kind == b.kind && a.channel == b.channel && a.detail == b.detail
+}
+
+
+#[test]
+fn test_remove_offline()
+{
+    init();
+    let mut location = JtLocation::default();
+    location.extras.insert(0x14, bytes::Bytes::from_static(&[0x00, 0x00, 0x00, 0x01]));
+    update("013800000402", &location);
+    assert_eq!(get("013800000402").len(), 1);
+    remove("013800000402");
+    assert!(get("013800000402").is_empty());
+}
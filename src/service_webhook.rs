@@ -0,0 +1,335 @@
+use std::{io::{Read, Seek, SeekFrom}, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+use crate::{config_model::WebhookConfig, service_event::{self, DeviceEvent, EventFilter}};
+
+//设备事件以HTTP POST(JSON)推送到配置的地址 每个地址一个发送队列 按顺序发送
+//失败时指数退避重试 超过重试次数写入死信文件(每行一个JSON)
+//配置了secret时请求头X-Gw808-Signature为 sha256=hex(HMAC-SHA256(secret, 请求体))
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_BASE: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+//从死信文件末尾每次读取的字节数
+const TAIL_BLOCK: u64 = 8192;
+
+static GLOBAL_WEBHOOK: std::sync::Mutex<Option<WebhookStore>> = std::sync::Mutex::new(None);
+
+struct WebhookStore {
+    targets: Vec<Arc<WebhookTarget>>,
+    dead_letter: String,
+}
+
+struct WebhookTarget {
+    config: WebhookConfig,
+    status: std::sync::Mutex<WebhookStatus>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WebhookStatus {
+    pub url: String,
+    pub types: String,
+    pub sims: String,
+    /// 队列中等待发送的事件数
+    pub queued: usize,
+    pub delivered: u64,
+    /// 失败的请求次数(含重试)
+    pub failures: u64,
+    /// 超过重试次数写入死信的事件数
+    pub dead: u64,
+    /// 队列满时丢弃(写入死信)的事件数
+    pub dropped: u64,
+    pub last_error: String,
+    /// unix秒
+    pub last_success: u64,
+    pub last_failure: u64,
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    url: &'a str,
+    /// unix秒
+    time: u64,
+    attempts: u32,
+    error: &'a str,
+    event: &'a DeviceEvent,
+}
+
+/// retries:失败后的最大重试次数 queue_size:每个地址的队列长度
+pub fn init(webhooks:Vec<WebhookConfig>, retries:u32, queue_size:usize, dead_letter:&str) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("[service-webhook]client failed:{}", err);
+            return;
+        },
+    };
+    let mut targets = Vec::new();
+    for config in webhooks {
+        let receiver = match service_event::subscribe() {
+            Some(receiver) => receiver,
+            None => {
+                log::error!("[service-webhook]event bus not initialized");
+                break;
+            },
+        };
+        log::info!("[service-webhook]target url:{} types:{} sims:{}", config.url, config.types, config.sims);
+        let status = WebhookStatus { url: config.url.clone(), types: config.types.clone(), sims: config.sims.clone(), ..Default::default() };
+        let target = Arc::new(WebhookTarget { config, status: std::sync::Mutex::new(status) });
+        let (sender, queue) = mpsc::channel(queue_size.max(1));
+
+        //订阅事件放入队列
+        let target_recv = target.clone();
+        let dead_letter_recv = dead_letter.to_string();
+        tokio::spawn(async move {
+            let mut receiver = receiver;
+            let filter = EventFilter::new(&target_recv.config.sims, &target_recv.config.types);
+            while let Some(event) = service_event::recv(&mut receiver, &filter).await {
+                //先计数 推送任务可能在try_send返回前就取出并减掉
+                target_recv.status.lock().unwrap().queued += 1;
+                match sender.try_send(event) {
+                    Ok(_) => {},
+                    Err(mpsc::error::TrySendError::Full(event)) => {
+                        {
+                            let mut status = target_recv.status.lock().unwrap();
+                            status.queued -= 1;
+                            status.dropped += 1;
+                        }
+                        write_dead_letter(&dead_letter_recv, &target_recv.config.url, 0, "queue full", &event).await;
+                    },
+                    Err(mpsc::error::TrySendError::Closed(_)) => {
+                        target_recv.status.lock().unwrap().queued -= 1;
+                        break;
+                    },
+                }
+            }
+        });
+
+        tokio::spawn(run_delivery(target.clone(), client.clone(), queue, retries, dead_letter.to_string()));
+        targets.push(target);
+    }
+    *GLOBAL_WEBHOOK.lock().unwrap() = Some(WebhookStore { targets, dead_letter: dead_letter.to_string() });
+}
+
+/// 各推送地址的状态
+pub fn status() -> Vec<WebhookStatus> {
+    GLOBAL_WEBHOOK.lock().unwrap().as_ref().map_or(Vec::new(), |t| t.targets.iter().map(|t| t.status.lock().unwrap().clone()).collect())
+}
+
+/// 第attempt次重试前的等待时间 从RETRY_BASE开始翻倍 不超过RETRY_MAX
+pub fn backoff(attempt:u32) -> Duration {
+    RETRY_BASE.saturating_mul(1 << attempt.min(16)).min(RETRY_MAX)
+}
+
+/// sha256=hex(HMAC-SHA256(secret, body))
+pub fn sign(secret:&str, body:&[u8]) -> String {
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return String::new(),
+    };
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn run_delivery(target:Arc<WebhookTarget>, client:reqwest::Client, mut queue:mpsc::Receiver<Arc<DeviceEvent>>, retries:u32, dead_letter:String) {
+    while let Some(event) = queue.recv().await {
+        let body = serde_json::to_vec(event.as_ref()).unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            let err = match post(&client, &target.config, &event.kind, &body).await {
+                Ok(_) => {
+                    let mut status = target.status.lock().unwrap();
+                    status.delivered += 1;
+                    status.last_success = unix_now();
+                    break;
+                },
+                Err(err) => err,
+            };
+            {
+                let mut status = target.status.lock().unwrap();
+                status.failures += 1;
+                status.last_failure = unix_now();
+                status.last_error = err.clone();
+                if attempt >= retries {
+                    status.dead += 1;
+                }
+            }
+            if attempt >= retries {
+                log::warn!("[service-webhook]dead url:{} kind:{} err:{}", target.config.url, event.kind, err);
+                write_dead_letter(&dead_letter, &target.config.url, attempt + 1, &err, &event).await;
+                break;
+            }
+            tokio::time::sleep(backoff(attempt)).await;
+            attempt += 1;
+        }
+        let mut status = target.status.lock().unwrap();
+        status.queued = status.queued.saturating_sub(1);
+    }
+}
+
+async fn post(client:&reqwest::Client, config:&WebhookConfig, kind:&str, body:&[u8]) -> Result<(), String> {
+    let mut request = client.post(&config.url)
+        .header("Content-Type", "application/json")
+        .header("X-Gw808-Event", kind)
+        .body(body.to_vec());
+    if !config.secret.is_empty() {
+        request = request.header("X-Gw808-Signature", sign(&config.secret, body));
+    }
+    let response = request.send().await.map_err(|t| t.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status().as_u16()));
+    }
+    Ok(())
+}
+
+async fn write_dead_letter(path:&str, url:&str, attempts:u32, error:&str, event:&DeviceEvent) {
+    let letter = DeadLetter { url, time: unix_now(), attempts, error, event };
+    let mut line = serde_json::to_vec(&letter).unwrap_or_default();
+    line.push(b'\n');
+    let result = match tokio::fs::OpenOptions::new().create(true).append(true).open(path).await {
+        Ok(mut file) => file.write_all(&line).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        log::warn!("[service-webhook]write dead letter failed:{} err:{}", path, err);
+    }
+}
+
+/// 死信文件最后limit条
+pub async fn dead_letters(limit:usize) -> Vec<serde_json::Value> {
+    let path = match GLOBAL_WEBHOOK.lock().unwrap().as_ref() {
+        Some(store) => store.dead_letter.clone(),
+        None => return Vec::new(),
+    };
+    let lines = tokio::task::spawn_blocking(move || read_tail(&path, limit)).await.unwrap_or_default();
+    lines.iter().filter_map(|t| serde_json::from_str(t).ok()).collect()
+}
+
+//从文件末尾向前按块读取 直到包含最后limit行 不读入整个文件
+fn read_tail(path:&str, limit:usize) -> Vec<String> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    let mut pos = file.metadata().map_or(0, |t| t.len());
+    let mut tail = Vec::new();
+    let mut newlines = 0;
+    while limit > 0 && pos > 0 && newlines <= limit {
+        let start = pos.saturating_sub(TAIL_BLOCK);
+        let mut block = vec![0; (pos - start) as usize];
+        if file.seek(SeekFrom::Start(start)).is_err() || file.read_exact(&mut block).is_err() {
+            break;
+        }
+        newlines += block.iter().filter(|t| **t == b'\n').count();
+        block.extend_from_slice(&tail);
+        tail = block;
+        pos = start;
+    }
+    //未读到文件开头时第一行不完整 最多取最后limit行时不会包含它
+    let text = String::from_utf8_lossy(&tail);
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(limit)..].iter().map(|t| t.to_string()).collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs())
+}
+
+
+#[test]
+fn test_sign_backoff()
+{
+    //RFC 4231 测试用例2
+    assert_eq!(sign("Jefe", b"what do ya want for nothing?"), "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    assert_eq!(backoff(0), Duration::from_secs(1));
+    assert_eq!(backoff(3), Duration::from_secs(8));
+    assert_eq!(backoff(10), RETRY_MAX);
+    assert_eq!(backoff(40), RETRY_MAX);
+}
+
+
+#[test]
+fn test_read_tail()
+{
+    let path = std::env::temp_dir().join(format!("dead-letter-{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    let lines: Vec<String> = (0..3000).map(|i| format!("{{\"n\":{}}}", i)).collect();
+    std::fs::write(path, lines.join("\n") + "\n").unwrap();
+    assert_eq!(read_tail(path, 3), vec!["{\"n\":2997}", "{\"n\":2998}", "{\"n\":2999}"]);
+    assert_eq!(read_tail(path, 2000), lines[1000..].to_vec());
+    assert_eq!(read_tail(path, 5000), lines);
+    assert!(read_tail(path, 0).is_empty());
+    let _ = std::fs::remove_file(path);
+    assert!(read_tail(path, 3).is_empty());
+}
+
+#[tokio::test]
+async fn test_delivery()
+{
+    use axum::{extract::State, http::{HeaderMap, StatusCode}, routing::post, Router};
+
+    //第一次请求返回500 之后按路径 /ok返回200 /fail一直返回500
+    #[derive(Default)]
+    struct Received {
+        ok: Vec<(String, bool)>,
+        fail: u32,
+    }
+    type Shared = Arc<std::sync::Mutex<Received>>;
+    async fn ok(State(received):State<Shared>, headers:HeaderMap, body:bytes::Bytes) -> StatusCode {
+        let signature = headers.get("X-Gw808-Signature").and_then(|t| t.to_str().ok()).and_then(|t| t.strip_prefix("sha256=")).and_then(|t| hex::decode(t).ok());
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(&body);
+        let verified = signature.is_some_and(|t| mac.verify_slice(&t).is_ok());
+        let mut received = received.lock().unwrap();
+        received.ok.push((String::from_utf8_lossy(&body).to_string(), verified));
+        if received.ok.len() == 1 { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK }
+    }
+    async fn fail(State(received):State<Shared>) -> StatusCode {
+        received.lock().unwrap().fail += 1;
+        StatusCode::BAD_GATEWAY
+    }
+
+    let received = Shared::default();
+    let app = Router::new().route("/ok", post(ok)).route("/fail", post(fail)).with_state(received.clone());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+
+    service_event::test_init();
+    let dead_letter = std::env::temp_dir().join(format!("webhook-{}.jsonl", std::process::id()));
+    let dead_letter = dead_letter.to_str().unwrap().to_string();
+    let sim = "013800000801";
+    let config = |path:&str| WebhookConfig { url: format!("http://{}{}", addr, path), types: "alarm".into(), sims: sim.into(), secret: "secret".into() };
+    init(vec![config("/ok"), config("/fail")], 1, 16, &dead_letter);
+    service_event::publish(DeviceEvent::new(sim, "position", &0));
+    service_event::publish(DeviceEvent::new(sim, "alarm", &serde_json::json!({"kind": "emergency"})));
+
+    //重试间隔1秒
+    let status = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let status = status();
+            if status[0].delivered == 1 && status[1].dead == 1 {
+                return status;
+            }
+        }
+    }).await.unwrap();
+    assert_eq!((status[0].failures, status[0].queued, status[1].failures, status[1].queued), (1, 0, 2, 0));
+    {
+        let received = received.lock().unwrap();
+        assert_eq!(received.ok.len(), 2);
+        assert_eq!(received.ok[0], received.ok[1]);
+        assert!(received.ok[1].1 && received.ok[1].0.contains("emergency"));
+        assert_eq!(received.fail, 2);
+    }
+
+    let letters = dead_letters(10).await;
+    let _ = std::fs::remove_file(&dead_letter);
+    assert_eq!(letters.len(), 1);
+    assert_eq!((letters[0]["url"].as_str(), letters[0]["attempts"].as_u64(), letters[0]["error"].as_str()), (Some(config("/fail").url.as_str()), Some(2), Some("status 502")));
+    assert_eq!(letters[0]["event"]["data"]["kind"], "emergency");
+}
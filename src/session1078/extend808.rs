@@ -0,0 +1,436 @@
+use bytes::Bytes;
+use chrono::{Local, TimeZone};
+use jt808::models::{Jt808, Jt808BodySerialize, Jt808BodyTrans, Ver808};
+use jt_util::{bytes::{IBuffRead, IBuffWrite}, bytes_gbk::BytesGBK};
+use serde::Serialize;
+
+use crate::session808::jt808_models::JtLocation;
+
+//jt1078库未实现的808扩展消息
+
+/// BCD[6]时间 0写全0 (jt1078库的datetime_to_bcd6按二进制写入)
+pub fn put_time_bcd6(buf:&mut dyn IBuffWrite, time:i64) {
+    match Local.timestamp_opt(time, 0).single() {
+        Some(dt) if time > 0 => buf.put_dt_bcd6(dt),
+        _ => buf.put_slice(&[0; 6]),
+    }
+}
+
+fn put_str(buf:&mut dyn IBuffWrite, s:&BytesGBK) {
+    buf.put_u8(s.bytes_len() as u8);
+    buf.put(s.get_bytes());
+}
+
+/// 文件上传指令
+#[derive(Debug, Default)]
+pub struct Jt0x9206 {
+    pub ipaddress: BytesGBK,
+    pub port: u16,
+    pub user: BytesGBK,
+    pub password: BytesGBK,
+    /// 文件上传路径
+    pub path: BytesGBK,
+    pub channel: u8,
+    pub starttime: i64,
+    pub endtime: i64,
+    pub alarm: u64,
+    /// 0:音视频 1:音频 2:视频 3:视频或音视频
+    pub media_type: u8,
+    /// 0:主码流或子码流 1:主码流 2:子码流
+    pub stream_type: u8,
+    /// 0:主存储器或灾备存储器 1:主存储器 2:灾备存储器
+    pub storage_type: u8,
+    /// 任务执行条件 bit0:WIFI bit1:LAN bit2:3G/4G
+    pub condition: u8,
+}
+
+impl Jt808BodySerialize for Jt0x9206 {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        put_str(buf, &self.ipaddress);
+        buf.put_u16(self.port);
+        put_str(buf, &self.user);
+        put_str(buf, &self.password);
+        put_str(buf, &self.path);
+        buf.put_u8(self.channel);
+        put_time_bcd6(buf, self.starttime);
+        put_time_bcd6(buf, self.endtime);
+        buf.put_u64(self.alarm);
+        buf.put_u8(self.media_type);
+        buf.put_u8(self.stream_type);
+        buf.put_u8(self.storage_type);
+        buf.put_u8(self.condition);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        self.ipaddress.bytes_len() + self.user.bytes_len() + self.password.bytes_len() + self.path.bytes_len() + 4 + 2 + 1 + 12 + 8 + 4
+    }
+}
+
+/// 文件上传控制
+#[derive(Debug, Default)]
+pub struct Jt0x9207 {
+    /// 对应0x9206的流水号
+    pub answer_sn: u16,
+    /// 0:暂停 1:继续 2:取消
+    pub control: u8,
+}
+
+impl Jt808BodySerialize for Jt0x9207 {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u16(self.answer_sn);
+        buf.put_u8(self.control);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        3
+    }
+}
+
+/// 文件上传完成通知
+#[derive(Debug, Default)]
+pub struct Jt0x1206 {
+    pub answer_sn: u16,
+    /// 0:成功 1:失败
+    pub result: u8,
+}
+
+impl Jt808BodyTrans for Jt0x1206 {
+    fn fill_new<T>(buf:&mut T, _jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        if buf.len() < 3 {
+            return Jt0x1206 { answer_sn: 0, result: 1 };
+        }
+        Jt0x1206 { answer_sn: buf.get_u16(), result: buf.get_u8() }
+    }
+}
+
+pub fn gbk(s:&str) -> BytesGBK {
+    BytesGBK::new_with_bytes(Bytes::from(s.to_string()))
+}
+
+/// 空消息体 如0x9003
+#[derive(Debug, Default)]
+pub struct JtEmpty {}
+
+impl Jt808BodySerialize for JtEmpty {
+    fn write(&mut self, _ver:&Ver808, _buf:&mut dyn IBuffWrite) {}
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        0
+    }
+}
+
+/// 终端上传音视频属性(0x9003的应答)
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Jt0x1003 {
+    /// 输入音频编码方式 见JT/T 1078表12
+    pub audio_codec: u8,
+    pub audio_codec_name: &'static str,
+    pub audio_channels: u8,
+    /// 采样率(Hz)
+    pub sample_rate: u32,
+    /// 采样位数
+    pub bit_depth: u8,
+    /// 音频帧长度
+    pub frame_length: u16,
+    /// 是否支持音频输出
+    pub audio_output: bool,
+    pub video_codec: u8,
+    pub video_codec_name: &'static str,
+    pub max_audio_channels: u8,
+    pub max_video_channels: u8,
+    /// 消息体长度正确 不足10字节时为false 其它字段为0
+    #[serde(skip)]
+    pub valid: bool,
+}
+
+impl Jt808BodyTrans for Jt0x1003 {
+    fn fill_new<T>(buf:&mut T, _jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        if buf.len() < 10 {
+            return Jt0x1003::default();
+        }
+        let audio_codec = buf.get_u8();
+        let audio_channels = buf.get_u8();
+        let sample_rate = match buf.get_u8() {
+            0 => 8000,
+            1 => 22050,
+            2 => 44100,
+            3 => 48000,
+            _ => 0,
+        };
+        let bit_depth = match buf.get_u8() {
+            0 => 8,
+            1 => 16,
+            2 => 32,
+            _ => 0,
+        };
+        let frame_length = buf.get_u16();
+        let audio_output = buf.get_u8() == 1;
+        let video_codec = buf.get_u8();
+        Jt0x1003 {
+            audio_codec,
+            audio_codec_name: codec_name(audio_codec),
+            audio_channels,
+            sample_rate,
+            bit_depth,
+            frame_length,
+            audio_output,
+            video_codec,
+            video_codec_name: codec_name(video_codec),
+            max_audio_channels: buf.get_u8(),
+            max_video_channels: buf.get_u8(),
+            valid: true,
+        }
+    }
+}
+
+/// 终端上传乘客流量
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Jt0x1005 {
+    pub starttime: i64,
+    pub endtime: i64,
+    /// 上车人数
+    pub boarding: u16,
+    /// 下车人数
+    pub alighting: u16,
+}
+
+impl Jt808BodyTrans for Jt0x1005 {
+    fn fill_new<T>(buf:&mut T, _jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        if buf.len() < 16 {
+            return Jt0x1005::default();
+        }
+        Jt0x1005 {
+            starttime: buf.get_dt_bcd6_timestamp(),
+            endtime: buf.get_dt_bcd6_timestamp(),
+            boarding: buf.get_u16(),
+            alighting: buf.get_u16(),
+        }
+    }
+}
+
+/// 音视频编码名称 JT/T 1078表12
+pub fn codec_name(pt:u8) -> &'static str {
+    match pt {
+        1 => "G.721",
+        2 => "G.722",
+        3 => "G.723",
+        4 => "G.728",
+        5 => "G.729",
+        6 => "G.711A",
+        7 => "G.711U",
+        8 => "G.726",
+        9 => "G.729A",
+        10 => "DVI4_3",
+        11 => "DVI4_4",
+        12 => "DVI4_8K",
+        13 => "DVI4_16K",
+        14 => "LPC",
+        15 => "S16BE_STEREO",
+        16 => "S16BE_MONO",
+        17 => "MPEGAUDIO",
+        18 => "LPCM",
+        19 => "AAC",
+        20 => "WMA9STD",
+        21 => "HEAAC",
+        22 => "PCM_VOICE",
+        23 => "PCM_AUDIO",
+        24 => "AACLC",
+        25 => "MP3",
+        26 => "ADPCMA",
+        27 => "MP4AUDIO",
+        28 => "AMR",
+        91 => "TRANSPARENT",
+        98 => "H.264",
+        99 => "H.265",
+        100 => "AVS",
+        101 => "SVAC",
+        _ => "UNKNOWN",
+    }
+}
+
+/// 云台旋转
+#[derive(Debug, Default)]
+pub struct Jt0x9301 {
+    pub channel: u8,
+    /// 0:停止 1:上 2:下 3:左 4:右
+    pub direction: u8,
+    pub speed: u8,
+}
+
+impl Jt808BodySerialize for Jt0x9301 {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u8(self.channel);
+        buf.put_u8(self.direction);
+        buf.put_u8(self.speed);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        3
+    }
+}
+
+/// 云台调整焦距/光圈/雨刷/红外补光/变倍 0x9302~0x9306
+#[derive(Debug, Default)]
+pub struct JtPtzControl {
+    pub channel: u8,
+    pub control: u8,
+}
+
+impl Jt808BodySerialize for JtPtzControl {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u8(self.channel);
+        buf.put_u8(self.control);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        2
+    }
+}
+
+/// 视频相关报警 由0x0200附加信息0x14~0x18解析
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VideoAlarm {
+    /// signal_loss occlusion storage_fault other_device_fault overcrowding abnormal_driving record_threshold
+    pub kind: &'static str,
+    /// 逻辑通道 仅信号丢失/遮挡
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    /// 存储器故障:main1~main12 backup1~backup4 异常驾驶:fatigue phone smoking custom11~custom15
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// 疲劳程度 0~100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<u8>,
+}
+
+impl VideoAlarm {
+    fn new(kind:&'static str) -> Self {
+        VideoAlarm { kind, channel: None, detail: None, level: None }
+    }
+}
+
+/// 解析0x0200中的视频报警 每个通道/存储器/行为一项
+pub fn video_alarms(location:&JtLocation) -> Vec<VideoAlarm> {
+    let flags = location.extra_u32(0x14).unwrap_or(0);
+    let mut alarms = Vec::new();
+
+    //0x15/0x16 按位表示逻辑通道1~32
+    for (bit, id, kind) in [(0, 0x15, "signal_loss"), (1, 0x16, "occlusion")] {
+        let channels = location.extra_u32(id).unwrap_or(0);
+        if channels == 0 {
+            if flags & (1 << bit) > 0 {
+                alarms.push(VideoAlarm::new(kind));
+            }
+            continue;
+        }
+        for i in 0..32 {
+            if channels & (1 << i) > 0 {
+                alarms.push(VideoAlarm { channel: Some(i as u8 + 1), ..VideoAlarm::new(kind) });
+            }
+        }
+    }
+
+    //0x17 位0~11主存储器 位12~15灾备存储器
+    let storages = location.extra_u32(0x17).unwrap_or(0);
+    if storages == 0 && flags & (1 << 2) > 0 {
+        alarms.push(VideoAlarm::new("storage_fault"));
+    }
+    for i in 0..16 {
+        if storages & (1 << i) > 0 {
+            let detail = if i < 12 { format!("main{}", i + 1) } else { format!("backup{}", i - 11) };
+            alarms.push(VideoAlarm { detail: Some(detail), ..VideoAlarm::new("storage_fault") });
+        }
+    }
+
+    if flags & (1 << 3) > 0 {
+        alarms.push(VideoAlarm::new("other_device_fault"));
+    }
+    if flags & (1 << 4) > 0 {
+        alarms.push(VideoAlarm::new("overcrowding"));
+    }
+
+    //0x18 WORD行为 位0:疲劳 1:打电话 2:抽烟 11~15:自定义 BYTE疲劳程度
+    match location.extra(0x18) {
+        Some(t) if t.len() >= 2 && u16::from_be_bytes([t[0], t[1]]) > 0 => {
+            let behaviors = u16::from_be_bytes([t[0], t[1]]);
+            for i in 0..16 {
+                if behaviors & (1 << i) == 0 {
+                    continue;
+                }
+                let (detail, level) = match i {
+                    0 => ("fatigue".to_string(), t.get(2).copied()),
+                    1 => ("phone".to_string(), None),
+                    2 => ("smoking".to_string(), None),
+                    _ => (format!("custom{}", i), None),
+                };
+                alarms.push(VideoAlarm { detail: Some(detail), level, ..VideoAlarm::new("abnormal_driving") });
+            }
+        },
+        _ => {
+            if flags & (1 << 5) > 0 {
+                alarms.push(VideoAlarm::new("abnormal_driving"));
+            }
+        },
+    }
+
+    if flags & (1 << 6) > 0 {
+        alarms.push(VideoAlarm::new("record_threshold"));
+    }
+    alarms
+}
+
+
+#[test]
+fn test_video_alarms()
+{
+    let mut location = JtLocation::default();
+    assert!(video_alarms(&location).is_empty());
+
+    location.extras.insert(0x14, Bytes::from_static(&[0x00, 0x00, 0x00, 0x35]));
+    location.extras.insert(0x15, Bytes::from_static(&[0x00, 0x00, 0x00, 0x05]));
+    location.extras.insert(0x17, Bytes::from_static(&[0x10, 0x00]));
+    location.extras.insert(0x18, Bytes::from_static(&[0x00, 0x03, 0x50]));
+    let alarms = video_alarms(&location);
+    let kinds: Vec<(&str, Option<u8>, Option<&str>)> = alarms.iter().map(|t| (t.kind, t.channel, t.detail.as_deref())).collect();
+    assert_eq!(kinds, vec![
+        ("signal_loss", Some(1), None),
+        ("signal_loss", Some(3), None),
+        ("storage_fault", None, Some("backup1")),
+        ("overcrowding", None, None),
+        ("abnormal_driving", None, Some("fatigue")),
+        ("abnormal_driving", None, Some("phone")),
+    ]);
+    assert_eq!(alarms[4].level, Some(80));
+}
+
+#[test]
+fn test_0x1003()
+{
+    use bytes::BytesMut;
+    use crate::session808::jt808_parse::{jt808_escape, Jt808DeserializeAndPackUp};
+
+    let parse_body = |body:&[u8]| {
+        let mut content = vec![0x10, 0x03, 0x00, body.len() as u8, 0x01, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+        content.extend_from_slice(body);
+        let mut buf = BytesMut::from(&jt808_escape(&content)[..]);
+        let mut parse = Jt808DeserializeAndPackUp::new();
+        parse.deserialize(&mut buf).ok().flatten().unwrap().trans_body::<Jt0x1003>()
+    };
+
+    let attr = parse_body(&[8, 1, 0, 1, 0x01, 0x40, 1, 98, 1, 4]);
+    assert!(attr.valid);
+    assert_eq!((attr.audio_codec_name, attr.sample_rate, attr.bit_depth, attr.frame_length), ("G.726", 8000, 16, 320));
+    assert!(attr.audio_output);
+    assert_eq!((attr.video_codec, attr.max_audio_channels, attr.max_video_channels), (98, 1, 4));
+
+    assert!(!parse_body(&[8, 1, 0, 1]).valid);
+}
@@ -0,0 +1,481 @@
+use bytes::{Buf, Bytes, BytesMut, BufMut};
+use jt1078::Jt1078CodecError;
+
+const HEAD1078: [u8; 4] = [0x30, 0x31, 0x63, 0x64];
+//单帧最大字节数 超出时丢弃该帧
+const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// 1078 RTP包
+#[derive(Debug, Clone)]
+pub struct Jt1078Package {
+    /// 2019版 sim 10字节
+    pub v19: bool,
+    /// 完整帧边界标记
+    pub m: bool,
+    /// 负载类型 98:H.264 99:H.265 6:G.711A 7:G.711U 26:ADPCMA 19:AAC
+    pub pt: u8,
+    /// 包序号
+    pub sn: u16,
+    pub sim: String,
+    /// 逻辑通道号
+    pub channel: u8,
+    /// 数据类型 0:I帧 1:P帧 2:B帧 3:音频帧 4:透传数据
+    pub data_type: u8,
+    /// 分包处理标记 0:原子包 1:第一个分包 2:最后一个分包 3:中间分包
+    pub sub_flag: u8,
+    /// 时间戳(毫秒) 透传数据没有
+    pub time_stamp: u64,
+    /// 与上一关键帧的时间间隔 仅视频帧
+    pub last_i_interval: u16,
+    /// 与上一帧的时间间隔 仅视频帧
+    pub last_interval: u16,
+    pub body: Bytes,
+}
+
+impl Jt1078Package {
+    pub fn is_video(&self) -> bool {
+        self.data_type <= 2
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.data_type == 3
+    }
+
+    //包头长度 不含数据体长度字段
+    fn head_len(v19:bool, data_type:u8) -> usize {
+        let sim_len = if v19 { 10 } else { 6 };
+        let base = 10 + sim_len;
+        match data_type {
+            0..=2 => base + 12,
+            3 => base + 8,
+            _ => base,
+        }
+    }
+
+    //按指定版本计算完整包长度 数据不足时返回None
+    fn package_len(buf:&[u8], v19:bool) -> Option<usize> {
+        let flag_index = if v19 { 19 } else { 15 };
+        let data_type = buf.get(flag_index)? >> 4;
+        //逻辑通道从1开始
+        if data_type > 4 || buf[flag_index] & 0x0f > 3 || buf[flag_index - 1] == 0 {
+            return None;
+        }
+        let len_index = Self::head_len(v19, data_type);
+        let dlen = u16::from_be_bytes([*buf.get(len_index)?, *buf.get(len_index + 1)?]) as usize;
+        Some(len_index + 2 + dlen)
+    }
+
+    //解析完整包
+    pub fn parse(mut buf:Bytes, v19:bool) -> Self {
+        buf.advance(4);
+        let _vpxcc = buf.get_u8();
+        let mpt = buf.get_u8();
+        let sn = buf.get_u16();
+        let sim = hex::encode(buf.split_to(if v19 { 10 } else { 6 }));
+        let channel = buf.get_u8();
+        let flag = buf.get_u8();
+        let data_type = flag >> 4;
+
+        let mut package = Jt1078Package {
+            v19,
+            m: mpt & 0x80 > 0,
+            pt: mpt & 0x7f,
+            sn,
+            sim,
+            channel,
+            data_type,
+            sub_flag: flag & 0x0f,
+            time_stamp: 0,
+            last_i_interval: 0,
+            last_interval: 0,
+            body: Bytes::new(),
+        };
+
+        if data_type != 4 {
+            package.time_stamp = buf.get_u64();
+        }
+        if data_type <= 2 {
+            package.last_i_interval = buf.get_u16();
+            package.last_interval = buf.get_u16();
+        }
+        let dlen = buf.get_u16() as usize;
+        package.body = buf.split_to(dlen);
+        package
+    }
+
+    /// 打包 用于下发音频等
+    pub fn serialize(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(Self::head_len(self.v19, self.data_type) + 2 + self.body.len());
+        buf.put_slice(&HEAD1078);
+        buf.put_u8(0x81);
+        buf.put_u8(if self.m { 0x80 } else { 0 } | (self.pt & 0x7f));
+        buf.put_u16(self.sn);
+        let sim_len = if self.v19 { 10 } else { 6 };
+        let sim = hex::decode(format!("{:0>width$}", self.sim, width = sim_len * 2)).unwrap_or_default();
+        buf.put_slice(&sim[sim.len().saturating_sub(sim_len)..]);
+        buf.put_u8(self.channel);
+        buf.put_u8((self.data_type << 4) | (self.sub_flag & 0x0f));
+        if self.data_type != 4 {
+            buf.put_u64(self.time_stamp);
+        }
+        if self.data_type <= 2 {
+            buf.put_u16(self.last_i_interval);
+            buf.put_u16(self.last_interval);
+        }
+        buf.put_u16(self.body.len() as u16);
+        buf.put_slice(&self.body);
+        buf.freeze()
+    }
+}
+
+/// 1078流解析 首包自动判断2013/2019版
+pub struct Jt1078Deserialize {
+    v19: Option<bool>,
+}
+
+impl Jt1078Deserialize {
+
+    pub fn new() -> Self {
+        Jt1078Deserialize {
+            v19: None,
+        }
+    }
+
+    /// is_end: buf是否为完整数据(UDP) 为false时判断版本需要下一个包头
+    pub fn deserialize(&mut self, buf:&mut BytesMut, is_end:bool) -> Result<Option<Jt1078Package>, Jt1078CodecError> {
+
+        loop {
+            //查找包头
+            match buf.windows(4).position(|t| t == HEAD1078) {
+                Some(0) => {},
+                Some(index) => {
+                    buf.advance(index);
+                },
+                None => {
+                    let keep = std::cmp::min(buf.len(), 3);
+                    buf.advance(buf.len() - keep);
+                    return Ok(None);
+                }
+            }
+
+            let v19 = match self.v19 {
+                Some(v19) => v19,
+                None => {
+                    match self.detect(buf, is_end) {
+                        Some(v19) => {
+                            self.v19 = Some(v19);
+                            v19
+                        },
+                        None => {
+                            if buf.len() > 2048 || is_end {
+                                return Err(Jt1078CodecError::No1078);
+                            }
+                            return Ok(None);
+                        }
+                    }
+                }
+            };
+
+            match Jt1078Package::package_len(buf, v19) {
+                Some(len) if buf.len() >= len => {
+                    let data = buf.split_to(len).freeze();
+                    return Ok(Some(Jt1078Package::parse(data, v19)));
+                },
+                Some(_) => return Ok(None),
+                None if buf.len() < 34 => return Ok(None),
+                None => {
+                    //包头错误 跳过重新查找
+                    buf.advance(4);
+                },
+            }
+        }
+    }
+
+    //包长度与下一个包头(或数据结尾)吻合的版本
+    fn detect(&self, buf:&[u8], is_end:bool) -> Option<bool> {
+        for v19 in [false, true] {
+            if let Some(len) = Jt1078Package::package_len(buf, v19) {
+                if is_end && buf.len() == len {
+                    return Some(v19);
+                }
+                if buf.len() >= len + 4 && buf[len..len + 4] == HEAD1078 {
+                    return Some(v19);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Default for Jt1078Deserialize {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 分包合并后的完整帧
+#[derive(Debug, Clone)]
+pub struct MediaFrame {
+    pub sim: String,
+    pub channel: u8,
+    pub pt: u8,
+    pub data_type: u8,
+    pub time_stamp: u64,
+    pub last_i_interval: u16,
+    pub last_interval: u16,
+    pub data: Bytes,
+}
+
+impl MediaFrame {
+    pub fn is_video(&self) -> bool {
+        self.data_type <= 2
+    }
+
+    pub fn is_key(&self) -> bool {
+        self.data_type == 0
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.data_type == 3
+    }
+}
+
+/// 单个(sim,通道)的分包合并 音频一般为原子包 可穿插在视频分包之间
+pub struct Jt1078FrameMerger {
+    first: Option<Jt1078Package>,
+    data: BytesMut,
+    //上一个包的序号
+    last_sn: Option<u16>,
+}
+
+impl Jt1078FrameMerger {
+    pub fn new() -> Self {
+        Jt1078FrameMerger {
+            first: None,
+            data: BytesMut::new(),
+            last_sn: None,
+        }
+    }
+
+    pub fn merge(&mut self, package:Jt1078Package) -> Option<MediaFrame> {
+        //包序号不连续 可能丢了分包
+        if matches!(self.last_sn, Some(sn) if sn.wrapping_add(1) != package.sn) {
+            self.reset();
+        }
+        self.last_sn = Some(package.sn);
+
+        match package.sub_flag {
+            0 => {
+                //同类型原子包打断了未完成的分包
+                if matches!(&self.first, Some(first) if first.data_type == package.data_type) {
+                    self.reset();
+                }
+                Some(Self::to_frame(&package, package.body.clone()))
+            },
+            1 => {
+                self.reset();
+                if package.body.len() > MAX_FRAME_SIZE {
+                    return None;
+                }
+                self.data.put_slice(&package.body);
+                self.first = Some(package);
+                None
+            },
+            flag => {
+                //缺少第一个分包或类型不一致时丢弃
+                match self.first.as_ref() {
+                    Some(first) if first.data_type == package.data_type => {},
+                    _ => {
+                        self.reset();
+                        return None;
+                    },
+                }
+                if self.data.len() + package.body.len() > MAX_FRAME_SIZE {
+                    self.reset();
+                    return None;
+                }
+                self.data.put_slice(&package.body);
+
+                if flag == 2 {
+                    let first = self.first.take()?;
+                    let data = self.data.split().freeze();
+                    return Some(Self::to_frame(&first, data));
+                }
+                None
+            }
+        }
+    }
+
+    //丢弃未完成的分包
+    fn reset(&mut self) {
+        self.first = None;
+        self.data = BytesMut::new();
+    }
+
+    fn to_frame(package:&Jt1078Package, data:Bytes) -> MediaFrame {
+        MediaFrame {
+            sim: package.sim.clone(),
+            channel: package.channel,
+            pt: package.pt,
+            data_type: package.data_type,
+            time_stamp: package.time_stamp,
+            last_i_interval: package.last_i_interval,
+            last_interval: package.last_interval,
+            data,
+        }
+    }
+}
+
+impl Default for Jt1078FrameMerger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[test]
+fn test_deserialize_and_merge()
+{
+    let mut package = Jt1078Package {
+        v19: false,
+        m: false,
+        pt: 98,
+        sn: 1,
+        sim: "013800000000".to_string(),
+        channel: 1,
+        data_type: 0,
+        sub_flag: 1,
+        time_stamp: 1000,
+        last_i_interval: 0,
+        last_interval: 0,
+        body: Bytes::from_static(b"abc"),
+    };
+    let mut buf = BytesMut::new();
+    buf.put(package.serialize());
+    package.sn = 2;
+    package.sub_flag = 2;
+    package.body = Bytes::from_static(b"def");
+    buf.put(package.serialize());
+    package.sn = 3;
+    package.sub_flag = 0;
+    package.data_type = 3;
+    package.pt = 6;
+    package.body = Bytes::from_static(b"au");
+    buf.put(package.serialize());
+
+    let mut parse = Jt1078Deserialize::new();
+    let mut merger = Jt1078FrameMerger::new();
+    let mut frames = Vec::new();
+    while let Some(package) = parse.deserialize(&mut buf, true).unwrap() {
+        assert_eq!(package.sim, "013800000000");
+        if let Some(frame) = merger.merge(package) {
+            frames.push(frame);
+        }
+    }
+    assert_eq!(frames.len(), 2);
+    assert!(frames[0].is_key());
+    assert_eq!(frames[0].data.as_ref(), b"abcdef");
+    assert_eq!(frames[0].time_stamp, 1000);
+    assert!(frames[1].is_audio());
+    assert_eq!(frames[1].data.as_ref(), b"au");
+}
+
+#[test]
+fn test_parse_v19()
+{
+    let mut head = jt1078::JtRtpHead::new(true);
+    head.set_sim("00000000013800000000".to_string());
+    head.set_channel(2);
+    head.set_xxxx(3);
+    head.set_yyyy(0);
+    head.set_pt(6);
+    head.set_time_stamp(40);
+    head.set_dlen(2);
+
+    let mut buf = BytesMut::from(head.get_buf());
+    buf.put_slice(b"au");
+
+    let package = Jt1078Deserialize::new().deserialize(&mut buf, true).unwrap().unwrap();
+    assert!(package.v19);
+    assert!(package.is_audio());
+    assert_eq!(package.sim, "00000000013800000000");
+    assert_eq!(package.channel, 2);
+    assert_eq!(package.time_stamp, 40);
+    assert_eq!(package.body.as_ref(), b"au");
+}
+
+#[test]
+fn test_skip_bad_heads()
+{
+    let package = Jt1078Package {
+        v19: false,
+        m: false,
+        pt: 6,
+        sn: 1,
+        sim: "013800000000".to_string(),
+        channel: 1,
+        data_type: 3,
+        sub_flag: 0,
+        time_stamp: 40,
+        last_i_interval: 0,
+        last_interval: 0,
+        body: Bytes::from_static(b"au"),
+    };
+    let mut parse = Jt1078Deserialize::new();
+    let mut buf = BytesMut::from(&package.serialize()[..]);
+    assert!(parse.deserialize(&mut buf, true).unwrap().is_some());
+
+    //大量错误包头不能导致栈溢出
+    for _ in 0..500000 {
+        buf.put_slice(&HEAD1078);
+        buf.put_bytes(0xff, 32);
+    }
+    buf.put(package.serialize());
+    let next = parse.deserialize(&mut buf, false).unwrap().unwrap();
+    assert_eq!(next.body.as_ref(), b"au");
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_merge_reset()
+{
+    let package = |sn:u16, sub_flag:u8, data_type:u8, body:Vec<u8>| Jt1078Package {
+        v19: false,
+        m: false,
+        pt: if data_type == 3 { 6 } else { 98 },
+        sn,
+        sim: "013800000000".to_string(),
+        channel: 1,
+        data_type,
+        sub_flag,
+        time_stamp: 1000,
+        last_i_interval: 0,
+        last_interval: 0,
+        body: Bytes::from(body),
+    };
+
+    let mut merger = Jt1078FrameMerger::new();
+    //丢了中间分包 整帧丢弃
+    assert!(merger.merge(package(1, 1, 0, b"ab".to_vec())).is_none());
+    assert!(merger.merge(package(3, 2, 0, b"cd".to_vec())).is_none());
+
+    //音频原子包穿插不影响视频分包
+    assert!(merger.merge(package(4, 1, 0, b"ab".to_vec())).is_none());
+    assert!(merger.merge(package(5, 0, 3, b"au".to_vec())).is_some());
+    assert!(merger.merge(package(6, 3, 0, b"cd".to_vec())).is_none());
+    let frame = merger.merge(package(7, 2, 0, b"ef".to_vec())).unwrap();
+    assert_eq!(frame.data.as_ref(), b"abcdef");
+
+    //同类型原子包打断分包
+    assert!(merger.merge(package(8, 1, 1, b"ab".to_vec())).is_none());
+    assert!(merger.merge(package(9, 0, 1, b"xy".to_vec())).is_some());
+    assert!(merger.merge(package(10, 2, 1, b"cd".to_vec())).is_none());
+
+    //超过最大帧长
+    let half = MAX_FRAME_SIZE / 2 + 1;
+    assert!(merger.merge(package(11, 1, 0, vec![0; half])).is_none());
+    assert!(merger.merge(package(12, 3, 0, vec![0; half])).is_none());
+    assert!(merger.merge(package(13, 2, 0, b"ef".to_vec())).is_none());
+    assert!(merger.merge(package(14, 1, 0, b"ab".to_vec())).is_none());
+    assert_eq!(merger.merge(package(15, 2, 0, b"cd".to_vec())).unwrap().data.as_ref(), b"abcd");
+}
@@ -0,0 +1,2 @@
+pub mod jt1078_parse;
+pub mod extend808;
@@ -0,0 +1,322 @@
+use bytes::Bytes;
+use jt808::{bytes::JtBytes, models::{Jt808, Jt808BodySerialize, Jt808BodyTrans, Ver808}};
+use jt_util::bytes::{IBuffRead, IBuffWrite};
+use serde::Serialize;
+
+use super::jt808_models::JtLocation;
+
+//主动安全(T/JSATL12) 0x0200附加信息0x64~0x67 报警附件0x9208/0x1210/0x1211/0x1212/0x9212
+//终端ID按7字节(2017版)
+
+/// 报警标识号 终端ID[7] 时间BCD[6] 序号 附件数量 预留
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct AlarmIdentifier {
+    pub terminal_id: String,
+    pub time: i64,
+    pub sn: u8,
+    pub attachments: u8,
+    /// 原始16字节 0x9208原样下发
+    #[serde(skip)]
+    pub raw: Bytes,
+}
+
+impl AlarmIdentifier {
+    fn read(buf:&mut JtBytes) -> Self {
+        let raw = buf.split_to(16);
+        let mut t = JtBytes::from(raw.clone());
+        let terminal_id = read_ascii(&t.split_to(7));
+        let time = t.get_dt_bcd6_timestamp();
+        AlarmIdentifier { terminal_id, time, sn: t.get_u8(), attachments: t.get_u8(), raw }
+    }
+}
+
+/// 主动安全报警
+#[derive(Debug, Clone, Serialize)]
+pub struct SafetyAlarm {
+    /// adas dsm tpms bsd
+    pub source: &'static str,
+    pub alarm_id: u32,
+    /// 0:不可用 1:开始 2:结束
+    pub flag: u8,
+    pub alarm_type: u8,
+    pub kind: &'static str,
+    /// 1:一级 2:二级
+    pub level: u8,
+    /// 车速(km/h)
+    pub speed: u8,
+    pub altitude: u16,
+    pub lat: f64,
+    pub lng: f64,
+    pub time: i64,
+    pub vehicle_state: u16,
+    pub identifier: AlarmIdentifier,
+    /// 各类报警特有的字段
+    pub detail: serde_json::Value,
+}
+
+/// 解析0x0200中的主动安全报警 长度不足的项忽略
+pub fn safety_alarms(location:&JtLocation) -> Vec<SafetyAlarm> {
+    let mut alarms = Vec::new();
+    for (id, source, min_len) in [(0x64, "adas", 47), (0x65, "dsm", 47), (0x66, "tpms", 41), (0x67, "bsd", 41)] {
+        let data = match location.extra(id) {
+            Some(data) if data.len() >= min_len => data.clone(),
+            _ => continue,
+        };
+        let mut buf = JtBytes::from(data);
+        let alarm_id = buf.get_u32();
+        let flag = buf.get_u8();
+
+        let (alarm_type, level, mut detail) = match id {
+            0x64 => {
+                let alarm_type = buf.get_u8();
+                let level = buf.get_u8();
+                let detail = serde_json::json!({
+                    "front_speed": buf.get_u8(),
+                    "front_distance": buf.get_u8(),
+                    "deviation_type": buf.get_u8(),
+                    "road_sign_type": buf.get_u8(),
+                    "road_sign_data": buf.get_u8(),
+                });
+                (alarm_type, level, detail)
+            },
+            0x65 => {
+                let alarm_type = buf.get_u8();
+                let level = buf.get_u8();
+                let fatigue_level = buf.get_u8();
+                buf.split_to(4);
+                (alarm_type, level, serde_json::json!({ "fatigue_level": fatigue_level }))
+            },
+            0x66 => (0, 0, serde_json::Value::Null),
+            _ => (buf.get_u8(), 0, serde_json::Value::Null),
+        };
+
+        let speed = buf.get_u8();
+        let altitude = buf.get_u16();
+        let lat = buf.get_u32() as f64 / 1e6;
+        let lng = buf.get_u32() as f64 / 1e6;
+        let time = buf.get_dt_bcd6_timestamp();
+        let vehicle_state = buf.get_u16();
+        let identifier = AlarmIdentifier::read(&mut buf);
+
+        //胎压 事件列表 胎位 报警类型 胎压(kPa) 胎温(℃) 电池电量(%)
+        if id == 0x66 {
+            let count = buf.get_u8() as usize;
+            let mut events = Vec::new();
+            while events.len() < count && buf.len() >= 9 {
+                events.push(serde_json::json!({
+                    "position": buf.get_u8(),
+                    "alarm_type": buf.get_u16(),
+                    "pressure": buf.get_u16(),
+                    "temperature": buf.get_u16(),
+                    "battery": buf.get_u16(),
+                }));
+            }
+            detail = serde_json::json!({ "events": events });
+        }
+
+        alarms.push(SafetyAlarm {
+            source,
+            alarm_id,
+            flag,
+            alarm_type,
+            kind: alarm_kind(id, alarm_type),
+            level,
+            speed,
+            altitude,
+            lat,
+            lng,
+            time,
+            vehicle_state,
+            identifier,
+            detail,
+        });
+    }
+    alarms
+}
+
+fn alarm_kind(id:u8, alarm_type:u8) -> &'static str {
+    match (id, alarm_type) {
+        (0x64, 0x01) => "forward_collision",
+        (0x64, 0x02) => "lane_departure",
+        (0x64, 0x03) => "headway",
+        (0x64, 0x04) => "pedestrian_collision",
+        (0x64, 0x05) => "frequent_lane_change",
+        (0x64, 0x06) => "road_sign_overlimit",
+        (0x64, 0x07) => "obstacle",
+        (0x64, 0x10) => "road_sign_recognition",
+        (0x64, 0x11) => "active_capture",
+        (0x65, 0x01) => "fatigue",
+        (0x65, 0x02) => "phone",
+        (0x65, 0x03) => "smoking",
+        (0x65, 0x04) => "distraction",
+        (0x65, 0x05) => "driver_abnormal",
+        (0x65, 0x10) => "auto_capture",
+        (0x65, 0x11) => "driver_change",
+        (0x66, _) => "tire",
+        (0x67, 0x01) => "rear_approach",
+        (0x67, 0x02) => "left_rear_approach",
+        (0x67, 0x03) => "right_rear_approach",
+        _ => "custom",
+    }
+}
+
+fn read_ascii(data:&[u8]) -> String {
+    String::from_utf8_lossy(data).trim_end_matches('\0').trim().to_string()
+}
+
+fn put_fixed(buf:&mut dyn IBuffWrite, data:&[u8], len:usize) {
+    let n = data.len().min(len);
+    buf.put_slice(&data[..n]);
+    buf.put_slice(&vec![0; len - n]);
+}
+
+/// 报警附件上传指令
+#[derive(Debug, Default)]
+pub struct Jt0x9208 {
+    pub ipaddress: String,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+    pub identifier: Bytes,
+    /// 平台分配的报警编号 32字节
+    pub alarm_number: String,
+}
+
+impl Jt808BodySerialize for Jt0x9208 {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u8(self.ipaddress.len() as u8);
+        buf.put_slice(self.ipaddress.as_bytes());
+        buf.put_u16(self.tcp_port);
+        buf.put_u16(self.udp_port);
+        put_fixed(buf, &self.identifier, 16);
+        put_fixed(buf, self.alarm_number.as_bytes(), 32);
+        buf.put_slice(&[0; 16]);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        1 + self.ipaddress.len() + 4 + 16 + 32 + 16
+    }
+}
+
+/// 报警附件信息
+#[derive(Debug, Default)]
+pub struct Jt0x1210 {
+    pub terminal_id: String,
+    pub identifier: Bytes,
+    pub alarm_number: String,
+    /// 0:正常报警文件信息 1:补传报警文件信息
+    pub info_type: u8,
+    /// (文件名, 大小)
+    pub files: Vec<(String, u32)>,
+}
+
+impl Jt808BodyTrans for Jt0x1210 {
+    fn fill_new<T>(buf:&mut T, _jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        if buf.len() < 57 {
+            return Jt0x1210::default();
+        }
+        let terminal_id = read_ascii(&buf.split_to(7));
+        let identifier = buf.split_to(16);
+        let alarm_number = read_ascii(&buf.split_to(32));
+        let info_type = buf.get_u8();
+        let count = buf.get_u8() as usize;
+        let mut files = Vec::new();
+        while files.len() < count && buf.len() >= 1 {
+            let len = buf.get_u8() as usize;
+            if buf.len() < len + 4 {
+                break;
+            }
+            let name = read_ascii(&buf.split_to(len));
+            files.push((name, buf.get_u32()));
+        }
+        Jt0x1210 { terminal_id, identifier, alarm_number, info_type, files }
+    }
+}
+
+/// 文件信息上传(0x1211)/文件上传完成(0x1212)
+#[derive(Debug, Default)]
+pub struct JtAttachmentFile {
+    pub name: String,
+    /// 0:图片 1:音频 2:视频 3:文本 4:其它
+    pub file_type: u8,
+    pub size: u32,
+}
+
+impl Jt808BodyTrans for JtAttachmentFile {
+    fn fill_new<T>(buf:&mut T, _jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        let len = match buf.get(0) {
+            Some(len) => *len as usize,
+            None => return JtAttachmentFile::default(),
+        };
+        if buf.len() < 1 + len + 5 {
+            return JtAttachmentFile::default();
+        }
+        buf.get_u8();
+        let name = read_ascii(&buf.split_to(len));
+        JtAttachmentFile { name, file_type: buf.get_u8(), size: buf.get_u32() }
+    }
+}
+
+/// 文件上传完成消息应答
+#[derive(Debug, Default)]
+pub struct Jt0x9212 {
+    pub name: String,
+    pub file_type: u8,
+    /// 0:完成 1:需要补传
+    pub result: u8,
+    /// 补传数据包 (偏移, 长度)
+    pub missing: Vec<(u32, u32)>,
+}
+
+impl Jt808BodySerialize for Jt0x9212 {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u8(self.name.len() as u8);
+        buf.put_slice(self.name.as_bytes());
+        buf.put_u8(self.file_type);
+        buf.put_u8(self.result);
+        let missing = &self.missing[..self.missing.len().min(255)];
+        buf.put_u8(missing.len() as u8);
+        for (offset, len) in missing {
+            buf.put_u32(*offset);
+            buf.put_u32(*len);
+        }
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        1 + self.name.len() + 3 + self.missing.len().min(255) * 8
+    }
+}
+
+
+#[test]
+fn test_safety_alarms()
+{
+    let mut adas = Vec::new();
+    adas.extend_from_slice(&7u32.to_be_bytes());
+    adas.extend_from_slice(&[1, 0x02, 1, 0, 0, 1, 0, 0, 60]);
+    adas.extend_from_slice(&[0x00, 0x10]);
+    adas.extend_from_slice(&22_543_100u32.to_be_bytes());
+    adas.extend_from_slice(&114_057_900u32.to_be_bytes());
+    adas.extend_from_slice(&[0x24, 0x01, 0x02, 0x03, 0x04, 0x05, 0x00, 0x01]);
+    adas.extend_from_slice(b"T000001");
+    adas.extend_from_slice(&[0x24, 0x01, 0x02, 0x03, 0x04, 0x05, 3, 2, 0]);
+    assert_eq!(adas.len(), 47);
+
+    let mut location = JtLocation::default();
+    location.extras.insert(0x64, Bytes::from(adas));
+    //长度不足忽略
+    location.extras.insert(0x65, Bytes::from_static(&[0; 10]));
+    let alarms = safety_alarms(&location);
+    assert_eq!(alarms.len(), 1);
+    let alarm = &alarms[0];
+    assert_eq!((alarm.source, alarm.kind, alarm.alarm_id, alarm.flag, alarm.level, alarm.speed), ("adas", "lane_departure", 7, 1, 1, 60));
+    assert_eq!(alarm.detail["deviation_type"], 1);
+    assert_eq!(alarm.identifier.terminal_id, "T000001");
+    assert_eq!((alarm.identifier.sn, alarm.identifier.attachments), (3, 2));
+    assert_eq!(alarm.identifier.raw.len(), 16);
+}
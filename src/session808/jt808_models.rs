@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use jt808::models::{Jt808, Jt808BodySerialize, Jt808BodyTrans, Ver808};
+use jt_util::{bytes::{IBuffRead, IBuffWrite}, bytes_gbk::BytesGBK};
+use serde::Serialize;
+
+//jt808库未实现或解析有误的消息
+//库中JtGpsExtend解析附加信息时未跳过长度字节 且不可访问 这里重新解析
+
+/// 位置信息汇报 0x0200
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct JtLocation {
+    pub alarm: u32,
+    pub state: u32,
+    /// 纬度(度) 南纬为负
+    pub lat: f64,
+    /// 经度(度) 西经为负
+    pub lng: f64,
+    pub altitude: u16,
+    /// 速度(km/h)
+    pub speed: f64,
+    pub direction: u16,
+    /// unix秒
+    pub time: i64,
+    /// 附加信息 ID->内容
+    #[serde(skip)]
+    pub extras: HashMap<u8, Bytes>,
+    /// 消息体长度正确 不足28字节时为false 其它字段为0
+    #[serde(skip)]
+    pub valid: bool,
+}
+
+impl JtLocation {
+    pub fn extra(&self, id:u8) -> Option<&Bytes> {
+        self.extras.get(&id)
+    }
+
+    /// 附加信息按大端整数读取 长度不足时返回None
+    pub fn extra_u32(&self, id:u8) -> Option<u32> {
+        let t = self.extras.get(&id)?;
+        match t.len() {
+            1 => Some(t[0] as u32),
+            2 => Some(u16::from_be_bytes([t[0], t[1]]) as u32),
+            n if n >= 4 => Some(u32::from_be_bytes([t[0], t[1], t[2], t[3]])),
+            _ => None,
+        }
+    }
+}
+
+impl Jt808BodyTrans for JtLocation {
+    fn fill_new<T>(buf:&mut T, _jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        if buf.len() < 28 {
+            return JtLocation::default();
+        }
+        let alarm = buf.get_u32();
+        let state = buf.get_u32();
+        let mut lat = buf.get_u32() as f64 / 1e6;
+        let mut lng = buf.get_u32() as f64 / 1e6;
+        //状态位2:南纬 位3:西经
+        if state & 0x04 > 0 {
+            lat = -lat;
+        }
+        if state & 0x08 > 0 {
+            lng = -lng;
+        }
+        let altitude = buf.get_u16();
+        let speed = buf.get_u16() as f64 / 10.0;
+        let direction = buf.get_u16();
+        let time = buf.get_dt_bcd6_timestamp();
+
+        let mut extras = HashMap::new();
+        while buf.len() >= 2 {
+            let id = buf.get_u8();
+            let len = buf.get_u8() as usize;
+            if buf.len() < len {
+                break;
+            }
+            extras.insert(id, buf.split_to(len));
+        }
+
+        JtLocation { alarm, state, lat, lng, altitude, speed, direction, time, extras, valid: true }
+    }
+}
+
+/// 定位数据批量上传
+#[derive(Debug, Default)]
+pub struct Jt0x0704 {
+    /// 0:正常位置批量汇报 1:盲区补报
+    pub data_type: u8,
+    pub locations: Vec<JtLocation>,
+}
+
+impl Jt808BodyTrans for Jt0x0704 {
+    fn fill_new<T>(buf:&mut T, jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        if buf.len() < 3 {
+            return Jt0x0704::default();
+        }
+        let count = buf.get_u16() as usize;
+        let data_type = buf.get_u8();
+        let mut locations = Vec::new();
+        while locations.len() < count && buf.len() >= 2 {
+            let len = buf.get_u16() as usize;
+            if buf.len() < len {
+                break;
+            }
+            let mut item = jt808::bytes::JtBytes::from(buf.split_to(len));
+            let location = JtLocation::fill_new(&mut item, jt808);
+            if location.valid {
+                locations.push(location);
+            }
+        }
+        Jt0x0704 { data_type, locations }
+    }
+}
+
+/// 摄像头立即拍摄命令
+#[derive(Debug, Default)]
+pub struct Jt0x8801 {
+    pub channel: u8,
+    /// 0:停止拍摄 0xFFFF:录像 其它:拍照张数
+    pub command: u16,
+    /// 拍照间隔/录像时间(秒)
+    pub interval: u16,
+    /// 1:保存 0:实时上传
+    pub save: u8,
+    /// 1:320*240 2:640*480 3:800*600 4:1024*768 ...
+    pub resolution: u8,
+    /// 1~10 1代表质量损失最小
+    pub quality: u8,
+    pub brightness: u8,
+    pub contrast: u8,
+    pub saturation: u8,
+    pub chroma: u8,
+}
+
+impl Jt808BodySerialize for Jt0x8801 {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u8(self.channel);
+        buf.put_u16(self.command);
+        buf.put_u16(self.interval);
+        buf.put_u8(self.save);
+        buf.put_u8(self.resolution);
+        buf.put_u8(self.quality);
+        buf.put_u8(self.brightness);
+        buf.put_u8(self.contrast);
+        buf.put_u8(self.saturation);
+        buf.put_u8(self.chroma);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        12
+    }
+}
+
+/// 摄像头立即拍摄命令应答
+#[derive(Debug, Default)]
+pub struct Jt0x0805 {
+    pub answer_sn: u16,
+    /// 0:成功 1:失败 2:通道不支持
+    pub result: u8,
+    /// 多媒体ID 之后由0x0801上传
+    pub ids: Vec<u32>,
+}
+
+impl Jt808BodyTrans for Jt0x0805 {
+    fn fill_new<T>(buf:&mut T, _jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        if buf.len() < 3 {
+            return Jt0x0805 { answer_sn: 0, result: 1, ids: Vec::new() };
+        }
+        let answer_sn = buf.get_u16();
+        let result = buf.get_u8();
+        let mut ids = Vec::new();
+        if result == 0 && buf.len() >= 2 {
+            let count = buf.get_u16() as usize;
+            while ids.len() < count && buf.len() >= 4 {
+                ids.push(buf.get_u32());
+            }
+        }
+        Jt0x0805 { answer_sn, result, ids }
+    }
+}
+
+/// 多媒体数据上传
+#[derive(Debug, Default, Serialize)]
+pub struct Jt0x0801 {
+    pub media_id: u32,
+    /// 0:图像 1:音频 2:视频
+    pub media_type: u8,
+    /// 0:JPEG 1:TIF 2:MP3 3:WAV 4:WMV
+    pub format: u8,
+    /// 0:平台下发指令 1:定时动作 2:抢劫报警 3:碰撞侧翻报警 ...
+    pub event: u8,
+    pub channel: u8,
+    pub location: JtLocation,
+    /// 多媒体数据字节数
+    pub size: usize,
+    #[serde(skip)]
+    pub data: Bytes,
+}
+
+impl Jt808BodyTrans for Jt0x0801 {
+    fn fill_new<T>(buf:&mut T, jt808:&Jt808) -> Self
+    where
+        T: IBuffRead,
+    {
+        if buf.len() < 36 {
+            return Jt0x0801::default();
+        }
+        let media_id = buf.get_u32();
+        let media_type = buf.get_u8();
+        let format = buf.get_u8();
+        let event = buf.get_u8();
+        let channel = buf.get_u8();
+        let location = JtLocation::fill_new(&mut jt808::bytes::JtBytes::from(buf.split_to(28)), jt808);
+        let data = buf.to_bytes();
+        Jt0x0801 { media_id, media_type, format, event, channel, location, size: data.len(), data }
+    }
+}
+
+/// 人工确认报警消息
+#[derive(Debug, Default)]
+pub struct Jt0x8203 {
+    /// 需确认的报警消息流水号 0表示该报警类型所有消息
+    pub alarm_sn: u16,
+    /// 人工确认报警类型 按0x0200报警标志位
+    pub alarm_type: u32,
+}
+
+impl Jt808BodySerialize for Jt0x8203 {
+    fn write(&mut self, _ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u16(self.alarm_sn);
+        buf.put_u32(self.alarm_type);
+    }
+
+    fn len(&self, _ver:&Ver808) -> usize {
+        6
+    }
+}
+
+/// 文本信息下发
+#[derive(Debug, Default)]
+pub struct Jt0x8300 {
+    /// bit0:紧急 bit2:终端显示器显示 bit3:终端TTS播读 bit4:广告屏显示
+    pub flag: u8,
+    /// 2019版 1:通知 2:服务
+    pub text_type: u8,
+    pub text: BytesGBK,
+}
+
+impl Jt808BodySerialize for Jt0x8300 {
+    fn write(&mut self, ver:&Ver808, buf:&mut dyn IBuffWrite) {
+        buf.put_u8(self.flag);
+        if matches!(ver, Ver808::V2019) {
+            buf.put_u8(self.text_type);
+        }
+        buf.put(self.text.get_bytes());
+    }
+
+    fn len(&self, ver:&Ver808) -> usize {
+        let head = if matches!(ver, Ver808::V2019) { 2 } else { 1 };
+        head + self.text.bytes_len()
+    }
+}
+
+
+#[test]
+fn test_location()
+{
+    use bytes::BytesMut;
+    use super::jt808_parse::{jt808_escape, Jt808DeserializeAndPackUp};
+
+    let mut content = vec![0x02, 0x00, 0x00, 0x00, 0x01, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+    let mut body = Vec::new();
+    body.extend_from_slice(&0x01u32.to_be_bytes());
+    body.extend_from_slice(&0x0Cu32.to_be_bytes());
+    body.extend_from_slice(&22_543_100u32.to_be_bytes());
+    body.extend_from_slice(&114_057_900u32.to_be_bytes());
+    body.extend_from_slice(&[0x00, 0x10, 0x02, 0x58, 0x00, 0x5A]);
+    body.extend_from_slice(&[0x24, 0x01, 0x02, 0x03, 0x04, 0x05]);
+    body.extend_from_slice(&[0x01, 0x04, 0x00, 0x00, 0x01, 0x00]);
+    body.extend_from_slice(&[0x14, 0x04, 0x00, 0x00, 0x00, 0x01]);
+    content[3] = body.len() as u8;
+    content.extend_from_slice(&body);
+
+    let mut buf = BytesMut::from(&jt808_escape(&content)[..]);
+    let mut parse = Jt808DeserializeAndPackUp::new();
+    let mut jtsub = parse.deserialize(&mut buf).ok().flatten().unwrap();
+    let location = jtsub.trans_body::<JtLocation>();
+    assert_eq!(location.alarm, 1);
+    assert!((location.lat + 22.5431).abs() < 1e-9);
+    assert!((location.lng + 114.0579).abs() < 1e-9);
+    assert_eq!(location.altitude, 16);
+    assert_eq!(location.speed, 60.0);
+    assert_eq!(location.direction, 90);
+    assert_eq!(location.extra_u32(0x01), Some(256));
+    assert_eq!(location.extra_u32(0x14), Some(1));
+    assert_eq!(location.extras.len(), 2);
+}
+
+#[test]
+fn test_location_short()
+{
+    use bytes::BytesMut;
+    use super::jt808_parse::{jt808_escape, Jt808DeserializeAndPackUp};
+
+    let mut content = vec![0x02, 0x00, 0x00, 0x04, 0x01, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+    content.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+    let mut buf = BytesMut::from(&jt808_escape(&content)[..]);
+    let mut jtsub = Jt808DeserializeAndPackUp::new().deserialize(&mut buf).ok().flatten().unwrap();
+    assert!(!jtsub.trans_body::<JtLocation>().valid);
+}
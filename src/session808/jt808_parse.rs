@@ -1,6 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
-use bytes::{BytesMut, BufMut, Buf};
+use bytes::{BytesMut, BufMut, Buf, Bytes};
 use jt808::{models::Jt808, codec::Jt808CodecError, JtSubMerger};
 
 pub struct Jt808Deserialize {
@@ -85,7 +85,9 @@ impl Jt808Deserialize {
                         let package = buf.split_to(newline_index + 1);
                         self.next_index = 0;
 
-                        return Jt808Deserialize::trans(&mut self.index_0x7d, package);
+                        let ret = Jt808Deserialize::trans(&self.index_0x7d, package);
+                        self.index_0x7d.clear();
+                        return ret;
                     } else {
                         self.find_0x7e = true;
                         self.index_0x7e = offset;
@@ -109,18 +111,18 @@ impl Jt808Deserialize {
         }
     }    
 
-    fn trans(index_0x7d: &mut Vec<usize>, mut package: BytesMut) -> Result<Option<Jt808>, Jt808CodecError>  {
+    fn trans(index_0x7d: &[usize], mut package: BytesMut) -> Result<Option<Jt808>, Jt808CodecError>  {
 
-        if index_0x7d.len() > 0 {
+        if !index_0x7d.is_empty() {
 
             let size = package.len() + index_0x7d.len();
 
             let mut bufnew = BytesMut::with_capacity(size);
+            //已处理到的原始包位置
             let mut newidx = 0;
 
             for idx in index_0x7d.iter() {
-                newidx = idx - newidx;
-                bufnew.put(package.split_to(newidx));
+                bufnew.put(package.split_to(idx - newidx));
                 if package[1] == 0x1 {
                     bufnew.put_u8(0x7d);
                 } else if package[1] == 0x2 {
@@ -129,20 +131,29 @@ impl Jt808Deserialize {
                     return Err(Jt808CodecError::No808);
                 }
                 package.advance(2);
-                newidx += 2;
+                newidx = idx + 2;
             }
-            if package.len() > 0 {
+            if !package.is_empty() {
                 bufnew.put(package);
             }
-            return Ok(Some(Jt808::from(bufnew.freeze())));
+            Ok(Some(Jt808::from(bufnew.freeze())))
         } else {
-            return Ok(Some(Jt808::from(package.freeze())));
+            Ok(Some(Jt808::from(package.freeze())))
         }
 
     }
 
 }
 
+impl Default for Jt808Deserialize {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//分包超过该时间没有收到后续包则丢弃
+const SUB_TIMEOUT_SECS: i64 = 60;
+
 pub struct Jt808PackUp {
     all_packdata: HashMap<u16, JtSubMerger>,
 }
@@ -156,23 +167,49 @@ impl Jt808PackUp {
     pub fn get_sub_merger(&mut self, jt: Jt808) -> Option<JtSubMerger> {
         // 拼分包
         if let (Some(i), Some(sum)) = (jt.package_index, jt.package_total) {
-            let first_sn = sum - i + 1;
+            //分包流水号连续 首包流水号=当前流水号-(包序号-1)
+            if i == 0 || i > sum {
+                return None;
+            }
+            self.remove_expired();
+            let first_sn = jt.sn.wrapping_sub(i - 1);
             match self.all_packdata.get_mut(&first_sn) {
                 Some(jtsub) => {
-                    jtsub.add(jt);
-                    if jtsub.check_pack() {
-                        return self.all_packdata.remove(&first_sn);
+                    //JtSubMerger::add用流水号相减计算序号 流水号回绕时溢出 按包序号放入
+                    if jtsub.get_first_jt().and_then(|t| t.package_total) != Some(sum) {
+                        return None;
                     }
+                    jtsub.data.insert(i - 1, jt);
+                    jtsub.last_rec_time = chrono::Local::now();
                 }
                 None => {
+                    //JtSubMerger::add_new把传入包当作首包
+                    if i != 1 {
+                        return None;
+                    }
                     self.all_packdata.insert(first_sn, JtSubMerger::add_new(first_sn, jt));
                 }
             }
+            if self.all_packdata.get(&first_sn).is_some_and(|t| t.check_pack()) {
+                return self.all_packdata.remove(&first_sn);
+            }
         } else {
             return Some(JtSubMerger::add_new(jt.sn, jt));
         }
         None
     }
+
+    //丢弃超时未收齐的分包
+    fn remove_expired(&mut self) {
+        let now = chrono::Local::now();
+        self.all_packdata.retain(|_, t| (now - t.last_rec_time).num_seconds() < SUB_TIMEOUT_SECS);
+    }
+}
+
+impl Default for Jt808PackUp {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub struct Jt808DeserializeAndPackUp {
@@ -193,21 +230,89 @@ impl Jt808DeserializeAndPackUp {
             Ok(package) => {
                 match package {
                     None => {
-                        return Ok(None);
+                        Ok(None)
                     }
                     Some(jt808) => {
-                        return Ok(self.jt808_packup.get_sub_merger(jt808));
+                        Ok(self.jt808_packup.get_sub_merger(jt808))
                     },
                 }
             },
             Err(err) => {
-                return Err(err);
+                Err(err)
             },
         }
     }
 }
 
+impl Default for Jt808DeserializeAndPackUp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 
+/// 按包序号取出全部分包 (JtSubMerger::end按流水号取data 流水号非0时总是返回None)
+pub fn jt808_sub_end(jtsub: &mut JtSubMerger) -> Option<VecDeque<Jt808>> {
+    let count = jtsub.data.len() as u16;
+    let mut packages = VecDeque::with_capacity(count as usize);
+    for i in 0..count {
+        packages.push_back(jtsub.data.remove(&i)?);
+    }
+    if packages.is_empty() {
+        return None;
+    }
+    Some(packages)
+}
+
+/// 按原始包(含7E 未转义)重新打包 修改流水号(及应答流水号) 重算校验码并转义
+pub fn jt808_repack(jt808: &Jt808, sn: u16, answer_sn: Option<u16>) -> Bytes {
+    let mut data = jt808.get_bytes().to_vec();
+    let sn_index = if jt808.v19 { 16 } else { 11 };
+    data[sn_index..sn_index + 2].copy_from_slice(&sn.to_be_bytes());
+
+    if let Some(answer_sn) = answer_sn {
+        let body_index = jt808.get_head_len();
+        if jt808.body_length >= 2 && data.len() >= body_index + 2 {
+            data[body_index..body_index + 2].copy_from_slice(&answer_sn.to_be_bytes());
+        }
+    }
+
+    jt808_escape(&data[1..data.len() - 2])
+}
+
+/// 取已转义的完整包(含7E)中的流水号
+pub fn jt808_sn(buf: &[u8]) -> Option<u16> {
+    let mut head = Vec::with_capacity(20);
+    let mut iter = buf.iter().skip(1);
+    //消息体属性bit14 2019版本
+    let mut sn_index = 10;
+    while head.len() < sn_index + 2 {
+        match iter.next()? {
+            0x7d => head.push(match iter.next()? { 0x01 => 0x7d, _ => 0x7e }),
+            b => head.push(*b),
+        }
+        if head.len() == 3 && head[2] & 0x40 > 0 {
+            sn_index = 15;
+        }
+    }
+    Some(u16::from_be_bytes([head[sn_index], head[sn_index + 1]]))
+}
+
+/// 头+消息体 计算校验码 加7E并转义
+pub fn jt808_escape(content: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(content.len() + 8);
+    let xor = content.iter().fold(0u8, |acc, b| acc ^ b);
+    buf.put_u8(0x7e);
+    for b in content.iter().chain(std::iter::once(&xor)) {
+        match *b {
+            0x7e => buf.put_slice(&[0x7d, 0x02]),
+            0x7d => buf.put_slice(&[0x7d, 0x01]),
+            b => buf.put_u8(b),
+        }
+    }
+    buf.put_u8(0x7e);
+    buf.freeze()
+}
 
 
 #[test]
@@ -222,4 +327,99 @@ fn test_bytes()
     // let t2 = buf.get_u8();
     // let t3 = buf.get_u8();
     // let t4 = buf.get_u8();
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_repack()
+{
+    //0x0200 流水号1 消息体含0x7e
+    let mut content = vec![0x02, 0x00, 0x00, 0x03, 0x01, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+    content.extend_from_slice(&[0x7e, 0x7d, 0x7e]);
+    let mut buf = BytesMut::from(&jt808_escape(&content)[..]);
+
+    let mut parse = Jt808Deserialize::new();
+    let jt808 = parse.deserialize(&mut buf).ok().flatten().unwrap();
+    assert_eq!(jt808.sn, 1);
+    assert_eq!(jt808.get_body().as_ref(), &[0x7e, 0x7d, 0x7e]);
+
+    let mut buf = BytesMut::from(&jt808_repack(&jt808, 0x1234, None)[..]);
+    let jt808 = parse.deserialize(&mut buf).ok().flatten().unwrap();
+    assert_eq!(jt808.sn, 0x1234);
+    assert_eq!(jt808.get_body().as_ref(), &[0x7e, 0x7d, 0x7e]);
+}
+
+#[test]
+fn test_sub_end()
+{
+    let content = [0x02, 0x00, 0x00, 0x00, 0x01, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05];
+    let mut buf = BytesMut::from(&jt808_escape(&content)[..]);
+
+    let mut parse = Jt808DeserializeAndPackUp::new();
+    let mut jtsub = parse.deserialize(&mut buf).ok().flatten().unwrap();
+    let packages = jt808_sub_end(&mut jtsub).unwrap();
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].sn, 5);
+}
+
+#[test]
+fn test_sn()
+{
+    //消息体属性含0x7e 需要反转义
+    let content = [0x81, 0x03, 0x00, 0x7e, 0x01, 0x38, 0x00, 0x00, 0x00, 0x00, 0x7d, 0x02];
+    assert_eq!(jt808_sn(&jt808_escape(&content)), Some(0x7d02));
+
+    let mut sim = jt_util::bytes_bcd::BytesBCD::new();
+    sim.set_bytes(bytes::Bytes::from_static(&[0, 0, 0, 0, 0, 0, 0, 0, 0x13, 0x38]));
+    let package = jt808::JtPackage::new(sim, true, 1, 1000);
+    package.distribute_sn(0x7e);
+    let buf = package.serialize(0x8104, 0, &mut jt808::models::Jt0x0001 { answer_sn: 0, answer_id: 0, result: 0 });
+    assert_eq!(jt808_sn(&buf), Some(0x7e));
+}
+
+#[test]
+fn test_pack_up()
+{
+    //0x1205 分2包 流水号20 21
+    let mut buf = BytesMut::new();
+    for (sn, index, body) in [(20u16, 1u16, [0x00u8, 0x05]), (21, 2, [0x00, 0x00])] {
+        let mut content = vec![0x12, 0x05, 0x20, 0x02, 0x01, 0x38, 0x00, 0x00, 0x00, 0x00];
+        content.extend_from_slice(&sn.to_be_bytes());
+        content.extend_from_slice(&[0x00, 0x02]);
+        content.extend_from_slice(&index.to_be_bytes());
+        content.extend_from_slice(&body);
+        buf.extend_from_slice(&jt808_escape(&content));
+    }
+
+    let mut parse = Jt808DeserializeAndPackUp::new();
+    assert!(parse.deserialize(&mut buf).ok().flatten().is_none());
+    let mut jtsub = parse.deserialize(&mut buf).ok().flatten().unwrap();
+    assert_eq!(jtsub.data.len(), 2);
+    assert_eq!(jt808_sub_end(&mut jtsub).unwrap().len(), 2);
+}
+
+#[test]
+fn test_pack_up_wrap()
+{
+    //流水号回绕 65535 0 1
+    let package = |sn:u16, index:u16| {
+        let mut content = vec![0x12, 0x05, 0x20, 0x01, 0x01, 0x38, 0x00, 0x00, 0x00, 0x00];
+        content.extend_from_slice(&sn.to_be_bytes());
+        content.extend_from_slice(&[0x00, 0x03]);
+        content.extend_from_slice(&index.to_be_bytes());
+        content.push(index as u8);
+        BytesMut::from(&jt808_escape(&content)[..])
+    };
+
+    let mut parse = Jt808DeserializeAndPackUp::new();
+    assert!(parse.deserialize(&mut package(65535, 1)).ok().flatten().is_none());
+    assert!(parse.deserialize(&mut package(0, 2)).ok().flatten().is_none());
+    let mut jtsub = parse.deserialize(&mut package(1, 3)).ok().flatten().unwrap();
+    assert_eq!(jt808_sub_end(&mut jtsub).unwrap().iter().map(|t| t.sn).collect::<Vec<_>>(), vec![65535, 0, 1]);
+    assert!(parse.jt808_packup.all_packdata.is_empty());
+
+    //超时未收齐的分包被清理
+    assert!(parse.deserialize(&mut package(100, 1)).ok().flatten().is_none());
+    parse.jt808_packup.all_packdata.get_mut(&100).unwrap().last_rec_time -= chrono::Duration::seconds(SUB_TIMEOUT_SECS);
+    assert!(parse.deserialize(&mut package(200, 1)).ok().flatten().is_none());
+    assert_eq!(parse.jt808_packup.all_packdata.keys().collect::<Vec<_>>(), vec![&200]);
+}
@@ -1,16 +1,24 @@
 use std::{sync::{Arc, atomic::{Ordering, AtomicI32, AtomicBool}}, collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
 
-use jt808::{models::{Jt0x0100, Jt0x0102, Jt0x8100, Jt0x0001, Jt808BodySerialize}, JtSubMerger, JtPackage};
+use jt808::{models::{Jt0x0100, Jt0x0102, Jt0x8100, Jt0x0001, Jt808BodySerialize, Jt808BodyTrans}, JtSubMerger, JtPackage};
 use jt_util::bytes_gbk::BytesGBK;
-use tokio::{net::tcp::OwnedWriteHalf, sync::{Mutex, Notify}, io::AsyncWriteExt, time::timeout};
+use tokio::{net::tcp::OwnedWriteHalf, sync::{Mutex, Notify, oneshot}, io::AsyncWriteExt, time::timeout};
 
-use crate::{service_forward::ForwardSimSender, session_forward::forward_item::ForwardItem};
+use crate::{service_alarm, service_driving, service_track, service_trip, service_809, service_geofence, service_avinfo, service_video_alarm, service_safety, session808::jt808_models::{JtLocation, Jt0x0704, Jt0x0801}, service_event::{self, DeviceEvent}, session1078::extend808::{Jt0x1003, Jt0x1005}, service_forward::ForwardSimSender, session_forward::{forward_item::ForwardItem, forward_filter::ForwardFilter}, session_passthrough::passthrough_link::PassthroughLink};
+
+use super::jt808_parse::{jt808_repack, jt808_sub_end, jt808_sn};
+
+type GwAnswer = (Arc<Notify>, Arc<AtomicI32>);
+//终端应答消息解析后交给等待者
+type GwRequest = Box<dyn FnOnce(&mut JtSubMerger) + Send>;
 
 pub struct Jt808SessionShared {
     sender : Arc<Mutex<OwnedWriteHalf>>,
     package : JtPackage,
     fw_ids: Mutex<HashMap<u16, Arc<ForwardItem>>>,
-    gw_ids: Mutex<HashMap<u16, (Arc<Notify>, Arc<AtomicI32>)>>,
+    gw_ids: Mutex<HashMap<u16, GwAnswer>>,
+    //(应答消息ID, 应答流水号) 应答不带流水号时为None
+    gw_requests: Mutex<HashMap<(u16, Option<u16>), GwRequest>>,
     is_closed:AtomicBool
 }
 
@@ -21,6 +29,7 @@ impl Jt808SessionShared {
             package,
             fw_ids:Mutex::new(HashMap::new()),
             gw_ids:Mutex::new(HashMap::new()),
+            gw_requests:Mutex::new(HashMap::new()),
             is_closed:AtomicBool::new(false)
         }
     }
@@ -32,45 +41,178 @@ impl Jt808SessionShared {
             return false;
         }
 
-        let mut sn = self.package.distribute_sn(jtsub.data.len() as u16);
+        let sn = self.package.distribute_sn(jtsub.data.len() as u16);
 
         self.fw_ids.lock().await.insert(sn, forward_item.clone());
 
+        self.send_raw(jtsub, sn).await;
+
+        true
+    }
+
+    //来自透传平台 返回分配的流水号 发送前先调用on_sn登记 避免应答先到
+    pub async fn passthrough_recv(&self, jtsub:&mut JtSubMerger, on_sn:impl FnOnce(u16)) -> Option<u16> {
+
+        if self.is_closed() {
+            return None;
+        }
+
+        let sn = self.package.distribute_sn(jtsub.data.len() as u16);
+        on_sn(sn);
+
+        self.send_raw(jtsub, sn).await;
+
+        Some(sn)
+    }
+
+    //原始包按本会话流水号重新打包下发
+    async fn send_raw(&self, jtsub:&mut JtSubMerger, mut sn:u16) {
         for i in 0..jtsub.data.len() as u16 {
             let tt = jtsub.data.get(&i).unwrap();
-            let data = tt.rec_modify_sn(sn);
+            let data = jt808_repack(tt, sn, None);
 
-            let _ = self.sender.lock().await.write_all(&data);
-            sn+=1;
+            let _ = self.sender.lock().await.write_all(&data).await;
+            sn = sn.wrapping_add(1);
         }
-
-        return true;
     }
 
     //来自http的发送
     pub async fn send_cmd<T: Jt808BodySerialize>(&self, id:u16, jtcmd:&mut T) -> i32 {
 
-        //todo:sn package.serialize 返回
-        let sn = self.package.get_sn();
         let buf = self.package.serialize(id, 0, jtcmd);
-        let mut writer: tokio::sync::MutexGuard<'_, OwnedWriteHalf> = self.sender.lock().await;
-        let _ = writer.write(&buf).await;
+        let sn = match jt808_sn(&buf) {
+            Some(sn) => sn,
+            None => return -1,
+        };
 
+        //先登记再发送 避免应答先到
         let notify = Arc::new(Notify::new());
         let ret = Arc::new(AtomicI32::new(0));
         self.gw_ids.lock().await.insert(sn, (notify.clone(), ret.clone()));
 
+        if let Err(err) = self.sender.lock().await.write_all(&buf).await {
+            log::warn!("[service-device][session]send 0x{:04x} sn:{} failed:{}", id, sn, err);
+            self.gw_ids.lock().await.remove(&sn);
+            return -1;
+        }
+
         match timeout(std::time::Duration::from_secs(5), notify.notified()).await {
             Ok(_) => {
-                return ret.load(Ordering::Relaxed);
+                ret.load(Ordering::Relaxed)
             },
             Err(_) => {
                 self.gw_ids.lock().await.remove(&sn);
-                return -1;
+                -1
             },
         }
     }
 
+    /// 发送指令并等待终端的应答消息(如0x9205->0x1205) by_sn:应答消息体以应答流水号开头
+    /// 终端以通用应答回复时返回Err(结果) 超时返回Err(-1)
+    pub async fn request<T: Jt808BodySerialize, R: Jt808BodyTrans + Default + Send + 'static>(&self, id:u16, jtcmd:&mut T, answer_id:u16, by_sn:bool, wait:std::time::Duration) -> Result<R, i32> {
+
+        let buf = self.package.serialize(id, 0, jtcmd);
+        let sn = jt808_sn(&buf).ok_or(-1)?;
+        let key = (answer_id, if by_sn { Some(sn) } else { None });
+
+        let rx = self.add_request::<R>(key).await;
+        let notify = Arc::new(Notify::new());
+        let ret = Arc::new(AtomicI32::new(0));
+        self.gw_ids.lock().await.insert(sn, (notify.clone(), ret.clone()));
+
+        if let Err(err) = self.sender.lock().await.write_all(&buf).await {
+            log::warn!("[service-device][session]send 0x{:04x} sn:{} failed:{}", id, sn, err);
+            self.gw_requests.lock().await.remove(&key);
+            self.gw_ids.lock().await.remove(&sn);
+            return Err(-1);
+        }
+
+        let result = tokio::select! {
+            answer = rx => answer.map_err(|_| -1),
+            _ = notify.notified() => Err(ret.load(Ordering::Relaxed)),
+            _ = tokio::time::sleep(wait) => Err(-1),
+        };
+        self.gw_requests.lock().await.remove(&key);
+        self.gw_ids.lock().await.remove(&sn);
+        result
+    }
+
+    /// 发送指令 等待通用应答 之后的应答消息(如0x9206->0x1206)由调用者接收
+    /// 返回(流水号, 通用应答结果, 应答消息) 不再等待时调用remove_request
+    pub async fn send_cmd_answer<T: Jt808BodySerialize, R: Jt808BodyTrans + Default + Send + 'static>(&self, id:u16, jtcmd:&mut T, answer_id:u16) -> (u16, i32, oneshot::Receiver<R>) {
+
+        let buf = self.package.serialize(id, 0, jtcmd);
+        let sn = jt808_sn(&buf).unwrap_or(0);
+        let rx = self.add_request::<R>((answer_id, Some(sn))).await;
+
+        let notify = Arc::new(Notify::new());
+        let ret = Arc::new(AtomicI32::new(0));
+        self.gw_ids.lock().await.insert(sn, (notify.clone(), ret.clone()));
+
+        if let Err(err) = self.sender.lock().await.write_all(&buf).await {
+            log::warn!("[service-device][session]send 0x{:04x} sn:{} failed:{}", id, sn, err);
+            self.gw_ids.lock().await.remove(&sn);
+            self.remove_request(answer_id, sn).await;
+            return (sn, -1, rx);
+        }
+
+        let ret = match timeout(std::time::Duration::from_secs(5), notify.notified()).await {
+            Ok(_) => ret.load(Ordering::Relaxed),
+            Err(_) => {
+                self.gw_ids.lock().await.remove(&sn);
+                -1
+            },
+        };
+        (sn, ret, rx)
+    }
+
+    pub async fn remove_request(&self, answer_id:u16, sn:u16) {
+        self.gw_requests.lock().await.remove(&(answer_id, Some(sn)));
+    }
+
+    async fn add_request<R: Jt808BodyTrans + Default + Send + 'static>(&self, key:(u16, Option<u16>)) -> oneshot::Receiver<R> {
+        let (tx, rx) = oneshot::channel();
+        let answer: GwRequest = Box::new(move |jtsub:&mut JtSubMerger| {
+            let _ = tx.send(jtsub.trans_body::<R>());
+        });
+        self.gw_requests.lock().await.insert(key, answer);
+        rx
+    }
+
+    //网关指令的通用应答 返回是否有等待者
+    pub(crate) async fn gw_answer(&self, answer_sn:u16, result:u8) -> bool {
+        match self.gw_ids.lock().await.remove(&answer_sn) {
+            Some((notify, ret)) => {
+                ret.store(result as i32, Ordering::Relaxed);
+                notify.notify_one();
+                true
+            },
+            None => false,
+        }
+    }
+
+    //终端应答消息 有等待者时解析
+    async fn answer(&self, jtsub:&mut JtSubMerger) {
+        let (id, answer_sn) = match jtsub.get_first_jt() {
+            Some(jt) => {
+                let body = jt.get_body();
+                (jt.id, if body.len() >= 2 { Some(u16::from_be_bytes([body[0], body[1]])) } else { None })
+            },
+            None => return,
+        };
+
+        let request = {
+            let mut gw_requests = self.gw_requests.lock().await;
+            match gw_requests.remove(&(id, answer_sn)) {
+                Some(request) => Some(request),
+                None => gw_requests.remove(&(id, None)),
+            }
+        };
+        if let Some(request) = request {
+            request(jtsub);
+        }
+    }
+
     pub fn is_closed(&self) -> bool
     {
         self.is_closed.load(Ordering::Relaxed)
@@ -86,10 +228,11 @@ pub struct Jt808Session {
     pub session_shared: Arc<Jt808SessionShared>,
     time_last_recv: u64,
     fw_sender:ForwardSimSender,
+    pt_links:Vec<Arc<PassthroughLink>>,
 }
 
 impl Jt808Session {
-    pub async fn new(session_shared:Arc<Jt808SessionShared>, mut fw_sender:ForwardSimSender) -> Self {
+    pub async fn new(session_shared:Arc<Jt808SessionShared>, mut fw_sender:ForwardSimSender, pt_links:Vec<Arc<PassthroughLink>>) -> Self {
         let time_now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -100,12 +243,15 @@ impl Jt808Session {
         Self {
             session_shared,
             time_last_recv:time_now,
-            fw_sender
+            fw_sender,
+            pt_links
         }
     }
     
     pub async fn handle(&mut self, jtsub:&mut JtSubMerger) {
 
+        self.session_shared.answer(jtsub).await;
+
         let jt = jtsub.get_first_jt().unwrap();
         let sn: u16 = jt.sn;
         let id = jt.id;
@@ -116,11 +262,14 @@ impl Jt808Session {
                 let jt0x0001 = jtsub.trans_body::<Jt0x0001>();
                 let answer_sn = jt0x0001.answer_sn;
                 let result = jt0x0001.result;
+                service_event::publish(DeviceEvent::new(&jt_sim(jtsub), "command_answer", &serde_json::json!({
+                    "answer_sn": answer_sn,
+                    "answer_id": jt0x0001.answer_id,
+                    "result": result,
+                })));
 
                 //网关应答
-                if let Some((notify, ret)) = self.session_shared.gw_ids.lock().await.remove(&answer_sn) {
-                    ret.store(result as i32, Ordering::Relaxed);
-                    notify.notify_one();
+                if self.session_shared.gw_answer(answer_sn, result).await {
                     return;
                 }
 
@@ -141,6 +290,14 @@ impl Jt808Session {
                 
                 log::info!("[service-device][session]recv 0x0100:{:?}", tt);
 
+                //保存注册信息 供透传平台注册使用
+                if let Some(jt) = jtsub.get_first_jt() {
+                    let body = jt.get_body();
+                    for link in &self.pt_links {
+                        link.on_register(&body).await;
+                    }
+                }
+
                 let mut resp0x8100 = Jt0x8100 {
                     answer_sn: sn,
                     result: 0,
@@ -152,6 +309,10 @@ impl Jt808Session {
                 let _ = writer.write(&buf).await;
 
                 log::info!("[service-device][session]response 0x8100:{:?}", resp0x8100);
+                service_event::publish(DeviceEvent::new(&jt_sim(jtsub), "register", &serde_json::json!({
+                    "register": tt,
+                    "result": resp0x8100.result,
+                })));
                 return;
             },
             0x0003 => { //终端注销
@@ -174,13 +335,57 @@ impl Jt808Session {
                 let _ = writer.write(&buf).await;
 
                 log::info!("[service-device][session]response 0x0102:{:?}", resp0x8001);
+                service_event::publish(DeviceEvent::new(&jt_sim(jtsub), "auth", &serde_json::json!({
+                    "auth": tt,
+                    "result": resp0x8001.result,
+                })));
                 return;
             },
             0x0104 => { //查询终端参数应答
                 
             }
             0x0200 => { //gps
-
+                let tt = jtsub.trans_body::<JtLocation>();
+                let sim = jt_sim(jtsub);
+                //消息体不完整 不参与报警/区域/轨迹等处理 只转发
+                if !tt.valid {
+                    log::warn!("[service-device][session]invalid 0x0200 sim:{}", sim);
+                    self.forward_send(jtsub).await;
+                    return;
+                }
+                if service_event::subscribed() {
+                    service_event::publish(DeviceEvent::new(&sim, "position", &tt));
+                }
+                service_video_alarm::update(&sim, &tt);
+                service_safety::update(&sim, &tt);
+                service_alarm::update(&sim, sn, &tt);
+                service_geofence::update(&sim, &tt);
+                service_driving::update(&sim, &tt);
+                service_trip::update(&sim, &tt);
+                service_track::record(&sim, &tt, false).await;
+                service_809::update(&sim, &tt);
+            }
+            0x0704 => { //定位数据批量上传 记录历史轨迹 809补报
+                let tt = jtsub.trans_body::<Jt0x0704>();
+                let sim = jt_sim(jtsub);
+                log::info!("[service-device][session]recv 0x0704 sim:{} type:{} count:{}", sim, tt.data_type, tt.locations.len());
+                for location in &tt.locations {
+                    service_track::record(&sim, location, tt.data_type == 1).await;
+                }
+                service_809::backfill(&sim, &tt.locations);
+            }
+            0x0801 => { //多媒体数据上传 分包合并后的完整数据
+                let tt = jtsub.trans_body::<Jt0x0801>();
+                service_event::publish(DeviceEvent::new(&jt_sim(jtsub), "media_upload", &tt));
+            }
+            0x1003 => { //终端上传音视频属性
+                let tt = jtsub.trans_body::<Jt0x1003>();
+                log::info!("[service-device][session]recv 0x1003:{:?}", tt);
+                service_avinfo::update(&jt_sim(jtsub), tt);
+            }
+            0x1005 => { //终端上传乘客流量
+                let tt = jtsub.trans_body::<Jt0x1005>();
+                service_event::publish(DeviceEvent::new(&jt_sim(jtsub), "passenger_flow", &tt));
             }
             _ => {
             }
@@ -190,18 +395,35 @@ impl Jt808Session {
     }
 
     pub async fn forward_send(&mut self, jtsub:&mut JtSubMerger) {
+
+        let (id, alarm) = match jtsub.get_first_jt() {
+            Some(jt) => (jt.id, ForwardFilter::get_alarm(jt)),
+            None => return,
+        };
         
-        match jtsub.end() {
-            Some(packages) => {
-                for item in packages {
-                    self.fw_sender.forward_send(&item.get_bytes()).await;
-                }
-            },
-            None => {},
+        if let Some(packages) = jt808_sub_end(jtsub) {
+            #[cfg(feature = "mqtt")]
+            crate::service_mqtt::publish_frames(&packages);
+
+            self.fw_sender.forward_send(id, alarm, &packages).await;
+
+            for link in &self.pt_links {
+                link.uplink(&packages).await;
+            }
+        }
+    }
+
+    //设备下线
+    pub fn close(&self) {
+        for link in &self.pt_links {
+            link.close();
         }
     }
 
     pub fn is_closed(&self) -> bool {
         self.session_shared.is_closed()
     }
-}
\ No newline at end of file
+}
+fn jt_sim(jtsub:&mut JtSubMerger) -> String {
+    jtsub.get_first_jt().map_or(String::new(), |t| t.sim.to_string())
+}
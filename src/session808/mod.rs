@@ -1,2 +1,5 @@
 pub mod jt808_parse;
-pub mod jt808_session;
\ No newline at end of file
+pub mod jt808_session;
+pub mod jt808_models;
+pub mod jt808_jsatl12;
+pub mod jt808_area;
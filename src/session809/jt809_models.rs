@@ -0,0 +1,179 @@
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use jt_util::bytes_gbk::BytesGBK;
+
+use crate::session808::jt808_models::JtLocation;
+
+//JT/T 809-2011 链路管理和车辆动态信息交换/报警信息交互消息
+
+//主链路
+pub const UP_CONNECT_REQ: u16 = 0x1001;
+pub const UP_CONNECT_RSP: u16 = 0x1002;
+pub const UP_DISCONNECT_REQ: u16 = 0x1003;
+pub const UP_LINKTEST_REQ: u16 = 0x1005;
+pub const UP_LINKTEST_RSP: u16 = 0x1006;
+//从链路
+pub const DOWN_CONNECT_REQ: u16 = 0x9001;
+pub const DOWN_CONNECT_RSP: u16 = 0x9002;
+pub const DOWN_DISCONNECT_REQ: u16 = 0x9003;
+pub const DOWN_DISCONNECT_RSP: u16 = 0x9004;
+pub const DOWN_LINKTEST_REQ: u16 = 0x9005;
+pub const DOWN_LINKTEST_RSP: u16 = 0x9006;
+//车辆动态信息交换
+pub const UP_EXG_MSG: u16 = 0x1200;
+pub const UP_EXG_MSG_REAL_LOCATION: u16 = 0x1202;
+pub const UP_EXG_MSG_HISTORY_LOCATION: u16 = 0x1203;
+//报警信息交互
+pub const UP_WARN_MSG: u16 = 0x1400;
+pub const UP_WARN_MSG_ADPT_INFO: u16 = 0x1402;
+
+/// 0x1203单条消息最多的定位数据
+pub const HISTORY_MAX: usize = 5;
+
+/// 主链路登录请求
+pub struct Jt809Login {
+    pub user_id: u32,
+    pub password: String,
+    /// 从链路服务端IP和端口
+    pub down_link_ip: String,
+    pub down_link_port: u16,
+}
+
+impl Jt809Login {
+    pub fn write(&self, buf:&mut BytesMut) {
+        buf.put_u32(self.user_id);
+        put_fixed(buf, self.password.as_bytes(), 8);
+        put_fixed(buf, self.down_link_ip.as_bytes(), 32);
+        buf.put_u16(self.down_link_port);
+    }
+}
+
+/// 主链路登录应答 result 0:成功 1:IP地址不正确 2:接入码不正确 3:用户没有注册 4:密码错误 5:资源紧张 9:其他
+pub struct Jt809LoginAnswer {
+    pub result: u8,
+    /// 从链路连接时使用的校验码
+    pub verify_code: u32,
+}
+
+impl Jt809LoginAnswer {
+    pub fn read(mut body:&[u8]) -> Option<Self> {
+        if body.len() < 5 {
+            return None;
+        }
+        Some(Jt809LoginAnswer { result: body.get_u8(), verify_code: body.get_u32() })
+    }
+}
+
+/// 车辆动态信息交换(0x1200)和报警信息交互(0x1400)的子业务 车牌号 车牌颜色 子业务类型 数据
+pub struct Jt809VehicleMsg<'a> {
+    pub plate: &'a str,
+    pub color: u8,
+    pub data_type: u16,
+    pub data: &'a [u8],
+}
+
+impl Jt809VehicleMsg<'_> {
+    pub fn write(&self, buf:&mut BytesMut) {
+        let mut plate = BytesGBK::new();
+        plate.set_val(self.plate);
+        put_fixed(buf, &plate.get_bytes(), 21);
+        buf.put_u8(self.color);
+        buf.put_u16(self.data_type);
+        buf.put_u32(self.data.len() as u32);
+        buf.put_slice(self.data);
+    }
+}
+
+/// 车辆定位信息 36字节 里程取附加信息0x01
+pub fn write_gnss(buf:&mut BytesMut, location:&JtLocation) {
+    let time = Local.timestamp_opt(location.time, 0).single().unwrap_or_else(Local::now);
+    //坐标未加密
+    buf.put_u8(0);
+    buf.put_u8(time.day() as u8);
+    buf.put_u8(time.month() as u8);
+    buf.put_u16(time.year() as u16);
+    buf.put_u8(time.hour() as u8);
+    buf.put_u8(time.minute() as u8);
+    buf.put_u8(time.second() as u8);
+    buf.put_u32((location.lng.abs() * 1e6).round() as u32);
+    buf.put_u32((location.lat.abs() * 1e6).round() as u32);
+    buf.put_u16(location.speed.round() as u16);
+    //行驶记录速度 没有时与卫星速度相同
+    buf.put_u16(location.extra_u32(0x03).map_or(location.speed.round() as u16, |t| (t / 10) as u16));
+    buf.put_u32(location.extra_u32(0x01).map_or(0, |t| t / 10));
+    buf.put_u16(location.direction);
+    buf.put_u16(location.altitude);
+    buf.put_u32(location.state);
+    buf.put_u32(location.alarm);
+}
+
+/// 上报报警信息(0x1402)
+pub struct Jt809WarnInfo {
+    /// 1:车载终端 2:企业监控平台 3:政府监管平台 9:其他
+    pub src: u8,
+    pub warn_type: u16,
+    /// UTC unix秒
+    pub time: i64,
+    pub info_id: u32,
+    pub content: String,
+}
+
+impl Jt809WarnInfo {
+    pub fn write(&self, buf:&mut BytesMut) {
+        let mut content = BytesGBK::new();
+        content.set_val(&self.content);
+        let content = content.get_bytes();
+        buf.put_u8(self.src);
+        buf.put_u16(self.warn_type);
+        buf.put_u64(self.time as u64);
+        buf.put_u32(self.info_id);
+        buf.put_u32(content.len() as u32);
+        buf.put_slice(&content);
+    }
+}
+
+/// 808报警标志位或平台事件对应的809报警类型 没有对应时为None
+pub fn warn_type(kind:&str) -> Option<u16> {
+    let warn_type = match kind {
+        "overspeed" => 0x0001,
+        "fatigue" => 0x0002,
+        "emergency" => 0x0003,
+        "geofence_enter" => 0x0004,
+        "geofence_exit" => 0x0005,
+        "route_deviation" => 0x000b,
+        "stolen" => 0x0009,
+        "illegal_move" => 0x000c,
+        "driving_timeout" => 0x000d,
+        "danger_warning" | "collision" | "rollover" | "illegal_ignition" | "illegal_door" | "area_in_out" | "route_in_out" => 0x00ff,
+        _ => return None,
+    };
+    Some(warn_type)
+}
+
+//定长字段 不足补0 超长截断
+fn put_fixed(buf:&mut BytesMut, data:&[u8], len:usize) {
+    let n = data.len().min(len);
+    buf.put_slice(&data[..n]);
+    buf.put_bytes(0, len - n);
+}
+
+
+#[test]
+fn test_jt809_models()
+{
+    let location = JtLocation { lat: 22.5431, lng: 114.0579, speed: 60.0, time: 1729339200, ..Default::default() };
+    let mut gnss = BytesMut::new();
+    write_gnss(&mut gnss, &location);
+    assert_eq!(gnss.len(), 36);
+    assert_eq!(&gnss[12..16], &22543100u32.to_be_bytes());
+
+    let mut buf = BytesMut::new();
+    Jt809VehicleMsg { plate: "粤B12345", color: 2, data_type: UP_EXG_MSG_REAL_LOCATION, data: &gnss }.write(&mut buf);
+    assert_eq!(buf.len(), 21 + 1 + 2 + 4 + 36);
+    assert_eq!(&buf[..2], &[0xd4, 0xc1]);
+    assert_eq!(&buf[22..24], &[0x12, 0x02]);
+
+    let mut buf = BytesMut::new();
+    Jt809Login { user_id: 1, password: "12345678".into(), down_link_ip: "127.0.0.1".into(), down_link_port: 9000 }.write(&mut buf);
+    assert_eq!(buf.len(), 46);
+}
@@ -0,0 +1,206 @@
+use std::{sync::atomic::{AtomicU32, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+//JT/T 809-2011 封包 0x5b 头 消息体 CRC 0x5d
+//头: 数据长度4 流水号4 业务类型2 下级平台接入码4 版本号3 加密标识1 密钥4
+//转义 0x5b->0x5a01 0x5a->0x5a02 0x5d->0x5e01 0x5e->0x5e02
+//CRC为CRC16-CCITT(初值0xffff) 范围从头到消息体 只加密消息体
+
+pub const HEAD_FLAG: u8 = 0x5b;
+pub const TAIL_FLAG: u8 = 0x5d;
+const HEAD_LEN: usize = 22;
+//头标识+头+CRC+尾标识
+const FRAME_MIN: usize = HEAD_LEN + 4;
+//转义后单个封包的上限
+const FRAME_MAX: usize = 64 * 1024;
+
+/// 加密参数 M1 IA1 IC1
+#[derive(Debug, Clone, Copy)]
+pub struct Jt809Cipher {
+    pub m1: u32,
+    pub ia1: u32,
+    pub ic1: u32,
+}
+
+impl Jt809Cipher {
+    /// 加解密相同
+    pub fn apply(&self, key:u32, data:&mut [u8]) {
+        let mut key = if key == 0 { 1 } else { key };
+        let m1 = if self.m1 == 0 { 1 } else { self.m1 };
+        for t in data.iter_mut() {
+            key = self.ia1.wrapping_mul(key % m1).wrapping_add(self.ic1);
+            *t ^= (key >> 20) as u8;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Jt809Frame {
+    pub sn: u32,
+    pub id: u16,
+    pub center_id: u32,
+    /// 已解密的消息体
+    pub body: Bytes,
+}
+
+/// 本平台发送的封包 流水号递增
+pub struct Jt809Package {
+    center_id: u32,
+    version: [u8; 3],
+    cipher: Option<Jt809Cipher>,
+    sn: AtomicU32,
+}
+
+impl Jt809Package {
+    pub fn new(center_id:u32, version:[u8; 3], cipher:Option<Jt809Cipher>) -> Self {
+        Jt809Package { center_id, version, cipher, sn: AtomicU32::new(0) }
+    }
+
+    pub fn serialize(&self, id:u16, body:&[u8]) -> Vec<u8> {
+        let sn = self.sn.fetch_add(1, Ordering::Relaxed);
+        let mut body = body.to_vec();
+        let key = match &self.cipher {
+            Some(cipher) => {
+                let key = SystemTime::now().duration_since(UNIX_EPOCH).map_or(sn, |t| t.subsec_nanos() ^ sn).max(1);
+                cipher.apply(key, &mut body);
+                key
+            },
+            None => 0,
+        };
+
+        let mut data = BytesMut::with_capacity(HEAD_LEN + body.len() + 2);
+        data.put_u32((FRAME_MIN + body.len()) as u32);
+        data.put_u32(sn);
+        data.put_u16(id);
+        data.put_u32(self.center_id);
+        data.put_slice(&self.version);
+        data.put_u8(self.cipher.is_some() as u8);
+        data.put_u32(key);
+        data.put_slice(&body);
+        data.put_u16(crc16(&data));
+        escape(&data)
+    }
+}
+
+pub fn crc16(data:&[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for t in data {
+        crc ^= (*t as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 > 0 { crc << 1 ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn escape(data:&[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 8);
+    out.push(HEAD_FLAG);
+    for t in data {
+        match *t {
+            0x5b => out.extend_from_slice(&[0x5a, 0x01]),
+            0x5a => out.extend_from_slice(&[0x5a, 0x02]),
+            0x5d => out.extend_from_slice(&[0x5e, 0x01]),
+            0x5e => out.extend_from_slice(&[0x5e, 0x02]),
+            t => out.push(t),
+        }
+    }
+    out.push(TAIL_FLAG);
+    out
+}
+
+fn unescape(data:&[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(t) = iter.next() {
+        match *t {
+            0x5a | 0x5e => {
+                let next = *iter.next()?;
+                out.push(match (*t, next) {
+                    (0x5a, 0x01) => 0x5b,
+                    (0x5a, 0x02) => 0x5a,
+                    (0x5e, 0x01) => 0x5d,
+                    (0x5e, 0x02) => 0x5e,
+                    _ => return None,
+                });
+            },
+            t => out.push(t),
+        }
+    }
+    Some(out)
+}
+
+/// 从缓冲区取出一个封包 Ok(None)表示数据不足 Err为格式错误原因(已丢弃该段数据)
+/// 加密的消息体按cipher解密
+pub fn deserialize(buffer:&mut BytesMut, cipher:Option<&Jt809Cipher>) -> Result<Option<Jt809Frame>, &'static str> {
+    //跳过头标识之前的数据
+    match buffer.iter().position(|t| *t == HEAD_FLAG) {
+        Some(start) => buffer.advance(start),
+        None => {
+            buffer.clear();
+            return Ok(None);
+        },
+    }
+    let end = match buffer.iter().position(|t| *t == TAIL_FLAG) {
+        Some(end) => end,
+        None => {
+            if buffer.len() > FRAME_MAX {
+                buffer.clear();
+                return Err("frame too long");
+            }
+            return Ok(None);
+        },
+    };
+    let frame = buffer.split_to(end + 1);
+    let mut data = unescape(&frame[1..end]).ok_or("invalid escape")?;
+    if data.len() < HEAD_LEN + 2 || data.len() + 2 != u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize {
+        return Err("invalid length");
+    }
+    let crc_pos = data.len() - 2;
+    if crc16(&data[..crc_pos]) != u16::from_be_bytes([data[crc_pos], data[crc_pos + 1]]) {
+        return Err("crc error");
+    }
+
+    let sn = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let id = u16::from_be_bytes([data[8], data[9]]);
+    let center_id = u32::from_be_bytes([data[10], data[11], data[12], data[13]]);
+    let encrypt = data[17];
+    let key = u32::from_be_bytes([data[18], data[19], data[20], data[21]]);
+    let body = &mut data[HEAD_LEN..crc_pos];
+    if encrypt == 1 {
+        match cipher {
+            Some(cipher) => cipher.apply(key, body),
+            None => return Err("encrypted without cipher"),
+        }
+    }
+    Ok(Some(Jt809Frame { sn, id, center_id, body: Bytes::copy_from_slice(body) }))
+}
+
+
+#[test]
+fn test_jt809_codec()
+{
+    assert_eq!(crc16(b"123456789"), 0x29b1);
+
+    let cipher = Jt809Cipher { m1: 10000000, ia1: 20000000, ic1: 30000000 };
+    let body = [0x5b, 0x5a, 0x5d, 0x5e, 0x01, 0x02, 0x03];
+    let mut data = body;
+    cipher.apply(0x1234, &mut data);
+    assert_ne!(data, body);
+    cipher.apply(0x1234, &mut data);
+    assert_eq!(data, body);
+
+    for cipher in [None, Some(cipher)] {
+        let package = Jt809Package::new(1001, [1, 0, 0], cipher);
+        let mut buffer = BytesMut::new();
+        buffer.put_slice(&[0x00, 0x01]);
+        buffer.put_slice(&package.serialize(0x1005, &[]));
+        buffer.put_slice(&package.serialize(0x1200, &body));
+        let first = deserialize(&mut buffer, cipher.as_ref()).unwrap().unwrap();
+        assert_eq!((first.sn, first.id, first.center_id, first.body.len()), (0, 0x1005, 1001, 0));
+        let second = deserialize(&mut buffer, cipher.as_ref()).unwrap().unwrap();
+        assert_eq!((second.sn, second.id, &second.body[..]), (1, 0x1200, &body[..]));
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,2 @@
+pub mod jt809_parse;
+pub mod jt809_models;
@@ -0,0 +1,195 @@
+use std::{collections::VecDeque, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
+
+use bytes::Bytes;
+use tokio::{sync::Mutex, net::tcp::OwnedWriteHalf, io::{self, AsyncWriteExt}};
+
+//断线缓存 按总字节数和保存时长淘汰最旧数据
+pub struct ForwardBuffer {
+    items: VecDeque<(Instant, Bytes)>,
+    size: usize,
+    max_size: usize,
+    max_age: Duration,
+}
+
+impl ForwardBuffer {
+    pub fn new(max_size:usize, max_age:Duration) -> Self {
+        ForwardBuffer {
+            items: VecDeque::new(),
+            size: 0,
+            max_size,
+            max_age,
+        }
+    }
+
+    pub fn push(&mut self, buf:Bytes) {
+        if buf.len() > self.max_size {
+            return;
+        }
+        self.size += buf.len();
+        self.items.push_back((Instant::now(), buf));
+
+        while self.size > self.max_size {
+            self.pop_front();
+        }
+        self.expire();
+    }
+
+    //最旧的一条 已过期的先丢弃
+    pub fn front(&mut self) -> Option<Bytes> {
+        self.expire();
+        self.items.front().map(|t| t.1.clone())
+    }
+
+    pub fn pop_front(&mut self) {
+        if let Some((_, buf)) = self.items.pop_front() {
+            self.size -= buf.len();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn expire(&mut self) {
+        while let Some((time, _)) = self.items.front() {
+            if time.elapsed() <= self.max_age {
+                break;
+            }
+            self.pop_front();
+        }
+    }
+}
+
+//转发连接输出 断线期间写入缓存 重连后按顺序补发
+pub struct ForwardOutput {
+    writer: Mutex<Option<OwnedWriteHalf>>,
+    //每次接入新连接加1 断线只处理当前连接
+    generation: AtomicU64,
+    buffer: std::sync::Mutex<Option<ForwardBuffer>>,
+}
+
+impl ForwardOutput {
+    pub fn new(writer:Option<OwnedWriteHalf>) -> Self {
+        ForwardOutput {
+            writer: Mutex::new(writer),
+            generation: AtomicU64::new(0),
+            buffer: std::sync::Mutex::new(None),
+        }
+    }
+
+    //开启断线缓存 max_size为0时不缓存
+    pub fn enable_buffer(&self, max_size:usize, max_age:Duration) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if max_size > 0 && buffer.is_none() {
+            *buffer = Some(ForwardBuffer::new(max_size, max_age));
+        }
+    }
+
+    pub fn is_buffered(&self) -> bool {
+        self.buffer.lock().unwrap().is_some()
+    }
+
+    pub fn buffer_len(&self) -> usize {
+        self.buffer.lock().unwrap().as_ref().map_or(0, |t| t.len())
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    //控制指令 未连接时返回错误
+    pub async fn send(&self, buf:&[u8]) -> io::Result<()> {
+        match self.writer.lock().await.as_mut() {
+            Some(writer) => writer.write_all(buf).await,
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    //转发数据 写失败或未连接时写入缓存
+    pub async fn forward(&self, buf:&Bytes) {
+        let mut writer = self.writer.lock().await;
+        if let Some(w) = writer.as_mut() {
+            match w.write_all(buf).await {
+                Ok(_) => return,
+                Err(err) => {
+                    log::info!("[service-forward]write failed:{}", err);
+                    *writer = None;
+                },
+            }
+        }
+
+        if let Some(buffer) = self.buffer.lock().unwrap().as_mut() {
+            buffer.push(buf.clone());
+        }
+    }
+
+    //取出当前连接 用于转交给其他会话
+    pub async fn take_writer(&self) -> Option<OwnedWriteHalf> {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.writer.lock().await.take()
+    }
+
+    //接入新连接 先补发缓存 返回连接代数
+    pub async fn attach(&self, mut new_writer:OwnedWriteHalf) -> io::Result<u64> {
+        let mut writer = self.writer.lock().await;
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        *writer = None;
+
+        let mut count = 0;
+        loop {
+            let buf = match self.buffer.lock().unwrap().as_mut().and_then(|t| t.front()) {
+                Some(buf) => buf,
+                None => break,
+            };
+            new_writer.write_all(&buf).await?;
+            if let Some(buffer) = self.buffer.lock().unwrap().as_mut() {
+                buffer.pop_front();
+            }
+            count += 1;
+        }
+        if count > 0 {
+            log::info!("[service-forward]replay buffered:{}", count);
+        }
+
+        *writer = Some(new_writer);
+        Ok(generation)
+    }
+
+    //连接断开 返回是否为当前连接
+    pub async fn detach(&self, generation:u64) -> bool {
+        let mut writer = self.writer.lock().await;
+        if self.generation() != generation {
+            return false;
+        }
+        *writer = None;
+        true
+    }
+
+    //断线后未重连
+    pub async fn is_detached(&self, generation:u64) -> bool {
+        let writer = self.writer.lock().await;
+        self.generation() == generation && writer.is_none()
+    }
+}
+
+#[test]
+fn test_forward_buffer() {
+    let mut buffer = ForwardBuffer::new(10, Duration::from_secs(60));
+    buffer.push(Bytes::from_static(b"1234"));
+    buffer.push(Bytes::from_static(b"5678"));
+    buffer.push(Bytes::from_static(b"90ab"));
+    //超出大小淘汰最旧
+    assert_eq!(buffer.len(), 2);
+    assert_eq!(buffer.front().unwrap().as_ref(), b"5678");
+    buffer.pop_front();
+    assert_eq!(buffer.front().unwrap().as_ref(), b"90ab");
+
+    let mut buffer = ForwardBuffer::new(10, Duration::from_secs(0));
+    buffer.push(Bytes::from_static(b"1234"));
+    std::thread::sleep(Duration::from_millis(2));
+    assert!(buffer.front().is_none());
+}
@@ -0,0 +1,87 @@
+use std::collections::HashSet;
+
+use jt808::models::Jt808;
+
+/// 过滤模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    /// 不过滤
+    All,
+    /// 只转发列表内的消息ID
+    Include,
+    /// 不转发列表内的消息ID
+    Exclude,
+}
+
+/// 转发订阅的消息过滤
+#[derive(Debug, Clone)]
+pub struct ForwardFilter {
+    pub mode: FilterMode,
+    pub ids: HashSet<u16>,
+    /// 0x0200只转发有报警位的
+    pub alarm_only: bool,
+}
+
+impl ForwardFilter {
+
+    pub fn new() -> Self {
+        ForwardFilter {
+            mode: FilterMode::All,
+            ids: HashSet::new(),
+            alarm_only: false,
+        }
+    }
+
+    pub fn accept(&self, id:u16, alarm:u32) -> bool {
+        let pass = match self.mode {
+            FilterMode::All => true,
+            FilterMode::Include => self.ids.contains(&id),
+            FilterMode::Exclude => !self.ids.contains(&id),
+        };
+
+        if pass && self.alarm_only && id == 0x0200 {
+            return alarm != 0;
+        }
+        pass
+    }
+
+    //0x0200报警标志 其他消息为0
+    pub fn get_alarm(jt808:&Jt808) -> u32 {
+        if jt808.id != 0x0200 {
+            return 0;
+        }
+        let body = jt808.get_body();
+        if body.len() < 4 {
+            return 0;
+        }
+        u32::from_be_bytes([body[0], body[1], body[2], body[3]])
+    }
+}
+
+impl Default for ForwardFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[test]
+fn test_forward_filter()
+{
+    let mut filter = ForwardFilter::new();
+    assert!(filter.accept(0x0200, 0));
+
+    filter.mode = FilterMode::Include;
+    filter.ids.insert(0x0200);
+    filter.ids.insert(0x1205);
+    assert!(filter.accept(0x1205, 0));
+    assert!(!filter.accept(0x0704, 0));
+
+    filter.alarm_only = true;
+    assert!(!filter.accept(0x0200, 0));
+    assert!(filter.accept(0x0200, 0x01));
+
+    filter.mode = FilterMode::Exclude;
+    assert!(!filter.accept(0x1205, 0));
+    assert!(filter.accept(0x0704, 0));
+}
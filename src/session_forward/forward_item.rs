@@ -2,22 +2,26 @@ use std::sync::Arc;
 
 use bytes::Bytes;
 use jt808::JtSubMerger;
-use tokio::{sync::{Mutex, RwLock}, net::tcp::OwnedWriteHalf, io::AsyncWriteExt};
+use tokio::sync::RwLock;
 
-use crate::session808::jt808_session::Jt808SessionShared;
+use crate::session808::{jt808_session::Jt808SessionShared, jt808_parse::jt808_sub_end};
+
+use super::{forward_filter::ForwardFilter, forward_buffer::ForwardOutput};
 
 
 pub struct ForwardItem {
     device_session:RwLock<Option<Arc<Jt808SessionShared>>>,
-    sender:Arc<Mutex<OwnedWriteHalf>>, 
+    sender:Arc<ForwardOutput>,
+    filter:Arc<std::sync::RwLock<ForwardFilter>>,
 }
 
 impl ForwardItem {
 
-    pub fn new(sender:Arc<Mutex<OwnedWriteHalf>>) -> Self {
+    pub fn new(sender:Arc<ForwardOutput>, filter:Arc<std::sync::RwLock<ForwardFilter>>) -> Self {
         ForwardItem{
             device_session:RwLock::new(None),
-            sender
+            sender,
+            filter
         }
     }
     
@@ -34,19 +38,26 @@ impl ForwardItem {
         } 
     }
 
+    //是否同一转发连接
+    pub fn same_output(&self, other:&ForwardItem) -> bool {
+        Arc::ptr_eq(&self.sender, &other.sender)
+    }
+
+    //是否需要转发
+    pub fn accept(&self, id:u16, alarm:u32) -> bool {
+        self.filter.read().unwrap().accept(id, alarm)
+    }
+
     pub async fn forward_send_bytes(&self, buf:&Bytes) {
-        let _ = self.sender.lock().await.write_all(&buf);
+        self.sender.forward(buf).await;
     }
 
     pub async fn forward_send(&self, jtsub:&mut JtSubMerger) {
-        match jtsub.end() {
-            Some(packages) => {
-                for item in packages {
-                    let _ = self.sender.lock().await.write_all(&item.get_bytes());
-                }
-            },
-            None => {},
+        if let Some(packages) = jt808_sub_end(jtsub) {
+            for item in packages {
+                self.sender.forward(&item.get_bytes()).await;
+            }
         }
     }
 
-}
\ No newline at end of file
+}
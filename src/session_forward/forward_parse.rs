@@ -1,17 +1,30 @@
-use bytes::{BytesMut, Buf};
+use bytes::{BytesMut, Buf, Bytes, BufMut};
 use jt808::{models::Jt808, JtSubMerger};
 use jt_util::bytes_bcd::BytesBCD;
 
 use crate::session808::jt808_parse::Jt808PackUp;
 
+use super::forward_filter::{ForwardFilter, FilterMode};
 
+
+/// 通配订阅 sim长度为0
+pub const ALL_SIMS: &str = "*";
+
+/// 控制指令封包最大长度(2字节长度)
+const CMD_MAX: usize = u16::MAX as usize;
+
+#[derive(Debug)]
 pub enum ForwardCodecError {
     /// 非转发封包格式
     NotForwardProtocol,
+    /// 打包时字段超长或sim格式错误
+    InvalidField,
 }
 
 pub enum ReturnType {
+    Login(String, String),
     Cmd(u8, Vec<String>),
+    Filter(ForwardFilter),
     Data(JtSubMerger)
 }
 
@@ -29,76 +42,274 @@ impl ForwardParse {
         }
     }
 
+    /// Ok(None)表示数据不足
     pub fn parse(&mut self, buf: &mut BytesMut) -> Result<Option<ReturnType>, ForwardCodecError>  {
-        
-        if self.package_size == 0 {
-            if buf.len() < 2 {
-                return Ok(None);
+
+        loop {
+            if self.package_size == 0 {
+                if buf.len() < 2 {
+                    return Ok(None);
+                }
+                self.package_size = buf.get_u16().into();
             }
-            self.package_size = buf.get_u16().into();
+
             if buf.len() < self.package_size {
                 return Ok(None);
             }
-        }
 
-        if buf.len() < self.package_size {
-            return Ok(None);
-        }
+            let mut data = buf.split_to(self.package_size);
+            self.package_size = 0;
 
-        if buf[0] == 0xff && buf[1] == 0xff && buf[2] == 0xff {
-            
-            if buf[3] == 0x01 || buf[3] == 0x02 || buf[3] == 0x03 {
-                let cmd_type = buf[3];
-                buf.advance(4);
+            if data.len() >= 4 && data[0] == 0xff && data[1] == 0xff && data[2] == 0xff {
+                let cmd_type = data[3];
+                data.advance(4);
 
-                let mut parse_size = 4;
-                loop {
-                    if buf.len() < 2 {
-                        return Err(ForwardCodecError::NotForwardProtocol);
-                    }
-                    let sim_size_reading = buf.get_u16().into();
-                    parse_size += 2;
-                    if buf.len() < sim_size_reading {
-                        return Err(ForwardCodecError::NotForwardProtocol);
-                    }
-                    let sim_bcd = buf.split_to(sim_size_reading);
-                    if sim_size_reading == 0 {
-                        return Err(ForwardCodecError::NotForwardProtocol);
-                    }
-                    parse_size += sim_size_reading;
-        
-                    let sim = BytesBCD::get_string(sim_bcd.into());
-                    let mut list_sims = Vec::new();
-                    list_sims.push(sim);
-
-                    self.package_size = 0;
-                    if parse_size == self.package_size {
-                        return Ok(Some(ReturnType::Cmd(cmd_type, list_sims)));
-                    } else if parse_size > self.package_size {
-                        return Err(ForwardCodecError::NotForwardProtocol);
+                match cmd_type {
+                    0x00 => {
+                        let (client_id, password) = ForwardParse::parse_login(data)?;
+                        return Ok(Some(ReturnType::Login(client_id, password)));
+                    },
+                    0x01..=0x03 => {
+                        return Ok(Some(ReturnType::Cmd(cmd_type, ForwardParse::parse_sims(data)?)));
+                    },
+                    0x04 => {
+                        return Ok(Some(ReturnType::Filter(ForwardParse::parse_filter(data)?)));
+                    },
+                    _ => {
+                        //未知指令丢弃
                     }
                 }
+            } else if data.len() >= 15 && (data[3] & 0x40 == 0 || data.len() >= 20) {
+                //7E开头的808原始包(2019版头更长)
+                let jt808: Jt808 = Jt808::from(data.freeze());
 
+                if let Some(jtsub) = self.jt808_packup.get_sub_merger(jt808) {
+                    return Ok(Some(ReturnType::Data(jtsub)));
+                }
             } else {
-                buf.advance( self.package_size);
+                return Err(ForwardCodecError::NotForwardProtocol);
             }
-        } else {
-            let data = buf.split_to(self.package_size);
-            let jt808: Jt808 = Jt808::from(data.freeze());
+        }
+    }
 
-            match self.jt808_packup.get_sub_merger(jt808) {
-                Some(jtsub) => {
-                    self.package_size = 0;
-                    return Ok(Some(ReturnType::Data(jtsub)));
-                },
-                None => {
+    //[2字节长度][客户端ID][2字节长度][密码]
+    fn parse_login(mut data: BytesMut) -> Result<(String, String), ForwardCodecError> {
+        let mut fields = Vec::with_capacity(2);
+        for _ in 0..2 {
+            if data.len() < 2 {
+                return Err(ForwardCodecError::NotForwardProtocol);
+            }
+            let size: usize = data.get_u16().into();
+            if data.len() < size {
+                return Err(ForwardCodecError::NotForwardProtocol);
+            }
+            fields.push(String::from_utf8_lossy(&data.split_to(size)).to_string());
+        }
+        let password = fields.pop().unwrap();
+        let client_id = fields.pop().unwrap();
+        Ok((client_id, password))
+    }
+
+    //[2字节长度][bcdsim]... 长度为0表示全部sim
+    fn parse_sims(mut data: BytesMut) -> Result<Vec<String>, ForwardCodecError> {
+        let mut list_sims = Vec::new();
+        while data.has_remaining() {
+            if data.len() < 2 {
+                return Err(ForwardCodecError::NotForwardProtocol);
+            }
+            let sim_size: usize = data.get_u16().into();
+            if sim_size == 0 {
+                list_sims.push(ALL_SIMS.to_string());
+                continue;
+            }
+            if data.len() < sim_size {
+                return Err(ForwardCodecError::NotForwardProtocol);
+            }
+            let sim_bcd = data.split_to(sim_size);
+            list_sims.push(BytesBCD::get_string(sim_bcd.freeze()));
+        }
+        Ok(list_sims)
+    }
+
+    //[1字节模式 0:不过滤 1:包含 2:排除][1字节 0x0200仅报警][2字节消息ID]...
+    fn parse_filter(mut data: BytesMut) -> Result<ForwardFilter, ForwardCodecError> {
+        if data.len() < 2 || !data.len().is_multiple_of(2) {
+            return Err(ForwardCodecError::NotForwardProtocol);
+        }
+        let mode = match data.get_u8() {
+            0 => FilterMode::All,
+            1 => FilterMode::Include,
+            2 => FilterMode::Exclude,
+            _ => return Err(ForwardCodecError::NotForwardProtocol),
+        };
+        let alarm_only = data.get_u8() > 0;
+
+        let mut filter = ForwardFilter::new();
+        filter.mode = mode;
+        filter.alarm_only = alarm_only;
+        while data.has_remaining() {
+            filter.ids.insert(data.get_u16());
+        }
+        Ok(filter)
+    }
+
+    pub fn pack_login(client_id:&str, password:&str) -> Result<Bytes, ForwardCodecError> {
+        let mut body = BytesMut::new();
+        for field in [client_id, password] {
+            let size = u16::try_from(field.len()).map_err(|_| ForwardCodecError::InvalidField)?;
+            body.put_u16(size);
+            body.put_slice(field.as_bytes());
+        }
+        ForwardParse::pack_cmd(0x00, body)
+    }
 
-                },
+    /// sim表超过单个封包长度时拆分为多个封包 重置(0x01)之后的封包为添加(0x02)
+    pub fn pack_sims(cmd_type:u8, sims:&[String]) -> Result<Bytes, ForwardCodecError> {
+        let mut buf = BytesMut::new();
+        let mut body = BytesMut::new();
+        let mut cmd_type = cmd_type;
+        for sim in sims {
+            let bcd = ForwardParse::sim_bcd(sim)?;
+            if body.len() + 2 + bcd.len() + 4 > CMD_MAX {
+                buf.put(ForwardParse::pack_cmd(cmd_type, body.split())?);
+                if cmd_type == 0x01 {
+                    cmd_type = 0x02;
+                }
             }
+            body.put_u16(bcd.len() as u16);
+            body.put_slice(&bcd);
+        }
+        if !body.is_empty() || buf.is_empty() {
+            buf.put(ForwardParse::pack_cmd(cmd_type, body)?);
         }
+        Ok(buf.freeze())
+    }
+
+    //数字sim转bcd 奇数位前面补0 通配为空
+    fn sim_bcd(sim:&str) -> Result<Vec<u8>, ForwardCodecError> {
+        if sim == ALL_SIMS {
+            return Ok(Vec::new());
+        }
+        if sim.is_empty() || sim.len() > 20 || !sim.bytes().all(|t| t.is_ascii_digit()) {
+            return Err(ForwardCodecError::InvalidField);
+        }
+        let digits: Vec<u8> = match sim.len() % 2 {
+            1 => std::iter::once(0).chain(sim.bytes().map(|t| t - b'0')).collect(),
+            _ => sim.bytes().map(|t| t - b'0').collect(),
+        };
+        Ok(digits.chunks(2).map(|t| t[0] << 4 | t[1]).collect())
+    }
+
+    pub fn pack_filter(filter:&ForwardFilter) -> Result<Bytes, ForwardCodecError> {
+        let mut body = BytesMut::new();
+        body.put_u8(match filter.mode {
+            FilterMode::All => 0,
+            FilterMode::Include => 1,
+            FilterMode::Exclude => 2,
+        });
+        body.put_u8(filter.alarm_only.into());
+        for id in &filter.ids {
+            body.put_u16(*id);
+        }
+        ForwardParse::pack_cmd(0x04, body)
+    }
+
+    fn pack_cmd(cmd_type:u8, body:BytesMut) -> Result<Bytes, ForwardCodecError> {
+        if body.len() + 4 > CMD_MAX {
+            return Err(ForwardCodecError::InvalidField);
+        }
+        let mut buf = BytesMut::with_capacity(body.len() + 6);
+        buf.put_u16((body.len() + 4) as u16);
+        buf.put_slice(&[0xff, 0xff, 0xff, cmd_type]);
+        buf.put(body);
+        Ok(buf.freeze())
+    }
+
+}
+
+impl Default for ForwardParse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 
-        self.package_size = 0;
-        return Ok(None);
+#[test]
+fn test_parse_filter()
+{
+    let mut parse = ForwardParse::new();
+    let mut buf = BytesMut::from(&[0x00, 0x0a, 0xff, 0xff, 0xff, 0x04, 0x01, 0x01, 0x02, 0x00, 0x12, 0x05][..]);
+
+    match parse.parse(&mut buf) {
+        Ok(Some(ReturnType::Filter(filter))) => {
+            assert_eq!(filter.mode, FilterMode::Include);
+            assert!(filter.alarm_only);
+            assert!(filter.ids.contains(&0x0200));
+            assert!(filter.ids.contains(&0x1205));
+        },
+        _ => panic!("filter frame not parsed"),
     }
+    assert!(buf.is_empty());
+}
 
-}
\ No newline at end of file
+#[test]
+fn test_pack_and_parse()
+{
+    let mut parse = ForwardParse::new();
+    let mut buf = BytesMut::new();
+    buf.put(ForwardParse::pack_login("platform1", "123456").unwrap());
+    buf.put(ForwardParse::pack_sims(0x01, &["013800000000".to_string(), "013800000001".to_string()]).unwrap());
+
+    match parse.parse(&mut buf) {
+        Ok(Some(ReturnType::Login(client_id, password))) => {
+            assert_eq!(client_id, "platform1");
+            assert_eq!(password, "123456");
+        },
+        _ => panic!("login frame not parsed"),
+    }
+    match parse.parse(&mut buf) {
+        Ok(Some(ReturnType::Cmd(0x01, sims))) => {
+            assert_eq!(sims, vec!["013800000000".to_string(), "013800000001".to_string()]);
+        },
+        _ => panic!("sim frame not parsed"),
+    }
+}
+
+#[test]
+fn test_wildcard_sims()
+{
+    let mut parse = ForwardParse::new();
+    let mut buf = BytesMut::new();
+    buf.put(ForwardParse::pack_sims(0x02, &[ALL_SIMS.to_string(), "013800000000".to_string()]).unwrap());
+
+    match parse.parse(&mut buf) {
+        Ok(Some(ReturnType::Cmd(0x02, sims))) => {
+            assert_eq!(sims, vec![ALL_SIMS.to_string(), "013800000000".to_string()]);
+        },
+        _ => panic!("sim frame not parsed"),
+    }
+}
+
+#[test]
+fn test_pack_limits()
+{
+    //奇数位sim前面补0
+    assert_eq!(ForwardParse::pack_sims(0x02, &["13800000000".to_string()]).unwrap(), ForwardParse::pack_sims(0x02, &["013800000000".to_string()]).unwrap());
+    assert!(ForwardParse::pack_sims(0x02, &["1380000000a".to_string()]).is_err());
+    assert!(ForwardParse::pack_login(&"a".repeat(70000), "").is_err());
+
+    //超过单个封包长度拆分 重置之后为添加
+    let sims: Vec<String> = (0..10000).map(|i| format!("{:012}", 13800000000u64 + i)).collect();
+    let mut buf = BytesMut::from(&ForwardParse::pack_sims(0x01, &sims).unwrap()[..]);
+    let mut parse = ForwardParse::new();
+    let mut parsed = Vec::new();
+    let mut cmd_types = Vec::new();
+    while let Ok(Some(ReturnType::Cmd(cmd_type, list))) = parse.parse(&mut buf) {
+        cmd_types.push(cmd_type);
+        parsed.extend(list);
+    }
+    assert!(cmd_types.len() > 1);
+    assert_eq!(cmd_types[0], 0x01);
+    assert!(cmd_types[1..].iter().all(|t| *t == 0x02));
+    assert_eq!(parsed, sims);
+}
@@ -1,98 +1,117 @@
-use std::{collections::HashMap, sync::{Arc, atomic::{Ordering, AtomicI32}}};
+use std::{collections::HashMap, sync::Arc};
 use jt808::JtSubMerger;
-use tokio::{sync::{RwLock, Mutex}, net::tcp::OwnedWriteHalf};
+use tokio::{sync::RwLock, net::tcp::OwnedWriteHalf};
 
-use super::forward_item::ForwardItem;
+use crate::{service_forward::ServiceForward, service_device};
 
-//UPDATE 有sim列表更新加1
-pub static UPDATE: AtomicI32 = AtomicI32::new(0);
+use super::{forward_item::ForwardItem, forward_filter::ForwardFilter, forward_buffer::ForwardOutput, forward_parse::ALL_SIMS};
 
 pub struct ForwardSession {
-    sender:Arc<Mutex<OwnedWriteHalf>>, 
+    sender:Arc<ForwardOutput>,
     map_sims:RwLock<HashMap<String, Arc<ForwardItem>>>,
+    filter:Arc<std::sync::RwLock<ForwardFilter>>,
+    //登录的客户端ID
+    client_id:std::sync::Mutex<String>,
 }
 
 impl ForwardSession {
 
     pub fn new(sender:OwnedWriteHalf) -> Self {
+        Self::new_with(Some(sender))
+    }
+
+    //未连接的会话 连接后通过output().attach接入
+    pub fn new_with(sender:Option<OwnedWriteHalf>) -> Self {
         ForwardSession{
-            sender: Arc::new(Mutex::new(sender)),
+            sender: Arc::new(ForwardOutput::new(sender)),
             map_sims: RwLock::new(HashMap::new()),
+            filter: Arc::new(std::sync::RwLock::new(ForwardFilter::new())),
+            client_id: std::sync::Mutex::new(String::new()),
         }
     }
 
+    pub fn output(&self) -> &Arc<ForwardOutput> {
+        &self.sender
+    }
+
+    pub fn get_client_id(&self) -> String {
+        self.client_id.lock().unwrap().clone()
+    }
+
+    pub fn set_client_id(&self, client_id:&str) {
+        *self.client_id.lock().unwrap() = client_id.to_string();
+    }
+
     //处理数据(808)
     pub async fn handle_data(&self, mut jtsub:JtSubMerger) {
 
         if let Some(jt808) = jtsub.get_first_jt() {
-            match self.map_sims.read().await.get(&jt808.sim.to_string()) {
-                Some(forward_item) => {
-                    ForwardItem::forward_recv(forward_item, jtsub).await;
-                },
-                None => {
-    
-                },
+            let sim = jt808.sim.to_string();
+            let map_sims = self.map_sims.read().await;
+            if let Some(forward_item) = map_sims.get(&sim) {
+                ForwardItem::forward_recv(forward_item, jtsub).await;
+            } else if let Some(forward_item) = map_sims.get(ALL_SIMS) {
+                //通配订阅 按sim查找在线设备
+                if let Some(device) = service_device::get_sender(&sim).await {
+                    device.forward_recv(&mut jtsub, forward_item).await;
+                }
             }
         }
     }
     //处理指令
-    pub async fn handle_cmd(&self, cmd_type:u8, sims:Vec<String>) {
+    pub async fn handle_cmd(&self, service:&ServiceForward, cmd_type:u8, sims:Vec<String>) {
         if cmd_type == 0x01 {
-            self.clear().await;
+            self.clear(service).await;
         }
 
         if cmd_type == 0x01 || cmd_type == 0x02 {
-            self.add(sims).await;
+            self.add(service, sims).await;
         } else if cmd_type == 0x03 {
-            self.sub(sims).await;
+            self.sub(service, sims).await;
         }
     }
 
-    async fn clear(&self) {
-        let mut map_sims = self.map_sims.write().await;
-        map_sims.clear();
+    //发送控制指令
+    pub async fn send_bytes(&self, buf:&[u8]) -> std::io::Result<()> {
+        self.sender.send(buf).await
+    }
 
-        UPDATE.fetch_add(1, Ordering::Relaxed);
+    //设置消息过滤 对该连接所有sim生效
+    pub fn handle_filter(&self, filter:ForwardFilter) {
+        log::info!("[service-forward]set filter:{:?}", filter);
+        *self.filter.write().unwrap() = filter;
     }
 
-    async fn add(&self, sims:Vec<String>) {
-        let mut map_sims = self.map_sims.write().await;
-        let mut is_down = false;
-        for sim in sims {
-            if map_sims.insert(sim, Arc::new(ForwardItem::new(self.sender.clone()))).is_some() {
-                is_down = true;
-            }
-        }
+    //连接断开 取消所有订阅
+    pub async fn close(&self, service:&ServiceForward) {
+        self.clear(service).await;
+    }
 
-        if is_down {
-            UPDATE.fetch_add(1, Ordering::Relaxed);
+    async fn clear(&self, service:&ServiceForward) {
+        let mut map_sims = self.map_sims.write().await;
+        for (sim, item) in map_sims.drain() {
+            service.unsubscribe(&sim, &item);
         }
     }
 
-    async fn sub(&self, sims:Vec<String>) {
+    async fn add(&self, service:&ServiceForward, sims:Vec<String>) {
         let mut map_sims = self.map_sims.write().await;
-        let mut is_down = false;
         for sim in sims {
-            if map_sims.remove(&sim).is_some() {
-                is_down = true;
+            let item = Arc::new(ForwardItem::new(self.sender.clone(), self.filter.clone()));
+            if let Some(old) = map_sims.insert(sim.clone(), item.clone()) {
+                service.unsubscribe(&sim, &old);
             }
-        }
-
-        if is_down {
-            UPDATE.fetch_add(1, Ordering::Relaxed);
+            service.subscribe(&sim, item).await;
         }
     }
 
-    pub async fn get_item(&self, sim:&String) -> Option<(Arc<ForwardItem>, i32)> {
-        let map_sims = self.map_sims.read().await;
-        match map_sims.get(sim) {
-            Some(item) => {
-                return Some((item.clone(), 0));
-            },
-            None => {
-                return None;
-            },
+    async fn sub(&self, service:&ServiceForward, sims:Vec<String>) {
+        let mut map_sims = self.map_sims.write().await;
+        for sim in sims {
+            if let Some(item) = map_sims.remove(&sim) {
+                service.unsubscribe(&sim, &item);
+            }
         }
     }
 
-}
\ No newline at end of file
+}
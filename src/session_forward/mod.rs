@@ -1,3 +1,5 @@
+pub mod forward_buffer;
+pub mod forward_filter;
 pub mod forward_item;
 pub mod forward_parse;
 pub mod forward_session;
@@ -0,0 +1 @@
+pub mod passthrough_link;
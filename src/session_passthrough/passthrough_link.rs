@@ -0,0 +1,292 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+
+use bytes::Bytes;
+use jt808::{models::{Jt808, Jt0x0102, Jt808BodySerialize, Ver808}, JtPackage, JtSubMerger};
+use jt_util::{bytes::IBuffWrite, bytes_gbk::BytesGBK};
+use tokio::{net::{TcpStream, tcp::OwnedWriteHalf}, sync::{Mutex, Notify}, io::{AsyncReadExt, AsyncWriteExt}};
+
+use crate::{service_passthrough::ServicePassthrough, session808::{jt808_session::Jt808SessionShared, jt808_parse::{Jt808DeserializeAndPackUp, jt808_repack}}};
+
+//带应答流水号的终端应答消息 消息体前2字节为应答流水号
+const ANSWER_IDS: [u16; 9] = [0x0001, 0x0104, 0x0201, 0x0302, 0x0500, 0x0700, 0x0805, 0x1205, 0x1206];
+//流水号映射上限 超过时淘汰超过保存时长的 仍然满时淘汰最旧的
+const SN_MAP_MAX: usize = 4096;
+const SN_MAP_AGE: Duration = Duration::from_secs(300);
+
+/// 原始消息体
+pub struct RawBody(pub Bytes);
+
+impl Jt808BodySerialize for RawBody {
+    fn write(&mut self, _ver: &Ver808, buf: &mut dyn IBuffWrite) {
+        buf.put(self.0.clone());
+    }
+
+    fn len(&self, _ver: &Ver808) -> usize {
+        self.0.len()
+    }
+}
+
+/// 单个sim到第三方808平台的连接
+pub struct PassthroughLink {
+    service: Arc<ServicePassthrough>,
+    address: String,
+    sim: String,
+    //上级平台方向的流水号
+    package: JtPackage,
+    device: Arc<Jt808SessionShared>,
+    sender: Mutex<Option<OwnedWriteHalf>>,
+    authed: AtomicBool,
+    closed: AtomicBool,
+    close_notify: Notify,
+    //设备流水号 -> (上级平台流水号, 登记时间)
+    sn_map: std::sync::Mutex<HashMap<u16, (u16, Instant)>>,
+}
+
+impl PassthroughLink {
+    pub fn new(service:Arc<ServicePassthrough>, address:String, sim:String, package:JtPackage, device:Arc<Jt808SessionShared>) -> Self {
+        PassthroughLink {
+            service,
+            address,
+            sim,
+            package,
+            device,
+            sender: Mutex::new(None),
+            authed: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            close_notify: Notify::new(),
+            sn_map: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn start(link:Arc<PassthroughLink>) {
+        tokio::spawn(async move {
+            let mut backoff = 1;
+            while !link.is_closed() {
+                match TcpStream::connect(&link.address).await {
+                    Ok(socket) => {
+                        log::info!("[service-passthrough]connected sim:{} target:{}", link.sim, link.address);
+                        backoff = 1;
+
+                        let (mut reader, writer) = socket.into_split();
+                        *link.sender.lock().await = Some(writer);
+                        link.login().await;
+
+                        let mut jt808_parse = Jt808DeserializeAndPackUp::new();
+                        let mut buffer = bytes::BytesMut::with_capacity(4096);
+                        loop {
+                            let n = tokio::select! {
+                                ret = reader.read_buf(&mut buffer) => ret.unwrap_or(0),
+                                _ = link.close_notify.notified() => 0,
+                            };
+                            if n == 0 {
+                                break;
+                            }
+                            match link.handle_buffer(&mut jt808_parse, &mut buffer).await {
+                                Ok(_) => {},
+                                Err(_) => break,
+                            }
+                        }
+
+                        link.authed.store(false, Ordering::Relaxed);
+                        *link.sender.lock().await = None;
+                        log::info!("[service-passthrough]disconnect sim:{} target:{}", link.sim, link.address);
+                    },
+                    Err(err) => {
+                        log::warn!("[service-passthrough]connect sim:{} target:{} failed:{}", link.sim, link.address, err);
+                    },
+                }
+
+                if link.is_closed() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(backoff)).await;
+                backoff = std::cmp::min(backoff * 2, 60);
+            }
+        });
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.close_notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn credential_key(&self) -> String {
+        format!("{}@{}", self.sim, self.address)
+    }
+
+    //有鉴权码直接鉴权 否则使用终端注册信息注册
+    async fn login(&self) {
+        let credential = self.service.get_credential(&self.credential_key());
+        if !credential.auth_code.is_empty() {
+            self.send_auth(&credential.auth_code).await;
+        } else if !credential.register.is_empty() {
+            match hex::decode(&credential.register) {
+                Ok(body) => {
+                    self.send(0x0100, &mut RawBody(Bytes::from(body))).await;
+                },
+                Err(_) => {
+                    log::warn!("[service-passthrough]invalid register info sim:{}", self.sim);
+                },
+            }
+        } else {
+            log::warn!("[service-passthrough]no credential sim:{} target:{}, waiting for terminal register", self.sim, self.address);
+        }
+    }
+
+    async fn send_auth(&self, auth_code:&str) {
+        let mut jt0x0102 = Jt0x0102::default();
+        jt0x0102.authority_code.set_val(auth_code);
+        self.send(0x0102, &mut jt0x0102).await;
+    }
+
+    async fn send<T: Jt808BodySerialize>(&self, id:u16, body:&mut T) {
+        let buf = self.package.serialize(id, 0, body);
+        self.write(&buf).await;
+    }
+
+    async fn write(&self, buf:&[u8]) {
+        if let Some(writer) = self.sender.lock().await.as_mut() {
+            let _ = writer.write_all(buf).await;
+        }
+    }
+
+    //终端注册 记录注册信息 未鉴权且没有鉴权码时立即注册
+    pub async fn on_register(&self, body:&Bytes) {
+        let key = self.credential_key();
+        self.service.set_register(&key, &hex::encode(body));
+
+        if !self.authed.load(Ordering::Relaxed) && self.service.get_credential(&key).auth_code.is_empty() {
+            self.send(0x0100, &mut RawBody(body.clone())).await;
+        }
+    }
+
+    //终端上行 重新编号后透传 应答消息替换为上级平台流水号
+    pub async fn uplink(&self, packages:&VecDeque<Jt808>) {
+        if !self.authed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        for jt808 in packages {
+            let mut answer_sn = None;
+            if ANSWER_IDS.contains(&jt808.id) && jt808.package_index.unwrap_or(1) == 1 {
+                let body = jt808.get_body();
+                if body.len() >= 2 {
+                    answer_sn = self.sn_map.lock().unwrap().remove(&u16::from_be_bytes([body[0], body[1]])).map(|t| t.0);
+                }
+                //不是该平台下发指令的应答 不透传
+                if answer_sn.is_none() {
+                    continue;
+                }
+            }
+
+            let sn = self.package.distribute_sn(1);
+            self.write(&jt808_repack(jt808, sn, answer_sn)).await;
+        }
+    }
+
+    async fn handle_buffer(&self, jt808_parse:&mut Jt808DeserializeAndPackUp, buffer:&mut bytes::BytesMut) -> Result<(), ()> {
+        loop {
+            let len = buffer.len();
+            match jt808_parse.deserialize(buffer) {
+                Ok(Some(mut jtsub)) => {
+                    self.handle_downlink(&mut jtsub).await;
+                },
+                Ok(None) => {
+                    if buffer.is_empty() || buffer.len() == len {
+                        return Ok(());
+                    }
+                },
+                Err(_) => {
+                    log::info!("[service-passthrough]protocol error sim:{}", self.sim);
+                    return Err(());
+                },
+            }
+        }
+    }
+
+    //上级平台下行
+    async fn handle_downlink(&self, jtsub:&mut JtSubMerger) {
+        let jt808 = match jtsub.get_first_jt() {
+            Some(jt808) => jt808,
+            None => return,
+        };
+        let body = jt808.get_body();
+
+        match jt808.id {
+            0x8100 => { //注册应答
+                if body.len() < 3 {
+                    return;
+                }
+                let result = body[2];
+                if result == 0 {
+                    let auth_code = BytesGBK::new_with_bytes(body.slice(3..)).get_val();
+                    log::info!("[service-passthrough]register ok sim:{} target:{}", self.sim, self.address);
+                    self.service.set_auth_code(&self.credential_key(), &auth_code);
+                    self.send_auth(&auth_code).await;
+                } else {
+                    log::warn!("[service-passthrough]register failed sim:{} target:{} result:{}", self.sim, self.address, result);
+                }
+            },
+            0x8001 => { //平台通用应答
+                if body.len() < 5 {
+                    return;
+                }
+                let answer_id = u16::from_be_bytes([body[2], body[3]]);
+                let result = body[4];
+                if answer_id == 0x0102 {
+                    if result == 0 {
+                        log::info!("[service-passthrough]auth ok sim:{} target:{}", self.sim, self.address);
+                        self.authed.store(true, Ordering::Relaxed);
+                    } else {
+                        //鉴权码失效 重新注册
+                        log::warn!("[service-passthrough]auth failed sim:{} target:{} result:{}", self.sim, self.address, result);
+                        self.service.set_auth_code(&self.credential_key(), "");
+                        self.login().await;
+                    }
+                }
+                //透传上行的应答不下发终端
+            },
+            _ => {
+                let upstream_sn = jt808.sn;
+                self.device.passthrough_recv(jtsub, |sn| sn_map_insert(&mut self.sn_map.lock().unwrap(), sn, upstream_sn, Instant::now())).await;
+            }
+        }
+    }
+}
+
+fn sn_map_insert(sn_map:&mut HashMap<u16, (u16, Instant)>, sn:u16, upstream_sn:u16, now:Instant) {
+    if sn_map.len() >= SN_MAP_MAX && !sn_map.contains_key(&sn) {
+        sn_map.retain(|_, t| now.saturating_duration_since(t.1) < SN_MAP_AGE);
+        if sn_map.len() >= SN_MAP_MAX {
+            if let Some(oldest) = sn_map.iter().min_by_key(|(_, t)| t.1).map(|(k, _)| *k) {
+                sn_map.remove(&oldest);
+            }
+        }
+    }
+    sn_map.insert(sn, (upstream_sn, now));
+}
+
+
+#[test]
+fn test_sn_map()
+{
+    let start = Instant::now();
+    let mut sn_map = HashMap::new();
+    for sn in 0..SN_MAP_MAX as u16 {
+        sn_map_insert(&mut sn_map, sn, sn + 1, start + Duration::from_millis(sn as u64));
+    }
+    //满时淘汰最旧的 其他待应答的保留
+    sn_map_insert(&mut sn_map, 5000, 1, start + Duration::from_secs(1));
+    assert_eq!(sn_map.len(), SN_MAP_MAX);
+    assert!(!sn_map.contains_key(&0));
+    assert_eq!(sn_map.get(&1).map(|t| t.0), Some(2));
+
+    //超过保存时长的全部淘汰
+    sn_map_insert(&mut sn_map, 5001, 1, start + SN_MAP_AGE + Duration::from_secs(10));
+    assert_eq!(sn_map.len(), 1);
+    assert!(sn_map.contains_key(&5001));
+}